@@ -65,6 +65,7 @@ impl App {
         } else {
             PieceStats {
                 total: piece_count,
+                missing: piece_count,
                 latest_completed: Some(Vec::new()),
                 ..Default::default()
             }
@@ -79,9 +80,11 @@ impl App {
                 alerts: TorrentAlertConf {
                     completed_pieces: true,
                     peers: true,
+                    known_peers: false,
                 },
                 ..Default::default()
             }),
+            file_priorities: None,
         })?;
 
         let torrent = Torrent {
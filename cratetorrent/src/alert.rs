@@ -21,7 +21,9 @@
 
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+    error::Error, metainfo::Metainfo, torrent::stats::TorrentStats, TorrentId,
+};
 
 pub(crate) type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received. See [`Alert`]
@@ -32,8 +34,30 @@ pub type AlertReceiver = UnboundedReceiver<Alert>;
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Alert {
+    /// Posted when the metadata of a torrent created from a magnet link has
+    /// been downloaded. The torrent is started right after.
+    MetadataReceived {
+        id: TorrentId,
+        metainfo: Box<Metainfo>,
+    },
     /// Posted when the torrent has finished downloading.
     TorrentComplete(TorrentId),
+    /// Posted every second while the torrent's pieces on disk are being
+    /// checked against their hashes.
+    RecheckProgress {
+        id: TorrentId,
+        /// The number of pieces checked so far.
+        checked_piece_count: usize,
+        /// The number of pieces being checked.
+        piece_count: usize,
+    },
+    /// Posted when the check of the torrent's pieces on disk has finished.
+    RecheckComplete {
+        id: TorrentId,
+        /// The number of pieces we have after the check, in the whole
+        /// torrent.
+        own_piece_count: usize,
+    },
     /// Each running torrent sends an update of its latest statistics every
     /// second via this alert.
     TorrentStats {
@@ -0,0 +1,365 @@
+//! This module contains a small zero-copy bencode decoder.
+//!
+//! Most bencoded data is deserialized into typed structs with
+//! `serde_bencode`, but that loses the exact encoding of the input: keys that
+//! the structs don't model are dropped, and the rest may be re-encoded
+//! differently. Some values, such as the `info` dictionary of a metainfo file
+//! whose hash identifies the torrent, are needed exactly as they were
+//! encoded, which this decoder provides by borrowing all values and the raw
+//! bytes of dictionary entries from the input.
+
+use std::{convert::TryFrom, fmt};
+
+pub(crate) type Result<T> = std::result::Result<T, DecodeError>;
+
+/// The maximum nesting depth of lists and dictionaries. The input may be
+/// untrusted, and deeper values would risk overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+/// The error returned when the input is not valid bencode.
+#[derive(Debug, PartialEq)]
+pub(crate) enum DecodeError {
+    /// The input ended before the value did.
+    UnexpectedEof,
+    /// The byte at the position doesn't start or continue a value.
+    InvalidByte(usize),
+    /// An integer or a string length at the position is invalid.
+    InvalidNumber(usize),
+    /// The lists and dictionaries are nested too deep.
+    TooDeep,
+    /// There are bytes left after the value.
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use DecodeError::*;
+        match self {
+            UnexpectedEof => write!(fmt, "unexpected end of input"),
+            InvalidByte(pos) => write!(fmt, "invalid byte at {}", pos),
+            InvalidNumber(pos) => write!(fmt, "invalid number at {}", pos),
+            TooDeep => write!(fmt, "values nested too deep"),
+            TrailingBytes => write!(fmt, "trailing bytes after value"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A decoded value, which borrows its strings from the input.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value<'a> {
+    Int(i64),
+    Bytes(&'a [u8]),
+    List(Vec<Value<'a>>),
+    Dict(Dict<'a>),
+}
+
+impl<'a> Value<'a> {
+    /// Returns the value as a dictionary, if it is one.
+    pub fn as_dict(&self) -> Option<&Dict<'a>> {
+        match self {
+            Self::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an integer, if it is one.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Self::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as a string, if it is one.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            Self::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded dictionary, whose entries are kept in the order in which they
+/// were encoded.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Dict<'a> {
+    entries: Vec<Entry<'a>>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry<'a> {
+    key: &'a [u8],
+    value: Value<'a>,
+    /// The encoded value.
+    raw: &'a [u8],
+}
+
+impl<'a> Dict<'a> {
+    /// Returns the value of the key, or that of its first occurrence if it's
+    /// present more than once.
+    pub fn get(&self, key: &[u8]) -> Option<&Value<'a>> {
+        self.entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| &entry.value)
+    }
+
+    /// Returns the keys and values of the dictionary, in the order in which
+    /// they were encoded.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &Value<'a>)> {
+        self.entries.iter().map(|entry| (entry.key, &entry.value))
+    }
+
+    /// Returns the value of the key as it was encoded, or that of its first
+    /// occurrence if it's present more than once.
+    pub fn get_raw(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.entries
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| entry.raw)
+    }
+}
+
+/// Decodes the value that makes up the whole buffer.
+pub(crate) fn decode(buf: &[u8]) -> Result<Value<'_>> {
+    let (value, len) = decode_prefix(buf)?;
+    if len == buf.len() {
+        Ok(value)
+    } else {
+        Err(DecodeError::TrailingBytes)
+    }
+}
+
+/// Decodes the value at the start of the buffer, returning it along with its
+/// encoded length. The buffer may contain other data after the value.
+pub(crate) fn decode_prefix(buf: &[u8]) -> Result<(Value<'_>, usize)> {
+    let mut decoder = Decoder { buf, pos: 0 };
+    let value = decoder.decode_value(0)?;
+    Ok((value, decoder.pos))
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn decode_value(&mut self, depth: usize) -> Result<Value<'a>> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let n = self.decode_int(b'e')?;
+                Ok(Value::Int(n))
+            }
+            b'0'..=b'9' => self.decode_bytes().map(Value::Bytes),
+            b'l' => {
+                let depth = self.enter(depth)?;
+                let mut values = Vec::new();
+                while self.peek()? != b'e' {
+                    values.push(self.decode_value(depth)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(values))
+            }
+            b'd' => {
+                let depth = self.enter(depth)?;
+                let mut entries = Vec::new();
+                while self.peek()? != b'e' {
+                    let key = self.decode_bytes()?;
+                    let start = self.pos;
+                    let value = self.decode_value(depth)?;
+                    entries.push(Entry {
+                        key,
+                        value,
+                        raw: &self.buf[start..self.pos],
+                    });
+                }
+                self.pos += 1;
+                Ok(Value::Dict(Dict { entries }))
+            }
+            _ => Err(DecodeError::InvalidByte(self.pos)),
+        }
+    }
+
+    /// Skips the opening byte of a list or dictionary, returning the depth of
+    /// its values.
+    fn enter(&mut self, depth: usize) -> Result<usize> {
+        if depth >= MAX_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+        self.pos += 1;
+        Ok(depth + 1)
+    }
+
+    /// Decodes a string, which is its length, a colon, and the bytes.
+    fn decode_bytes(&mut self) -> Result<&'a [u8]> {
+        if !self.peek()?.is_ascii_digit() {
+            return Err(DecodeError::InvalidByte(self.pos));
+        }
+        let len_pos = self.pos;
+        let len = self.decode_int(b':')?;
+        let len = usize::try_from(len)
+            .map_err(|_| DecodeError::InvalidNumber(len_pos))?;
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(DecodeError::InvalidNumber(len_pos))?;
+        if end > self.buf.len() {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// Decodes a decimal integer ending with the terminator, which is
+    /// skipped.
+    fn decode_int(&mut self, terminator: u8) -> Result<i64> {
+        let start = self.pos;
+        let len = self.buf[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let digits = &self.buf[start..start + len];
+        let is_valid = match digits {
+            [b'-', rest @ ..] => {
+                !rest.is_empty() && rest.iter().all(u8::is_ascii_digit)
+            }
+            _ => !digits.is_empty() && digits.iter().all(u8::is_ascii_digit),
+        };
+        if !is_valid {
+            return Err(DecodeError::InvalidNumber(start));
+        }
+        // the digits are ASCII, so they are valid UTF-8
+        let n = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or(DecodeError::InvalidNumber(start))?;
+        self.pos = start + len + 1;
+        Ok(n)
+    }
+
+    fn peek(&self) -> Result<u8> {
+        self.buf
+            .get(self.pos)
+            .copied()
+            .ok_or(DecodeError::UnexpectedEof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Appends the encoding of the value to the buffer, with dictionary
+    /// entries in the order in which they were decoded.
+    fn encode(value: &Value, buf: &mut Vec<u8>) {
+        let encode_bytes = |bytes: &[u8], buf: &mut Vec<u8>| {
+            buf.extend_from_slice(bytes.len().to_string().as_bytes());
+            buf.push(b':');
+            buf.extend_from_slice(bytes);
+        };
+        match value {
+            Value::Int(n) => {
+                buf.extend_from_slice(format!("i{}e", n).as_bytes());
+            }
+            Value::Bytes(bytes) => encode_bytes(bytes, buf),
+            Value::List(values) => {
+                buf.push(b'l');
+                for value in values {
+                    encode(value, buf);
+                }
+                buf.push(b'e');
+            }
+            Value::Dict(dict) => {
+                buf.push(b'd');
+                for entry in dict.entries.iter() {
+                    encode_bytes(entry.key, buf);
+                    encode(&entry.value, buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+
+    #[test]
+    fn should_decode_values() {
+        assert_eq!(decode(b"i42e"), Ok(Value::Int(42)));
+        assert_eq!(decode(b"i-7e"), Ok(Value::Int(-7)));
+        assert_eq!(decode(b"4:spam"), Ok(Value::Bytes(b"spam")));
+        assert_eq!(decode(b"0:"), Ok(Value::Bytes(b"")));
+        assert_eq!(
+            decode(b"l4:spami1ee"),
+            Ok(Value::List(vec![Value::Bytes(b"spam"), Value::Int(1)]))
+        );
+
+        let value = decode(b"d1:bi1e1:ad1:xle1:y0:ee").unwrap();
+        let dict = value.as_dict().unwrap();
+        // the entries are kept in their original order, even if unsorted
+        let keys: Vec<_> = dict.entries.iter().map(|e| e.key).collect();
+        assert_eq!(keys, vec![&b"b"[..], b"a"]);
+        assert_eq!(dict.entries[0].value, Value::Int(1));
+        assert_eq!(dict.get_raw(b"b"), Some(&b"i1e"[..]));
+        assert_eq!(dict.get_raw(b"a"), Some(&b"d1:xle1:y0:e"[..]));
+        assert_eq!(dict.get_raw(b"c"), None);
+        assert_eq!(dict.get(b"b").and_then(Value::as_int), Some(1));
+        assert_eq!(dict.get(b"a").and_then(Value::as_bytes), None);
+        let keys: Vec<_> = dict.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec![&b"b"[..], b"a"]);
+    }
+
+    #[test]
+    fn should_decode_prefix() {
+        let (value, len) =
+            decode_prefix(b"d8:msg_typei1e5:piecei0eeRAWDATA").unwrap();
+        assert_eq!(len, 25);
+        assert!(value.as_dict().is_some());
+        assert_eq!(
+            decode(b"d8:msg_typei1e5:piecei0eeRAWDATA"),
+            Err(DecodeError::TrailingBytes)
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_values() {
+        assert_eq!(decode(b""), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"i42"), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"ie"), Err(DecodeError::InvalidNumber(1)));
+        assert_eq!(decode(b"i-e"), Err(DecodeError::InvalidNumber(1)));
+        assert_eq!(decode(b"i+1e"), Err(DecodeError::InvalidNumber(1)));
+        assert_eq!(decode(b"5:spam"), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"l4:spam"), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decode(b"di1ei2ee"), Err(DecodeError::InvalidByte(1)));
+        assert_eq!(decode(b"e"), Err(DecodeError::InvalidByte(0)));
+        assert_eq!(
+            decode(b"99999999999999999999999:"),
+            Err(DecodeError::InvalidNumber(0))
+        );
+
+        let mut deep = vec![b'l'; MAX_DEPTH + 1];
+        deep.extend(vec![b'e'; MAX_DEPTH + 1]);
+        assert_eq!(decode(&deep), Err(DecodeError::TooDeep));
+        assert!(decode(&deep[1..deep.len() - 1]).is_ok());
+    }
+
+    #[test]
+    fn should_encode_decoded_values_unchanged() {
+        let inputs: &[&[u8]] = &[
+            b"i0e",
+            b"i-123e",
+            b"3:\x00\xff\x01",
+            b"le",
+            b"de",
+            b"l4:spaml1:ai2eed3:key5:valueee",
+            // unsorted and duplicate keys are kept as they are
+            b"d1:zi1e1:ai2e1:ai3ee",
+        ];
+        for input in inputs {
+            let mut buf = Vec::new();
+            encode(&decode(input).unwrap(), &mut buf);
+            assert_eq!(&buf[..], *input);
+        }
+    }
+}
@@ -1,6 +1,10 @@
 //! This module defines types used to configure the engine and its parts.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use crate::PeerId;
 
@@ -24,6 +28,14 @@ impl Conf {
             engine: EngineConf {
                 client_id: *CRATETORRENT_CLIENT_ID,
                 download_dir: download_dir.into(),
+                // the port 0 tells the kernel to assign a free port from the
+                // dynamic range
+                listen_addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+                encryption: EncryptionPolicy::default(),
+                dht: None,
+                resume_dir: None,
+                download_rate_limit: None,
+                upload_rate_limit: None,
             },
             torrent: TorrentConf::default(),
         }
@@ -38,6 +50,98 @@ pub struct EngineConf {
     /// The directory in which a torrent's files are placed upon download and
     /// from which they are seeded.
     pub download_dir: PathBuf,
+    /// The address on which the engine listens for new peers. Peers are
+    /// passed to the torrent whose info hash they send in their handshake,
+    /// and rejected if there is no such torrent. A torrent may listen on its
+    /// own address instead, see [`crate::engine::TorrentParams::listen_addr`].
+    ///
+    /// If this is the unspecified IPv6 address, `::`, peers may connect over
+    /// both IPv4 and IPv6. If the host doesn't support IPv6, the engine falls
+    /// back to listening on IPv4 only.
+    ///
+    /// By default a free port is assigned on `::`, which can be queried with
+    /// [`crate::engine::EngineHandle::listen_addr`].
+    pub listen_addr: SocketAddr,
+    /// Whether connections with peers are encrypted.
+    ///
+    /// It is disabled by default.
+    pub encryption: EncryptionPolicy,
+    /// If set, the engine runs a DHT node with this configuration, through
+    /// which torrents find peers without trackers.
+    ///
+    /// It is not enabled by default. Use [`DhtConf::default`] for a node that
+    /// bootstraps from the well-known public routers.
+    pub dht: Option<DhtConf>,
+    /// If set, the state of each torrent is saved in this directory when the
+    /// torrent is stopped or completes, and restored from it when the torrent
+    /// is created again, so that its pieces don't have to be downloaded
+    /// again. Torrents are identified by their info hash.
+    ///
+    /// It is not set by default.
+    pub resume_dir: Option<PathBuf>,
+    /// The maximum number of payload bytes per second that all torrents may
+    /// download together. A limit of 0 is the same as no limit.
+    ///
+    /// It is not limited by default. It can be changed at runtime with
+    /// [`crate::engine::EngineHandle::set_rate_limits`].
+    pub download_rate_limit: Option<u64>,
+    /// The maximum number of payload bytes per second that all torrents may
+    /// upload together. A limit of 0 is the same as no limit.
+    ///
+    /// It is not limited by default.
+    pub upload_rate_limit: Option<u64>,
+}
+
+/// Whether connections with peers are encrypted with [Message Stream
+/// Encryption](https://wiki.vuze.com/w/Message_Stream_Encryption), which
+/// hides BitTorrent traffic from ISPs that throttle it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionPolicy {
+    /// Connections are never encrypted, and peers that want to encrypt the
+    /// connection are rejected.
+    Disabled,
+    /// We try to encrypt the connections we make, but fall back to plaintext
+    /// if the peer doesn't support encryption. Peers connecting to us may
+    /// encrypt the connection or not.
+    Enabled,
+    /// Only encrypted connections are allowed.
+    Required,
+}
+
+impl Default for EncryptionPolicy {
+    fn default() -> Self {
+        Self::Disabled
+    }
+}
+
+/// Configuration of the engine's DHT node.
+#[derive(Clone, Debug)]
+pub struct DhtConf {
+    /// The UDP address on which the DHT node listens for messages from other
+    /// nodes.
+    pub listen_addr: SocketAddr,
+    /// The nodes, as `host:port` strings, from which the DHT node joins the
+    /// network when it doesn't know any other nodes.
+    pub bootstrap_nodes: Vec<String>,
+    /// If set, the node's id and routing table are saved to this file and
+    /// restored from it on the next start, which makes joining the network
+    /// faster and doesn't depend on the bootstrap nodes.
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for DhtConf {
+    fn default() -> Self {
+        Self {
+            // this is the port most clients use for the DHT
+            listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 6881),
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".into(),
+                "dht.transmissionbt.com:6881".into(),
+                "router.utorrent.com:6881".into(),
+            ],
+            state_path: None,
+        }
+    }
 }
 
 /// Configuration for a torrent.
@@ -53,6 +157,10 @@ pub struct TorrentConf {
     /// The max number of connected peers the torrent should have.
     pub max_connected_peer_count: usize,
 
+    /// The number of peers we upload to at the same time. One of these slots
+    /// is used to optimistically unchoke a random peer.
+    pub upload_slot_count: usize,
+
     /// If the tracker doesn't provide a minimum announce interval, we default
     /// to announcing every 30 seconds.
     pub announce_interval: Duration,
@@ -60,6 +168,44 @@ pub struct TorrentConf {
     /// After this many attempts, the torrent stops announcing to a tracker.
     pub tracker_error_threshold: usize,
 
+    /// The time a torrent created from a magnet link has to download its
+    /// metadata from peers. If the metadata is not downloaded by then, the
+    /// torrent is removed and [`crate::error::TorrentError::MetadataTimeout`]
+    /// is posted.
+    pub metadata_timeout: Duration,
+
+    /// The interval at which trackers are scraped for the statistics of the
+    /// torrent's swarm, which are reported in the torrent stats alert. If
+    /// not set, trackers are not scraped.
+    pub scrape_interval: Option<Duration>,
+
+    /// If set, pieces are downloaded in order rather than rarest first. This
+    /// is useful for streaming media files while they are downloaded, but
+    /// hurts the health of the swarm, so it should only be used when needed.
+    pub sequential_download: bool,
+
+    /// If set, the pieces already on disk are checked against their hashes
+    /// when the torrent is added, and only those that fail the check are
+    /// downloaded. This overrides the pieces we'd otherwise assume to have,
+    /// based on the download mode or the resume data.
+    pub recheck_on_add: bool,
+
+    /// The maximum number of payload bytes per second that the torrent may
+    /// download. A limit of 0 is the same as no limit.
+    ///
+    /// This and the below limits can be changed at runtime with
+    /// [`crate::engine::EngineHandle::set_torrent_conf`].
+    pub download_rate_limit: Option<u64>,
+    /// The maximum number of payload bytes per second that the torrent may
+    /// upload. A limit of 0 is the same as no limit.
+    pub upload_rate_limit: Option<u64>,
+    /// The maximum number of payload bytes per second that the torrent may
+    /// download from each of its peers. A limit of 0 is the same as no limit.
+    pub peer_download_rate_limit: Option<u64>,
+    /// The maximum number of payload bytes per second that the torrent may
+    /// upload to each of its peers. A limit of 0 is the same as no limit.
+    pub peer_upload_rate_limit: Option<u64>,
+
     /// Specifies which optional alerts to send, besides the default periodic
     /// stats update.
     pub alerts: TorrentAlertConf,
@@ -84,6 +230,12 @@ pub struct TorrentAlertConf {
     /// when it is specifically needed, e.g. when the UI is showing the peers of
     /// a torrent.
     pub peers: bool,
+    /// Receive the torrent's peer list, with all peers it knows of, not just
+    /// the connected ones.
+    ///
+    /// Like the peer statistics, this may be relatively expensive and should
+    /// only be turned on when needed.
+    pub known_peers: bool,
 }
 
 impl Default for TorrentConf {
@@ -96,10 +248,26 @@ impl Default for TorrentConf {
             // This value is mostly picked for performance while keeping in mind
             // not to overwhelm the host.
             max_connected_peer_count: 50,
+            // This is the value used by the reference implementation, which is
+            // enough to saturate most upload links.
+            upload_slot_count: 4,
             // needs teting
             announce_interval: Duration::from_secs(60 * 60),
             // needs testing
             tracker_error_threshold: 15,
+            // peers of a live swarm usually send the metadata within seconds,
+            // but finding them via the DHT or trackers may take a while
+            metadata_timeout: Duration::from_secs(10 * 60),
+            // the swarm statistics don't change quickly and announces return
+            // most of them anyway
+            scrape_interval: Some(Duration::from_secs(30 * 60)),
+            sequential_download: false,
+            recheck_on_add: false,
+            // transfers are only limited by the network by default
+            download_rate_limit: None,
+            upload_rate_limit: None,
+            peer_download_rate_limit: None,
+            peer_upload_rate_limit: None,
             alerts: Default::default(),
         }
     }
@@ -90,6 +90,15 @@ impl Counter {
     // TODO: turn this into a const generic parameter once that's supported
     const WEIGHT: u64 = 5;
 
+    /// Creates a counter whose total starts at the given value, such as the
+    /// total restored from a previous session.
+    pub fn with_total(total: u64) -> Self {
+        Self {
+            total,
+            ..Default::default()
+        }
+    }
+
     /// Records some bytes that were transferred.
     pub fn add(&mut self, bytes: u64) {
         self.total += bytes;
@@ -0,0 +1,867 @@
+//! This module contains the engine's node in the mainline DHT, the
+//! distributed hash table of [BEP 5](http://bittorrent.org/beps/bep_0005.html)
+//! through which peers of a torrent are found without a tracker.
+//!
+//! The node is spawned as a separate task that communicates with other nodes
+//! over UDP. Torrents send it [`Command::GetPeers`] to look up the peers of
+//! their info hash and to announce themselves as peers, and it sends the
+//! peers it finds back to them.
+//!
+//! Each node has a random 160 bit id, and the node keeps the contact
+//! information of other nodes in its [routing table](routing::RoutingTable),
+//! knowing more nodes whose ids are close to its own. The peers of a torrent
+//! are stored by the nodes whose ids are closest to the info hash, which are
+//! found with an [iterative lookup](lookup::Lookup).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    select,
+    stream::{self, Fuse, StreamExt},
+};
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{
+        udp::{RecvHalf, SendHalf},
+        UdpSocket,
+    },
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+
+use crate::{conf::DhtConf, Sha1Hash};
+use error::*;
+use lookup::Lookup;
+use message::{
+    Message, MessageKind, NodeId, NodeInfo, Query, Response, PROTOCOL_ERROR,
+};
+use peer_store::PeerStore;
+use routing::{RoutingTable, K};
+
+pub(crate) mod error;
+mod lookup;
+mod message;
+mod peer_store;
+mod routing;
+
+/// Spawns the DHT node as a tokio task and returns a tuple with the task join
+/// handle and the node handle used for sending commands.
+pub(crate) fn spawn(
+    conf: DhtConf,
+) -> crate::error::Result<(JoinHandle, Sender)> {
+    log::info!("Spawning DHT task");
+    let (dht, dht_tx) = Dht::new(conf)?;
+    let join_handle = task::spawn(dht.run());
+    log::info!("Spawned DHT task");
+    Ok((join_handle, dht_tx))
+}
+
+pub(crate) type JoinHandle = task::JoinHandle<Result<()>>;
+
+/// The channel for sending commands to the DHT task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel the DHT task uses to listen for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The channel on which the DHT node sends the peers it found.
+pub(crate) type PeerSender = UnboundedSender<Vec<SocketAddr>>;
+/// The channel on which torrents receive the peers found by the DHT node.
+pub(crate) type PeerReceiver = UnboundedReceiver<Vec<SocketAddr>>;
+
+/// The commands the DHT node can execute.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Looks up the peers of the torrent, sending them on `peer_tx` in batches
+    /// as they are found. The sender is dropped once the lookup completes.
+    GetPeers {
+        info_hash: Sha1Hash,
+        /// If set, we announce ourselves as a peer of the torrent, listening
+        /// on this port, to the nodes closest to the info hash.
+        announce_port: Option<u16>,
+        peer_tx: PeerSender,
+    },
+    /// Saves the routing table, if configured, and stops the node.
+    Shutdown,
+}
+
+/// The largest message we accept. Messages are not fragmented so they fit
+/// into the usual MTU.
+const MAX_PACKET_LEN: usize = 1500;
+
+/// A query that isn't answered in this much time is considered failed.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// While the routing table has fewer than [`K`] nodes, we try to join the
+/// network via the bootstrap nodes this often.
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The routing table is refreshed (and saved) this often, by looking up
+/// a random id, which replaces nodes that are no longer around.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The secret from which announce tokens are derived is changed this often.
+/// Tokens derived from the previous secret are also accepted, so a token is
+/// valid for 5 to 10 minutes, as recommended by BEP 5.
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The length of the announce tokens we hand out.
+const TOKEN_LEN: usize = 8;
+
+/// The DHT node.
+struct Dht {
+    /// The address on which the node listens.
+    local_addr: SocketAddr,
+    /// The sending half of the node's socket.
+    socket_tx: SendHalf,
+    /// The receiving half of the node's socket. It is taken when the node is
+    /// run and turned into a stream of packets.
+    socket_rx: Option<RecvHalf>,
+    cmd_rx: Fuse<Receiver>,
+
+    routing: RoutingTable,
+    /// The configured bootstrap nodes, which are only resolved when needed.
+    bootstrap_nodes: Vec<String>,
+    /// The resolved addresses of the bootstrap nodes.
+    bootstrap_addrs: Vec<SocketAddr>,
+    last_bootstrap_time: Option<Instant>,
+    last_refresh_time: Instant,
+    /// Where the routing table is saved, if set.
+    state_path: Option<PathBuf>,
+
+    /// The queries we're waiting for a response to, by transaction id.
+    pending_queries: HashMap<u16, PendingQuery>,
+    next_transaction_id: u16,
+    /// The ongoing lookups, by an id used to route responses to them.
+    lookups: HashMap<u64, LookupEntry>,
+    next_lookup_id: u64,
+
+    /// The peers other nodes announced to us.
+    peer_store: PeerStore,
+    token_secret: [u8; 20],
+    prev_token_secret: [u8; 20],
+    last_token_rotation_time: Instant,
+}
+
+/// A query sent to another node.
+struct PendingQuery {
+    addr: SocketAddr,
+    /// The id of the node, unless it's a bootstrap node whose id we don't
+    /// know yet.
+    node_id: Option<NodeId>,
+    /// The lookup that sent the query, if any.
+    lookup_id: Option<u64>,
+    sent_time: Instant,
+}
+
+struct LookupEntry {
+    lookup: Lookup,
+    kind: LookupKind,
+}
+
+enum LookupKind {
+    /// Finds the nodes closest to the target, which fills up the routing
+    /// table.
+    FindNode,
+    /// Finds the peers of a torrent.
+    GetPeers {
+        announce_port: Option<u16>,
+        peer_tx: PeerSender,
+    },
+}
+
+impl Dht {
+    /// Creates the node and binds its socket, returning the node and its
+    /// command channel.
+    ///
+    /// The routing table is restored from the state file, if it is set and
+    /// exists, otherwise the node starts with a new random id.
+    fn new(conf: DhtConf) -> crate::error::Result<(Self, Sender)> {
+        let socket = std::net::UdpSocket::bind(conf.listen_addr)?;
+        let socket = UdpSocket::from_std(socket)?;
+        let local_addr = socket.local_addr()?;
+        let (socket_rx, socket_tx) = socket.split();
+        log::info!("DHT node listening on {}", local_addr);
+
+        let now = Instant::now();
+        let routing = conf
+            .state_path
+            .as_deref()
+            .and_then(|path| load_routing_table(path, now))
+            .unwrap_or_else(|| RoutingTable::new(rand::random()));
+
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        Ok((
+            Self {
+                local_addr,
+                socket_tx,
+                socket_rx: Some(socket_rx),
+                cmd_rx: cmd_rx.fuse(),
+                routing,
+                bootstrap_nodes: conf.bootstrap_nodes,
+                bootstrap_addrs: Vec::new(),
+                last_bootstrap_time: None,
+                last_refresh_time: now,
+                state_path: conf.state_path,
+                pending_queries: HashMap::new(),
+                next_transaction_id: rand::random(),
+                lookups: HashMap::new(),
+                next_lookup_id: 0,
+                peer_store: PeerStore::default(),
+                token_secret: rand::random(),
+                prev_token_secret: rand::random(),
+                last_token_rotation_time: now,
+            },
+            cmd_tx,
+        ))
+    }
+
+    /// Runs the node until it's shut down or its command channel is closed.
+    async fn run(mut self) -> Result<()> {
+        log::info!("Starting DHT node {}", hex::encode(self.routing.own_id()));
+
+        let socket_rx = self.socket_rx.take().expect("DHT socket missing");
+        let mut packets = stream::unfold(socket_rx, |mut socket_rx| async {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            let result =
+                socket_rx.recv_from(&mut buf).await.map(|(len, addr)| {
+                    buf.truncate(len);
+                    (buf, addr)
+                });
+            Some((result, socket_rx))
+        })
+        .boxed()
+        .fuse();
+        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    self.tick(now.into_std()).await;
+                }
+                packet = packets.select_next_some() => {
+                    match packet {
+                        Ok((buf, addr)) => self.handle_packet(&buf, addr).await,
+                        Err(e) => log::debug!("DHT socket error: {}", e),
+                    }
+                }
+                cmd = self.cmd_rx.next() => {
+                    match cmd {
+                        Some(Command::GetPeers {
+                            info_hash,
+                            announce_port,
+                            peer_tx,
+                        }) => {
+                            log::debug!(
+                                "Looking up peers of {} in DHT",
+                                hex::encode(info_hash)
+                            );
+                            self.start_lookup(
+                                info_hash,
+                                LookupKind::GetPeers {
+                                    announce_port,
+                                    peer_tx,
+                                },
+                            )
+                            .await;
+                        }
+                        Some(Command::Shutdown) | None => break,
+                    }
+                }
+            }
+        }
+
+        log::info!("Shutting down DHT node");
+        self.save_routing_table()
+    }
+
+    /// Runs every second to time out queries and to perform the periodic
+    /// maintenance of the node.
+    async fn tick(&mut self, now: Instant) {
+        // time out queries that weren't answered
+        let timed_out: Vec<_> = self
+            .pending_queries
+            .iter()
+            .filter(|(_, q)| {
+                now.saturating_duration_since(q.sent_time) >= QUERY_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        let mut lookup_ids = HashSet::new();
+        for transaction_id in timed_out {
+            if let Some(query) = self.pending_queries.remove(&transaction_id) {
+                log::trace!("DHT query to {} timed out", query.addr);
+                if let Some(id) = &query.node_id {
+                    self.routing.mark_failed(id);
+                }
+                if let Some(lookup_id) = query.lookup_id {
+                    if let Some(entry) = self.lookups.get_mut(&lookup_id) {
+                        entry.lookup.handle_failure(query.node_id.as_ref());
+                        lookup_ids.insert(lookup_id);
+                    }
+                }
+            }
+        }
+        for lookup_id in lookup_ids {
+            self.drive_lookup(lookup_id).await;
+        }
+
+        if now.saturating_duration_since(self.last_token_rotation_time)
+            >= TOKEN_ROTATION_INTERVAL
+        {
+            self.prev_token_secret = self.token_secret;
+            self.token_secret = rand::random();
+            self.last_token_rotation_time = now;
+        }
+
+        self.peer_store.remove_expired(now);
+
+        let is_finding_nodes = self
+            .lookups
+            .values()
+            .any(|l| matches!(l.kind, LookupKind::FindNode));
+        let should_bootstrap = self.routing.len() < K
+            && match self.last_bootstrap_time {
+                Some(t) => {
+                    now.saturating_duration_since(t) >= BOOTSTRAP_INTERVAL
+                }
+                None => true,
+            };
+        if should_bootstrap && !is_finding_nodes {
+            self.last_bootstrap_time = Some(now);
+            if self.bootstrap_addrs.is_empty() {
+                self.resolve_bootstrap_nodes().await;
+            }
+            // looking up our own id finds the nodes closest to us and makes
+            // us known to them
+            let own_id = *self.routing.own_id();
+            self.start_lookup(own_id, LookupKind::FindNode).await;
+        } else if now.saturating_duration_since(self.last_refresh_time)
+            >= REFRESH_INTERVAL
+        {
+            self.last_refresh_time = now;
+            self.start_lookup(rand::random(), LookupKind::FindNode)
+                .await;
+            if let Err(e) = self.save_routing_table() {
+                log::warn!("Error saving DHT routing table: {}", e);
+            }
+        }
+    }
+
+    /// Resolves the addresses of the bootstrap nodes.
+    ///
+    /// This is done on a blocking thread as resolving host names may block.
+    async fn resolve_bootstrap_nodes(&mut self) {
+        let nodes = self.bootstrap_nodes.clone();
+        let local_addr = self.local_addr;
+        let addrs = task::spawn_blocking(move || {
+            nodes
+                .iter()
+                .filter_map(|node| match node.to_socket_addrs() {
+                    Ok(addrs) => Some(addrs),
+                    Err(e) => {
+                        log::warn!(
+                            "Cannot resolve DHT bootstrap node {}: {}",
+                            node,
+                            e
+                        );
+                        None
+                    }
+                })
+                .flatten()
+                // we can only reach addresses of the socket's IP version
+                .filter(|addr| {
+                    addr.is_ipv4() == local_addr.is_ipv4() && *addr != local_addr
+                })
+                .collect()
+        })
+        .await;
+        match addrs {
+            Ok(addrs) => self.bootstrap_addrs = addrs,
+            Err(e) => log::warn!("Error resolving DHT bootstrap nodes: {}", e),
+        }
+    }
+
+    async fn handle_packet(&mut self, buf: &[u8], addr: SocketAddr) {
+        let msg = match Message::decode(buf) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::debug!("Invalid DHT message from {}: {}", addr, e);
+                return;
+            }
+        };
+        match msg.kind {
+            MessageKind::Query(query) => {
+                self.handle_query(msg.transaction_id, query, addr).await
+            }
+            MessageKind::Response(response) => {
+                self.handle_response(&msg.transaction_id, response, addr)
+                    .await
+            }
+            MessageKind::Error { code, message } => {
+                log::debug!(
+                    "DHT node {} sent error {}: {}",
+                    addr,
+                    code,
+                    message
+                );
+                if let Some(query) =
+                    self.take_pending_query(&msg.transaction_id, addr)
+                {
+                    if let Some(lookup_id) = query.lookup_id {
+                        if let Some(entry) = self.lookups.get_mut(&lookup_id) {
+                            entry.lookup.handle_failure(query.node_id.as_ref());
+                        }
+                        self.drive_lookup(lookup_id).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers a query from another node.
+    async fn handle_query(
+        &mut self,
+        transaction_id: Vec<u8>,
+        query: Query,
+        addr: SocketAddr,
+    ) {
+        log::trace!("DHT query from {}: {:?}", addr, query);
+        let now = Instant::now();
+        self.routing.insert(
+            NodeInfo {
+                id: *query.id(),
+                addr,
+            },
+            now,
+        );
+
+        let id = *self.routing.own_id();
+        let kind = match query {
+            Query::Ping { .. } => MessageKind::Response(Response {
+                id,
+                ..Default::default()
+            }),
+            Query::FindNode { target, .. } => MessageKind::Response(Response {
+                id,
+                nodes: self.routing.closest(&target, K),
+                ..Default::default()
+            }),
+            Query::GetPeers { info_hash, .. } => {
+                MessageKind::Response(Response {
+                    id,
+                    // the nodes are always sent, even if we know peers, to
+                    // help the querying node find more of them
+                    nodes: self.routing.closest(&info_hash, K),
+                    values: self.peer_store.peers(&info_hash),
+                    token: Some(create_token(&self.token_secret, addr.ip())),
+                })
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token,
+                ..
+            } => {
+                if self.is_valid_token(&token, addr.ip()) {
+                    let port = if implied_port { addr.port() } else { port };
+                    self.peer_store.insert(
+                        info_hash,
+                        SocketAddr::new(addr.ip(), port),
+                        now,
+                    );
+                    MessageKind::Response(Response {
+                        id,
+                        ..Default::default()
+                    })
+                } else {
+                    log::debug!("DHT node {} sent invalid token", addr);
+                    MessageKind::Error {
+                        code: PROTOCOL_ERROR,
+                        message: "invalid token".into(),
+                    }
+                }
+            }
+        };
+
+        let msg = Message {
+            transaction_id,
+            kind,
+        };
+        if let Err(e) = self.send(&msg, addr).await {
+            log::debug!("Error responding to DHT node {}: {}", addr, e);
+        }
+    }
+
+    /// Handles the response to one of our queries.
+    async fn handle_response(
+        &mut self,
+        transaction_id: &[u8],
+        response: Response,
+        addr: SocketAddr,
+    ) {
+        let query = match self.take_pending_query(transaction_id, addr) {
+            Some(query) => query,
+            None => {
+                log::debug!("Unexpected DHT response from {}", addr);
+                return;
+            }
+        };
+        log::trace!("DHT response from {}: {:?}", addr, response);
+
+        let responder = NodeInfo {
+            id: response.id,
+            addr,
+        };
+        self.routing.insert(responder, Instant::now());
+
+        if let Some(lookup_id) = query.lookup_id {
+            if let Some(entry) = self.lookups.get_mut(&lookup_id) {
+                // other nodes may know us, but we don't need to query
+                // ourselves
+                let own_id = self.routing.own_id();
+                let nodes = response
+                    .nodes
+                    .into_iter()
+                    .filter(|node| &node.id != own_id)
+                    .collect();
+                let peers = entry.lookup.handle_response(
+                    query.node_id.as_ref(),
+                    responder,
+                    response.token,
+                    nodes,
+                    response.values,
+                );
+                if let LookupKind::GetPeers { peer_tx, .. } = &entry.kind {
+                    if !peers.is_empty() {
+                        peer_tx.send(peers).ok();
+                    }
+                }
+            }
+            self.drive_lookup(lookup_id).await;
+        }
+    }
+
+    /// Removes and returns the query with the transaction id, if we sent it
+    /// to the address.
+    fn take_pending_query(
+        &mut self,
+        transaction_id: &[u8],
+        addr: SocketAddr,
+    ) -> Option<PendingQuery> {
+        if transaction_id.len() != 2 {
+            return None;
+        }
+        let transaction_id =
+            u16::from_be_bytes([transaction_id[0], transaction_id[1]]);
+        match self.pending_queries.get(&transaction_id) {
+            Some(query) if query.addr == addr => {
+                self.pending_queries.remove(&transaction_id)
+            }
+            _ => None,
+        }
+    }
+
+    /// Starts a new lookup of the target, from the nodes closest to it in our
+    /// routing table.
+    async fn start_lookup(&mut self, target: NodeId, kind: LookupKind) {
+        let mut lookup = Lookup::new(target);
+        lookup.add_nodes(self.routing.closest(&target, K));
+        if self.routing.len() < K {
+            lookup.add_bootstrap_nodes(&self.bootstrap_addrs);
+        }
+
+        // we may have been announced peers of the torrent ourselves
+        if let LookupKind::GetPeers { peer_tx, .. } = &kind {
+            let peers = lookup.add_peers(self.peer_store.peers(&target));
+            if !peers.is_empty() {
+                peer_tx.send(peers).ok();
+            }
+        }
+
+        let lookup_id = self.next_lookup_id;
+        self.next_lookup_id += 1;
+        self.lookups.insert(lookup_id, LookupEntry { lookup, kind });
+        self.drive_lookup(lookup_id).await;
+    }
+
+    /// Sends the next queries of the lookup, or if it's done, completes it.
+    async fn drive_lookup(&mut self, lookup_id: u64) {
+        let own_id = *self.routing.own_id();
+        loop {
+            let entry = match self.lookups.get_mut(&lookup_id) {
+                Some(entry) => entry,
+                None => return,
+            };
+            let (bootstrap_addrs, nodes) = entry.lookup.next_queries();
+            if bootstrap_addrs.is_empty() && nodes.is_empty() {
+                break;
+            }
+
+            let target = *entry.lookup.target();
+            let is_get_peers =
+                matches!(entry.kind, LookupKind::GetPeers { .. });
+            let queries =
+                bootstrap_addrs.into_iter().map(|addr| (addr, None)).chain(
+                    nodes.into_iter().map(|node| (node.addr, Some(node.id))),
+                );
+            for (addr, node_id) in queries {
+                let query = if is_get_peers {
+                    Query::GetPeers {
+                        id: own_id,
+                        info_hash: target,
+                    }
+                } else {
+                    Query::FindNode { id: own_id, target }
+                };
+                if let Err(e) =
+                    self.send_query(query, addr, node_id, Some(lookup_id)).await
+                {
+                    log::debug!("Error querying DHT node {}: {}", addr, e);
+                    if let Some(entry) = self.lookups.get_mut(&lookup_id) {
+                        entry.lookup.handle_failure(node_id.as_ref());
+                    }
+                }
+            }
+        }
+
+        if let Some(entry) = self.lookups.get(&lookup_id) {
+            if entry.lookup.is_done() {
+                let entry = self.lookups.remove(&lookup_id).unwrap();
+                self.complete_lookup(entry).await;
+            }
+        }
+    }
+
+    /// Announces ourselves to the closest nodes if the lookup was started for
+    /// that. Dropping the lookup closes its peer channel.
+    async fn complete_lookup(&mut self, entry: LookupEntry) {
+        let LookupEntry { lookup, kind } = entry;
+        log::debug!(
+            "DHT lookup of {} done, routing table has {} nodes",
+            hex::encode(lookup.target()),
+            self.routing.len()
+        );
+        if let LookupKind::GetPeers {
+            announce_port: Some(port),
+            ..
+        } = kind
+        {
+            let own_id = *self.routing.own_id();
+            for (node, token) in lookup.announce_targets() {
+                let query = Query::AnnouncePeer {
+                    id: own_id,
+                    info_hash: *lookup.target(),
+                    port,
+                    implied_port: false,
+                    token,
+                };
+                if let Err(e) =
+                    self.send_query(query, node.addr, Some(node.id), None).await
+                {
+                    log::debug!("Error announcing to {}: {}", node.addr, e);
+                }
+            }
+        }
+    }
+
+    /// Sends the query and records it so that its response can be matched.
+    async fn send_query(
+        &mut self,
+        query: Query,
+        addr: SocketAddr,
+        node_id: Option<NodeId>,
+        lookup_id: Option<u64>,
+    ) -> Result<()> {
+        let transaction_id = self.next_transaction_id;
+        self.next_transaction_id = self.next_transaction_id.wrapping_add(1);
+        let msg = Message {
+            transaction_id: transaction_id.to_be_bytes().to_vec(),
+            kind: MessageKind::Query(query),
+        };
+        self.send(&msg, addr).await?;
+        self.pending_queries.insert(
+            transaction_id,
+            PendingQuery {
+                addr,
+                node_id,
+                lookup_id,
+                sent_time: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &Message, addr: SocketAddr) -> Result<()> {
+        let buf = msg.encode()?;
+        self.socket_tx.send_to(&buf, &addr).await?;
+        Ok(())
+    }
+
+    /// Returns whether we handed out the token to the IP address, recently
+    /// enough.
+    fn is_valid_token(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == create_token(&self.token_secret, ip).as_slice()
+            || token == create_token(&self.prev_token_secret, ip).as_slice()
+    }
+
+    /// Saves the routing table to the state file, if set.
+    fn save_routing_table(&self) -> Result<()> {
+        if let Some(path) = &self.state_path {
+            log::debug!("Saving DHT routing table to {:?}", path);
+            fs::write(path, self.routing.to_bytes()?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads the saved routing table, if it exists and is valid.
+fn load_routing_table(path: &Path, now: Instant) -> Option<RoutingTable> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::warn!("Cannot read DHT state {:?}: {}", path, e);
+            }
+            return None;
+        }
+    };
+    match RoutingTable::from_bytes(&buf, now) {
+        Ok(routing) => {
+            log::info!("Restored {} DHT nodes from {:?}", routing.len(), path);
+            Some(routing)
+        }
+        Err(e) => {
+            log::warn!("Invalid DHT state {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Creates the token handed out to the IP address in `get_peers` responses,
+/// which it has to present when announcing itself as a peer.
+///
+/// The token is derived from the address and our secret, so we don't have to
+/// store the tokens we handed out.
+fn create_token(secret: &[u8], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(ip.octets()),
+        IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..TOKEN_LEN].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// Creates a node listening on a random loopback port, bootstrapping from
+    /// the given nodes, and returns its address and command channel.
+    fn spawn_node(
+        bootstrap_node: Option<SocketAddr>,
+        state_path: Option<PathBuf>,
+    ) -> (SocketAddr, Sender, JoinHandle) {
+        let conf = DhtConf {
+            listen_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            bootstrap_nodes: bootstrap_node
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            state_path,
+        };
+        let (dht, dht_tx) = Dht::new(conf).unwrap();
+        let addr = dht.local_addr;
+        let join_handle = task::spawn(dht.run());
+        (addr, dht_tx, join_handle)
+    }
+
+    /// Looks up the peers of the torrent, returning them once the lookup
+    /// completes.
+    async fn get_peers(
+        dht_tx: &Sender,
+        info_hash: Sha1Hash,
+        announce_port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let (peer_tx, peer_rx) = mpsc::unbounded_channel();
+        dht_tx
+            .send(Command::GetPeers {
+                info_hash,
+                announce_port,
+                peer_tx,
+            })
+            .unwrap();
+        let lookup = peer_rx.concat();
+        time::timeout(Duration::from_secs(10), lookup)
+            .await
+            .expect("DHT lookup timed out")
+    }
+
+    #[tokio::test]
+    async fn should_find_announced_peers_via_loopback_nodes() {
+        let (bootstrap_addr, bootstrap_tx, _) = spawn_node(None, None);
+        let nodes: Vec<_> = (0..4)
+            .map(|_| spawn_node(Some(bootstrap_addr), None))
+            .collect();
+        // let the nodes join the network
+        time::delay_for(Duration::from_millis(500)).await;
+
+        let info_hash = [0xab; 20];
+        assert!(get_peers(&nodes[0].1, info_hash, Some(51413))
+            .await
+            .is_empty());
+
+        // all other nodes find the announced peer, at the announcing node's
+        // IP address
+        let peer = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 51413);
+        for (_, dht_tx, _) in &nodes[1..] {
+            assert_eq!(get_peers(dht_tx, info_hash, None).await, vec![peer]);
+        }
+        // an info hash nobody announced has no peers
+        assert!(get_peers(&nodes[1].1, [0xcd; 20], None).await.is_empty());
+
+        bootstrap_tx.send(Command::Shutdown).unwrap();
+        for (_, dht_tx, join_handle) in nodes {
+            dht_tx.send(Command::Shutdown).unwrap();
+            join_handle.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn should_restore_routing_table() {
+        let state_path = std::env::temp_dir()
+            .join(format!("cratetorrent-dht-state-{}", rand::random::<u32>()));
+        let (bootstrap_addr, bootstrap_tx, _) = spawn_node(None, None);
+        let (_, dht_tx, join_handle) =
+            spawn_node(Some(bootstrap_addr), Some(state_path.clone()));
+        time::delay_for(Duration::from_millis(500)).await;
+        dht_tx.send(Command::Shutdown).unwrap();
+        join_handle.await.unwrap().unwrap();
+
+        let routing = load_routing_table(&state_path, Instant::now()).unwrap();
+        assert_eq!(routing.closest(&[0; 20], K).len(), 1);
+        assert_eq!(routing.closest(&[0; 20], K)[0].addr, bootstrap_addr);
+
+        bootstrap_tx.send(Command::Shutdown).unwrap();
+        fs::remove_file(&state_path).unwrap();
+    }
+
+    #[test]
+    fn should_derive_tokens_from_secret_and_ip() {
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 1).into();
+        let token = create_token(&[1; 20], ip);
+        assert_eq!(token.len(), TOKEN_LEN);
+        assert_eq!(token, create_token(&[1; 20], ip));
+        assert_ne!(token, create_token(&[2; 20], ip));
+        assert_ne!(token, create_token(&[1; 20], Ipv4Addr::LOCALHOST.into()));
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+
+pub use serde_bencode::Error as BencodeError;
+pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
+
+pub(crate) type Result<T, E = DhtError> = std::result::Result<T, E>;
+
+/// Error type returned by the DHT node.
+///
+/// Errors of individual messages are non-fatal: the offending message is
+/// simply dropped.
+#[derive(Debug)]
+pub(crate) enum DhtError {
+    /// Holds bencode serialization or deserialization related errors.
+    Bencode(BencodeError),
+    /// The channel on which some component in engine was listening or sending
+    /// died.
+    Channel,
+    /// The message is valid bencode but not a valid KRPC message.
+    InvalidMessage,
+    /// Holds IO related errors.
+    Io(IoError),
+}
+
+impl fmt::Display for DhtError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DhtError::*;
+        match self {
+            Bencode(e) => e.fmt(fmt),
+            Channel => write!(fmt, "channel error"),
+            InvalidMessage => write!(fmt, "invalid KRPC message"),
+            Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl From<BencodeError> for DhtError {
+    fn from(e: BencodeError) -> Self {
+        Self::Bencode(e)
+    }
+}
+
+impl From<IoError> for DhtError {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl<T> From<SendError<T>> for DhtError {
+    fn from(_: SendError<T>) -> Self {
+        Self::Channel
+    }
+}
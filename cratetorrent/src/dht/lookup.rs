@@ -0,0 +1,308 @@
+//! This module contains the state of iterative lookups in the DHT.
+//!
+//! A lookup finds the nodes closest to a target id (and for `get_peers`
+//! lookups, the peers of the torrent with that info hash) by repeatedly
+//! querying the closest nodes known so far, which return nodes even closer to
+//! the target. The lookup is done once the [`K`] closest nodes that we know of
+//! have all responded.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::SocketAddr,
+};
+
+use crate::dht::{
+    message::{NodeId, NodeInfo},
+    routing::{distance, K},
+};
+
+/// The number of queries a lookup keeps in flight at the same time.
+const ALPHA: usize = 3;
+
+/// The state of a node in a lookup.
+#[derive(Debug, PartialEq)]
+enum NodeState {
+    NotQueried,
+    InFlight,
+    /// The node responded, with the token it gave us, if any.
+    Responded {
+        token: Option<Vec<u8>>,
+    },
+    Failed,
+}
+
+#[derive(Debug)]
+struct LookupNode {
+    info: NodeInfo,
+    state: NodeState,
+}
+
+/// An iterative lookup of the nodes closest to a target.
+pub(crate) struct Lookup {
+    target: NodeId,
+    /// The nodes we learned about, keyed by their distance to the target, so
+    /// that they are ordered from closest to furthest.
+    nodes: BTreeMap<NodeId, LookupNode>,
+    /// Nodes whose ids we don't know, used to start the lookup when the
+    /// routing table is empty.
+    bootstrap_nodes: Vec<SocketAddr>,
+    /// The peers found so far, used to only report each peer once.
+    peers: HashSet<SocketAddr>,
+    in_flight_count: usize,
+}
+
+impl Lookup {
+    pub fn new(target: NodeId) -> Self {
+        Self {
+            target,
+            nodes: BTreeMap::new(),
+            bootstrap_nodes: Vec::new(),
+            peers: HashSet::new(),
+            in_flight_count: 0,
+        }
+    }
+
+    pub fn target(&self) -> &NodeId {
+        &self.target
+    }
+
+    /// Adds nodes to be queried by the lookup, ignoring nodes already known.
+    pub fn add_nodes(&mut self, nodes: impl IntoIterator<Item = NodeInfo>) {
+        for info in nodes {
+            self.nodes
+                .entry(distance(&info.id, &self.target))
+                .or_insert(LookupNode {
+                    info,
+                    state: NodeState::NotQueried,
+                });
+        }
+    }
+
+    /// Adds nodes whose ids we don't know to be queried first.
+    pub fn add_bootstrap_nodes(&mut self, addrs: &[SocketAddr]) {
+        self.bootstrap_nodes.extend_from_slice(addrs);
+    }
+
+    /// Returns the addresses of the bootstrap nodes and the nodes that should
+    /// be queried next, marking them as in flight.
+    pub fn next_queries(&mut self) -> (Vec<SocketAddr>, Vec<NodeInfo>) {
+        let bootstrap_nodes = std::mem::take(&mut self.bootstrap_nodes);
+        self.in_flight_count += bootstrap_nodes.len();
+
+        let mut nodes = Vec::new();
+        // only the closest nodes need to be queried: once these have all
+        // responded, we can't get any closer to the target
+        for node in self
+            .nodes
+            .values_mut()
+            .filter(|n| n.state != NodeState::Failed)
+            .take(K)
+        {
+            if self.in_flight_count >= ALPHA {
+                break;
+            }
+            if node.state == NodeState::NotQueried {
+                node.state = NodeState::InFlight;
+                self.in_flight_count += 1;
+                nodes.push(node.info);
+            }
+        }
+
+        (bootstrap_nodes, nodes)
+    }
+
+    /// Records the response of a node, returning the peers it sent that we
+    /// haven't seen before.
+    ///
+    /// The id of the node is `None` if it was a bootstrap node.
+    pub fn handle_response(
+        &mut self,
+        id: Option<&NodeId>,
+        responder: NodeInfo,
+        token: Option<Vec<u8>>,
+        nodes: Vec<NodeInfo>,
+        peers: Vec<SocketAddr>,
+    ) -> Vec<SocketAddr> {
+        self.in_flight_count = self.in_flight_count.saturating_sub(1);
+        match id {
+            Some(id) => {
+                if let Some(node) =
+                    self.nodes.get_mut(&distance(id, &self.target))
+                {
+                    node.state = NodeState::Responded { token };
+                }
+            }
+            None => {
+                // bootstrap nodes are added as responded nodes now that we
+                // know their ids, as they may be among the closest nodes
+                self.nodes.insert(
+                    distance(&responder.id, &self.target),
+                    LookupNode {
+                        info: responder,
+                        state: NodeState::Responded { token },
+                    },
+                );
+            }
+        }
+        self.add_nodes(nodes);
+        self.add_peers(peers)
+    }
+
+    /// Records the peers found by the lookup, returning the ones we haven't
+    /// seen before.
+    pub fn add_peers(&mut self, peers: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        peers
+            .into_iter()
+            .filter(|peer| self.peers.insert(*peer))
+            .collect()
+    }
+
+    /// Records that a node failed to respond.
+    ///
+    /// The id of the node is `None` if it was a bootstrap node.
+    pub fn handle_failure(&mut self, id: Option<&NodeId>) {
+        self.in_flight_count = self.in_flight_count.saturating_sub(1);
+        if let Some(id) = id {
+            if let Some(node) = self.nodes.get_mut(&distance(id, &self.target))
+            {
+                node.state = NodeState::Failed;
+            }
+        }
+    }
+
+    /// Returns whether the lookup is done, that is, whether there are no
+    /// queries in flight and no more nodes to query.
+    pub fn is_done(&self) -> bool {
+        self.in_flight_count == 0
+            && self.bootstrap_nodes.is_empty()
+            && !self
+                .nodes
+                .values()
+                .filter(|n| n.state != NodeState::Failed)
+                .take(K)
+                .any(|n| n.state == NodeState::NotQueried)
+    }
+
+    /// Returns the closest nodes that responded with a token, to which we can
+    /// announce ourselves as a peer.
+    pub fn announce_targets(&self) -> Vec<(NodeInfo, Vec<u8>)> {
+        self.nodes
+            .values()
+            .filter_map(|n| match &n.state {
+                NodeState::Responded { token: Some(token) } => {
+                    Some((n.info, token.clone()))
+                }
+                _ => None,
+            })
+            .take(K)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: u8) -> NodeInfo {
+        let mut node_id = [0; 20];
+        node_id[19] = id;
+        NodeInfo {
+            id: node_id,
+            addr: SocketAddr::from(([127, 0, 0, 1], id as u16)),
+        }
+    }
+
+    #[test]
+    fn should_query_closest_nodes_until_done() {
+        let mut lookup = Lookup::new([0; 20]);
+        lookup.add_nodes((20..30).map(node));
+
+        // only ALPHA queries are in flight at a time, starting with the
+        // closest nodes
+        let (bootstrap_nodes, nodes) = lookup.next_queries();
+        assert!(bootstrap_nodes.is_empty());
+        assert_eq!(nodes, vec![node(20), node(21), node(22)]);
+        assert!(lookup.next_queries().1.is_empty());
+        assert!(!lookup.is_done());
+
+        // a response with closer nodes makes the lookup query those next
+        let peers = lookup.handle_response(
+            Some(&node(20).id),
+            node(20),
+            Some(vec![1]),
+            vec![node(1), node(2)],
+            vec!["10.0.0.1:1".parse().unwrap()],
+        );
+        assert_eq!(peers, vec!["10.0.0.1:1".parse().unwrap()]);
+        assert_eq!(lookup.next_queries().1, vec![node(1)]);
+
+        lookup.handle_failure(Some(&node(21).id));
+        assert_eq!(lookup.next_queries().1, vec![node(2)]);
+
+        // the same peer is not reported twice
+        let peers = lookup.handle_response(
+            Some(&node(1).id),
+            node(1),
+            Some(vec![2]),
+            Vec::new(),
+            vec!["10.0.0.1:1".parse().unwrap()],
+        );
+        assert!(peers.is_empty());
+
+        // respond to all queries until the closest K nodes have responded
+        loop {
+            let (_, nodes) = lookup.next_queries();
+            if nodes.is_empty() {
+                break;
+            }
+            for node in nodes {
+                lookup.handle_response(
+                    Some(&node.id),
+                    node,
+                    None,
+                    Vec::new(),
+                    Vec::new(),
+                );
+            }
+        }
+        for id in &[2, 22] {
+            lookup.handle_response(
+                Some(&node(*id).id),
+                node(*id),
+                None,
+                Vec::new(),
+                Vec::new(),
+            );
+        }
+        assert!(lookup.is_done());
+
+        // the nodes further than the closest K are never queried
+        assert_eq!(lookup.nodes[&node(29).id].state, NodeState::NotQueried);
+        assert_eq!(
+            lookup.announce_targets(),
+            vec![(node(1), vec![2]), (node(20), vec![1])]
+        );
+    }
+
+    #[test]
+    fn should_query_bootstrap_nodes() {
+        let mut lookup = Lookup::new([0; 20]);
+        let addr = "10.0.0.1:6881".parse().unwrap();
+        lookup.add_bootstrap_nodes(&[addr]);
+        let (bootstrap_nodes, nodes) = lookup.next_queries();
+        assert_eq!(bootstrap_nodes, vec![addr]);
+        assert!(nodes.is_empty());
+        assert!(!lookup.is_done());
+
+        lookup.handle_response(
+            None,
+            NodeInfo { id: [1; 20], addr },
+            None,
+            vec![node(5)],
+            Vec::new(),
+        );
+        assert_eq!(lookup.next_queries().1, vec![node(5)]);
+        lookup.handle_failure(Some(&node(5).id));
+        assert!(lookup.is_done());
+    }
+}
@@ -0,0 +1,525 @@
+//! This module contains the [KRPC](http://bittorrent.org/beps/bep_0005.html#krpc-protocol)
+//! messages exchanged by DHT nodes.
+//!
+//! Each message is a bencoded dictionary sent in a single UDP packet. There
+//! are three kinds of messages: queries, responses to queries, and errors.
+//! Queries and their responses (or errors) are matched by a transaction id
+//! chosen by the querying node.
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use serde_bytes::ByteBuf;
+
+use crate::{dht::error::*, Sha1Hash};
+
+/// Nodes are identified by a 160 bit id, in the same space as info hashes.
+pub(crate) type NodeId = [u8; 20];
+
+/// The length of a node's contact information in compact form: the node id
+/// followed by its IPv4 address and port.
+const COMPACT_NODE_LEN: usize = 26;
+
+/// The length of a peer's contact information in compact form: its IPv4
+/// address and port.
+const COMPACT_PEER_LEN: usize = 6;
+
+/// The error code for protocol errors, such as malformed packets or invalid
+/// tokens.
+pub(crate) const PROTOCOL_ERROR: i64 = 203;
+
+/// The address and id of a DHT node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// A KRPC message.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Message {
+    /// The transaction id, chosen by the querying node and echoed back in the
+    /// response.
+    pub transaction_id: Vec<u8>,
+    pub kind: MessageKind,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum MessageKind {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// The queries of the DHT protocol, each with the id of the querying node.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Query {
+    /// Checks whether the node is alive.
+    Ping { id: NodeId },
+    /// Asks for the contact information of the nodes closest to the target.
+    FindNode { id: NodeId, target: NodeId },
+    /// Asks for the peers of the torrent, or if the node doesn't know any, the
+    /// nodes closest to the info hash.
+    GetPeers { id: NodeId, info_hash: Sha1Hash },
+    /// Announces that the querying node is a peer of the torrent.
+    AnnouncePeer {
+        id: NodeId,
+        info_hash: Sha1Hash,
+        /// The port on which the peer accepts connections.
+        port: u16,
+        /// If set, the port is to be ignored and the source port of the
+        /// packet is to be used instead.
+        implied_port: bool,
+        /// The token received in a previous response to `get_peers`.
+        token: Vec<u8>,
+    },
+}
+
+impl Query {
+    /// Returns the id of the querying node.
+    pub fn id(&self) -> &NodeId {
+        match self {
+            Self::Ping { id }
+            | Self::FindNode { id, .. }
+            | Self::GetPeers { id, .. }
+            | Self::AnnouncePeer { id, .. } => id,
+        }
+    }
+}
+
+/// A response to a query. All queries share the same response format, but
+/// apart from the id of the responding node, fields are only set in
+/// responses to specific queries.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Response {
+    /// The id of the responding node.
+    pub id: NodeId,
+    /// The nodes closest to the target of `find_node` or `get_peers`.
+    pub nodes: Vec<NodeInfo>,
+    /// The peers of the torrent, in response to `get_peers`.
+    pub values: Vec<SocketAddr>,
+    /// The token to be sent in a subsequent `announce_peer`, in response to
+    /// `get_peers`.
+    pub token: Option<Vec<u8>>,
+}
+
+/// The bencoded representation of all KRPC messages.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawMessage {
+    #[serde(with = "serde_bytes")]
+    t: Vec<u8>,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawBody>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, ByteBuf)>,
+}
+
+/// The query arguments and response values, which are both dictionaries
+/// with the id of the sender and a few optional fields.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawBody {
+    #[serde(with = "serde_bytes")]
+    id: Vec<u8>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    target: Option<Vec<u8>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    info_hash: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    token: Option<Vec<u8>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    nodes: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl Message {
+    /// Decodes a message from the payload of a UDP packet.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let raw: RawMessage = serde_bencode::from_bytes(buf)?;
+        let kind = match raw.y.as_str() {
+            "q" => {
+                let method = raw.q.ok_or(DhtError::InvalidMessage)?;
+                let args = raw.a.ok_or(DhtError::InvalidMessage)?;
+                MessageKind::Query(decode_query(&method, args)?)
+            }
+            "r" => {
+                let values = raw.r.ok_or(DhtError::InvalidMessage)?;
+                MessageKind::Response(decode_response(values)?)
+            }
+            "e" => {
+                let (code, message) = raw.e.ok_or(DhtError::InvalidMessage)?;
+                MessageKind::Error {
+                    code,
+                    message: String::from_utf8_lossy(&message).into_owned(),
+                }
+            }
+            _ => return Err(DhtError::InvalidMessage),
+        };
+        Ok(Self {
+            transaction_id: raw.t,
+            kind,
+        })
+    }
+
+    /// Encodes the message into the payload of a UDP packet.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut raw = RawMessage {
+            t: self.transaction_id.clone(),
+            ..Default::default()
+        };
+        match &self.kind {
+            MessageKind::Query(query) => {
+                let (method, args) = encode_query(query);
+                raw.y = "q".into();
+                raw.q = Some(method.into());
+                raw.a = Some(args);
+            }
+            MessageKind::Response(response) => {
+                raw.y = "r".into();
+                raw.r = Some(encode_response(response));
+            }
+            MessageKind::Error { code, message } => {
+                raw.y = "e".into();
+                raw.e = Some((*code, ByteBuf::from(message.as_bytes())));
+            }
+        }
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+}
+
+fn decode_query(method: &str, args: RawBody) -> Result<Query> {
+    let id = to_id(&args.id)?;
+    let query = match method {
+        "ping" => Query::Ping { id },
+        "find_node" => Query::FindNode {
+            id,
+            target: to_id(args.target.as_deref().unwrap_or_default())?,
+        },
+        "get_peers" => Query::GetPeers {
+            id,
+            info_hash: to_id(args.info_hash.as_deref().unwrap_or_default())?,
+        },
+        "announce_peer" => {
+            let port = args.port.ok_or(DhtError::InvalidMessage)?;
+            if port <= 0 || port > u16::MAX as i64 {
+                return Err(DhtError::InvalidMessage);
+            }
+            Query::AnnouncePeer {
+                id,
+                info_hash: to_id(
+                    args.info_hash.as_deref().unwrap_or_default(),
+                )?,
+                port: port as u16,
+                implied_port: args.implied_port.unwrap_or_default() != 0,
+                token: args.token.ok_or(DhtError::InvalidMessage)?,
+            }
+        }
+        _ => {
+            log::debug!("Unknown DHT query method {}", method);
+            return Err(DhtError::InvalidMessage);
+        }
+    };
+    Ok(query)
+}
+
+fn encode_query(query: &Query) -> (&'static str, RawBody) {
+    let mut args = RawBody {
+        id: query.id().to_vec(),
+        ..Default::default()
+    };
+    let method = match query {
+        Query::Ping { .. } => "ping",
+        Query::FindNode { target, .. } => {
+            args.target = Some(target.to_vec());
+            "find_node"
+        }
+        Query::GetPeers { info_hash, .. } => {
+            args.info_hash = Some(info_hash.to_vec());
+            "get_peers"
+        }
+        Query::AnnouncePeer {
+            info_hash,
+            port,
+            implied_port,
+            token,
+            ..
+        } => {
+            args.info_hash = Some(info_hash.to_vec());
+            args.port = Some(*port as i64);
+            args.implied_port = Some(*implied_port as i64);
+            args.token = Some(token.clone());
+            "announce_peer"
+        }
+    };
+    (method, args)
+}
+
+fn decode_response(values: RawBody) -> Result<Response> {
+    Ok(Response {
+        id: to_id(&values.id)?,
+        nodes: values
+            .nodes
+            .as_deref()
+            .map(decode_compact_nodes)
+            .transpose()?
+            .unwrap_or_default(),
+        // peers that are not in the compact IPv4 format are skipped rather
+        // than rejecting the whole response
+        values: values
+            .values
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|peer| decode_compact_peer(peer))
+            .collect(),
+        token: values.token,
+    })
+}
+
+fn encode_response(response: &Response) -> RawBody {
+    RawBody {
+        id: response.id.to_vec(),
+        nodes: if response.nodes.is_empty() {
+            None
+        } else {
+            Some(encode_compact_nodes(&response.nodes))
+        },
+        values: if response.values.is_empty() {
+            None
+        } else {
+            Some(
+                response
+                    .values
+                    .iter()
+                    .filter_map(encode_compact_peer)
+                    .map(ByteBuf::from)
+                    .collect(),
+            )
+        },
+        token: response.token.clone(),
+        ..Default::default()
+    }
+}
+
+fn to_id(buf: &[u8]) -> Result<NodeId> {
+    if buf.len() != 20 {
+        return Err(DhtError::InvalidMessage);
+    }
+    let mut id = [0; 20];
+    id.copy_from_slice(buf);
+    Ok(id)
+}
+
+/// Decodes the concatenation of compact node infos.
+pub(crate) fn decode_compact_nodes(buf: &[u8]) -> Result<Vec<NodeInfo>> {
+    let nodes = buf.chunks_exact(COMPACT_NODE_LEN);
+    if !nodes.remainder().is_empty() {
+        return Err(DhtError::InvalidMessage);
+    }
+    Ok(nodes
+        .filter_map(|node| {
+            let addr = decode_compact_peer(&node[20..])?;
+            let mut id = [0; 20];
+            id.copy_from_slice(&node[..20]);
+            Some(NodeInfo { id, addr })
+        })
+        .collect())
+}
+
+/// Encodes the nodes into the concatenation of their compact node infos.
+/// Nodes with IPv6 addresses are skipped as they don't have a compact form.
+pub(crate) fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+    for node in nodes {
+        if let Some(addr) = encode_compact_peer(&node.addr) {
+            buf.extend_from_slice(&node.id);
+            buf.extend_from_slice(&addr);
+        }
+    }
+    buf
+}
+
+fn decode_compact_peer(buf: &[u8]) -> Option<SocketAddr> {
+    if buf.len() != COMPACT_PEER_LEN {
+        return None;
+    }
+    let ip = Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3]);
+    let port = u16::from_be_bytes([buf[4], buf[5]]);
+    Some(SocketAddr::new(ip.into(), port))
+}
+
+fn encode_compact_peer(addr: &SocketAddr) -> Option<Vec<u8>> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let mut buf = ip.octets().to_vec();
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+            Some(buf)
+        }
+        IpAddr::V6(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_decode_spec_messages() {
+        // the examples in BEP 5
+        let msg = Message::decode(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert_eq!(
+            msg,
+            Message {
+                transaction_id: b"aa".to_vec(),
+                kind: MessageKind::Query(Query::Ping {
+                    id: *b"abcdefghij0123456789"
+                }),
+            }
+        );
+
+        let msg = Message::decode(
+            b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456\
+            4:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe",
+        )
+        .unwrap();
+        assert_eq!(
+            msg.kind,
+            MessageKind::Query(Query::AnnouncePeer {
+                id: *b"abcdefghij0123456789",
+                info_hash: *b"mnopqrstuvwxyz123456",
+                port: 6881,
+                implied_port: false,
+                token: b"aoeusnth".to_vec(),
+            })
+        );
+
+        let msg = Message::decode(
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl\
+            6:axje.u6:idhtnmee1:t2:aa1:y1:re",
+        )
+        .unwrap();
+        assert_eq!(
+            msg.kind,
+            MessageKind::Response(Response {
+                id: *b"abcdefghij0123456789",
+                nodes: Vec::new(),
+                values: vec![
+                    SocketAddr::new(
+                        Ipv4Addr::new(97, 120, 106, 101).into(),
+                        11893
+                    ),
+                    SocketAddr::new(
+                        Ipv4Addr::new(105, 100, 104, 116).into(),
+                        28269
+                    ),
+                ],
+                token: Some(b"aoeusnth".to_vec()),
+            })
+        );
+
+        let msg = Message::decode(
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+        )
+        .unwrap();
+        assert_eq!(
+            msg.kind,
+            MessageKind::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn should_encode_and_decode_messages() {
+        let node = NodeInfo {
+            id: [7; 20],
+            addr: "10.0.0.1:6881".parse().unwrap(),
+        };
+        let kinds = vec![
+            MessageKind::Query(Query::FindNode {
+                id: [1; 20],
+                target: [2; 20],
+            }),
+            MessageKind::Query(Query::GetPeers {
+                id: [1; 20],
+                info_hash: [3; 20],
+            }),
+            MessageKind::Query(Query::AnnouncePeer {
+                id: [1; 20],
+                info_hash: [3; 20],
+                port: 51413,
+                implied_port: true,
+                token: vec![1, 2, 3, 4],
+            }),
+            MessageKind::Response(Response {
+                id: [4; 20],
+                nodes: vec![node],
+                values: vec!["192.168.1.2:1234".parse().unwrap()],
+                token: Some(vec![5; 8]),
+            }),
+            MessageKind::Error {
+                code: PROTOCOL_ERROR,
+                message: "bad token".into(),
+            },
+        ];
+        for kind in kinds {
+            let msg = Message {
+                transaction_id: vec![0, 1],
+                kind,
+            };
+            let encoded = msg.encode().unwrap();
+            assert_eq!(Message::decode(&encoded).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_messages() {
+        // not bencode
+        assert!(Message::decode(b"hello").is_err());
+        // unknown message type
+        assert!(Message::decode(b"d1:t2:aa1:y1:xe").is_err());
+        // query without arguments
+        assert!(Message::decode(b"d1:q4:ping1:t2:aa1:y1:qe").is_err());
+        // invalid node id length
+        assert!(
+            Message::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err()
+        );
+        // unknown query method
+        assert!(Message::decode(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:aa1:y1:qe"
+        )
+        .is_err());
+        // nodes not a multiple of the compact node length
+        assert!(Message::decode(
+            b"d1:rd2:id20:abcdefghij01234567895:nodes3:abce1:t2:aa1:y1:re"
+        )
+        .is_err());
+    }
+}
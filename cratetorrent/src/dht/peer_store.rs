@@ -0,0 +1,123 @@
+//! This module contains the store of peers announced to our DHT node.
+//!
+//! Other nodes announce themselves as peers of torrents whose info hash is
+//! close to our node id, and we hand out these peers to nodes that ask for
+//! them with `get_peers`. Peers that don't re-announce themselves are
+//! eventually forgotten.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+use crate::Sha1Hash;
+
+/// Peers that haven't announced themselves in this much time are removed.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The maximum number of torrents whose peers we store. This, and the limit
+/// on the peers per torrent, bounds the memory other nodes can make us use.
+const MAX_TORRENT_COUNT: usize = 2000;
+
+/// The maximum number of peers we store per torrent.
+const MAX_PEER_COUNT: usize = 200;
+
+/// The maximum number of peers returned in a `get_peers` response, so that
+/// the response fits in a single UDP packet.
+const MAX_RETURNED_PEER_COUNT: usize = 50;
+
+/// The peers announced to us, by torrent info hash.
+#[derive(Default)]
+pub(crate) struct PeerStore {
+    /// The peers of each torrent along with the time of their last
+    /// announce.
+    torrents: HashMap<Sha1Hash, HashMap<SocketAddr, Instant>>,
+}
+
+impl PeerStore {
+    /// Records that the peer announced itself for the torrent, unless we've
+    /// reached the store's limits.
+    pub fn insert(
+        &mut self,
+        info_hash: Sha1Hash,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        if !self.torrents.contains_key(&info_hash)
+            && self.torrents.len() >= MAX_TORRENT_COUNT
+        {
+            log::debug!("DHT peer store full, dropping announce");
+            return;
+        }
+        let peers = self.torrents.entry(info_hash).or_default();
+        if peers.len() < MAX_PEER_COUNT || peers.contains_key(&addr) {
+            peers.insert(addr, now);
+        }
+    }
+
+    /// Returns a random selection of the torrent's peers.
+    pub fn peers(&self, info_hash: &Sha1Hash) -> Vec<SocketAddr> {
+        match self.torrents.get(info_hash) {
+            Some(peers) => peers.keys().copied().choose_multiple(
+                &mut rand::thread_rng(),
+                MAX_RETURNED_PEER_COUNT,
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes the peers that haven't announced themselves for too long.
+    pub fn remove_expired(&mut self, now: Instant) {
+        for peers in self.torrents.values_mut() {
+            peers.retain(|_, announce_time| {
+                now.saturating_duration_since(*announce_time) < PEER_TTL
+            });
+        }
+        self.torrents.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_store_and_expire_peers() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        let addr1: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:2".parse().unwrap();
+        store.insert([1; 20], addr1, now);
+        store.insert([1; 20], addr2, now + PEER_TTL / 2);
+
+        let mut peers = store.peers(&[1; 20]);
+        peers.sort();
+        assert_eq!(peers, vec![addr1, addr2]);
+        assert!(store.peers(&[2; 20]).is_empty());
+
+        store.remove_expired(now + PEER_TTL);
+        assert_eq!(store.peers(&[1; 20]), vec![addr2]);
+
+        // re-announcing refreshes the peer
+        store.insert([1; 20], addr2, now + PEER_TTL);
+        store.remove_expired(now + PEER_TTL * 3 / 2);
+        assert_eq!(store.peers(&[1; 20]), vec![addr2]);
+
+        store.remove_expired(now + PEER_TTL * 2);
+        assert!(store.torrents.is_empty());
+    }
+
+    #[test]
+    fn should_limit_peers() {
+        let now = Instant::now();
+        let mut store = PeerStore::default();
+        for port in 0..MAX_PEER_COUNT as u16 + 10 {
+            store.insert([1; 20], SocketAddr::from(([10, 0, 0, 1], port)), now);
+        }
+        assert_eq!(store.torrents[&[1; 20]].len(), MAX_PEER_COUNT);
+        assert_eq!(store.peers(&[1; 20]).len(), MAX_RETURNED_PEER_COUNT);
+    }
+}
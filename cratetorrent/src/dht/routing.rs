@@ -0,0 +1,340 @@
+//! This module contains the DHT node's routing table, which stores the
+//! contact information of other nodes.
+//!
+//! The table is organized into buckets by the XOR distance of the nodes' ids
+//! from our own id: the bucket of a node is determined by the length of the
+//! common prefix of its id and ours. Each bucket holds at most [`K`] nodes, so
+//! we know many nodes close to us and fewer and fewer nodes further away.
+
+use std::time::Instant;
+
+use serde_bytes::ByteBuf;
+
+use crate::dht::{
+    error::*,
+    message::{decode_compact_nodes, encode_compact_nodes, NodeId, NodeInfo},
+};
+
+/// The maximum number of nodes in a bucket, which is also the number of
+/// closest nodes returned to `find_node` and `get_peers` queries.
+pub(crate) const K: usize = 8;
+
+/// A node that failed to respond to this many queries in a row is removed
+/// from the routing table.
+const MAX_FAILED_QUERY_COUNT: usize = 3;
+
+/// A node in the routing table.
+#[derive(Debug)]
+struct Entry {
+    info: NodeInfo,
+    /// The last time we received a message from the node.
+    last_seen: Instant,
+    /// The number of consecutive queries the node failed to respond to.
+    failed_query_count: usize,
+}
+
+/// The routing table of the DHT node.
+pub(crate) struct RoutingTable {
+    own_id: NodeId,
+    /// There is a bucket for each possible common prefix length, that is, for
+    /// each bit in the id.
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    /// Returns our own node id.
+    pub fn own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    /// Returns the number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Records that we've heard from the node, adding it to the table if it's
+    /// not yet present and there is room for it in its bucket.
+    ///
+    /// Room is made in a full bucket by evicting a node that has failed to
+    /// respond to a query. Otherwise the new node is dropped, as nodes that
+    /// have been around for longer are more likely to stay around.
+    ///
+    /// Returns whether the node is in the table.
+    pub fn insert(&mut self, info: NodeInfo, now: Instant) -> bool {
+        let index = match self.bucket_index(&info.id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(entry) = bucket.iter_mut().find(|e| e.info.id == info.id) {
+            entry.info.addr = info.addr;
+            entry.last_seen = now;
+            entry.failed_query_count = 0;
+            return true;
+        }
+
+        let entry = Entry {
+            info,
+            last_seen: now,
+            failed_query_count: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(entry);
+            return true;
+        }
+
+        // replace the least reliable node, if any node is unreliable
+        let worst = bucket
+            .iter_mut()
+            .filter(|e| e.failed_query_count > 0)
+            .max_by_key(|e| {
+                (e.failed_query_count, std::cmp::Reverse(e.last_seen))
+            });
+        match worst {
+            Some(worst) => {
+                *worst = entry;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that the node failed to respond to a query, removing it from
+    /// the table if it has failed too many times.
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        let index = match self.bucket_index(id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(pos) = bucket.iter().position(|e| &e.info.id == id) {
+            bucket[pos].failed_query_count += 1;
+            if bucket[pos].failed_query_count >= MAX_FAILED_QUERY_COUNT {
+                bucket.remove(pos);
+            }
+        }
+    }
+
+    /// Returns at most `count` nodes closest to the target, ordered by their
+    /// distance.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.info)
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Serializes our node id and the nodes in the table, so that the table
+    /// may be restored after a restart.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let nodes: Vec<_> = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.info)
+            .collect();
+        let state = RawState {
+            id: ByteBuf::from(self.own_id.to_vec()),
+            nodes: ByteBuf::from(encode_compact_nodes(&nodes)),
+        };
+        Ok(serde_bencode::to_bytes(&state)?)
+    }
+
+    /// Restores the table saved with [`Self::to_bytes`].
+    ///
+    /// Since we don't know how long ago the table was saved, the nodes are
+    /// considered to have been seen now. Nodes that are no longer around are
+    /// removed once they fail to respond to queries.
+    pub fn from_bytes(buf: &[u8], now: Instant) -> Result<Self> {
+        let state: RawState = serde_bencode::from_bytes(buf)?;
+        if state.id.len() != 20 {
+            return Err(DhtError::InvalidMessage);
+        }
+        let mut own_id = [0; 20];
+        own_id.copy_from_slice(&state.id);
+        let mut table = Self::new(own_id);
+        for node in decode_compact_nodes(&state.nodes)? {
+            table.insert(node, now);
+        }
+        Ok(table)
+    }
+
+    /// Returns the index of the bucket to which the node belongs, which is
+    /// the number of leading bits its id shares with our id, or `None` if the
+    /// id is our own.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let leading_zeros = leading_zeros(&distance);
+        if leading_zeros == 160 {
+            None
+        } else {
+            Some(leading_zeros)
+        }
+    }
+}
+
+/// The persisted state of the routing table.
+#[derive(Serialize, Deserialize)]
+struct RawState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+/// Returns the XOR distance of the two ids. Since the distance is returned as
+/// a big-endian byte array, distances can be compared directly.
+pub(crate) fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for (d, (a, b)) in distance.iter_mut().zip(a.iter().zip(b.iter())) {
+        *d = a ^ b;
+    }
+    distance
+}
+
+fn leading_zeros(id: &NodeId) -> usize {
+    let mut count = 0;
+    for byte in id.iter() {
+        if *byte == 0 {
+            count += 8;
+        } else {
+            count += byte.leading_zeros() as usize;
+            break;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+
+    fn node(id: NodeId, port: u16) -> NodeInfo {
+        NodeInfo {
+            id,
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+        }
+    }
+
+    /// Returns the address of the node with the given id, if it's in the
+    /// table.
+    fn addr(table: &RoutingTable, id: &NodeId) -> Option<SocketAddr> {
+        table
+            .closest(id, 1)
+            .into_iter()
+            .find(|node| &node.id == id)
+            .map(|node| node.addr)
+    }
+
+    /// Returns an id that shares exactly `prefix_len` leading bits with the
+    /// zero id, followed by the given byte as the last byte.
+    fn id_with_prefix(prefix_len: usize, last: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[prefix_len / 8] = 0x80 >> (prefix_len % 8);
+        id[19] |= last;
+        id
+    }
+
+    #[test]
+    fn should_place_nodes_in_buckets_by_distance() {
+        let table = RoutingTable::new([0; 20]);
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+        assert_eq!(table.bucket_index(&[0xff; 20]), Some(0));
+        assert_eq!(table.bucket_index(&id_with_prefix(9, 0)), Some(9));
+        assert_eq!(table.bucket_index(&id_with_prefix(159, 0)), Some(159));
+    }
+
+    #[test]
+    fn should_limit_bucket_size() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        // all these nodes fall into the first bucket
+        for i in 0..K as u8 {
+            assert!(table.insert(node(id_with_prefix(0, i), i as u16), now));
+        }
+        assert_eq!(table.len(), K);
+
+        // the bucket is full of good nodes, so new nodes are dropped
+        let new_node = node(id_with_prefix(0, 100), 100);
+        assert!(!table.insert(new_node, now));
+        assert_eq!(addr(&table, &new_node.id), None);
+
+        // but already present nodes are updated
+        let moved_node = node(id_with_prefix(0, 1), 1000);
+        assert!(table.insert(moved_node, now));
+        assert_eq!(addr(&table, &moved_node.id), Some(moved_node.addr));
+
+        // a node that failed to respond is replaced
+        let bad_id = id_with_prefix(0, 2);
+        table.mark_failed(&bad_id);
+        assert!(table.insert(new_node, now));
+        assert_eq!(addr(&table, &new_node.id), Some(new_node.addr));
+        assert_eq!(addr(&table, &bad_id), None);
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn should_remove_failing_nodes() {
+        let mut table = RoutingTable::new([0; 20]);
+        let id = id_with_prefix(3, 0);
+        table.insert(node(id, 1), Instant::now());
+        for _ in 0..MAX_FAILED_QUERY_COUNT - 1 {
+            table.mark_failed(&id);
+            assert!(addr(&table, &id).is_some());
+        }
+        table.mark_failed(&id);
+        assert_eq!(table.len(), 0);
+    }
+
+    #[test]
+    fn should_return_closest_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        for prefix_len in 0..20 {
+            table.insert(node(id_with_prefix(prefix_len, 0), 1), now);
+        }
+        // the target shares the first 10 bits with all nodes in buckets 10
+        // and above, and of these the nodes that share the most bits after
+        // the 11th bit are the furthest from us
+        let target = id_with_prefix(10, 1);
+        let closest = table.closest(&target, 3);
+        assert_eq!(
+            closest.iter().map(|n| n.id).collect::<Vec<_>>(),
+            vec![
+                id_with_prefix(10, 0),
+                id_with_prefix(19, 0),
+                id_with_prefix(18, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn should_restore_saved_table() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([5; 20]);
+        for prefix_len in 0..10 {
+            table.insert(node(id_with_prefix(prefix_len, 0), 1), now);
+        }
+        let buf = table.to_bytes().unwrap();
+
+        let restored = RoutingTable::from_bytes(&buf, now).unwrap();
+        assert_eq!(restored.own_id(), table.own_id());
+        assert_eq!(restored.len(), table.len());
+        assert_eq!(restored.closest(&[0; 20], K), table.closest(&[0; 20], K));
+
+        assert!(RoutingTable::from_bytes(b"d2:id3:abc5:nodes0:e", now).is_err());
+    }
+}
@@ -1,19 +1,24 @@
 //! This module defines the entity responsible for disk IO and various utility
 //! types and functions.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range};
 
 use tokio::{
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, RwLock,
     },
     task,
 };
 
 use crate::{
-    engine, error::Error, peer, storage_info::StorageInfo, torrent, BlockInfo,
-    TorrentId,
+    engine,
+    error::Error,
+    metainfo::PieceHashes,
+    peer,
+    resume::PartialPiece,
+    storage_info::{FilePriority, StorageInfo},
+    torrent, BlockInfo, PieceIndex, TorrentId,
 };
 use error::*;
 use io::torrent::Torrent;
@@ -47,7 +52,14 @@ pub(crate) enum Command {
     NewTorrent {
         id: TorrentId,
         storage_info: StorageInfo,
-        piece_hashes: Vec<u8>,
+        /// The priority of each file. Skipped files are not created.
+        file_priorities: Vec<FilePriority>,
+        /// The expected hashes of the pieces, with which downloaded and
+        /// checked pieces are verified.
+        piece_hashes: PieceHashes,
+        /// The blocks of the pieces that were being downloaded when the
+        /// torrent was last stopped, which are read back from disk.
+        partial_pieces: Vec<PartialPiece>,
         torrent_tx: torrent::Sender,
     },
     /// Request to eventually write a block to disk.
@@ -63,6 +75,28 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         result_tx: peer::Sender,
     },
+    /// Checks the pieces in the range against their expected hashes, sending
+    /// the result of each to the torrent.
+    CheckPieces {
+        id: TorrentId,
+        pieces: Range<PieceIndex>,
+    },
+    /// Writes the blocks of the torrent's incomplete pieces to disk and
+    /// returns them via the sender, so that they can be restored after
+    /// a restart.
+    FlushPartialPieces {
+        id: TorrentId,
+        result_tx: oneshot::Sender<Vec<PartialPiece>>,
+    },
+    /// Removes the torrent from disk, and if set, deletes its files too.
+    ///
+    /// The blocks of the incomplete pieces that are not yet written to disk
+    /// are discarded.
+    RemoveTorrent {
+        id: TorrentId,
+        delete_files: bool,
+        result_tx: oneshot::Sender<std::io::Result<()>>,
+    },
     /// Eventually shut down the disk task.
     Shutdown,
 }
@@ -104,7 +138,9 @@ impl Disk {
                 Command::NewTorrent {
                     id,
                     storage_info,
+                    file_priorities,
                     piece_hashes,
+                    partial_pieces,
                     torrent_tx,
                 } => {
                     log::trace!(
@@ -126,11 +162,19 @@ impl Disk {
                     // NOTE: Do _NOT_ return on failure, we don't want to kill
                     // the disk task due to potential disk IO errors: we just
                     // want to log it and notify engine of it.
-                    let torrent_res =
-                        Torrent::new(storage_info, piece_hashes, torrent_tx);
+                    let torrent_res = Torrent::new(
+                        storage_info,
+                        &file_priorities,
+                        piece_hashes,
+                        torrent_tx,
+                    );
                     match torrent_res {
-                        Ok(torrent) => {
+                        Ok(mut torrent) => {
                             log::info!("Torrent {} successfully allocated", id);
+                            if !partial_pieces.is_empty() {
+                                torrent
+                                    .restore_partial_pieces(&partial_pieces)?;
+                            }
                             self.torrents.insert(id, RwLock::new(torrent));
                             // send notificaiton of allocation success
                             self.engine_tx.send(
@@ -170,6 +214,19 @@ impl Disk {
                 } => {
                     self.read_block(id, block_info, result_tx).await?;
                 }
+                Command::CheckPieces { id, pieces } => {
+                    self.check_pieces(id, pieces).await?;
+                }
+                Command::FlushPartialPieces { id, result_tx } => {
+                    self.flush_partial_pieces(id, result_tx).await?;
+                }
+                Command::RemoveTorrent {
+                    id,
+                    delete_files,
+                    result_tx,
+                } => {
+                    self.remove_torrent(id, delete_files, result_tx);
+                }
                 Command::Shutdown => {
                     log::info!("Shutting down disk event loop");
                     break;
@@ -231,6 +288,66 @@ impl Disk {
         })?;
         torrent.read().await.read_block(block_info, tx)
     }
+
+    /// Starts checking the torrent's pieces in the range against their
+    /// expected hashes.
+    ///
+    /// Returns an error if the torrent id is invalid.
+    async fn check_pieces(
+        &self,
+        id: TorrentId,
+        pieces: Range<PieceIndex>,
+    ) -> Result<()> {
+        log::trace!("Checking torrent {} pieces {:?}", id, pieces);
+        let torrent = self.torrents.get(&id).ok_or_else(|| {
+            log::error!("Torrent {} not found", id);
+            Error::InvalidTorrentId
+        })?;
+        torrent.read().await.check_pieces(pieces);
+        Ok(())
+    }
+
+    /// Writes the blocks of the torrent's incomplete pieces to disk and
+    /// returns them via the given sender.
+    ///
+    /// Returns an error if the torrent id is invalid.
+    async fn flush_partial_pieces(
+        &self,
+        id: TorrentId,
+        result_tx: oneshot::Sender<Vec<PartialPiece>>,
+    ) -> Result<()> {
+        log::trace!("Flushing torrent {} partial pieces to disk", id);
+        let torrent = self.torrents.get(&id).ok_or_else(|| {
+            log::error!("Torrent {} not found", id);
+            Error::InvalidTorrentId
+        })?;
+        let partial_pieces = torrent.read().await.flush_partial_pieces();
+        // the torrent may have stopped waiting for the result
+        result_tx.send(partial_pieces).ok();
+        Ok(())
+    }
+
+    /// Removes the torrent, deleting its files if set, and returns the result
+    /// via the given sender.
+    ///
+    /// A torrent that couldn't be allocated has nothing to remove, so an
+    /// unknown torrent id is not an error here.
+    fn remove_torrent(
+        &mut self,
+        id: TorrentId,
+        delete_files: bool,
+        result_tx: oneshot::Sender<std::io::Result<()>>,
+    ) {
+        log::trace!("Removing torrent {} (delete files: {})", id, delete_files);
+        let result = match self.torrents.remove(&id) {
+            Some(torrent) if delete_files => {
+                torrent.into_inner().delete_files()
+            }
+            _ => Ok(()),
+        };
+        // the engine may have stopped waiting for the result
+        result_tx.send(result).ok();
+    }
 }
 
 #[cfg(test)]
@@ -266,7 +383,9 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
                 piece_hashes: piece_hashes.clone(),
+                partial_pieces: Vec::new(),
                 torrent_tx: torrent_tx.clone(),
             })
             .unwrap();
@@ -286,7 +405,9 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info,
+                file_priorities: vec![FilePriority::Normal],
                 piece_hashes,
+                partial_pieces: Vec::new(),
                 torrent_tx: torrent_tx.clone(),
             })
             .unwrap();
@@ -323,7 +444,9 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
                 piece_hashes: piece_hashes.clone(),
+                partial_pieces: Vec::new(),
                 torrent_tx: torrent_tx.clone(),
             })
             .unwrap();
@@ -386,7 +509,9 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
                 piece_hashes: piece_hashes.clone(),
+                partial_pieces: Vec::new(),
                 torrent_tx: torrent_tx.clone(),
             })
             .unwrap();
@@ -444,7 +569,9 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
                 piece_hashes: piece_hashes.clone(),
+                partial_pieces: Vec::new(),
                 torrent_tx: torrent_tx.clone(),
             })
             .unwrap();
@@ -512,6 +639,223 @@ mod tests {
             .expect("cannot clean up disk test torrent file");
     }
 
+    /// Tests that checking the pieces on disk reports only the pieces that
+    /// were written as valid.
+    #[tokio::test]
+    async fn should_check_pieces() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            mut torrent_rx,
+        } = Env::new("check_pieces");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
+                piece_hashes,
+                partial_pieces: Vec::new(),
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // write a piece to disk
+        let index = 1;
+        let piece = &pieces[index];
+        for_each_block(index, piece.len() as u32, |block| {
+            let block_end = block.offset + block.len;
+            let data = &piece[block.offset as usize..block_end as usize];
+            disk_tx
+                .send(Command::WriteBlock {
+                    id,
+                    block_info: block,
+                    data: data.to_vec(),
+                })
+                .unwrap();
+        });
+        assert!(torrent_rx.recv().await.is_some());
+
+        disk_tx
+            .send(Command::CheckPieces {
+                id,
+                pieces: 0..pieces.len(),
+            })
+            .unwrap();
+        for expected_index in 0..pieces.len() {
+            if let Some(torrent::Command::PieceChecked { index, is_valid }) =
+                torrent_rx.recv().await
+            {
+                assert_eq!(index, expected_index);
+                assert_eq!(is_valid, index == 1);
+            } else {
+                panic!("piece could not be checked");
+            }
+        }
+
+        // clean up test env
+        let file = info.files.first().unwrap();
+        fs::remove_file(info.download_dir.join(&file.path))
+            .expect("cannot clean up disk test torrent file");
+    }
+
+    /// Tests that the blocks of an incomplete piece are written to disk when
+    /// flushed, and that a torrent created with them completes the piece
+    /// with only the rest of its blocks.
+    #[tokio::test]
+    async fn should_flush_and_restore_partial_pieces() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            mut torrent_rx,
+        } = Env::new("flush_and_restore_partial_pieces");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
+                piece_hashes: piece_hashes.clone(),
+                partial_pieces: Vec::new(),
+                torrent_tx: torrent_tx.clone(),
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // write every other block of the piece
+        let index = 1;
+        let piece = &pieces[index];
+        let write_block = |id, block: BlockInfo| {
+            let block_end = block.offset + block.len;
+            let data = &piece[block.offset as usize..block_end as usize];
+            disk_tx
+                .send(Command::WriteBlock {
+                    id,
+                    block_info: block,
+                    data: data.to_vec(),
+                })
+                .unwrap();
+        };
+        for_each_block(index, piece.len() as u32, |block| {
+            if block.index_in_piece() % 2 == 0 {
+                write_block(id, block);
+            }
+        });
+
+        let (result_tx, result_rx) = oneshot::channel();
+        disk_tx
+            .send(Command::FlushPartialPieces { id, result_tx })
+            .unwrap();
+        let partial_pieces = result_rx.await.unwrap();
+        assert_eq!(
+            partial_pieces,
+            vec![PartialPiece {
+                index,
+                blocks: vec![0, 2 * BLOCK_LEN],
+            }]
+        );
+
+        // the restarted torrent only needs the rest of the blocks
+        let id = TorrentId::new();
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
+                piece_hashes,
+                partial_pieces,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+        for_each_block(index, piece.len() as u32, |block| {
+            if block.index_in_piece() % 2 == 1 {
+                write_block(id, block);
+            }
+        });
+
+        if let Some(torrent::Command::PieceCompletion(Ok(piece))) =
+            torrent_rx.recv().await
+        {
+            assert_eq!(piece.index, index);
+            assert!(piece.is_valid);
+        } else {
+            panic!("piece could not be written to disk");
+        }
+
+        // clean up test env
+        let file = info.files.first().unwrap();
+        fs::remove_file(info.download_dir.join(&file.path))
+            .expect("cannot clean up disk test torrent file");
+    }
+
+    /// Tests that removing a torrent deletes its files when asked to, and
+    /// that removing an unknown torrent is not an error.
+    #[tokio::test]
+    async fn should_remove_torrent_and_delete_files() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("remove_torrent_and_delete_files");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![FilePriority::Normal],
+                piece_hashes,
+                partial_pieces: Vec::new(),
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+        let file = info.files.first().unwrap();
+        let path = info.download_dir.join(&file.path);
+        assert!(path.is_file());
+
+        let (result_tx, result_rx) = oneshot::channel();
+        disk_tx
+            .send(Command::RemoveTorrent {
+                id,
+                delete_files: true,
+                result_tx,
+            })
+            .unwrap();
+        assert!(result_rx.await.unwrap().is_ok());
+        assert!(!path.exists());
+        // the download directory is shared with other torrents
+        assert!(info.download_dir.is_dir());
+
+        let (result_tx, result_rx) = oneshot::channel();
+        disk_tx
+            .send(Command::RemoveTorrent {
+                id,
+                delete_files: true,
+                result_tx,
+            })
+            .unwrap();
+        assert!(result_rx.await.unwrap().is_ok());
+    }
+
     /// Calls the provided function for each block in piece, passing it the
     /// block's `BlockInfo`.
     fn for_each_block(
@@ -546,7 +890,7 @@ mod tests {
     struct Env {
         id: TorrentId,
         pieces: Vec<Vec<u8>>,
-        piece_hashes: Vec<u8>,
+        piece_hashes: PieceHashes,
         info: StorageInfo,
         torrent_tx: torrent::Sender,
         torrent_rx: torrent::Receiver,
@@ -589,6 +933,7 @@ mod tests {
                 piece_hashes.extend(hash.as_slice());
             }
             assert_eq!(piece_hashes.len(), pieces.len() * 20);
+            let piece_hashes = PieceHashes::V1(piece_hashes);
 
             // clean up any potential previous test env
             {
@@ -619,6 +964,7 @@ mod tests {
                     torrent_offset: 0,
                     len: download_len,
                 }],
+                is_piece_aligned: false,
             };
 
             let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
@@ -24,6 +24,7 @@ mod tests {
             },
         },
         iovecs::IoVec,
+        metainfo::PieceHash,
         storage_info::FileInfo,
         FileIndex, BLOCK_LEN,
     };
@@ -62,6 +63,8 @@ mod tests {
         // read and compare
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -102,6 +105,8 @@ mod tests {
         let mut file = files[0].write().unwrap();
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -237,6 +242,8 @@ mod tests {
             let mut file = file.write().unwrap();
             let mut file_content = Vec::new();
             file.handle
+                .as_mut()
+                .unwrap()
                 .read_to_end(&mut file_content)
                 .expect("cannot read test file");
             // compare the content of file to the portion that corresponds to
@@ -325,6 +332,67 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    /// Tests that a deferred file is only created when a piece is written to
+    /// it.
+    #[test]
+    fn should_create_deferred_file_on_write() {
+        let file_range = 0..2;
+        let piece = make_piece(file_range.clone());
+        let download_dir = Path::new(DOWNLOAD_DIR);
+        let subdir = download_dir.join("Piece_write_deferred");
+        if subdir.exists() {
+            fs::remove_dir_all(&subdir).expect("cannot clean up test dir");
+        }
+        let file1 = TorrentFile::new(
+            download_dir,
+            FileInfo {
+                path: PathBuf::from("Piece_write_deferred1.test"),
+                torrent_offset: 0,
+                len: BLOCK_LEN as u64 + 3,
+            },
+        )
+        .expect("cannot create test file 1");
+        let file2 = TorrentFile::new_deferred(
+            download_dir,
+            FileInfo {
+                path: subdir.join("Piece_write_deferred2.test"),
+                torrent_offset: file1.info.len,
+                len: piece.len as u64 - file1.info.len,
+            },
+        );
+        let file2_path = download_dir.join(&file2.info.path);
+        let files = &[sync::RwLock::new(file1), sync::RwLock::new(file2)];
+        assert!(!file2_path.exists());
+
+        // the deferred file has no data to read
+        let torrent_piece_offset = 0;
+        let result = piece::read(
+            torrent_piece_offset,
+            file_range.clone(),
+            files,
+            piece.len,
+        );
+        assert!(matches!(result, Err(ReadError::MissingData)));
+
+        piece
+            .write(torrent_piece_offset, files)
+            .expect("cannot write piece to files");
+        assert!(file2_path.exists());
+        let blocks =
+            piece::read(torrent_piece_offset, file_range, files, piece.len)
+                .expect("cannot read piece from files");
+        let actual: Vec<_> =
+            blocks.iter().flat_map(|b| b.iter().copied()).collect();
+        let expected: Vec<_> =
+            piece.blocks.values().flatten().copied().collect();
+        assert_eq!(actual, expected);
+
+        // clean up env
+        fs::remove_file(download_dir.join(&files[0].read().unwrap().info.path))
+            .expect("cannot remove test file");
+        fs::remove_dir_all(&subdir).expect("cannot remove test dir");
+    }
+
     /// Creates a piece for testing that has 4 blocks of length `BLOCK_LEN`.
     fn make_piece(files: Range<FileIndex>) -> Piece {
         let blocks = vec![
@@ -350,7 +418,7 @@ mod tests {
             for block in blocks.iter() {
                 hasher.update(&block);
             }
-            hasher.finalize().into()
+            PieceHash::V1(hasher.finalize().into())
         };
         let len = blocks.len() as u32 * BLOCK_LEN;
         // convert blocks to a b-tree map
@@ -1,7 +1,7 @@
 use std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
     os::unix::io::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use nix::sys::uio::{preadv, pwritev};
@@ -16,7 +16,11 @@ use crate::{
 
 pub(crate) struct TorrentFile {
     pub info: FileInfo,
-    pub handle: File,
+    /// The handle of the opened file, or `None` if the file is not yet
+    /// created.
+    pub handle: Option<File>,
+    /// The absolute path of the file.
+    path: PathBuf,
 }
 
 impl TorrentFile {
@@ -32,17 +36,38 @@ impl TorrentFile {
             download_dir
         );
         let path = download_dir.join(&info.path);
-        let handle = OpenOptions::new()
+        let handle = Self::open(&path).map_err(|e| {
+            log::warn!("Failed to open file {:?}", path);
+            NewTorrentError::Io(e)
+        })?;
+        debug_assert!(path.exists());
+        Ok(Self {
+            info,
+            handle: Some(handle),
+            path,
+        })
+    }
+
+    /// Returns a file that is only created, along with its parent
+    /// directories, when it is first written to.
+    ///
+    /// This is used for the files the user doesn't want to download, which
+    /// are still written to if a downloaded piece overlaps with them.
+    pub fn new_deferred(download_dir: &Path, info: FileInfo) -> Self {
+        let path = download_dir.join(&info.path);
+        Self {
+            info,
+            handle: None,
+            path,
+        }
+    }
+
+    fn open(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new()
             .create(true)
             .write(true)
             .read(true)
-            .open(&path)
-            .map_err(|e| {
-                log::warn!("Failed to open file {:?}", path);
-                NewTorrentError::Io(e)
-            })?;
-        debug_assert!(path.exists());
-        Ok(Self { info, handle })
+            .open(path)
     }
 
     /// Writes to file at most the slice length number of bytes of blocks at the
@@ -59,10 +84,19 @@ impl TorrentFile {
     /// Since the syscall may be invoked repeatedly to perform disk IO, this
     /// means that this operation is not guaranteed to be atomic.
     pub fn write<'a>(
-        &self,
+        &mut self,
         file_slice: FileSlice,
         blocks: &'a mut [IoVec<&'a [u8]>],
     ) -> Result<&'a mut [IoVec<&'a [u8]>], WriteError> {
+        if self.handle.is_none() {
+            log::info!("Creating deferred file {:?}", self.path);
+            if let Some(subdir) = self.path.parent() {
+                fs::create_dir_all(subdir).map_err(WriteError::Io)?;
+            }
+            self.handle = Some(Self::open(&self.path).map_err(WriteError::Io)?);
+        }
+        let handle = self.handle.as_ref().expect("file not created");
+
         let mut iovecs = IoVecs::bounded(blocks, file_slice.len as usize);
         // the write buffer cannot be larger than the file slice we want to
         // write to
@@ -81,7 +115,7 @@ impl TorrentFile {
         let mut total_write_count = 0;
         while !iovecs.as_slice().is_empty() {
             let write_count = pwritev(
-                self.handle.as_raw_fd(),
+                handle.as_raw_fd(),
                 iovecs.as_slice(),
                 file_slice.offset as i64,
             )
@@ -127,6 +161,9 @@ impl TorrentFile {
         file_slice: FileSlice,
         mut iovecs: &'a mut [IoVec<&'a mut [u8]>],
     ) -> Result<&'a mut [IoVec<&'a mut [u8]>], ReadError> {
+        // a file that was not yet created has no data to read
+        let handle = self.handle.as_ref().ok_or(ReadError::MissingData)?;
+
         // This is simpler than the write implementation as the preadv method
         // stops reading in from the file if reaching EOF. We do need to advance
         // the iovecs read buffer cursor after a read as we may want to read
@@ -138,16 +175,17 @@ impl TorrentFile {
         // transferred to disk (or an error occurs)
         let mut total_read_count = 0;
         while !iovecs.is_empty() && (total_read_count as u64) < file_slice.len {
-            let read_count = preadv(
-                self.handle.as_raw_fd(),
-                iovecs,
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
-                log::warn!("File {:?} read error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                ReadError::Io(std::io::Error::last_os_error())
-            })?;
+            let read_count =
+                preadv(handle.as_raw_fd(), iovecs, file_slice.offset as i64)
+                    .map_err(|e| {
+                        log::warn!(
+                            "File {:?} read error: {}",
+                            self.info.path,
+                            e
+                        );
+                        // FIXME: convert actual error here
+                        ReadError::Io(std::io::Error::last_os_error())
+                    })?;
 
             // if there was nothing to read from file it means we tried to
             // read a piece from a portion of a file not yet downloaded or
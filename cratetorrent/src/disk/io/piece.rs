@@ -10,14 +10,16 @@ use crate::{
     block_count, block_len,
     disk::{error::*, io::file::TorrentFile},
     iovecs::IoVec,
-    CachedBlock, FileIndex, Sha1Hash,
+    merkle,
+    metainfo::PieceHash,
+    CachedBlock, FileIndex,
 };
 
 /// An in-progress piece download that keeps in memory the so far downloaded
 /// blocks and the expected hash of the piece.
 pub(crate) struct Piece {
     /// The expected hash of the whole piece.
-    pub expected_hash: Sha1Hash,
+    pub expected_hash: PieceHash,
     /// The length of the piece, in bytes.
     pub len: u32,
     /// The so far downloaded blocks. Once the size of this map reaches the
@@ -68,13 +70,7 @@ impl Piece {
         // sanity check that we only call this method if we have all blocks in
         // piece
         debug_assert_eq!(self.blocks.len(), block_count(self.len));
-        let mut hasher = Sha1::new();
-        for block in self.blocks.values() {
-            hasher.update(&block);
-        }
-        let hash = hasher.finalize();
-        log::debug!("Piece hash: {:x}", hash);
-        hash.as_slice() == self.expected_hash
+        matches_hash(self.blocks.values(), &self.expected_hash)
     }
 
     /// Writes the piece's blocks to the files the piece overlaps with.
@@ -95,53 +91,102 @@ impl Piece {
             .values()
             .map(|b| IoVec::from_slice(&b))
             .collect();
-        // the actual slice of blocks being worked on
-        let mut bufs = blocks.as_mut_slice();
-
-        // loop through all files piece overlaps with and write that part of
-        // piece to file
-        let files = &files[self.file_range.clone()];
-        debug_assert!(!files.is_empty());
-        // the offset at which we need to write in torrent, which is updated
-        // with each write
-        let mut torrent_write_offset = torrent_piece_offset;
-        let mut total_write_count = 0;
-
-        for file in files.iter() {
-            let file = file.write().unwrap();
-
-            // determine which part of the file we need to write to
-            debug_assert!(self.len as u64 > total_write_count);
-            let remaining_piece_len = self.len as u64 - total_write_count;
-            let file_slice = file
-                .info
-                .get_slice(torrent_write_offset, remaining_piece_len);
-            // an empty file slice shouldn't occur as it would mean that piece
-            // was thought to span fewer files than it actually does
-            debug_assert!(file_slice.len > 0);
-            // the write buffer should still contain bytes to write
-            debug_assert!(!bufs.is_empty());
-            debug_assert!(!bufs[0].as_slice().is_empty());
-
-            // write to file
-            let tail = file.write(file_slice, bufs)?;
-
-            // `write_vectored_at` only writes at most `slice.len` bytes of
-            // `bufs` to disk and returns the portion that wasn't
-            // written, which we can use to set the write buffer for the next
-            // round
-            bufs = tail;
-
-            torrent_write_offset += file_slice.len as u64;
-            total_write_count += file_slice.len;
+        write(
+            torrent_piece_offset,
+            self.len as u64,
+            blocks.as_mut_slice(),
+            &files[self.file_range.clone()],
+        )
+    }
+}
+
+/// Calculates the hash of the piece made up of the blocks, in order, and
+/// returns if it matches the expected hash.
+///
+/// The hash of v1 pieces is the SHA-1 hash of the whole piece, while that of
+/// v2 pieces is the root of their merkle subtree.
+///
+/// # Important
+///
+/// This is potentially a computationally expensive function and should be
+/// executed on a thread pool and not the executor.
+pub(super) fn matches_hash(
+    blocks: impl Iterator<Item = impl AsRef<[u8]>>,
+    expected_hash: &PieceHash,
+) -> bool {
+    match expected_hash {
+        PieceHash::V1(expected_hash) => {
+            let mut hasher = Sha1::new();
+            for block in blocks {
+                hasher.update(block.as_ref());
+            }
+            let hash = hasher.finalize();
+            log::debug!("Piece hash: {:x}", hash);
+            hash.as_slice() == expected_hash
+        }
+        PieceHash::V2 { root, leaf_count } => {
+            let hash = merkle::piece_root(blocks, *leaf_count);
+            log::debug!("Piece hash: {}", hex::encode(hash));
+            &hash == root
         }
+    }
+}
+
+/// Writes the buffers, which are `len` bytes in total, at the absolute offset
+/// in the whole torrent. `files` must be the files that these bytes overlap
+/// with.
+///
+/// # Important
+///
+/// This performs sync IO and is thus potentially blocking and should be
+/// executed on a thread pool, and not the async executor.
+pub(super) fn write<'a>(
+    torrent_offset: u64,
+    len: u64,
+    mut bufs: &'a mut [IoVec<&'a [u8]>],
+    files: &[sync::RwLock<TorrentFile>],
+) -> Result<(), WriteError> {
+    // loop through all files the buffers overlap with and write that part of
+    // the buffers to file
+    debug_assert!(!files.is_empty());
+    // the offset at which we need to write in torrent, which is updated
+    // with each write
+    let mut torrent_write_offset = torrent_offset;
+    let mut total_write_count = 0;
 
-        // we should have used up all write buffers (i.e. written all blocks to
-        // disk)
-        debug_assert!(bufs.is_empty());
+    for file in files.iter() {
+        let mut file = file.write().unwrap();
 
-        Ok(())
+        // determine which part of the file we need to write to
+        debug_assert!(len > total_write_count);
+        let remaining_len = len - total_write_count;
+        let file_slice =
+            file.info.get_slice(torrent_write_offset, remaining_len);
+        // an empty file slice shouldn't occur as it would mean that the
+        // buffers were thought to span fewer files than they actually do
+        debug_assert!(file_slice.len > 0);
+        // the write buffer should still contain bytes to write
+        debug_assert!(!bufs.is_empty());
+        debug_assert!(!bufs[0].as_slice().is_empty());
+
+        // write to file
+        let tail = file.write(file_slice, bufs)?;
+
+        // `write_vectored_at` only writes at most `slice.len` bytes of
+        // `bufs` to disk and returns the portion that wasn't
+        // written, which we can use to set the write buffer for the next
+        // round
+        bufs = tail;
+
+        torrent_write_offset += file_slice.len as u64;
+        total_write_count += file_slice.len;
     }
+
+    // we should have used up all write buffers (i.e. written all bytes to
+    // disk)
+    debug_assert!(bufs.is_empty());
+
+    Ok(())
 }
 
 /// Reads a piece's blocks from the specified portion of the file from disk.
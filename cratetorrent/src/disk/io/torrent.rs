@@ -1,6 +1,7 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    fs,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    ops::Range,
     sync::{
         self,
         atomic::{AtomicU64, AtomicUsize, Ordering},
@@ -12,6 +13,7 @@ use lru::LruCache;
 use tokio::task;
 
 use crate::{
+    block_len,
     disk::{
         error::*,
         io::{
@@ -19,10 +21,13 @@ use crate::{
             piece::{self, Piece},
         },
     },
+    iovecs::IoVec,
+    metainfo::PieceHashes,
     peer,
-    storage_info::StorageInfo,
+    resume::PartialPiece,
+    storage_info::{FilePriority, StorageInfo},
     torrent::{self, PieceCompletion},
-    Block, BlockInfo, CachedBlock, PieceIndex,
+    Block, BlockInfo, CachedBlock, PieceIndex, BLOCK_LEN,
 };
 
 /// Torrent information related to disk IO.
@@ -46,8 +51,8 @@ pub(crate) struct Torrent {
     /// them to an IO worker threads. See more in [`ThreadContext`].
     thread_ctx: Arc<ThreadContext>,
 
-    /// The concatenation of all expected piece hashes.
-    piece_hashes: Vec<u8>,
+    /// The expected hashes of all pieces.
+    piece_hashes: PieceHashes,
 }
 
 /// Contains fields that are commonly accessed by torrent's IO threads.
@@ -96,7 +101,8 @@ struct ThreadContext {
     read_cache: sync::Mutex<LruCache<PieceIndex, Vec<CachedBlock>>>,
 
     /// Handles of all files in torrent, opened in advance during torrent
-    /// creation.
+    /// creation, except for skipped files, which are only created if
+    /// a downloaded piece overlaps with them.
     ///
     /// Each writer thread will get exclusive access to the file handle it
     /// needs, referring to it directly in the vector (hence the arc).
@@ -138,9 +144,12 @@ impl Torrent {
     /// For a single file, there is a path validity check and then the file is
     /// opened. For multi-file torrents, if there are any subdirectories in the
     /// torrent archive, they are created and all files are opened.
+    ///
+    /// Files with [`FilePriority::Skip`] are not created.
     pub fn new(
         info: StorageInfo,
-        piece_hashes: Vec<u8>,
+        file_priorities: &[FilePriority],
+        piece_hashes: PieceHashes,
         torrent_tx: torrent::Sender,
     ) -> Result<Self, NewTorrentError> {
         // TODO: since this is done as part of a tokio::task, should we use
@@ -156,6 +165,7 @@ impl Torrent {
 
         // TODO: return error instead
         debug_assert_ne!(info.files.len(), 0, "torrent must have files");
+        debug_assert_eq!(info.files.len(), file_priorities.len());
         let files = if info.files.len() == 1 {
             let file = &info.files[0];
             log::debug!(
@@ -163,21 +173,34 @@ impl Torrent {
                 file.len,
                 file.path
             );
-            vec![sync::RwLock::new(TorrentFile::new(
-                &info.download_dir,
-                file.clone(),
-            )?)]
+            let file = if file_priorities[0] == FilePriority::Skip {
+                TorrentFile::new_deferred(&info.download_dir, file.clone())
+            } else {
+                TorrentFile::new(&info.download_dir, file.clone())?
+            };
+            vec![sync::RwLock::new(file)]
         } else {
             debug_assert!(!info.files.is_empty());
             log::debug!("Torrent is multi file: {:?}", info.files);
             log::debug!("Setting up directory structure");
 
             let mut torrent_files = Vec::with_capacity(info.files.len());
-            for file in info.files.iter() {
+            for (file, priority) in info.files.iter().zip(file_priorities) {
+                // skipped files, along with their subdirectories, are only
+                // created when first written to
+                if *priority == FilePriority::Skip {
+                    log::debug!("Deferring creation of file {:?}", file.path);
+                    torrent_files.push(sync::RwLock::new(
+                        TorrentFile::new_deferred(
+                            &info.download_dir,
+                            file.clone(),
+                        ),
+                    ));
+                    continue;
+                }
+
+                // the file may already exist if the torrent is resumed
                 let path = info.download_dir.join(&file.path);
-                // file or subdirectory in download root must not exist if
-                // download root does not exists
-                debug_assert!(!path.exists());
                 debug_assert!(path.is_absolute());
 
                 // get the parent of the file path: if there is one (i.e.
@@ -322,6 +345,213 @@ impl Torrent {
         Ok(())
     }
 
+    /// Reads back into the write buffer the blocks of the pieces that were
+    /// not complete when the torrent was last stopped, so that the pieces can
+    /// be completed with the rest of their blocks.
+    ///
+    /// The torrent is notified of the blocks that could not be read, which
+    /// need to be downloaded again.
+    ///
+    /// # Important
+    ///
+    /// This performs sync IO, but it's only done once, when the torrent is
+    /// created, and only for the few pieces that were being downloaded.
+    pub fn restore_partial_pieces(
+        &mut self,
+        pieces: &[PartialPiece],
+    ) -> Result<()> {
+        let mut failed_block_count = 0;
+        for piece in pieces.iter() {
+            if !self.write_buf.contains_key(&piece.index) {
+                self.start_new_piece(piece.index);
+            }
+            let piece_len = self.info.piece_len(piece.index);
+            let torrent_piece_offset =
+                self.info.torrent_piece_offset(piece.index);
+            for &offset in piece.blocks.iter() {
+                let block_info = BlockInfo {
+                    piece_index: piece.index,
+                    offset,
+                    len: block_len(piece_len, (offset / BLOCK_LEN) as usize),
+                };
+                let torrent_offset = torrent_piece_offset + offset as u64;
+                let file_range = self.info.files_intersecting_bytes(
+                    torrent_offset..torrent_offset + block_info.len as u64,
+                );
+                match piece::read(
+                    torrent_offset,
+                    file_range,
+                    &self.thread_ctx.files,
+                    block_info.len,
+                ) {
+                    Ok(mut blocks) => {
+                        debug_assert_eq!(blocks.len(), 1);
+                        let data = Arc::try_unwrap(blocks.remove(0))
+                            .unwrap_or_else(|block| block.to_vec());
+                        self.write_buf
+                            .get_mut(&piece.index)
+                            .expect("Newly inserted piece not present")
+                            .enqueue_block(offset, data);
+                    }
+                    Err(e) => {
+                        log::warn!(
+                            "Cannot restore block {}: {}",
+                            block_info,
+                            e
+                        );
+                        failed_block_count += 1;
+                        self.thread_ctx.tx.send(
+                            torrent::Command::RestoreError {
+                                block_info,
+                                error: e,
+                            },
+                        )?;
+                    }
+                }
+            }
+        }
+        log::info!(
+            "Restored {} partial piece(s) ({} block(s) lost)",
+            pieces.len(),
+            failed_block_count
+        );
+        Ok(())
+    }
+
+    /// Writes the blocks of the pieces that are not yet complete to disk and
+    /// returns them, so that the pieces can be resumed after a restart.
+    ///
+    /// The pieces are kept in the write buffer. Blocks that fail to be
+    /// written are left out.
+    ///
+    /// # Important
+    ///
+    /// This performs sync IO, but it's only done when the torrent is stopped
+    /// and the write buffer is bounded by the pieces being downloaded.
+    pub fn flush_partial_pieces(&self) -> Vec<PartialPiece> {
+        let mut partial_pieces = Vec::with_capacity(self.write_buf.len());
+        for (&index, piece) in self.write_buf.iter() {
+            let torrent_piece_offset = self.info.torrent_piece_offset(index);
+            let mut blocks = Vec::with_capacity(piece.blocks.len());
+            for (&offset, data) in piece.blocks.iter() {
+                let torrent_offset = torrent_piece_offset + offset as u64;
+                let len = data.len() as u64;
+                let file_range = self.info.files_intersecting_bytes(
+                    torrent_offset..torrent_offset + len,
+                );
+                let mut bufs = [IoVec::from_slice(data)];
+                match piece::write(
+                    torrent_offset,
+                    len,
+                    &mut bufs,
+                    &self.thread_ctx.files[file_range],
+                ) {
+                    Ok(()) => blocks.push(offset),
+                    Err(e) => log::warn!(
+                        "Cannot save piece {} block at offset {}: {}",
+                        index,
+                        offset,
+                        e
+                    ),
+                }
+            }
+            if !blocks.is_empty() {
+                partial_pieces.push(PartialPiece { index, blocks });
+            }
+        }
+        partial_pieces
+    }
+
+    /// Deletes the torrent's files, along with the directories of the torrent
+    /// that are left empty. Files that were never created are skipped.
+    ///
+    /// The directories are only removed if they're empty, so that files that
+    /// the user put there are kept.
+    pub fn delete_files(self) -> io::Result<()> {
+        let Self {
+            info, thread_ctx, ..
+        } = self;
+        // close the file handles first
+        drop(thread_ctx);
+
+        let mut subdirs = BTreeSet::new();
+        for file in info.files.iter() {
+            let path = info.download_dir.join(&file.path);
+            match fs::remove_file(&path) {
+                Ok(()) => log::info!("Deleted file {:?}", path),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    log::warn!("Failed to delete file {:?}: {}", path, e);
+                    return Err(e);
+                }
+            }
+            subdirs.extend(
+                file.path
+                    .ancestors()
+                    .skip(1)
+                    .filter(|dir| !dir.as_os_str().is_empty())
+                    .map(|dir| dir.to_path_buf()),
+            );
+        }
+
+        // a directory is ordered after its parent, so going in reverse removes
+        // the subdirectories before their parents
+        for subdir in subdirs.iter().rev() {
+            fs::remove_dir(info.download_dir.join(subdir)).ok();
+        }
+        // an archive is downloaded into its own directory, but a single file
+        // is downloaded directly into the download directory, which is kept
+        if info.files.len() > 1 {
+            fs::remove_dir(&info.download_dir).ok();
+        }
+
+        Ok(())
+    }
+
+    /// Reads the pieces from disk and checks them against their expected
+    /// hashes, sending the result of each to the torrent.
+    ///
+    /// Pieces that can't be read, such as when their files don't exist, are
+    /// reported as invalid.
+    pub fn check_pieces(&self, pieces: Range<PieceIndex>) {
+        log::info!("Checking pieces {:?}", pieces);
+        debug_assert!(pieces.end <= self.info.piece_count);
+
+        // don't block the reactor with the sync file reads and the hashing
+        let info = self.info.clone();
+        let piece_hashes = self.piece_hashes.clone();
+        let ctx = Arc::clone(&self.thread_ctx);
+        task::spawn_blocking(move || {
+            for index in pieces {
+                let expected_hash = piece_hashes.get(index);
+                let is_valid = match piece::read(
+                    info.torrent_piece_offset(index),
+                    info.files_intersecting_piece(index),
+                    &ctx.files,
+                    info.piece_len(index),
+                ) {
+                    Ok(blocks) => piece::matches_hash(
+                        blocks.iter().map(|b| b.as_slice()),
+                        &expected_hash,
+                    ),
+                    Err(e) => {
+                        log::debug!("Cannot read piece {}: {}", index, e);
+                        false
+                    }
+                };
+
+                if ctx
+                    .tx
+                    .send(torrent::Command::PieceChecked { index, is_valid })
+                    .is_err()
+                {
+                    log::debug!("Torrent stopped, aborting piece check");
+                    return;
+                }
+            }
+        });
+    }
+
     /// Starts a new in-progress piece, creating metadata for it in self.
     ///
     /// This involves getting the expected hash of the piece, its length, and
@@ -334,19 +564,8 @@ impl Torrent {
             "piece index is invalid"
         );
 
-        // get the position of the piece in the concatenated hash string
-        let hash_pos = piece_index * 20;
-        // the above assert should take care of this, but just in case
-        debug_assert!(hash_pos + 20 <= self.piece_hashes.len());
-
-        let hash_slice = &self.piece_hashes[hash_pos..hash_pos + 20];
-        let mut expected_hash = [0; 20];
-        expected_hash.copy_from_slice(hash_slice);
-        log::debug!(
-            "Piece {} expected hash {}",
-            piece_index,
-            hex::encode(&expected_hash)
-        );
+        let expected_hash = self.piece_hashes.get(piece_index);
+        log::debug!("Piece {} expected hash {}", piece_index, expected_hash);
 
         let len = self.info.piece_len(piece_index);
         log::debug!("Piece {} is {} bytes long", piece_index, len);
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, net::SocketAddr};
 
 use crate::{block_count, block_len, BlockInfo, PieceIndex, BLOCK_LEN};
 
@@ -25,6 +25,9 @@ pub(crate) struct PieceDownload {
     /// The blocks in this piece, tracking which are downloaded, pending, or
     /// received. The vec is preallocated to the number of blocks in piece.
     blocks: Vec<BlockStatus>,
+    /// The peers that sent the received blocks, which are to blame if the
+    /// piece turns out to be corrupt.
+    peers: HashSet<SocketAddr>,
 }
 
 impl PieceDownload {
@@ -33,7 +36,12 @@ impl PieceDownload {
         let block_count = block_count(len);
         let mut blocks = Vec::new();
         blocks.resize_with(block_count, Default::default);
-        Self { index, len, blocks }
+        Self {
+            index,
+            len,
+            blocks,
+            peers: HashSet::new(),
+        }
     }
 
     /// Returns the index of the piece that is downloaded.
@@ -109,11 +117,16 @@ impl PieceDownload {
         }
     }
 
-    /// Marks the given block as received so that it is not picked again.
+    /// Marks the given block, sent by the peer at `addr`, as received so that
+    /// it is not picked again.
     ///
     /// The previous status of the block is returned. This can be used to check
     /// whether the block has already been downloaded, for example.
-    pub fn received_block(&mut self, block: &BlockInfo) -> BlockStatus {
+    pub fn received_block(
+        &mut self,
+        block: &BlockInfo,
+        addr: SocketAddr,
+    ) -> BlockStatus {
         log::trace!("Received piece {} block {:?}", self.index, block);
 
         // TODO(https://github.com/mandreyel/cratetorrent/issues/16): this
@@ -135,18 +148,41 @@ impl PieceDownload {
         let block = &mut self.blocks[block.index_in_piece()];
         let prev_status = *block;
         *block = BlockStatus::Received;
+        // a block that was already received is discarded, so the peer
+        // didn't contribute to the piece
+        if prev_status != BlockStatus::Received {
+            self.peers.insert(addr);
+        }
         prev_status
     }
 
+    /// Returns the peers that sent the blocks of the piece.
+    pub fn peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.iter()
+    }
+
+    /// Marks the block at the offset as received without it having been
+    /// requested, as its data was saved to disk before the torrent was
+    /// restarted.
+    pub fn restore_block(&mut self, offset: u32) {
+        debug_assert!(offset < self.len);
+        self.blocks[(offset / BLOCK_LEN) as usize] = BlockStatus::Received;
+    }
+
     /// Marks all blocks free to be requested again.
     pub fn free_all_blocks(&mut self) {
         log::trace!("Canceling all blocks in piece {}", self.index);
         for block in self.blocks.iter_mut() {
             *block = BlockStatus::Free;
         }
+        self.peers.clear();
     }
 
     /// Marks a previously requested block free to request again.
+    ///
+    /// A block that was received in the meantime, e.g. from another peer, is
+    /// left as is, as the request may be freed (when timed out or rejected by
+    /// the peer) after the block had arrived.
     pub fn free_block(&mut self, block: &BlockInfo) {
         log::trace!(
             "Canceling request for piece {} block {:?}",
@@ -161,7 +197,10 @@ impl PieceDownload {
         debug_assert!(block.offset < self.len);
         debug_assert!(block.len <= self.len);
 
-        self.blocks[block.index_in_piece()] = BlockStatus::Free;
+        let block = &mut self.blocks[block.index_in_piece()];
+        if *block == BlockStatus::Requested {
+            *block = BlockStatus::Free;
+        }
     }
 }
 
@@ -171,6 +210,10 @@ mod tests {
 
     use super::*;
 
+    fn addr() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 6881))
+    }
+
     /// Tests that repeatedly requesting as many blocks as are in the piece
     /// returns all blocks, none of them previously picked.
     #[test]
@@ -252,8 +295,9 @@ mod tests {
 
         // mark all blocks as requested
         for block in picked_blocks.iter() {
-            download.received_block(block);
+            download.received_block(block, addr());
         }
+        assert_eq!(download.peers().collect::<Vec<_>>(), vec![&addr()]);
 
         let mut picked_blocks = Vec::new();
         download.pick_blocks(
@@ -289,7 +333,7 @@ mod tests {
         // mark 3 of them as received
         let received_block_count = 3;
         for block in picked_blocks.iter().take(received_block_count) {
-            download.received_block(block);
+            download.received_block(block, addr());
         }
 
         let block_count = block_count(piece_len);
@@ -368,4 +412,41 @@ mod tests {
             picked.insert(block);
         }
     }
+
+    /// Tests that freed blocks, such as those whose request the peer
+    /// rejected, are picked again, unless they were received in the meantime.
+    #[test]
+    fn should_free_only_requested_blocks() {
+        let piece_index = 0;
+        let piece_len = 2 * BLOCK_LEN;
+        let in_end_game = false;
+
+        let mut download = PieceDownload::new(piece_index, piece_len);
+        let mut picked_blocks = Vec::new();
+        download.pick_blocks(
+            2,
+            &mut picked_blocks,
+            in_end_game,
+            &HashSet::new(),
+        );
+        assert_eq!(picked_blocks.len(), 2);
+
+        // the first block arrives before its request is freed, the second is
+        // freed while still requested
+        download.received_block(&picked_blocks[0], addr());
+        download.free_block(&picked_blocks[0]);
+        download.free_block(&picked_blocks[1]);
+        assert_eq!(download.blocks[0], BlockStatus::Received);
+        assert_eq!(download.blocks[1], BlockStatus::Free);
+
+        // so only the second block is picked again
+        let mut picked_again = Vec::new();
+        download.pick_blocks(
+            2,
+            &mut picked_again,
+            in_end_game,
+            &HashSet::new(),
+        );
+        assert_eq!(picked_again, vec![picked_blocks[1]]);
+    }
 }
@@ -14,27 +14,38 @@
 //!
 //! For usage examples, see the [library documentation](crate).
 
-use std::{
-    collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
-};
+use std::{collections::HashMap, net::SocketAddr, ops::Range, path::PathBuf};
 
-use futures::stream::StreamExt;
+use futures::{
+    future::{self, AbortHandle},
+    stream::StreamExt,
+};
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    net::TcpListener,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot, watch,
+    },
     task,
 };
 
 use crate::{
-    alert::{AlertReceiver, AlertSender},
-    conf::{Conf, TorrentConf},
+    alert::{Alert, AlertReceiver, AlertSender},
+    conf::{Conf, EncryptionPolicy, TorrentConf},
+    dht,
     disk::{self, error::NewTorrentError},
     error::*,
-    metainfo::Metainfo,
-    storage_info::StorageInfo,
-    torrent::{self, Torrent},
+    magnet::MagnetLink,
+    metadata::{self, MetadataDownload},
+    metainfo::{Metainfo, TrackerUrl},
+    net,
+    peer::IncomingConnection,
+    rate_limiter::RateLimits,
+    resume::ResumeData,
+    storage_info::{FilePriority, StorageInfo},
+    torrent::{self, stats::TorrentStats, Torrent},
     tracker::Tracker,
-    Bitfield, TorrentId,
+    Bitfield, FileIndex, PieceIndex, Sha1Hash, Sha256Hash, TorrentId,
 };
 
 /// Spawns the engine as a tokio task.
@@ -51,6 +62,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     // create alert channels and return alert port to user
     let (alert_tx, alert_rx) = mpsc::unbounded_channel();
     let (mut engine, tx) = Engine::new(conf, alert_tx)?;
+    let listen_addr = engine.listen_addr;
 
     let join_handle = task::spawn(async move { engine.run().await });
     log::info!("Spawned engine task");
@@ -58,6 +70,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     Ok((
         EngineHandle {
             tx,
+            listen_addr,
             join_handle: Some(join_handle),
         },
         alert_rx,
@@ -69,10 +82,18 @@ type JoinHandle = task::JoinHandle<Result<()>>;
 /// A handle to the currently running torrent engine.
 pub struct EngineHandle {
     tx: Sender,
+    /// The address on which the engine listens for new peers.
+    listen_addr: SocketAddr,
     join_handle: Option<JoinHandle>,
 }
 
 impl EngineHandle {
+    /// Returns the address on which the engine listens for new peers, which
+    /// has the actual port if the configured one was 0.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
     /// Creates and starts a torrent, if its metainfo is valid.
     ///
     /// If successful, it returns the id of the torrent. This id can be used to
@@ -84,6 +105,194 @@ impl EngineHandle {
         Ok(id)
     }
 
+    /// Creates a torrent from a magnet link.
+    ///
+    /// The torrent's metadata is first downloaded from the peers found via the
+    /// magnet link's trackers and peers, after which the torrent is started
+    /// as if created with [`Self::create_torrent`], in download mode. An
+    /// [`Alert::MetadataReceived`] is posted when this happens.
+    ///
+    /// The piece layers of v2-only torrents are not part of their metadata, so
+    /// they are requested from the same peer as the metadata, and verified
+    /// against the files' pieces roots.
+    ///
+    /// If successful, it returns the id of the torrent. This id can be used to
+    /// identify the torrent when issuing further commands to engine.
+    pub fn create_magnet_torrent(
+        &self,
+        params: MagnetParams,
+    ) -> Result<TorrentId> {
+        log::trace!("Creating torrent from magnet link");
+        let id = TorrentId::new();
+        self.tx.send(Command::CreateMagnetTorrent { id, params })?;
+        Ok(id)
+    }
+
+    /// Sets the download priority of each file of the torrent, which must
+    /// have as many entries as the torrent has files.
+    ///
+    /// Skipped files are not downloaded, and once all other files are
+    /// downloaded, the torrent is complete.
+    pub fn set_file_priorities(
+        &self,
+        id: TorrentId,
+        priorities: Vec<FilePriority>,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} file priorities", id);
+        self.tx
+            .send(Command::SetFilePriorities { id, priorities })?;
+        Ok(())
+    }
+
+    /// Sets the read-ahead window of the torrent, the pieces of which are
+    /// downloaded, in order, before any other piece. This is used to stream
+    /// a file while it's downloading, by moving the window along with the
+    /// playback position. If `None`, the window is cleared.
+    ///
+    /// A window outside of the torrent is ignored.
+    pub fn set_read_ahead(
+        &self,
+        id: TorrentId,
+        read_ahead: Option<ReadAhead>,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} read-ahead to {:?}", id, read_ahead);
+        self.tx.send(Command::SetReadAhead { id, read_ahead })?;
+        Ok(())
+    }
+
+    /// Changes the download and upload rate limits shared by all torrents, in
+    /// bytes per second. `None` or 0 lifts the limit.
+    pub fn set_rate_limits(
+        &self,
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> Result<()> {
+        log::trace!(
+            "Setting rate limits to {:?} down, {:?} up",
+            download,
+            upload
+        );
+        self.tx.send(Command::SetRateLimits { download, upload })?;
+        Ok(())
+    }
+
+    /// Checks the pieces of the torrent on disk against their hashes, so that
+    /// we know which pieces we really have, such as after the files were
+    /// modified outside of the engine.
+    ///
+    /// Only the pieces in the range are checked, or all pieces if `None`.
+    /// Progress is reported by [`Alert::RecheckProgress`], and
+    /// [`Alert::RecheckComplete`] is posted once all pieces are checked. The
+    /// torrent keeps running during the check.
+    pub fn recheck(
+        &self,
+        id: TorrentId,
+        pieces: Option<Range<usize>>,
+    ) -> Result<()> {
+        log::trace!("Rechecking torrent {} pieces {:?}", id, pieces);
+        self.tx.send(Command::Recheck { id, pieces })?;
+        Ok(())
+    }
+
+    /// Pauses the torrent until it's resumed.
+    ///
+    /// Its peers are disconnected and it stops accepting new ones, the pieces
+    /// that are still being downloaded are written to disk, and its trackers
+    /// are told that it stopped. The time spent paused is not counted in
+    /// [`TorrentStats::run_duration`], and [`TorrentStats::state`] is
+    /// [`TorrentState::Paused`](crate::torrent::stats::TorrentState::Paused).
+    ///
+    /// This returns once the torrent received the command.
+    pub async fn pause(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Pausing torrent {}", id);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::Pause { id, result_tx })?;
+        result_rx.await?
+    }
+
+    /// Resumes a paused torrent, which reconnects to its previous peers and
+    /// announces to its trackers that it started again.
+    pub async fn resume(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Resuming torrent {}", id);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::Resume { id, result_tx })?;
+        result_rx.await?
+    }
+
+    /// Stops the torrent and removes it from the engine, along with its
+    /// resume data. If `delete_files` is set, its downloaded files are
+    /// deleted too.
+    ///
+    /// This returns once the torrent has told its trackers that it's leaving
+    /// and its files are deleted.
+    pub async fn remove_torrent(
+        &self,
+        id: TorrentId,
+        delete_files: bool,
+    ) -> Result<()> {
+        log::trace!("Removing torrent {} (delete files: {})", id, delete_files);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::RemoveTorrent {
+            id,
+            delete_files,
+            result_tx,
+        })?;
+        result_rx.await?
+    }
+
+    /// Replaces the configuration of the torrent while it's running.
+    pub async fn set_torrent_conf(
+        &self,
+        id: TorrentId,
+        conf: TorrentConf,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} conf to {:?}", id, conf);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::SetTorrentConf {
+            id,
+            conf,
+            result_tx,
+        })?;
+        result_rx.await?
+    }
+
+    /// Adds peers that the torrent can connect to, besides those it finds
+    /// itself.
+    pub async fn add_peers(
+        &self,
+        id: TorrentId,
+        peers: Vec<SocketAddr>,
+    ) -> Result<()> {
+        log::trace!("Adding torrent {} peers {:?}", id, peers);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::AddPeers {
+            id,
+            peers,
+            result_tx,
+        })?;
+        result_rx.await?
+    }
+
+    /// Announces the torrent to its trackers, and to the DHT if enabled,
+    /// without waiting for the next announce to be due.
+    pub async fn reannounce(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Reannouncing torrent {}", id);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::Reannounce { id, result_tx })?;
+        result_rx.await?
+    }
+
+    /// Returns the id and current stats of each torrent.
+    ///
+    /// Torrents created from magnet links are only included once their
+    /// metadata is downloaded.
+    pub async fn torrents(&self) -> Result<Vec<(TorrentId, TorrentStats)>> {
+        log::trace!("Listing torrents");
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::ListTorrents { result_tx })?;
+        Ok(result_rx.await?)
+    }
+
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     ///
@@ -112,34 +321,70 @@ pub struct TorrentParams {
     pub metainfo: Metainfo,
     /// If set, overrides the default global config.
     pub conf: Option<TorrentConf>,
+    /// If set, the download priority of each file of the torrent, in the
+    /// order of [`Metainfo::files`]. Otherwise all files are downloaded with
+    /// normal priority.
+    pub file_priorities: Option<Vec<FilePriority>>,
     /// Whether to download or seed the torrent.
     ///
     /// This is expected to be removed as this will become automatic once
     /// torrent resume data is supported.
     pub mode: Mode,
-    /// The address on which the torrent should listen for new peers.
-    ///
-    /// This has to be unique for each torrent. If not set, or if already in
-    /// use, a random port is assigned.
-    // TODO: probably use an engine wide address, but requires some
-    // rearchitecting
+    /// If set, the torrent listens for new peers on this address, instead of
+    /// on the engine's listen address. It has to be unique for each torrent.
+    /// If the port is 0, a free port is assigned.
+    pub listen_addr: Option<SocketAddr>,
+}
+
+/// Information for creating a new torrent from a magnet link.
+pub struct MagnetParams {
+    /// The parsed magnet link.
+    pub magnet: MagnetLink,
+    /// If set, overrides the default global config.
+    pub conf: Option<TorrentConf>,
+    /// If set, the address on which the torrent listens for new peers once
+    /// its metadata is downloaded. See [`TorrentParams::listen_addr`].
     pub listen_addr: Option<SocketAddr>,
 }
 
 /// The download mode.
+///
+/// In seed mode, the files are assumed to be complete, and in download mode,
+/// to be missing, unless [`TorrentConf::recheck_on_add`] is set, in which
+/// case the files on disk are checked.
 // TODO: remove in favor of automatic detection
-// TODO: when seeding is specified, we need to verify that the files to be
-// seeded exist and are complete
 #[derive(Debug)]
 pub enum Mode {
     Download { seeds: Vec<SocketAddr> },
     Seed,
 }
 
+/// The part of a torrent that is downloaded before the rest, such as the bytes
+/// after the playback position of a media file that is being streamed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReadAhead {
+    /// A byte range in a file of the torrent. The range doesn't extend past
+    /// the end of the file.
+    File {
+        /// The index of the file in [`Metainfo::files`].
+        index: FileIndex,
+        /// The offset of the first byte of the window within the file.
+        offset: u64,
+        /// The length of the window, in bytes.
+        len: u64,
+    },
+    /// A byte range in the torrent, when all its files are viewed as a single
+    /// contiguous byte array. The range may span multiple files.
+    Bytes(Range<u64>),
+}
+
 /// The channel through which the user can send commands to the engine.
 pub(crate) type Sender = UnboundedSender<Command>;
 /// The channel on which the engine listens for commands from the user.
-type Receiver = UnboundedReceiver<Command>;
+pub(crate) type Receiver = UnboundedReceiver<Command>;
+/// The channel on which the engine returns the result of a command to the
+/// user.
+type ResultSender<T = ()> = oneshot::Sender<Result<T>>;
 
 /// The type of commands that the engine can receive.
 pub(crate) enum Command {
@@ -148,6 +393,23 @@ pub(crate) enum Command {
         id: TorrentId,
         params: TorrentParams,
     },
+    /// Contains the information for creating a new torrent from a magnet link.
+    CreateMagnetTorrent { id: TorrentId, params: MagnetParams },
+    /// Sent by the metadata download of a torrent created from a magnet link
+    /// once the torrent's metadata is downloaded and verified.
+    MetadataDownloaded {
+        id: TorrentId,
+        /// The raw bencoded info dictionary.
+        info: Vec<u8>,
+        /// The piece layers of a v2-only torrent, downloaded from the same
+        /// peer as the info dictionary.
+        piece_layers: Vec<(Sha256Hash, Vec<Sha256Hash>)>,
+        /// The peers found while downloading the metadata.
+        peers: Vec<SocketAddr>,
+    },
+    /// Sent by the metadata download of a torrent created from a magnet link
+    /// if it gave up on downloading the metadata.
+    MetadataDownloadFailed { id: TorrentId, error: TorrentError },
     /// Torrent allocation result. If successful, the id of the allocated
     /// torrent is returned for identification, if not, the reason of the error
     /// is included.
@@ -155,6 +417,67 @@ pub(crate) enum Command {
         id: TorrentId,
         result: Result<(), NewTorrentError>,
     },
+    /// Sets the file priorities of a torrent.
+    SetFilePriorities {
+        id: TorrentId,
+        priorities: Vec<FilePriority>,
+    },
+    /// Sets the read-ahead window of a torrent.
+    SetReadAhead {
+        id: TorrentId,
+        read_ahead: Option<ReadAhead>,
+    },
+    /// Checks the pieces of a torrent on disk against their hashes.
+    Recheck {
+        id: TorrentId,
+        pieces: Option<Range<PieceIndex>>,
+    },
+    /// Pauses a torrent.
+    Pause {
+        id: TorrentId,
+        result_tx: ResultSender,
+    },
+    /// Resumes a paused torrent, which reconnects to its previous peers and
+    /// announces to its trackers that it started again.
+    Resume {
+        id: TorrentId,
+        result_tx: ResultSender,
+    },
+    /// Stops and removes a torrent, optionally deleting its files.
+    RemoveTorrent {
+        id: TorrentId,
+        delete_files: bool,
+        result_tx: ResultSender,
+    },
+    /// Replaces the configuration of a torrent.
+    SetTorrentConf {
+        id: TorrentId,
+        conf: TorrentConf,
+        result_tx: ResultSender,
+    },
+    /// Adds peers that a torrent can connect to.
+    AddPeers {
+        id: TorrentId,
+        peers: Vec<SocketAddr>,
+        result_tx: ResultSender,
+    },
+    /// Announces a torrent to its trackers and the DHT right away.
+    Reannounce {
+        id: TorrentId,
+        result_tx: ResultSender,
+    },
+    /// Changes the engine's rate limits.
+    SetRateLimits {
+        download: Option<u64>,
+        upload: Option<u64>,
+    },
+    /// Sent by the listener when a peer connects and sends its handshake, so
+    /// that the connection is passed to the torrent it's for.
+    IncomingPeer(IncomingConnection),
+    /// Returns the id and stats of each torrent.
+    ListTorrents {
+        result_tx: oneshot::Sender<Vec<(TorrentId, TorrentStats)>>,
+    },
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     Shutdown,
@@ -163,6 +486,12 @@ pub(crate) enum Command {
 struct Engine {
     /// All currently running torrents in engine.
     torrents: HashMap<TorrentId, TorrentEntry>,
+    /// The torrents created from magnet links whose metadata is still being
+    /// downloaded.
+    metadata_downloads: HashMap<TorrentId, MetadataDownloadEntry>,
+
+    /// A copy of the engine's command sender, passed to metadata downloads.
+    cmd_tx: Sender,
 
     /// The port on which other entities in the engine, or the API consumer
     /// sends the engine commands.
@@ -172,9 +501,24 @@ struct Engine {
     disk_tx: disk::Sender,
     disk_join_handle: Option<disk::JoinHandle>,
 
+    /// The DHT node's channel, if the DHT is enabled.
+    dht_tx: Option<dht::Sender>,
+    dht_join_handle: Option<dht::JoinHandle>,
+
     /// The channel on which tasks in the engine post alerts to user.
     alert_tx: AlertSender,
 
+    /// The rate limits shared by the peers of all torrents.
+    rate_limits: RateLimits,
+
+    /// The address on which the engine listens for new peers.
+    listen_addr: SocketAddr,
+    /// Used to stop accepting new peers when the engine is shut down.
+    listener_abort_handle: AbortHandle,
+    /// Publishes the info hashes of the running torrents to the listener, as
+    /// they are needed to set up encrypted connections.
+    info_hashes_tx: watch::Sender<Vec<Sha1Hash>>,
+
     /// The global engine configuration that includes defaults for torrents
     /// whose config is not overridden.
     conf: Conf,
@@ -184,23 +528,77 @@ struct Engine {
 struct TorrentEntry {
     /// The torrent's command channel on which engine sends commands to torrent.
     tx: torrent::Sender,
+    /// Identifies the torrent's resume data.
+    info_hash: Sha1Hash,
+    /// The truncated v2 info hash of a hybrid torrent, with which peers of the
+    /// v2 swarm connect to it.
+    hybrid_info_hash: Option<Sha1Hash>,
     /// The torrent task's join handle, used during shutdown.
     join_handle: Option<task::JoinHandle<torrent::error::Result<()>>>,
 }
 
+/// The entry of a torrent in the engine whose metadata is being downloaded.
+///
+/// The parameters with which the torrent is started once its metadata is
+/// downloaded are kept here.
+struct MetadataDownloadEntry {
+    conf: Option<TorrentConf>,
+    listen_addr: Option<SocketAddr>,
+    trackers: Vec<TrackerUrl>,
+    /// Used to stop the download task if the engine is shut down before the
+    /// download completes.
+    abort_handle: AbortHandle,
+}
+
 impl Engine {
-    /// Creates a new engine, spawning the disk task.
+    /// Creates a new engine, spawning the disk task and, if enabled, the DHT
+    /// node.
     fn new(conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
+        let (dht_join_handle, dht_tx) = match conf.engine.dht.clone() {
+            Some(dht_conf) => {
+                let (join_handle, tx) = dht::spawn(dht_conf)?;
+                (Some(join_handle), Some(tx))
+            }
+            None => (None, None),
+        };
+
+        // the listener is bound synchronously so that binding errors are
+        // returned to the user right away
+        let listener = TcpListener::from_std(net::bind_tcp_listener(
+            conf.engine.listen_addr,
+        )?)?;
+        let listen_addr = listener.local_addr()?;
+        log::info!("Listening for peers on {}", listen_addr);
+        let (info_hashes_tx, info_hashes_rx) = watch::channel(Vec::new());
+        let (listener, listener_abort_handle) =
+            future::abortable(accept_peers(
+                listener,
+                cmd_tx.clone(),
+                conf.engine.encryption,
+                info_hashes_rx,
+            ));
+        task::spawn(listener);
 
         Ok((
             Self {
                 torrents: HashMap::new(),
+                metadata_downloads: HashMap::new(),
+                cmd_tx: cmd_tx.clone(),
                 cmd_rx,
                 disk_tx,
                 disk_join_handle: Some(disk_join_handle),
+                dht_tx,
+                dht_join_handle,
                 alert_tx,
+                rate_limits: RateLimits::new(
+                    conf.engine.download_rate_limit,
+                    conf.engine.upload_rate_limit,
+                ),
+                listen_addr,
+                listener_abort_handle,
+                info_hashes_tx,
                 conf,
             },
             cmd_tx,
@@ -217,6 +615,26 @@ impl Engine {
                 Command::CreateTorrent { id, params } => {
                     self.create_torrent(id, params).await?;
                 }
+                Command::CreateMagnetTorrent { id, params } => {
+                    self.create_magnet_torrent(id, params);
+                }
+                Command::MetadataDownloaded {
+                    id,
+                    info,
+                    piece_layers,
+                    peers,
+                } => {
+                    self.handle_downloaded_metadata(
+                        id,
+                        info,
+                        piece_layers,
+                        peers,
+                    )
+                    .await?;
+                }
+                Command::MetadataDownloadFailed { id, error } => {
+                    self.handle_failed_metadata_download(id, error);
+                }
                 Command::TorrentAllocation { id, result } => match result {
                     Ok(_) => {
                         log::info!("Torrent {} allocated on disk", id);
@@ -229,6 +647,90 @@ impl Engine {
                         );
                     }
                 },
+                Command::SetFilePriorities { id, priorities } => {
+                    self.send_torrent_command(
+                        id,
+                        torrent::Command::SetFilePriorities(priorities),
+                    );
+                }
+                Command::SetReadAhead { id, read_ahead } => {
+                    self.send_torrent_command(
+                        id,
+                        torrent::Command::SetReadAhead(read_ahead),
+                    );
+                }
+                Command::Recheck { id, pieces } => {
+                    self.send_torrent_command(
+                        id,
+                        torrent::Command::Recheck(pieces),
+                    );
+                }
+                Command::Pause { id, result_tx } => {
+                    self.forward_torrent_command(
+                        id,
+                        torrent::Command::Pause,
+                        result_tx,
+                    );
+                }
+                Command::Resume { id, result_tx } => {
+                    self.forward_torrent_command(
+                        id,
+                        torrent::Command::Resume,
+                        result_tx,
+                    );
+                }
+                Command::RemoveTorrent {
+                    id,
+                    delete_files,
+                    result_tx,
+                } => {
+                    self.remove_torrent(id, delete_files, result_tx);
+                }
+                Command::SetTorrentConf {
+                    id,
+                    conf,
+                    result_tx,
+                } => {
+                    self.forward_torrent_command(
+                        id,
+                        torrent::Command::SetConf(conf),
+                        result_tx,
+                    );
+                }
+                Command::AddPeers {
+                    id,
+                    peers,
+                    result_tx,
+                } => {
+                    self.forward_torrent_command(
+                        id,
+                        torrent::Command::AddPeers(peers),
+                        result_tx,
+                    );
+                }
+                Command::Reannounce { id, result_tx } => {
+                    self.forward_torrent_command(
+                        id,
+                        torrent::Command::Reannounce,
+                        result_tx,
+                    );
+                }
+                Command::SetRateLimits { download, upload } => {
+                    log::info!(
+                        "Setting rate limits to {:?} down, {:?} up",
+                        download,
+                        upload
+                    );
+                    self.rate_limits.set_rates(download, upload);
+                    self.conf.engine.download_rate_limit = download;
+                    self.conf.engine.upload_rate_limit = upload;
+                }
+                Command::IncomingPeer(conn) => {
+                    self.route_incoming_peer(conn);
+                }
+                Command::ListTorrents { result_tx } => {
+                    self.list_torrents(result_tx);
+                }
                 Command::Shutdown => {
                     self.shutdown().await?;
                     break;
@@ -245,20 +747,58 @@ impl Engine {
         id: TorrentId,
         params: TorrentParams,
     ) -> Result<()> {
+        let file_count = params.metainfo.files.len();
+        let file_priorities = match params.file_priorities {
+            Some(priorities) if priorities.len() != file_count => {
+                log::warn!("Torrent {} file priorities don't match files", id);
+                self.alert_tx
+                    .send(Alert::Error(Error::Torrent {
+                        id,
+                        error: TorrentError::InvalidFilePriorities,
+                    }))
+                    .ok();
+                return Ok(());
+            }
+            Some(priorities) => priorities,
+            None => vec![FilePriority::Normal; file_count],
+        };
         let conf = params.conf.unwrap_or_else(|| self.conf.torrent.clone());
         let storage_info = StorageInfo::new(
             &params.metainfo,
             self.conf.engine.download_dir.clone(),
         );
+        let hybrid_info_hash = params.metainfo.hybrid_info_hash();
+        let piece_hashes = params.metainfo.piece_hashes();
         // TODO: don't duplicate trackers if multiple torrents use the same
         // ones (common in practice)
         let trackers = params
             .metainfo
             .trackers
             .into_iter()
-            .map(Tracker::new)
+            .map(|tier| tier.into_iter().map(Tracker::new).collect())
             .collect();
-        let own_pieces = params.mode.own_pieces(storage_info.piece_count);
+        let info_hash = params.metainfo.info_hash;
+        let resume_data =
+            self.conf.engine.resume_dir.as_deref().and_then(|dir| {
+                ResumeData::load(dir, &info_hash, &storage_info)
+            });
+        // the resume data knows better which pieces we have than the mode
+        let own_pieces = match &resume_data {
+            Some(resume_data) => resume_data.own_pieces.clone(),
+            None => params.mode.own_pieces(storage_info.piece_count),
+        };
+        let mut seeds = params.mode.seeds();
+        let partial_pieces = match &resume_data {
+            Some(resume_data) => {
+                for addr in resume_data.peers.iter() {
+                    if !seeds.contains(addr) {
+                        seeds.push(*addr);
+                    }
+                }
+                resume_data.partial_pieces.clone()
+            }
+            None => Vec::new(),
+        };
 
         // create and spawn torrent
         // TODO: For now we spawn automatically, but later when we add torrent
@@ -268,18 +808,25 @@ impl Engine {
         let (mut torrent, torrent_tx) = Torrent::new(torrent::Params {
             id,
             disk_tx: self.disk_tx.clone(),
-            info_hash: params.metainfo.info_hash,
+            info_hash,
+            hybrid_info_hash,
+            raw_info: params.metainfo.raw_info,
+            merkle_trees: params.metainfo.merkle_trees,
             storage_info: storage_info.clone(),
+            file_priorities: file_priorities.clone(),
             own_pieces,
             trackers,
             client_id: self.conf.engine.client_id,
-            listen_addr: params.listen_addr.unwrap_or_else(|| {
-                // the port 0 tells the kernel to assign a free port from the
-                // dynamic range
-                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
-            }),
+            listen_addr: params.listen_addr,
+            engine_listen_addr: self.listen_addr,
+            encryption: self.conf.engine.encryption,
+            is_private: params.metainfo.is_private,
             conf,
+            dht_tx: self.dht_tx.clone(),
             alert_tx: self.alert_tx.clone(),
+            engine_rate_limits: self.rate_limits.clone(),
+            resume_dir: self.conf.engine.resume_dir.clone(),
+            resume_data,
         });
 
         // Allocate torrent on disk. This is an asynchronous process and we can
@@ -300,11 +847,12 @@ impl Engine {
         self.disk_tx.send(disk::Command::NewTorrent {
             id,
             storage_info,
-            piece_hashes: params.metainfo.pieces,
+            file_priorities,
+            piece_hashes,
+            partial_pieces,
             torrent_tx: torrent_tx.clone(),
         })?;
 
-        let seeds = params.mode.seeds();
         let join_handle =
             task::spawn(async move { torrent.start(&seeds).await });
 
@@ -312,17 +860,297 @@ impl Engine {
             id,
             TorrentEntry {
                 tx: torrent_tx,
+                info_hash,
+                hybrid_info_hash,
                 join_handle: Some(join_handle),
             },
         );
+        self.publish_info_hashes();
 
         Ok(())
     }
 
+    /// Spawns the download of the metadata of a torrent created from a magnet
+    /// link.
+    fn create_magnet_torrent(&mut self, id: TorrentId, params: MagnetParams) {
+        let MagnetParams {
+            magnet,
+            conf,
+            listen_addr,
+        } = params;
+        let download = MetadataDownload::new(metadata::Params {
+            id,
+            info_hash: magnet.info_hash,
+            client_id: self.conf.engine.client_id,
+            encryption: self.conf.engine.encryption,
+            port: listen_addr
+                .map_or(self.listen_addr.port(), |addr| addr.port()),
+            trackers: magnet
+                .trackers
+                .iter()
+                .cloned()
+                .map(Tracker::new)
+                .collect(),
+            peers: magnet.peers,
+            conf: conf.clone().unwrap_or_else(|| self.conf.torrent.clone()),
+            engine_tx: self.cmd_tx.clone(),
+            dht_tx: self.dht_tx.clone(),
+            alert_tx: self.alert_tx.clone(),
+        });
+
+        let (download, abort_handle) = future::abortable(download.run());
+        task::spawn(download);
+
+        self.metadata_downloads.insert(
+            id,
+            MetadataDownloadEntry {
+                conf,
+                listen_addr,
+                trackers: magnet.trackers,
+                abort_handle,
+            },
+        );
+    }
+
+    /// Creates the torrent from its downloaded metadata, which at this point
+    /// is verified to match the torrent's info hash.
+    async fn handle_downloaded_metadata(
+        &mut self,
+        id: TorrentId,
+        info: Vec<u8>,
+        piece_layers: Vec<(Sha256Hash, Vec<Sha256Hash>)>,
+        peers: Vec<SocketAddr>,
+    ) -> Result<()> {
+        let entry = match self.metadata_downloads.remove(&id) {
+            Some(entry) => entry,
+            None => {
+                log::warn!("Metadata downloaded for unknown torrent {}", id);
+                return Ok(());
+            }
+        };
+
+        // the magnet link doesn't specify tiers, so its trackers are treated
+        // as equals by putting them in a single tier
+        let trackers = if entry.trackers.is_empty() {
+            Vec::new()
+        } else {
+            vec![entry.trackers]
+        };
+        let metainfo = match Metainfo::from_info_and_piece_layers(
+            &info,
+            &piece_layers,
+            trackers,
+        ) {
+            Ok(metainfo) => metainfo,
+            Err(e) => {
+                log::error!("Torrent {} metadata is invalid: {}", id, e);
+                self.alert_tx
+                    .send(Alert::Error(Error::Torrent {
+                        id,
+                        error: TorrentError::Metainfo(e),
+                    }))
+                    .ok();
+                return Ok(());
+            }
+        };
+        log::info!("Torrent {} metadata: {:?}", id, metainfo);
+        self.alert_tx
+            .send(Alert::MetadataReceived {
+                id,
+                metainfo: Box::new(metainfo.clone()),
+            })
+            .ok();
+
+        self.create_torrent(
+            id,
+            TorrentParams {
+                metainfo,
+                conf: entry.conf,
+                mode: Mode::Download { seeds: peers },
+                listen_addr: entry.listen_addr,
+                file_priorities: None,
+            },
+        )
+        .await
+    }
+
+    /// Removes the torrent whose metadata couldn't be downloaded, so that it
+    /// may be created again, and tells the user why.
+    fn handle_failed_metadata_download(
+        &mut self,
+        id: TorrentId,
+        error: TorrentError,
+    ) {
+        if self.metadata_downloads.remove(&id).is_none() {
+            log::warn!("Metadata download failed for unknown torrent {}", id);
+            return;
+        }
+        log::error!("Torrent {} metadata download failed: {}", id, error);
+        self.alert_tx
+            .send(Alert::Error(Error::Torrent { id, error }))
+            .ok();
+    }
+
+    /// Passes a peer that connected to the engine's listener to the torrent
+    /// whose info hash it sent, or drops the connection if there is no such
+    /// torrent.
+    fn route_incoming_peer(&self, conn: IncomingConnection) {
+        let torrent = self.torrents.values().find(|torrent| {
+            &torrent.info_hash == conn.info_hash()
+                || torrent.hybrid_info_hash.as_ref() == Some(conn.info_hash())
+        });
+        match torrent {
+            Some(torrent) => {
+                // the torrent task may no longer be running, in which case the
+                // connection is dropped
+                torrent.tx.send(torrent::Command::IncomingPeer(conn)).ok();
+            }
+            None => {
+                log::info!(
+                    "Rejecting peer {} for unknown torrent {}",
+                    conn.addr,
+                    hex::encode(conn.info_hash())
+                );
+            }
+        }
+    }
+
+    /// Forwards a command from the user to the torrent, or reports an error
+    /// if the torrent doesn't exist.
+    fn send_torrent_command(&self, id: TorrentId, cmd: torrent::Command) {
+        match self.torrents.get(&id) {
+            Some(torrent) => {
+                // the torrent task may no longer be running, so don't panic
+                // here
+                torrent.tx.send(cmd).ok();
+            }
+            None => {
+                log::warn!("Cannot send command to unknown torrent {}", id);
+                self.alert_tx
+                    .send(Alert::Error(Error::InvalidTorrentId))
+                    .ok();
+            }
+        }
+    }
+
+    /// Forwards a command from the user to the torrent, and returns the result
+    /// of sending it via the sender, which is an error if the torrent doesn't
+    /// exist.
+    fn forward_torrent_command(
+        &self,
+        id: TorrentId,
+        cmd: torrent::Command,
+        result_tx: ResultSender,
+    ) {
+        let result = match self.torrents.get(&id) {
+            Some(torrent) => torrent.tx.send(cmd).map_err(Error::from),
+            None => {
+                log::warn!("Cannot send command to unknown torrent {}", id);
+                Err(Error::InvalidTorrentId)
+            }
+        };
+        // the user may no longer be waiting for the result
+        result_tx.send(result).ok();
+    }
+
+    /// Removes the torrent from the engine, and spawns a task that stops it
+    /// and cleans up after it, after which the result is returned via the
+    /// sender.
+    ///
+    /// A torrent whose metadata is still being downloaded has nothing to clean
+    /// up, so its download is simply aborted.
+    fn remove_torrent(
+        &mut self,
+        id: TorrentId,
+        delete_files: bool,
+        result_tx: ResultSender,
+    ) {
+        if let Some(download) = self.metadata_downloads.remove(&id) {
+            log::info!("Removing torrent {} metadata download", id);
+            download.abort_handle.abort();
+            result_tx.send(Ok(())).ok();
+            return;
+        }
+        let torrent = match self.torrents.remove(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Cannot remove unknown torrent {}", id);
+                result_tx.send(Err(Error::InvalidTorrentId)).ok();
+                return;
+            }
+        };
+        self.publish_info_hashes();
+
+        log::info!("Removing torrent {}", id);
+        let disk_tx = self.disk_tx.clone();
+        let resume_dir = self.conf.engine.resume_dir.clone();
+        task::spawn(async move {
+            let result =
+                remove_torrent(id, torrent, delete_files, disk_tx, resume_dir)
+                    .await;
+            // the user may no longer be waiting for the result
+            result_tx.send(result).ok();
+        });
+    }
+
+    /// Sends the info hashes of the running torrents to the listener.
+    fn publish_info_hashes(&self) {
+        let info_hashes = self
+            .torrents
+            .values()
+            .flat_map(|t| {
+                std::iter::once(t.info_hash).chain(t.hybrid_info_hash)
+            })
+            .collect();
+        // the listener is only gone once the engine is shutting down
+        self.info_hashes_tx.broadcast(info_hashes).ok();
+    }
+
+    /// Collects the stats of all torrents and returns them via the sender.
+    ///
+    /// The stats are awaited in a separate task, so that the engine is not
+    /// blocked by torrents that are busy, e.g. announcing to a tracker.
+    fn list_torrents(
+        &self,
+        result_tx: oneshot::Sender<Vec<(TorrentId, TorrentStats)>>,
+    ) {
+        let mut stats_rxs = Vec::with_capacity(self.torrents.len());
+        for (id, torrent) in self.torrents.iter() {
+            let (stats_tx, stats_rx) = oneshot::channel();
+            if torrent
+                .tx
+                .send(torrent::Command::GetStats(stats_tx))
+                .is_ok()
+            {
+                stats_rxs.push((*id, stats_rx));
+            }
+        }
+        task::spawn(async move {
+            let mut torrents = Vec::with_capacity(stats_rxs.len());
+            for (id, stats_rx) in stats_rxs {
+                // the torrent may have stopped in the meantime
+                if let Ok(stats) = stats_rx.await {
+                    torrents.push((id, stats));
+                }
+            }
+            // the user may no longer be waiting for the result
+            result_tx.send(torrents).ok();
+        });
+    }
+
     /// Gracefully shuts down the engine and all its components.
     async fn shutdown(&mut self) -> Result<()> {
         log::info!("Shutting down engine");
 
+        // stop accepting new peers
+        self.listener_abort_handle.abort();
+
+        // metadata downloads have no state worth preserving so they're simply
+        // aborted
+        for download in self.metadata_downloads.values() {
+            download.abort_handle.abort();
+        }
+
         // tell all torrents to shut down and join their tasks
         for torrent in self.torrents.values_mut() {
             // the torrent task may no longer be running, so don't panic here
@@ -344,6 +1172,16 @@ impl Engine {
             }
         }
 
+        // torrents no longer need the DHT node, so it can be shut down too
+        if let Some(dht_tx) = &self.dht_tx {
+            dht_tx.send(dht::Command::Shutdown)?;
+        }
+        if let Some(join_handle) = self.dht_join_handle.take() {
+            if let Err(e) = join_handle.await.expect("DHT task has panicked") {
+                log::error!("DHT error: {}", e);
+            }
+        }
+
         // send a shutdown command to disk
         self.disk_tx.send(disk::Command::Shutdown)?;
         // and join on its handle
@@ -358,6 +1196,80 @@ impl Engine {
     }
 }
 
+/// Stops the torrent, as on shutdown, so that its trackers are told that
+/// we're leaving, then removes it from disk, deleting its files if set, and
+/// deletes its resume data, if any.
+async fn remove_torrent(
+    id: TorrentId,
+    mut torrent: TorrentEntry,
+    delete_files: bool,
+    disk_tx: disk::Sender,
+    resume_dir: Option<PathBuf>,
+) -> Result<()> {
+    // the torrent task may no longer be running, so don't panic here
+    torrent.tx.send(torrent::Command::Shutdown).ok();
+    if let Some(join_handle) = torrent.join_handle.take() {
+        if let Err(e) = join_handle.await.expect("task error") {
+            log::error!("Torrent error: {}", e);
+        }
+    }
+
+    // the torrent's peers are stopped by now, so they no longer use the disk
+    let (result_tx, result_rx) = oneshot::channel();
+    disk_tx.send(disk::Command::RemoveTorrent {
+        id,
+        delete_files,
+        result_tx,
+    })?;
+    result_rx.await??;
+
+    if let Some(resume_dir) = resume_dir {
+        ResumeData::remove(&resume_dir, &torrent.info_hash)?;
+    }
+    log::info!("Removed torrent {}", id);
+
+    Ok(())
+}
+
+/// Accepts new peers until the task is aborted. Each peer's handshake is
+/// received in its own task, after which the connection is sent to the engine
+/// to be routed to its torrent.
+async fn accept_peers(
+    mut listener: TcpListener,
+    engine_tx: Sender,
+    encryption: EncryptionPolicy,
+    info_hashes_rx: watch::Receiver<Vec<Sha1Hash>>,
+) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::info!("Error accepting peer connection: {}", e);
+                continue;
+            }
+        };
+        // peers connecting over IPv4 to a dual-stack listener are known by
+        // their IPv4 address
+        let addr = net::canonical_addr(addr);
+        log::info!("New connection {:?}", addr);
+        let engine_tx = engine_tx.clone();
+        let info_hashes = info_hashes_rx.borrow().clone();
+        task::spawn(async move {
+            if let Some(conn) = IncomingConnection::receive_handshake(
+                socket,
+                addr,
+                encryption,
+                &info_hashes,
+            )
+            .await
+            {
+                // the engine may have been shut down in the meantime
+                engine_tx.send(Command::IncomingPeer(conn)).ok();
+            }
+        });
+    }
+}
+
 impl Mode {
     fn own_pieces(&self, piece_count: usize) -> Bitfield {
         match self {
@@ -373,3 +1285,393 @@ impl Mode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs, iter,
+        net::{Ipv4Addr, TcpListener as StdTcpListener},
+        time::{Duration, Instant},
+    };
+
+    use mockito::{mock, Matcher};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        time,
+    };
+
+    use super::*;
+    use crate::{
+        magnet::MagnetLink, merkle, metainfo::NetProtocol, rate_limiter,
+        BLOCK_LEN,
+    };
+
+    /// Tests that changing the engine's rate limits while it's running
+    /// updates the limiters it shares with its torrents' peer sessions.
+    #[tokio::test]
+    async fn should_set_rate_limits_at_runtime() {
+        let mut conf = Conf::new("/tmp");
+        conf.engine.listen_addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let (alert_tx, _alert_rx) = mpsc::unbounded_channel();
+        let (mut engine, tx) = Engine::new(conf, alert_tx).unwrap();
+        // torrents are given clones of these, which share their buckets
+        let rate_limits = engine.rate_limits.clone();
+        let engine = EngineHandle {
+            tx,
+            listen_addr: engine.listen_addr,
+            join_handle: Some(task::spawn(async move { engine.run().await })),
+        };
+
+        engine.set_rate_limits(Some(1000), Some(2000)).unwrap();
+        // the engine handles commands in order, so the limits are set by the
+        // time it shuts down
+        engine.shutdown().await.unwrap();
+
+        // the buckets start out empty after the change, so transferring a
+        // second's worth of bytes has to wait about a second
+        for &(limiter, rate) in
+            &[(&rate_limits.down, 1000), (&rate_limits.up, 2000)]
+        {
+            let wait = rate_limiter::reserve(iter::once(limiter), rate)
+                .expect("transfer not limited")
+                .saturating_duration_since(Instant::now());
+            assert!(
+                wait > Duration::from_millis(900)
+                    && wait <= Duration::from_secs(1),
+                "transfer has to wait {:?}",
+                wait
+            );
+        }
+    }
+
+    /// Tests that a torrent created from a magnet link whose peers never send
+    /// the metadata is removed once its metadata timeout expires.
+    #[tokio::test]
+    async fn should_time_out_metadata_download() {
+        // a peer that accepts connections but never responds
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let peer_addr = listener.local_addr().unwrap();
+        task::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let mut conf = Conf::new("/tmp");
+        conf.engine.listen_addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let (engine, mut alert_rx) = spawn(conf).unwrap();
+        let mut magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567",
+        )
+        .unwrap();
+        magnet.peers.push(peer_addr);
+        let id = engine
+            .create_magnet_torrent(MagnetParams {
+                magnet,
+                conf: Some(TorrentConf {
+                    metadata_timeout: Duration::from_millis(500),
+                    ..TorrentConf::default()
+                }),
+                listen_addr: None,
+            })
+            .unwrap();
+
+        let start = Instant::now();
+        let alert =
+            tokio::time::timeout(Duration::from_secs(5), alert_rx.recv())
+                .await
+                .expect("metadata download didn't time out")
+                .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(
+            matches!(
+                alert,
+                Alert::Error(Error::Torrent {
+                    id: alert_id,
+                    error: TorrentError::MetadataTimeout,
+                }) if alert_id == id
+            ),
+            "unexpected alert {:?}",
+            alert
+        );
+
+        // the torrent is gone, so it can't be removed again
+        assert!(matches!(
+            engine.remove_torrent(id, false).await,
+            Err(Error::InvalidTorrentId)
+        ));
+        engine.shutdown().await.unwrap();
+    }
+
+    /// Spawns an engine listening on localhost, with its downloads in
+    /// a directory of its own, which is returned so that it can be removed
+    /// after the test.
+    fn spawn_engine(test_name: &str) -> (EngineHandle, AlertReceiver, PathBuf) {
+        let download_dir =
+            std::env::temp_dir().join(format!("engine_test_{}", test_name));
+        fs::create_dir_all(&download_dir).unwrap();
+        let mut conf = Conf::new(&download_dir);
+        conf.engine.listen_addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let (engine, alert_rx) = spawn(conf).unwrap();
+        (engine, alert_rx, download_dir)
+    }
+
+    /// Returns the metainfo of a hybrid torrent, which can be joined with
+    /// both its v1 and its truncated v2 info hash, without trackers.
+    fn hybrid_metainfo() -> Metainfo {
+        let mut metainfo = Metainfo::from_bytes(include_bytes!(
+            "../tests/fixtures/libtorrent_hybrid.torrent"
+        ))
+        .unwrap();
+        metainfo.trackers.clear();
+        metainfo
+    }
+
+    /// Creates the torrent in download mode.
+    fn create_torrent(
+        engine: &EngineHandle,
+        metainfo: Metainfo,
+        listen_addr: Option<SocketAddr>,
+    ) -> TorrentId {
+        engine
+            .create_torrent(TorrentParams {
+                metainfo,
+                conf: None,
+                mode: Mode::Download { seeds: Vec::new() },
+                listen_addr,
+                file_priorities: None,
+            })
+            .unwrap()
+    }
+
+    /// Connects to the address as a peer and sends a handshake with the info
+    /// hash. Returns the info hash in the handshake sent back, or `None` if
+    /// the connection was closed instead.
+    async fn handshake(
+        addr: SocketAddr,
+        info_hash: Sha1Hash,
+    ) -> Option<Sha1Hash> {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut handshake = b"\x13BitTorrent protocol".to_vec();
+        handshake.extend_from_slice(&[0; 8]);
+        handshake.extend_from_slice(&info_hash);
+        handshake.extend_from_slice(&[9; 20]);
+        socket.write_all(&handshake).await.unwrap();
+
+        let mut reply = [0; 68];
+        let result = time::timeout(
+            Duration::from_secs(5),
+            socket.read_exact(&mut reply),
+        )
+        .await
+        .expect("connection neither answered nor closed");
+        match result {
+            Ok(_) => {
+                let mut info_hash = [0; 20];
+                info_hash.copy_from_slice(&reply[28..48]);
+                Some(info_hash)
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Tests that a peer connecting to the engine's listener is passed to the
+    /// torrent whose info hash it sent.
+    #[tokio::test]
+    async fn should_route_incoming_peer_by_info_hash() {
+        let (engine, _alert_rx, download_dir) =
+            spawn_engine("route_by_info_hash");
+        let metainfo = hybrid_metainfo();
+        let info_hash = metainfo.info_hash;
+        // a torrent the peer doesn't want, so that the right one has to be
+        // picked
+        let mut other_metainfo = hybrid_metainfo();
+        other_metainfo.name = "other".into();
+        other_metainfo.info_hash = [1; 20];
+        other_metainfo.info_hash_v2 = None;
+        create_torrent(&engine, other_metainfo, None);
+        create_torrent(&engine, metainfo, None);
+
+        assert_eq!(
+            handshake(engine.listen_addr(), info_hash).await,
+            Some(info_hash)
+        );
+
+        engine.shutdown().await.unwrap();
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    /// Tests that a peer of the v2 swarm of a hybrid torrent, which only knows
+    /// its truncated v2 info hash, is passed to the torrent.
+    #[tokio::test]
+    async fn should_route_incoming_peer_by_hybrid_info_hash() {
+        let (engine, _alert_rx, download_dir) =
+            spawn_engine("route_by_hybrid_info_hash");
+        let metainfo = hybrid_metainfo();
+        let hybrid_info_hash = metainfo.hybrid_info_hash().unwrap();
+        assert_ne!(hybrid_info_hash, metainfo.info_hash);
+        create_torrent(&engine, metainfo, None);
+
+        // the torrent answers with the info hash the peer knows it by
+        assert_eq!(
+            handshake(engine.listen_addr(), hybrid_info_hash).await,
+            Some(hybrid_info_hash)
+        );
+
+        engine.shutdown().await.unwrap();
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    /// Tests that the connection of a peer that wants a torrent we don't have
+    /// is closed.
+    #[tokio::test]
+    async fn should_reject_incoming_peer_with_unknown_info_hash() {
+        let (engine, _alert_rx, download_dir) =
+            spawn_engine("reject_unknown_info_hash");
+        create_torrent(&engine, hybrid_metainfo(), None);
+
+        assert_eq!(handshake(engine.listen_addr(), [2; 20]).await, None);
+
+        engine.shutdown().await.unwrap();
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    /// Tests that a torrent with its own listen address accepts peers on it
+    /// and announces its port, rather than the engine's, to trackers.
+    #[tokio::test]
+    async fn should_listen_on_torrent_listen_addr() {
+        let (engine, _alert_rx, download_dir) =
+            spawn_engine("torrent_listen_addr");
+        // find a free port for the torrent
+        let listen_addr = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        assert_ne!(listen_addr, engine.listen_addr());
+
+        // the torrent announces that it started, and may announce again on
+        // its first tick, but always with its own port
+        let path = "/engine_torrent_listen_addr";
+        let own_port_announce = mock("GET", path)
+            .match_query(Matcher::UrlEncoded(
+                "port".into(),
+                listen_addr.port().to_string(),
+            ))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect_at_least(1)
+            .create();
+        let engine_port_announce = mock("GET", path)
+            .match_query(Matcher::UrlEncoded(
+                "port".into(),
+                engine.listen_addr().port().to_string(),
+            ))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect(0)
+            .create();
+
+        let mut metainfo = hybrid_metainfo();
+        let info_hash = metainfo.info_hash;
+        metainfo.trackers = vec![vec![TrackerUrl {
+            url: format!("{}{}", mockito::server_url(), path)
+                .parse()
+                .unwrap(),
+            protocol: NetProtocol::HTTP,
+        }]];
+        create_torrent(&engine, metainfo, Some(listen_addr));
+        // the torrent only answers once it has started, by which time it's
+        // listening and has announced itself
+        engine.torrents().await.unwrap();
+
+        assert_eq!(handshake(listen_addr, info_hash).await, Some(info_hash));
+        own_port_announce.assert();
+        engine_port_announce.assert();
+
+        engine.shutdown().await.unwrap();
+        fs::remove_dir_all(download_dir).unwrap();
+    }
+
+    /// Tests that a v2-only torrent is downloaded from a magnet link, by
+    /// requesting its piece layers from the seed along with its metadata.
+    #[tokio::test]
+    async fn should_download_v2_magnet_from_seed() {
+        // a single file spanning 3 pieces of a block each, so that it has
+        // a piece layer
+        let contents: Vec<_> =
+            (0..2 * BLOCK_LEN + 100).map(|i| (i % 251) as u8).collect();
+        let layer: Vec<_> = contents
+            .chunks(BLOCK_LEN as usize)
+            .map(merkle::hash)
+            .collect();
+        let pieces_root = merkle::root(layer.clone(), 4, [0; 32]);
+
+        let mut info = format!(
+            "d9:file treed1:fd0:d6:lengthi{}e11:pieces root32:",
+            contents.len()
+        )
+        .into_bytes();
+        info.extend_from_slice(&pieces_root);
+        info.extend_from_slice(
+            b"eee12:meta versioni2e4:name1:f12:piece lengthi16384ee",
+        );
+        let mut buf = b"d4:info".to_vec();
+        buf.extend_from_slice(&info);
+        buf.extend_from_slice(b"12:piece layersd32:");
+        buf.extend_from_slice(&pieces_root);
+        buf.extend_from_slice(format!("{}:", layer.len() * 32).as_bytes());
+        for hash in layer.iter() {
+            buf.extend_from_slice(hash);
+        }
+        buf.extend_from_slice(b"ee");
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        let info_hash_v2 = metainfo.info_hash_v2.unwrap();
+
+        let (seed, _seed_alert_rx, seed_dir) = spawn_engine("v2_magnet_seed");
+        fs::write(seed_dir.join("f"), &contents).unwrap();
+        seed.create_torrent(TorrentParams {
+            metainfo,
+            conf: None,
+            mode: Mode::Seed,
+            listen_addr: None,
+            file_priorities: None,
+        })
+        .unwrap();
+        // the torrent only answers once it has started
+        seed.torrents().await.unwrap();
+
+        let (leech, mut alert_rx, leech_dir) = spawn_engine("v2_magnet_leech");
+        let mut magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btmh:1220{}",
+            hex::encode(info_hash_v2)
+        ))
+        .unwrap();
+        magnet.peers.push(seed.listen_addr());
+        let id = leech
+            .create_magnet_torrent(MagnetParams {
+                magnet,
+                conf: None,
+                listen_addr: None,
+            })
+            .unwrap();
+
+        time::timeout(Duration::from_secs(10), async {
+            loop {
+                match alert_rx.recv().await.unwrap() {
+                    Alert::TorrentComplete(alert_id) if alert_id == id => break,
+                    Alert::Error(e) => panic!("unexpected error {}", e),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .expect("torrent not downloaded");
+        assert_eq!(fs::read(leech_dir.join("f")).unwrap(), contents);
+
+        leech.shutdown().await.unwrap();
+        seed.shutdown().await.unwrap();
+        fs::remove_dir_all(leech_dir).unwrap();
+        fs::remove_dir_all(seed_dir).unwrap();
+    }
+}
@@ -9,7 +9,10 @@ use crate::TorrentId;
 pub use crate::{
     peer::error::PeerError, torrent::error::TorrentError, tracker::TrackerError,
 };
-pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
+pub use tokio::{
+    io::Error as IoError,
+    sync::{mpsc::error::SendError, oneshot::error::RecvError},
+};
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -84,3 +87,9 @@ impl<T> From<SendError<T>> for Error {
         Self::Channel
     }
 }
+
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Self {
+        Self::Channel
+    }
+}
@@ -1,5 +1,6 @@
 //! `cratetorrent` is a peer-to-peer file-sharing engine implementing the
-//! BitTorrent version 1 protocol.
+//! BitTorrent version 1 protocol, as well as version 2 and hybrid torrents
+//! ([BEP 52](https://www.bittorrent.org/beps/bep_0052.html)).
 //!
 //! It is built on top of [`tokio`](https://docs.rs/tokio/0.2.16/tokio/) for
 //! async IO.
@@ -9,10 +10,8 @@
 //! The engine currently only supports Linux. This is expected to change in the
 //! future, however.
 //!
-//! It also lacks most features present in battle-hardened torrent engines, such
-//! as [libtorrent](https://github.com/arvidn/libtorrent). These include: DHT
-//! for peer exchange, magnet links, stream encryption, UDP trackers, and many
-//! more.
+//! It also lacks many features present in battle-hardened torrent engines, such
+//! as [libtorrent](https://github.com/arvidn/libtorrent).
 //!
 //! Therefore in the current state of the project, this should only be viewed as
 //! a toy program.
@@ -46,8 +45,22 @@
 //! metainfo is semantically or syntactically invalid.
 //!
 //! Note that in order to download a torrent the metainfo has to contain HTTP
-//! trackers, or some seeds have to be manually specified. As mentioned above,
-//! DHT or even UDP trackers are not currently supported.
+//! or UDP trackers, or some seeds have to be manually specified, unless the
+//! DHT is enabled via [`EngineConf::dht`](crate::conf::EngineConf::dht).
+//! Once connected, peers also tell us about other peers in the swarm via [peer
+//! exchange](http://bittorrent.org/beps/bep_0011.html).
+//!
+//! Torrents marked [private](crate::metainfo::Metainfo::is_private) only get
+//! peers from their trackers, so they use neither the DHT nor peer exchange.
+//! They also announce themselves with a peer id of their own, which is kept
+//! across restarts if resume data is enabled.
+//!
+//! The peers that a torrent learns of are kept in its peer list, from which
+//! the most promising ones are connected. Peers that can't be connected to
+//! are retried with exponential backoff, while peers that send corrupt pieces
+//! or break the protocol are banned. The list can be received with the torrent
+//! stats by enabling
+//! [`TorrentAlertConf::known_peers`](crate::conf::TorrentAlertConf::known_peers).
 //!
 //! Once this is done, a command to the engine has to be sent to create the
 //! torrent. This is done using
@@ -89,6 +102,7 @@
 //!         listen_addr: None,
 //!         mode: Mode::Download { seeds: Vec::new() },
 //!         conf: None,
+//!         file_priorities: None,
 //!     })?;
 //!
 //!     // listen to alerts from the engine
@@ -130,6 +144,27 @@
 //!
 //! Therefore the application must make sure to provide its own way of stopping
 //! the download.
+//!
+//! New torrents to seed are created from files on disk with the
+//! [`MetainfoBuilder`](crate::metainfo::MetainfoBuilder), which hashes the
+//! pieces of the files and returns the bencoded `.torrent` file along with the
+//! parsed metainfo.
+//!
+//! # Magnet links
+//!
+//! A torrent may also be downloaded without its metainfo, from
+//! a [magnet link](crate::magnet::MagnetLink) parsed with
+//! [`MagnetLink::parse`](crate::magnet::MagnetLink::parse). The link is passed
+//! to [`EngineHandle::create_magnet_torrent`](crate::engine::EngineHandle::create_magnet_torrent),
+//! which first downloads the torrent's metadata from peers in the swarm (found
+//! via the link's trackers and peers). Once this is done, the engine sends an
+//! [`Alert::MetadataReceived`](crate::alert::Alert::MetadataReceived) alert
+//! with the torrent's metainfo and starts downloading the torrent as if it had
+//! been created from the metainfo.
+//!
+//! The piece layers with which the pieces of v2-only torrents are verified are
+//! not part of the metadata, so they are requested from the same peer as the
+//! metadata.
 
 // needed by the `select!` macro reaching the default recursion limit
 #![recursion_limit = "256"]
@@ -150,17 +185,25 @@ pub use storage_info::FileInfo;
 
 pub mod alert;
 mod avg;
+mod bencode;
 pub mod conf;
 mod counter;
+mod dht;
 mod disk;
 mod download;
 pub mod engine;
 pub mod error;
 pub mod iovecs;
+pub mod magnet;
+mod merkle;
+mod metadata;
 pub mod metainfo;
+mod net;
 pub mod peer;
 mod piece_picker;
 pub mod prelude;
+mod rate_limiter;
+mod resume;
 pub mod storage_info;
 pub mod torrent;
 mod tracker;
@@ -205,6 +248,9 @@ pub type PeerId = [u8; 20];
 /// A SHA-1 hash digest, 20 bytes long.
 pub type Sha1Hash = [u8; 20];
 
+/// A SHA-256 hash digest, 32 bytes long, as used by BitTorrent v2 torrents.
+pub type Sha256Hash = [u8; 32];
+
 /// The bitfield represents the piece availability of a peer.
 ///
 /// It is a compact bool vector of most significant bits to least significants
@@ -0,0 +1,366 @@
+//! This module contains the parser of [magnet
+//! links](http://bittorrent.org/beps/bep_0009.html#magnet-uri-format).
+//!
+//! A magnet link identifies a torrent by its info hash alone, rather than by
+//! its full metainfo. The metainfo is then downloaded from other peers in the
+//! swarm, via the metadata exchange extension. To help find these peers, the
+//! link may contain trackers and peer addresses.
+//!
+//! Both v1 info hashes (`urn:btih:`) and the v2 info hashes of
+//! [BEP 52](https://www.bittorrent.org/beps/bep_0052.html) (`urn:btmh:`) are
+//! supported.
+//!
+//! A torrent may be started from a magnet link using
+//! [`EngineHandle::create_magnet_torrent`](crate::engine::EngineHandle::create_magnet_torrent).
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{metainfo::TrackerUrl, Sha1Hash};
+
+pub(crate) type Result<T> = crate::error::Result<T, MagnetError>;
+
+/// The errors that may occur when parsing a magnet link.
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum MagnetError {
+    /// The string is not a valid `magnet:` URI.
+    InvalidUri,
+    /// The magnet link doesn't contain a BitTorrent info hash (`xt` parameter
+    /// with the `urn:btih:` or `urn:btmh:` prefix).
+    MissingInfoHash,
+    /// The v1 info hash is neither a 40 character hex string nor a 32
+    /// character base32 string, or the v2 info hash is not a hex encoded
+    /// SHA-256 multihash.
+    InvalidInfoHash,
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MagnetError::*;
+        match self {
+            InvalidUri => write!(f, "invalid magnet URI"),
+            MissingInfoHash => write!(f, "missing info hash"),
+            InvalidInfoHash => write!(f, "invalid info hash"),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+impl From<url::ParseError> for MagnetError {
+    fn from(_: url::ParseError) -> Self {
+        Self::InvalidUri
+    }
+}
+
+/// The parsed magnet link, containing the information needed to start
+/// downloading the torrent's metadata.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MagnetLink {
+    /// The torrent's info hash, which is the only mandatory part of the link.
+    ///
+    /// If the link only has the v2 info hash, as is the case for v2-only
+    /// torrents, this is the v2 info hash truncated to 20 bytes, by which the
+    /// torrent is identified in its swarm.
+    pub info_hash: Sha1Hash,
+    /// The display name of the torrent (`dn` parameter), if present.
+    pub name: Option<String>,
+    /// The trackers included in the link (`tr` parameters). Trackers with
+    /// unsupported protocols are skipped.
+    pub trackers: Vec<TrackerUrl>,
+    /// The addresses of peers included in the link (`x.pe` parameters).
+    /// Peers given as host names rather than IP addresses are skipped.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    /// Parses a magnet link from its URI representation, or aborts with an
+    /// error if the URI is not valid or doesn't contain an info hash.
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri)?;
+        if url.scheme() != "magnet" {
+            log::warn!("Magnet link has scheme {}", url.scheme());
+            return Err(MagnetError::InvalidUri);
+        }
+
+        let mut info_hash = None;
+        let mut v2_info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            // there may be multiple exact topics, possibly numbered (e.g.
+            // `xt.1`), but we're only interested in the first BitTorrent v1
+            // and v2 ones
+            if key == "xt" || key.starts_with("xt.") {
+                if let Some(hash) = strip_prefix(&value, BTIH_PREFIX) {
+                    if info_hash.is_none() {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                } else if let Some(hash) = strip_prefix(&value, BTMH_PREFIX) {
+                    if v2_info_hash.is_none() {
+                        v2_info_hash = Some(parse_v2_info_hash(hash)?);
+                    }
+                }
+            } else if key == "dn" {
+                name = Some(value.into_owned());
+            } else if key == "tr" || key.starts_with("tr.") {
+                match Url::parse(&value).ok().and_then(TrackerUrl::from_url) {
+                    Some(tracker) => trackers.push(tracker),
+                    None => {
+                        log::warn!("Skipping unsupported tracker {}", value)
+                    }
+                }
+            } else if key == "x.pe" {
+                match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => log::warn!("Skipping invalid peer {}", value),
+                }
+            }
+        }
+
+        // hybrid torrents have both info hashes, in which case they are
+        // identified by the v1 one, as in their metainfo
+        Ok(Self {
+            info_hash: info_hash
+                .or(v2_info_hash)
+                .ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+/// The URN prefix of BitTorrent v1 info hashes in the exact topic parameter.
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// The URN prefix of BitTorrent v2 info hashes in the exact topic parameter,
+/// which are multihashes.
+const BTMH_PREFIX: &str = "urn:btmh:";
+
+/// The hex encoded multihash prefix of SHA-256 hashes: the code of the hash
+/// function followed by the length of the digest.
+const SHA256_MULTIHASH_PREFIX: &str = "1220";
+
+/// Returns the exact topic without its URN prefix, or `None` if it doesn't
+/// have the prefix, which is matched case-insensitively.
+fn strip_prefix<'a>(topic: &'a str, prefix: &str) -> Option<&'a str> {
+    if topic.len() < prefix.len()
+        || !topic.is_char_boundary(prefix.len())
+        || !topic[..prefix.len()].eq_ignore_ascii_case(prefix)
+    {
+        return None;
+    }
+    Some(&topic[prefix.len()..])
+}
+
+/// Parses the v2 info hash, returning it truncated to 20 bytes.
+fn parse_v2_info_hash(hash: &str) -> Result<Sha1Hash> {
+    let hash = strip_prefix(hash, SHA256_MULTIHASH_PREFIX)
+        .filter(|hash| hash.len() == 64)
+        .ok_or(MagnetError::InvalidInfoHash)?;
+    let mut info_hash_v2 = [0; 32];
+    hex::decode_to_slice(hash, &mut info_hash_v2)
+        .map_err(|_| MagnetError::InvalidInfoHash)?;
+
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&info_hash_v2[..20]);
+    Ok(info_hash)
+}
+
+/// Parses the v1 info hash, which is either hex or base32 encoded.
+fn parse_info_hash(hash: &str) -> Result<Sha1Hash> {
+    let mut info_hash = [0; 20];
+    match hash.len() {
+        40 => {
+            hex::decode_to_slice(hash, &mut info_hash)
+                .map_err(|_| MagnetError::InvalidInfoHash)?;
+        }
+        32 => {
+            decode_base32(hash.as_bytes(), &mut info_hash)?;
+        }
+        _ => return Err(MagnetError::InvalidInfoHash),
+    }
+
+    Ok(info_hash)
+}
+
+/// Decodes the unpadded RFC 4648 base32 string into the output buffer, whose
+/// length must be exactly the decoded length of the input.
+fn decode_base32(input: &[u8], out: &mut [u8]) -> Result<()> {
+    debug_assert_eq!(input.len() * 5, out.len() * 8);
+
+    // each character encodes 5 bits, which are accumulated until there is
+    // a full byte to output
+    let mut acc: u16 = 0;
+    let mut acc_bits = 0;
+    let mut out_pos = 0;
+    for c in input {
+        let val = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return Err(MagnetError::InvalidInfoHash),
+        };
+        acc = (acc << 5) | val as u16;
+        acc_bits += 5;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            out[out_pos] = (acc >> acc_bits) as u8;
+            out_pos += 1;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metainfo::NetProtocol;
+
+    const INFO_HASH: Sha1Hash = [
+        0xc1, 0x2f, 0xe1, 0xc0, 0x6b, 0xba, 0x25, 0x4a, 0x9d, 0xc9, 0xf5, 0x19,
+        0xb3, 0x35, 0xaa, 0x7c, 0x13, 0x67, 0xa8, 0x8a,
+    ];
+
+    #[test]
+    fn should_parse_hex_info_hash() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet,
+            MagnetLink {
+                info_hash: INFO_HASH,
+                name: None,
+                trackers: Vec::new(),
+                peers: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn should_parse_base32_info_hash() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+    }
+
+    #[test]
+    fn should_parse_v2_info_hash() {
+        let v2_hash =
+            "d8dd32ac93357c368556af3ac1d95c9d76bd0dff6fa9833ecdac3d53134efabb";
+        let magnet =
+            MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1220{}", v2_hash))
+                .unwrap();
+        // v2-only torrents are identified by the truncated v2 info hash
+        assert_eq!(hex::encode(magnet.info_hash), v2_hash[..40]);
+
+        // while hybrid torrents are identified by their v1 info hash
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btmh:1220{}\
+            &xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a",
+            v2_hash
+        ))
+        .unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+
+        // only SHA-256 multihashes are v2 info hashes
+        assert_eq!(
+            MagnetLink::parse(&format!("magnet:?xt=urn:btmh:1320{}", v2_hash)),
+            Err(MagnetError::InvalidInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse(&format!(
+                "magnet:?xt=urn:btmh:1220{}",
+                &v2_hash[..62]
+            )),
+            Err(MagnetError::InvalidInfoHash)
+        );
+    }
+
+    #[test]
+    fn should_parse_all_fields() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a\
+            &dn=Some+File%20Name\
+            &tr=http%3A%2F%2Ftracker.example.com%2Fannounce\
+            &tr=udp%3A%2F%2Ftracker.example.org%3A6969\
+            &tr=wss%3A%2F%2Ftracker.example.net\
+            &x.pe=10.0.0.1%3A6881\
+            &x.pe=%5B%3A%3A1%5D%3A51413\
+            &x.pe=peer.example.com%3A6881",
+        )
+        .unwrap();
+
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("Some File Name"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                TrackerUrl {
+                    url: "http://tracker.example.com/announce".parse().unwrap(),
+                    protocol: NetProtocol::HTTP,
+                },
+                TrackerUrl {
+                    url: "udp://tracker.example.org:6969".parse().unwrap(),
+                    protocol: NetProtocol::UDP,
+                },
+            ]
+        );
+        assert_eq!(
+            magnet.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:51413".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_links() {
+        assert_eq!(
+            MagnetLink::parse("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::InvalidUri)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=name"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:sha1:abcdef"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btih:c12fe1c06bba254a"),
+            Err(MagnetError::InvalidInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse(
+                "magnet:?xt=urn:btih:x12fe1c06bba254a9dc9f519b335aa7c1367a88a"
+            ),
+            Err(MagnetError::InvalidInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse(
+                "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1"
+            ),
+            Err(MagnetError::InvalidInfoHash)
+        );
+    }
+}
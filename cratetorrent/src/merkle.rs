@@ -0,0 +1,317 @@
+//! This module contains the merkle hash trees with which the files of
+//! BitTorrent v2 torrents are verified, as described in
+//! [BEP 52](https://www.bittorrent.org/beps/bep_0052.html).
+//!
+//! Each file has its own binary tree of SHA-256 hashes, whose leaves are the
+//! hashes of the file's 16 KiB blocks. The tree is always full: leaves past
+//! the end of the file are zero hashes. The root of the tree, the "pieces
+//! root", is in the metainfo, along with the layer of the tree in which each
+//! hash covers a piece, the "piece layer".
+
+use sha2::{Digest, Sha256};
+
+use crate::{Sha256Hash, BLOCK_LEN};
+
+/// The most hashes we send in response to a single hash request.
+pub(crate) const MAX_HASH_REQUEST_LEN: u32 = 512;
+
+/// Returns the SHA-256 hash of the buffer.
+pub(crate) fn hash(buf: &[u8]) -> Sha256Hash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(buf));
+    hash
+}
+
+/// Returns the hash of the parent of the two nodes.
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+/// Returns the hashes of the layer above the given one, where nodes missing
+/// from the end of the layer are the pad hash.
+fn parent_layer(layer: &[Sha256Hash], pad: &Sha256Hash) -> Vec<Sha256Hash> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(pad)))
+        .collect()
+}
+
+/// Returns the root of the tree whose lowest layer has `width` nodes, the
+/// first of which are the given hashes and the rest are the pad hash.
+///
+/// # Panics
+///
+/// Panics if the width is not a power of two or if there are more hashes
+/// than the width.
+pub(crate) fn root(
+    mut layer: Vec<Sha256Hash>,
+    width: usize,
+    mut pad: Sha256Hash,
+) -> Sha256Hash {
+    assert!(width.is_power_of_two(), "tree width must be a power of two");
+    assert!(layer.len() <= width, "too many hashes for tree width");
+    let mut width = width;
+    while width > 1 {
+        layer = parent_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+/// Returns the root of a subtree whose leaves are all zero hashes. Such
+/// subtrees pad the layers of a file's tree past the end of the file.
+pub(crate) fn pad_hash(leaf_count: usize) -> Sha256Hash {
+    root(Vec::new(), leaf_count, [0; 32])
+}
+
+/// Returns the root of the subtree of the piece made up of the blocks, in
+/// order, which has `leaf_count` leaves.
+///
+/// # Important
+///
+/// This is potentially a computationally expensive function and should be
+/// executed on a thread pool and not the executor.
+pub(crate) fn piece_root(
+    blocks: impl Iterator<Item = impl AsRef<[u8]>>,
+    leaf_count: usize,
+) -> Sha256Hash {
+    let leaves = blocks.map(|block| hash(block.as_ref())).collect();
+    root(leaves, leaf_count, [0; 32])
+}
+
+/// Returns the number of leaves of the subtree covering a piece of the given
+/// length, which is the number of blocks in it.
+pub(crate) fn piece_leaf_count(piece_len: u32) -> usize {
+    (piece_len / BLOCK_LEN) as usize
+}
+
+/// Returns the index of the layer, counting from the leaves, in which each
+/// hash covers a piece of the given length.
+pub(crate) fn piece_layer_index(piece_len: u32) -> u32 {
+    piece_leaf_count(piece_len).trailing_zeros()
+}
+
+/// Returns the hashes of the piece layer requested by a peer, followed by
+/// the uncle hashes with which the peer can verify them against the file's
+/// pieces root, or `None` if the request is invalid.
+///
+/// The requested hashes are `len` hashes starting at `index`, where `len`
+/// must be a power of two and `index` its multiple. These form a subtree,
+/// above which `proof_layers` uncle hashes are included, stopping below the
+/// root.
+pub(crate) fn piece_layer_proof(
+    piece_layer: &[Sha256Hash],
+    piece_len: u32,
+    index: u32,
+    len: u32,
+    proof_layers: u32,
+) -> Option<Vec<Sha256Hash>> {
+    let width = piece_layer.len().next_power_of_two();
+    if !len.is_power_of_two()
+        || len > MAX_HASH_REQUEST_LEN
+        || index % len != 0
+        || index as usize >= width
+        || (index + len) as usize > width
+    {
+        return None;
+    }
+
+    let mut pad = pad_hash(piece_leaf_count(piece_len));
+    let start = index as usize;
+    let end = start + len as usize;
+    let mut hashes: Vec<_> = (start..end)
+        .map(|i| piece_layer.get(i).copied().unwrap_or(pad))
+        .collect();
+
+    // go up to the layer in which a single node is the root of the requested
+    // hashes' subtree
+    let mut layer = piece_layer.to_vec();
+    let mut pos = start;
+    let mut layer_width = width;
+    let mut subtree_len = len as usize;
+    while subtree_len > 1 {
+        layer = parent_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        pos /= 2;
+        layer_width /= 2;
+        subtree_len /= 2;
+    }
+
+    // then collect the siblings of the subtree root's ancestors
+    for _ in 0..proof_layers {
+        if layer_width <= 1 {
+            break;
+        }
+        let sibling = pos ^ 1;
+        hashes.push(layer.get(sibling).copied().unwrap_or(pad));
+        layer = parent_layer(&layer, &pad);
+        pad = hash_pair(&pad, &pad);
+        pos /= 2;
+        layer_width /= 2;
+    }
+
+    Some(hashes)
+}
+
+/// Verifies the hashes that a peer sent in response to our request for `len`
+/// hashes of a file's piece layer starting at `index`, followed by the uncle
+/// hashes up to the file's pieces root, returning the requested hashes if
+/// they are valid.
+///
+/// `width` is the number of hashes in the piece layer including the padding
+/// past the end of the file, which is a power of two. The request must have
+/// asked for all uncle hashes, so that the root can be computed.
+pub(crate) fn verify_piece_layer_proof<'a>(
+    pieces_root: &Sha256Hash,
+    width: usize,
+    index: u32,
+    len: u32,
+    hashes: &'a [Sha256Hash],
+) -> Option<&'a [Sha256Hash]> {
+    let len = len as usize;
+    if !len.is_power_of_two() || len > width || index as usize % len != 0 {
+        return None;
+    }
+    let proof_layers = (width / len).trailing_zeros() as usize;
+    if hashes.len() != len + proof_layers {
+        return None;
+    }
+
+    let (layer, uncles) = hashes.split_at(len);
+    let mut node = root(layer.to_vec(), len, [0; 32]);
+    let mut pos = index as usize / len;
+    for uncle in uncles {
+        node = if pos % 2 == 0 {
+            hash_pair(&node, uncle)
+        } else {
+            hash_pair(uncle, &node)
+        };
+        pos /= 2;
+    }
+
+    if node == *pieces_root {
+        Some(layer)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(n: u8) -> Sha256Hash {
+        hash(&[n])
+    }
+
+    #[test]
+    fn should_compute_root_with_padding() {
+        let (a, b, c) = (leaf(1), leaf(2), leaf(3));
+        // a single leaf is its own root
+        assert_eq!(root(vec![a], 1, [0; 32]), a);
+        assert_eq!(root(vec![a, b], 2, [0; 32]), hash_pair(&a, &b));
+        // missing leaves are padded with the pad hash, and their parents
+        // with the parent of the pad hashes
+        let zero = [0; 32];
+        assert_eq!(
+            root(vec![a, b, c], 4, zero),
+            hash_pair(&hash_pair(&a, &b), &hash_pair(&c, &zero))
+        );
+        assert_eq!(
+            root(vec![a], 4, zero),
+            hash_pair(&hash_pair(&a, &zero), &hash_pair(&zero, &zero))
+        );
+        assert_eq!(pad_hash(4), root(Vec::new(), 4, zero));
+        assert_eq!(pad_hash(2), hash_pair(&zero, &zero));
+    }
+
+    #[test]
+    fn should_compute_piece_root() {
+        let piece_len = 4 * BLOCK_LEN;
+        let data = vec![7; 2 * BLOCK_LEN as usize + 10];
+        let blocks: Vec<_> = data.chunks(BLOCK_LEN as usize).collect();
+        let leaves = blocks.iter().map(|b| hash(b)).collect::<Vec<_>>();
+        let zero = [0; 32];
+        assert_eq!(
+            piece_root(blocks.iter(), piece_leaf_count(piece_len)),
+            hash_pair(
+                &hash_pair(&leaves[0], &leaves[1]),
+                &hash_pair(&leaves[2], &zero)
+            )
+        );
+        assert_eq!(piece_layer_index(BLOCK_LEN), 0);
+        assert_eq!(piece_layer_index(piece_len), 2);
+    }
+
+    #[test]
+    fn should_prove_piece_layer_hashes() {
+        let piece_len = 2 * BLOCK_LEN;
+        let pad = pad_hash(piece_leaf_count(piece_len));
+        let layer = vec![leaf(1), leaf(2), leaf(3)];
+        let l1 = hash_pair(&layer[0], &layer[1]);
+        let r1 = hash_pair(&layer[2], &pad);
+
+        // the whole layer, padded to the width of the tree
+        assert_eq!(
+            piece_layer_proof(&layer, piece_len, 0, 4, 0),
+            Some(vec![layer[0], layer[1], layer[2], pad])
+        );
+        // a single hash with all its uncles up to the root
+        assert_eq!(
+            piece_layer_proof(&layer, piece_len, 2, 1, 10),
+            Some(vec![layer[2], pad, l1])
+        );
+        // a pair of hashes with the uncle of their parent
+        assert_eq!(
+            piece_layer_proof(&layer, piece_len, 0, 2, 1),
+            Some(vec![layer[0], layer[1], r1])
+        );
+        // the uncle hashes prove the requested hashes against the root
+        let root = root(layer.clone(), 4, pad);
+        assert_eq!(hash_pair(&l1, &r1), root);
+
+        // invalid requests
+        assert_eq!(piece_layer_proof(&layer, piece_len, 1, 2, 0), None);
+        assert_eq!(piece_layer_proof(&layer, piece_len, 0, 3, 0), None);
+        assert_eq!(piece_layer_proof(&layer, piece_len, 4, 1, 0), None);
+        assert_eq!(piece_layer_proof(&layer, piece_len, 0, 8, 0), None);
+    }
+
+    #[test]
+    fn should_verify_piece_layer_proof() {
+        let piece_len = 2 * BLOCK_LEN;
+        let pad = pad_hash(piece_leaf_count(piece_len));
+        let layer = vec![leaf(1), leaf(2), leaf(3)];
+        let root = root(layer.clone(), 4, pad);
+
+        // the hashes of each half of the layer are proven by the root of the
+        // other half
+        let first = piece_layer_proof(&layer, piece_len, 0, 2, 1).unwrap();
+        assert_eq!(
+            verify_piece_layer_proof(&root, 4, 0, 2, &first),
+            Some(&layer[..2])
+        );
+        let second = piece_layer_proof(&layer, piece_len, 2, 2, 1).unwrap();
+        assert_eq!(
+            verify_piece_layer_proof(&root, 4, 2, 2, &second),
+            Some(&[layer[2], pad][..])
+        );
+        let whole = piece_layer_proof(&layer, piece_len, 0, 4, 0).unwrap();
+        assert!(verify_piece_layer_proof(&root, 4, 0, 4, &whole).is_some());
+
+        // tampered hashes, hashes of the wrong position, and missing uncles
+        // are rejected
+        let mut tampered = first.clone();
+        tampered[1][0] ^= 1;
+        assert_eq!(verify_piece_layer_proof(&root, 4, 0, 2, &tampered), None);
+        assert_eq!(verify_piece_layer_proof(&root, 4, 2, 2, &first), None);
+        assert_eq!(verify_piece_layer_proof(&root, 4, 0, 2, &first[..2]), None);
+        assert_eq!(verify_piece_layer_proof(&root, 4, 1, 2, &first), None);
+    }
+}
@@ -0,0 +1,398 @@
+//! This module contains the entity that downloads the metadata of torrents
+//! started from a magnet link.
+//!
+//! Until the metadata (the info dictionary of the metainfo) is known, the
+//! torrent's storage layout and pieces are unknown, so a
+//! [`Torrent`](crate::torrent::Torrent) cannot be started yet. Instead, the
+//! engine spawns a [`MetadataDownload`] which finds peers via the magnet link's
+//! trackers, and downloads the metadata from them via the metadata exchange
+//! extension. Once the metadata is verified, it is sent back to the engine,
+//! which then starts the torrent proper.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    select,
+    stream::{Fuse, FuturesUnordered, StreamExt},
+};
+use tokio::{sync::mpsc, time};
+
+use crate::{
+    alert::{Alert, AlertSender},
+    conf::{EncryptionPolicy, TorrentConf},
+    dht, engine,
+    error::{Error, TorrentError},
+    peer::{self, MetadataSession},
+    tracker::{Announce, Tracker},
+    PeerId, Sha1Hash, TorrentId,
+};
+
+/// Parameters for the metadata download constructor.
+pub(crate) struct Params {
+    pub id: TorrentId,
+    pub info_hash: Sha1Hash,
+    pub client_id: PeerId,
+    pub encryption: EncryptionPolicy,
+    /// The port announced to trackers, on which the torrent will later listen
+    /// for peers.
+    pub port: u16,
+    pub trackers: Vec<Tracker>,
+    pub peers: Vec<SocketAddr>,
+    pub conf: TorrentConf,
+    pub engine_tx: engine::Sender,
+    pub dht_tx: Option<dht::Sender>,
+    pub alert_tx: AlertSender,
+}
+
+/// Downloads the metadata of a torrent from the peers in its swarm.
+pub(crate) struct MetadataDownload {
+    id: TorrentId,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+    encryption: EncryptionPolicy,
+    port: u16,
+    /// The trackers we can request peers from.
+    trackers: Vec<TrackerEntry>,
+    /// The random key sent in all announces.
+    announce_key: u32,
+    /// The peers we haven't tried to download the metadata from yet.
+    available_peers: Vec<SocketAddr>,
+    /// All the peers we know of. This is used to not try the same peers
+    /// multiple times, and the peers are passed on to the torrent once the
+    /// metadata is downloaded.
+    known_peers: HashSet<SocketAddr>,
+    conf: TorrentConf,
+    /// The engine is notified of the downloaded metadata on this channel.
+    engine_tx: engine::Sender,
+    /// The engine's DHT node, if the DHT is enabled.
+    dht_tx: Option<dht::Sender>,
+    /// The channel on which the DHT node sends the peers it finds.
+    dht_peer_tx: dht::PeerSender,
+    dht_peer_rx: Fuse<dht::PeerReceiver>,
+    last_dht_lookup_time: Option<Instant>,
+    alert_tx: AlertSender,
+}
+
+/// The result of a metadata session: the address of the peer and the metadata
+/// it sent, if it was successful.
+type SessionResult = (SocketAddr, peer::error::Result<peer::Metadata>);
+
+impl MetadataDownload {
+    pub fn new(params: Params) -> Self {
+        let Params {
+            id,
+            info_hash,
+            client_id,
+            encryption,
+            port,
+            trackers,
+            peers,
+            conf,
+            engine_tx,
+            dht_tx,
+            alert_tx,
+        } = params;
+        let (dht_peer_tx, dht_peer_rx) = mpsc::unbounded_channel();
+
+        let mut download = Self {
+            id,
+            info_hash,
+            client_id,
+            encryption,
+            port,
+            trackers: trackers.into_iter().map(TrackerEntry::new).collect(),
+            announce_key: rand::random(),
+            available_peers: Vec::new(),
+            known_peers: HashSet::new(),
+            conf,
+            engine_tx,
+            dht_tx,
+            dht_peer_tx,
+            dht_peer_rx: dht_peer_rx.fuse(),
+            last_dht_lookup_time: None,
+            alert_tx,
+        };
+        download.add_peers(peers);
+        download
+    }
+
+    /// Runs the download until the metadata is downloaded from a peer, or
+    /// until the configured metadata timeout expires, in which case the
+    /// engine is told that the download failed.
+    ///
+    /// There is no explicit stop command: the task is aborted by the engine if
+    /// the torrent is removed or the engine is shut down in the meantime.
+    pub async fn run(mut self) {
+        log::info!("Starting metadata download of torrent {}", self.id);
+
+        let mut sessions = FuturesUnordered::new();
+        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+        let mut timeout = time::delay_for(self.conf.metadata_timeout).fuse();
+
+        loop {
+            select! {
+                _ = timeout => {
+                    log::warn!(
+                        "Torrent {} metadata download timed out",
+                        self.id
+                    );
+                    self.engine_tx
+                        .send(engine::Command::MetadataDownloadFailed {
+                            id: self.id,
+                            error: TorrentError::MetadataTimeout,
+                        })
+                        .ok();
+                    return;
+                }
+                now = tick_timer.select_next_some() => {
+                    // only ask for more peers when we've run out of them
+                    if sessions.is_empty() && self.available_peers.is_empty() {
+                        self.announce_to_trackers(now.into_std()).await;
+                        self.request_dht_peers(now.into_std());
+                    }
+                    self.connect_peers(&mut sessions);
+                }
+                peers = self.dht_peer_rx.select_next_some() => {
+                    log::debug!(
+                        "Received peers for torrent {} from DHT: {:?}",
+                        self.id,
+                        peers
+                    );
+                    self.add_peers(peers);
+                }
+                (addr, result) = sessions.select_next_some() => {
+                    match result {
+                        Ok(metadata) => {
+                            log::info!(
+                                "Downloaded torrent {} metadata from peer {}",
+                                self.id,
+                                addr
+                            );
+                            self.engine_tx
+                                .send(engine::Command::MetadataDownloaded {
+                                    id: self.id,
+                                    info: metadata.info,
+                                    piece_layers: metadata.piece_layers,
+                                    peers: self.known_peers.into_iter().collect(),
+                                })
+                                .ok();
+                            return;
+                        }
+                        Err(e) => {
+                            log::info!(
+                                "Failed to download torrent {} metadata from \
+                                peer {}: {}",
+                                self.id,
+                                addr,
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Adds the peers that we haven't seen before to the peers we can
+    /// connect to.
+    fn add_peers(&mut self, peers: impl IntoIterator<Item = SocketAddr>) {
+        for addr in peers {
+            if self.known_peers.insert(addr) {
+                self.available_peers.push(addr);
+            }
+        }
+    }
+
+    /// Starts metadata sessions with available peers, if we can have more
+    /// sessions.
+    fn connect_peers(
+        &mut self,
+        sessions: &mut FuturesUnordered<BoxFuture<'static, SessionResult>>,
+    ) {
+        let connect_count = MAX_SESSION_COUNT
+            .saturating_sub(sessions.len())
+            .min(self.available_peers.len());
+        for addr in self.available_peers.drain(0..connect_count) {
+            log::debug!(
+                "Connecting to peer {} for torrent {} metadata",
+                addr,
+                self.id
+            );
+            let session = MetadataSession::new(
+                self.id,
+                self.info_hash,
+                self.client_id,
+                self.encryption,
+                addr,
+            );
+            sessions.push(
+                async move {
+                    let result =
+                        match time::timeout(SESSION_TIMEOUT, session.run())
+                            .await
+                        {
+                            Ok(result) => result,
+                            Err(_) => {
+                                Err(io::Error::from(io::ErrorKind::TimedOut)
+                                    .into())
+                            }
+                        };
+                    (addr, result)
+                }
+                .boxed(),
+            );
+        }
+    }
+
+    /// Starts a DHT lookup of the torrent's peers, if the DHT is enabled and
+    /// we haven't done so recently.
+    ///
+    /// We don't announce ourselves yet, as we can't serve the torrent without
+    /// its metadata.
+    fn request_dht_peers(&mut self, now: Instant) {
+        let dht_tx = match &self.dht_tx {
+            Some(dht_tx) => dht_tx,
+            None => return,
+        };
+        if let Some(last_lookup_time) = self.last_dht_lookup_time {
+            if now.saturating_duration_since(last_lookup_time)
+                < DHT_LOOKUP_INTERVAL
+            {
+                return;
+            }
+        }
+
+        log::debug!("Looking up torrent {} peers in DHT", self.id);
+        let cmd = dht::Command::GetPeers {
+            info_hash: self.info_hash,
+            announce_port: None,
+            peer_tx: self.dht_peer_tx.clone(),
+        };
+        if dht_tx.send(cmd).is_err() {
+            log::warn!("DHT node is no longer running");
+            self.dht_tx = None;
+        }
+        self.last_dht_lookup_time = Some(now);
+    }
+
+    /// Requests peers from the trackers that we're allowed to announce to.
+    async fn announce_to_trackers(&mut self, now: Instant) {
+        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        let mut peers = Vec::new();
+        for tracker in self
+            .trackers
+            .iter_mut()
+            .filter(|t| t.error_count < tracker_error_threshold)
+        {
+            if !tracker.can_announce(now) {
+                continue;
+            }
+
+            let params = Announce {
+                tracker_id: None,
+                info_hash: self.info_hash,
+                peer_id: self.client_id,
+                key: self.announce_key,
+                port: self.port,
+                peer_count: Some(self.conf.max_connected_peer_count),
+                uploaded: 0,
+                downloaded: 0,
+                // we don't know the torrent's size yet, but if we announced
+                // nothing left to download we would appear as a seed, to which
+                // trackers don't necessarily send other seeds
+                left: UNKNOWN_LEFT,
+                ip: None,
+                ipv6: None,
+                event: None,
+            };
+            tracker.last_announce_time = Some(now);
+            match tracker.client.announce(params).await {
+                Ok(resp) => {
+                    log::info!(
+                        "Announced torrent {} to tracker {}, response: {:?}",
+                        self.id,
+                        tracker.client,
+                        resp
+                    );
+                    if let Some(min_interval) = resp.min_interval {
+                        tracker.min_interval = Some(min_interval);
+                    }
+                    peers.extend(resp.peers);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Error announcing to tracker {}: {}",
+                        tracker.client,
+                        e
+                    );
+                    tracker.error_count += 1;
+                    self.alert_tx
+                        .send(Alert::Error(Error::Tracker {
+                            id: self.id,
+                            error: e,
+                        }))
+                        .ok();
+                }
+            }
+        }
+        self.add_peers(peers);
+    }
+}
+
+/// The minimum time between DHT lookups of the torrent's peers.
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The maximum number of peers from which we try to download the metadata at
+/// the same time.
+const MAX_SESSION_COUNT: usize = 10;
+
+/// A metadata session that doesn't complete in this much time is aborted.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// If the tracker doesn't tell us its minimum announce interval, we wait this
+/// much before requesting more peers from it.
+const DEFAULT_MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The value announced to trackers as the number of bytes left to download,
+/// while the torrent size is not known. This is the same value as used by
+/// libtorrent.
+const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+/// A tracker that we can request peers from, along with its announce state.
+struct TrackerEntry {
+    client: Tracker,
+    last_announce_time: Option<Instant>,
+    /// The minimum announce interval, if the tracker sent it.
+    min_interval: Option<Duration>,
+    /// The number of failed announces. After reaching the configured
+    /// threshold, we stop announcing to the tracker.
+    error_count: usize,
+}
+
+impl TrackerEntry {
+    fn new(client: Tracker) -> Self {
+        Self {
+            client,
+            last_announce_time: None,
+            min_interval: None,
+            error_count: 0,
+        }
+    }
+
+    /// Determines whether we're allowed to announce at the given time.
+    fn can_announce(&self, t: Instant) -> bool {
+        match self.last_announce_time {
+            Some(last_announce_time) => {
+                t > last_announce_time
+                    + self.min_interval.unwrap_or(DEFAULT_MIN_ANNOUNCE_INTERVAL)
+            }
+            None => true,
+        }
+    }
+}
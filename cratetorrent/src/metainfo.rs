@@ -1,33 +1,59 @@
 //! This module contains a type safe representation of a torrent's metainfo, as
-//! well as utilities to construct it.
+//! well as utilities to construct it, including the [`MetainfoBuilder`] with
+//! which new torrents are created.
+//!
+//! Besides BitTorrent v1 torrents, the metainfo of v2 and hybrid torrents, as
+//! described in [BEP 52](https://www.bittorrent.org/beps/bep_0052.html), is
+//! supported. Hybrid torrents have both the v1 and the v2 metadata of the same
+//! files, and are thus part of both the v1 and the v2 swarm.
 
 use std::{
+    convert::TryFrom,
     fmt,
     path::{Path, PathBuf},
 };
 
 use reqwest::Url;
+use sha1::{Digest, Sha1};
 
-use crate::{FileInfo, Sha1Hash};
+use crate::{
+    bencode::{self, Value},
+    merkle, FileIndex, FileInfo, PieceIndex, Sha1Hash, Sha256Hash, BLOCK_LEN,
+};
 
+pub use builder::{MetainfoBuilder, NewTorrent};
 pub use serde_bencode::Error as BencodeError;
 
+mod builder;
+
 pub(crate) type Result<T> = crate::error::Result<T, MetainfoError>;
 
 /// The protocol that the tracker is using
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum NetProtocol {
     UDP,
     HTTP,
 }
 
-
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TrackerUrl {
     pub url: Url,
     pub protocol: NetProtocol,
 }
 
+impl TrackerUrl {
+    /// Returns the tracker URL with its protocol determined from the URL's
+    /// scheme, or `None` if the scheme is not a supported tracker protocol.
+    pub fn from_url(url: Url) -> Option<Self> {
+        let protocol = match url.scheme() {
+            "http" | "https" => NetProtocol::HTTP,
+            "udp" => NetProtocol::UDP,
+            _ => return None,
+        };
+        Some(Self { url, protocol })
+    }
+}
+
 #[derive(Debug)]
 pub enum MetainfoError {
     /// Holds bencode serialization or deserialization related errors.
@@ -38,8 +64,18 @@ pub enum MetainfoError {
     /// a multiple of 20, or is otherwise invalid and thus the torrent could not
     /// be started.
     InvalidPieces,
+    /// The piece layers of a v2 torrent are malformed or don't match the
+    /// files' merkle roots.
+    InvalidPieceLayers,
+    /// The piece layers of a v2-only torrent are missing. This is the case
+    /// when only its info dictionary is available, such as the metadata
+    /// downloaded for a magnet link, without the piece layers that are
+    /// requested from peers along with it.
+    MissingPieceLayers,
     /// The tracker URL is not a valid URL.
     InvalidTrackerUrl,
+    /// The files of a new torrent could not be read.
+    Io(std::io::Error),
 }
 
 impl From<BencodeError> for MetainfoError {
@@ -48,6 +84,12 @@ impl From<BencodeError> for MetainfoError {
     }
 }
 
+impl From<std::io::Error> for MetainfoError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl From<url::ParseError> for MetainfoError {
     fn from(_: url::ParseError) -> Self {
         Self::InvalidTrackerUrl
@@ -61,7 +103,10 @@ impl fmt::Display for MetainfoError {
             Bencode(e) => e.fmt(f),
             InvalidMetainfo => write!(f, "invalid metainfo"),
             InvalidPieces => write!(f, "invalid pieces"),
+            InvalidPieceLayers => write!(f, "invalid piece layers"),
+            MissingPieceLayers => write!(f, "missing piece layers"),
             InvalidTrackerUrl => write!(f, "invalid tracker URL"),
+            Io(e) => e.fmt(f),
         }
     }
 }
@@ -70,6 +115,7 @@ impl std::error::Error for MetainfoError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Self::Bencode(e) => Some(e),
+            Self::Io(e) => Some(e),
             _ => None,
         }
     }
@@ -83,19 +129,39 @@ pub struct Metainfo {
     /// path.
     pub name: String,
     /// This hash is used to identify a torrent with trackers and peers.
+    ///
+    /// For v2-only torrents this is the v2 info hash truncated to 20 bytes,
+    /// while hybrid torrents are identified by their v1 info hash, and by the
+    /// truncated v2 info hash in the v2 swarm.
     pub info_hash: Sha1Hash,
+    /// The SHA-256 hash of the `info` dictionary of v2 and hybrid torrents.
+    pub info_hash_v2: Option<Sha256Hash>,
+    /// The bencoded `info` dictionary from which the info hash is derived.
+    /// This is sent to peers that request the torrent's metadata.
+    pub raw_info: Vec<u8>,
     /// The concatenation of the 20 byte SHA-1 hash of each piece in torrent.
     /// This is used to verify the data sent to us by peers.
+    ///
+    /// This is empty for v2-only torrents, whose pieces are verified with the
+    /// merkle trees of their files.
     pub pieces: Vec<u8>,
+    /// The merkle hash trees of the files of v2 and hybrid torrents, in the
+    /// order of the files. Padding files don't have one.
+    pub merkle_trees: Vec<MerkleTree>,
     /// The nominal lengths of a piece, that is, the length of all but
     /// potentially the last piece, which may be smaller.
     pub piece_len: u32,
     /// The paths and lenths of the files in torrent.
     pub files: Vec<FileInfo>,
-    /// The trackers that we can announce to.
-    /// The tier information is not currently present in this field as
-    /// cratetorrent doesn't use it. In the future it may be added.
-    pub trackers: Vec<TrackerUrl>,
+    /// The trackers that we can announce to, grouped into tiers in order of
+    /// preference, as described in
+    /// [BEP 12](https://www.bittorrent.org/beps/bep_0012.html).
+    ///
+    /// Trackers with unsupported protocols are not included.
+    pub trackers: Vec<Vec<TrackerUrl>>,
+    /// Whether the torrent is marked private, in which case peers may only be
+    /// obtained from its trackers.
+    pub is_private: bool,
 }
 
 impl Metainfo {
@@ -110,130 +176,216 @@ impl Metainfo {
         // verify it afterwards
         let metainfo: raw::Metainfo = serde_bencode::from_bytes(buf)?;
 
-        // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
-        // must be a multiple of 20
-        if metainfo.info.pieces.len() % 20 != 0 {
-            return Err(MetainfoError::InvalidPieces);
-        }
-
-        // verify download structure and build up files metadata
-        let mut files = Vec::new();
-        if let Some(len) = metainfo.info.len {
-            if metainfo.info.files.is_some() {
-                log::warn!("Metainfo cannot contain both `length` and `files`");
-                return Err(MetainfoError::InvalidMetainfo);
+        // if the announce list is present, the announce field is ignored, as
+        // per BEP 12
+        let mut trackers = Vec::new();
+        if !metainfo.announce_list.is_empty() {
+            for tier in metainfo.announce_list.iter() {
+                let tier = parse_tier(tier)?;
+                if !tier.is_empty() {
+                    trackers.push(tier);
+                }
             }
-            if len == 0 {
-                log::warn!("File length is 0");
-                return Err(MetainfoError::InvalidMetainfo);
+        } else if let Some(tracker) = &metainfo.announce {
+            let tier = parse_tier(std::slice::from_ref(tracker))?;
+            if !tier.is_empty() {
+                trackers.push(tier);
             }
+        }
 
-            // the path of this file is just the torrent name
-            files.push(FileInfo {
-                path: metainfo.info.name.clone().into(),
-                len,
-                torrent_offset: 0,
-            });
-        } else if let Some(raw_files) = &metainfo.info.files {
-            if raw_files.is_empty() {
-                log::warn!("Metainfo files must not be empty");
-                return Err(MetainfoError::InvalidMetainfo);
-            }
+        if trackers.is_empty() {
+            log::warn!("No supported trackers in metainfo");
+        }
 
-            files.reserve_exact(raw_files.len());
+        // the info hash has to be derived from the info dictionary exactly as
+        // it was encoded, as re-encoding the parsed dictionary would drop the
+        // keys that we don't model (and may reorder the rest)
+        let value = bencode::decode(buf).map_err(|e| {
+            log::warn!("Invalid metainfo encoding: {}", e);
+            MetainfoError::InvalidMetainfo
+        })?;
+        let dict = value.as_dict().ok_or(MetainfoError::InvalidMetainfo)?;
+        let raw_info = dict
+            .get_raw(b"info")
+            .ok_or(MetainfoError::InvalidMetainfo)?
+            .to_vec();
+        // the piece layers of v2 torrents are outside the info dictionary, as
+        // they can be verified against the files' merkle roots
+        let piece_layers = match dict.get(b"piece layers") {
+            Some(layers) => Some(layers.as_dict().ok_or_else(|| {
+                log::warn!("Piece layers must be a dictionary");
+                MetainfoError::InvalidPieceLayers
+            })?),
+            None => None,
+        };
+        Self::from_raw_info(metainfo.info, raw_info, piece_layers, trackers)
+    }
 
-            // and sum up the file offsets in the torrent
-            let mut torrent_offset = 0;
-            for file in raw_files.iter() {
-                // verify that the file length is non-zero
-                if file.len == 0 {
-                    log::warn!("File {:?} length is 0", file.path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
+    /// Parses a new [`Metainfo`] instance from the bencoded `info` dictionary
+    /// alone, such as the one downloaded from peers via the metadata exchange
+    /// extension when starting a torrent from a magnet link.
+    ///
+    /// Since the info dictionary doesn't include trackers, these have to be
+    /// passed in separately.
+    ///
+    /// The info hash is the SHA-1 hash of the buffer as is, so it is the
+    /// caller's responsibility to verify it against the expected info hash.
+    ///
+    /// The piece layers of v2 torrents are not part of the info dictionary,
+    /// so this fails with [`MetainfoError::MissingPieceLayers`] for v2-only
+    /// torrents that have files longer than a piece. Hybrid torrents are
+    /// verified with their v1 piece hashes, so their merkle trees are left
+    /// without piece layers.
+    pub fn from_info_bytes(
+        buf: &[u8],
+        trackers: Vec<Vec<TrackerUrl>>,
+    ) -> Result<Self> {
+        let info: raw::Info = serde_bencode::from_bytes(buf)?;
+        Self::from_raw_info(info, buf.to_vec(), None, trackers)
+    }
 
-                // verify that the path is not empty
-                let path: PathBuf = file.path.iter().collect();
-                if path == PathBuf::new() {
-                    log::warn!("Path in metainfo is empty");
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
+    /// Parses a new [`Metainfo`] instance from the bencoded `info`
+    /// dictionary of a v2-only torrent and the piece layers of its files,
+    /// keyed by the files' pieces roots, which were requested from peers
+    /// along with the metadata.
+    ///
+    /// The piece layers are verified against the pieces roots, as when
+    /// they're in the metainfo. Without piece layers this is the same as
+    /// [`Self::from_info_bytes`].
+    pub(crate) fn from_info_and_piece_layers(
+        buf: &[u8],
+        piece_layers: &[(Sha256Hash, Vec<Sha256Hash>)],
+        trackers: Vec<Vec<TrackerUrl>>,
+    ) -> Result<Self> {
+        if piece_layers.is_empty() {
+            return Self::from_info_bytes(buf, trackers);
+        }
 
-                // verify that the path is not absolute
-                if path.is_absolute() {
-                    log::warn!("Path {:?} is absolute", path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
+        let info: raw::Info = serde_bencode::from_bytes(buf)?;
+        // the layers are parsed from their metainfo encoding, so that they're
+        // verified the same way
+        let encoded = encode_piece_layers(piece_layers);
+        let value = bencode::decode(&encoded)
+            .map_err(|_| MetainfoError::InvalidPieceLayers)?;
+        let piece_layers =
+            value.as_dict().ok_or(MetainfoError::InvalidPieceLayers)?;
+        Self::from_raw_info(info, buf.to_vec(), Some(piece_layers), trackers)
+    }
 
-                // verify that the path is not the root
-                if path == Path::new("/") {
-                    log::warn!("Path {:?} is root", path);
-                    return Err(MetainfoError::InvalidMetainfo);
-                }
+    /// Verifies the semantic validity of the parsed `info` dictionary and
+    /// creates the metainfo from it and its bencoded form.
+    fn from_raw_info(
+        info: raw::Info,
+        raw_info: Vec<u8>,
+        piece_layers: Option<&bencode::Dict>,
+        trackers: Vec<Vec<TrackerUrl>>,
+    ) -> Result<Self> {
+        // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
+        // must be a multiple of 20
+        if info.pieces.len() % 20 != 0 {
+            return Err(MetainfoError::InvalidPieces);
+        }
 
-                // file is now verified, we can collect it
-                files.push(FileInfo {
-                    path,
-                    torrent_offset,
-                    len: file.len,
-                });
+        // the v2 keys are not modeled by the serde types, as the keys of the
+        // file tree are the names of the files
+        let info_value = bencode::decode(&raw_info).map_err(|e| {
+            log::warn!("Invalid info dictionary encoding: {}", e);
+            MetainfoError::InvalidMetainfo
+        })?;
+        let info_dict =
+            info_value.as_dict().ok_or(MetainfoError::InvalidMetainfo)?;
+        // BEP 52 only defines version 2, so anything else is treated as a v1
+        // torrent
+        let is_v2 =
+            info_dict.get(b"meta version").and_then(Value::as_int) == Some(2);
 
-                // advance offset for next file
-                torrent_offset += file.len;
+        // v2 torrents may also have the v1 keys, in which case they are hybrid
+        let v1_files = if !is_v2
+            || info.len.is_some()
+            || info.files.is_some()
+            || !info.pieces.is_empty()
+        {
+            if info.pieces.is_empty() {
+                log::warn!("No pieces in metainfo");
+                return Err(MetainfoError::InvalidPieces);
             }
+            Some(parse_files(&info)?)
         } else {
-            log::warn!("No `length` or `files` key present in metainfo");
-            return Err(MetainfoError::InvalidMetainfo);
-        }
+            None
+        };
 
-        let mut trackers: Vec<TrackerUrl> = Vec::new();
-        if !metainfo.announce_list.is_empty() {
-            let tracker_count = metainfo
-                .announce_list
-                .iter()
-                .map(|t| t.len())
-                .sum::<usize>()
-                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
-            trackers.reserve(tracker_count);
+        let digest = Sha1::digest(&raw_info);
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&digest);
 
-            for tier in metainfo.announce_list.iter() {
-                for tracker in tier.iter() {
-                    let url = Url::parse(&tracker)?;
-                    match url.scheme() {
-                        "http" | "https" => {
-                            trackers.push(TrackerUrl { url, protocol: NetProtocol::HTTP })
-                        }
-                        "udp" => {
-                            trackers.push(TrackerUrl { url, protocol: NetProtocol::UDP })
-                        }
-                        _ => {unimplemented!()}
-                    }
-                }
+        let (files, info_hash_v2, merkle_trees) = if is_v2 {
+            // the leaves of the merkle trees are blocks, so a piece has to
+            // cover a whole subtree of them
+            if info.piece_len < BLOCK_LEN || !info.piece_len.is_power_of_two() {
+                log::warn!("Invalid v2 piece length {}", info.piece_len);
+                return Err(MetainfoError::InvalidMetainfo);
             }
-        } else if let Some(tracker) = &metainfo.announce {
-            let url = Url::parse(&tracker)?;
-            match url.scheme() {
-                "http" | "https" => {
-                    trackers.push(TrackerUrl { url, protocol: NetProtocol::HTTP })
+            let file_tree = info_dict
+                .get(b"file tree")
+                .and_then(Value::as_dict)
+                .ok_or_else(|| {
+                    log::warn!("No `file tree` key present in metainfo");
+                    MetainfoError::InvalidMetainfo
+                })?;
+            let mut tree_files = Vec::new();
+            parse_file_tree(file_tree, &mut PathBuf::new(), &mut tree_files)?;
+            if tree_files.is_empty() {
+                log::warn!("Metainfo file tree must not be empty");
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+
+            let is_hybrid = v1_files.is_some();
+            let (files, file_indices) = match v1_files {
+                Some(files) => {
+                    let file_indices =
+                        match_hybrid_files(&info, &files, &tree_files)?;
+                    (files, file_indices)
                 }
-                "udp" => {
-                    trackers.push(TrackerUrl { url, protocol: NetProtocol::UDP })
+                None => {
+                    let files = align_files(&tree_files, info.piece_len);
+                    (files, (0..tree_files.len()).collect())
                 }
-                _ => {}
-            }
-        }
+            };
 
-        if trackers.is_empty() {
-            log::warn!("No HTTP trackers in metainfo");
-        }
+            let mut merkle_trees = Vec::with_capacity(tree_files.len());
+            for (file, file_index) in tree_files.iter().zip(file_indices) {
+                let piece_layer = parse_piece_layer(
+                    file,
+                    info.piece_len,
+                    piece_layers,
+                    is_hybrid,
+                )?;
+                merkle_trees.push(MerkleTree {
+                    file_index,
+                    pieces_root: file.pieces_root,
+                    piece_layer,
+                });
+            }
 
-        // create info hash as a last step
-        let info_hash = metainfo.create_info_hash()?;
+            let info_hash_v2 = merkle::hash(&raw_info);
+            // v2-only torrents are identified by the truncated v2 info hash
+            if !is_hybrid {
+                info_hash.copy_from_slice(&info_hash_v2[..20]);
+            }
+            (files, Some(info_hash_v2), merkle_trees)
+        } else {
+            (v1_files.unwrap_or_default(), None, Vec::new())
+        };
 
         Ok(Self {
-            name: metainfo.info.name,
+            is_private: info.private == Some(1),
+            name: info.name,
             info_hash,
-            pieces: metainfo.info.pieces,
-            piece_len: metainfo.info.piece_len,
+            info_hash_v2,
+            raw_info,
+            pieces: info.pieces,
+            merkle_trees,
+            piece_len: info.piece_len,
             files,
             trackers,
         })
@@ -244,6 +396,38 @@ impl Metainfo {
         self.files.len() > 1
     }
 
+    /// Returns true if the torrent has v2 metadata, which is the case for
+    /// both v2-only and hybrid torrents.
+    pub fn is_v2(&self) -> bool {
+        self.info_hash_v2.is_some()
+    }
+
+    /// Returns true if the torrent has both v1 and v2 metadata.
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v2() && !self.pieces.is_empty()
+    }
+
+    /// Returns the info hash with which a hybrid torrent is identified in the
+    /// v2 swarm, which is its v2 info hash truncated to 20 bytes, or `None`
+    /// if the torrent is not hybrid.
+    pub fn hybrid_info_hash(&self) -> Option<Sha1Hash> {
+        if !self.is_hybrid() {
+            return None;
+        }
+        self.info_hash_v2.map(|hash| {
+            let mut info_hash = [0; 20];
+            info_hash.copy_from_slice(&hash[..20]);
+            info_hash
+        })
+    }
+
+    /// Returns true if each file starts at a piece boundary, as is the case in
+    /// v2-only torrents, so that no piece spans multiple files. Hybrid
+    /// torrents align their files with padding files instead.
+    pub fn is_piece_aligned(&self) -> bool {
+        self.is_v2() && !self.is_hybrid()
+    }
+
     /// Returns the total download size in bytes.
     ///
     /// Note that this is an O(n) operation for archive downloads, where n is
@@ -253,19 +437,491 @@ impl Metainfo {
     }
 
     /// Returns the number of pieces in this torrent.
+    ///
+    /// For v2-only torrents this is an O(n) operation, where n is the number
+    /// of files, as each file has its own pieces.
     pub fn piece_count(&self) -> usize {
-        self.pieces.len() / 20
+        if self.is_piece_aligned() {
+            let piece_len = self.piece_len as u64;
+            self.files
+                .iter()
+                .map(|f| ((f.len + piece_len - 1) / piece_len) as usize)
+                .sum()
+        } else {
+            self.pieces.len() / 20
+        }
+    }
+
+    /// Returns the expected hashes of the pieces, with which the downloaded
+    /// pieces are verified.
+    ///
+    /// The v1 piece hashes of hybrid torrents are used, as these cover the
+    /// padding files too.
+    pub(crate) fn piece_hashes(&self) -> PieceHashes {
+        if !self.is_piece_aligned() {
+            return PieceHashes::V1(self.pieces.clone());
+        }
+
+        let piece_len = self.piece_len as u64;
+        let mut hashes = Vec::with_capacity(self.piece_count());
+        for tree in self.merkle_trees.iter() {
+            let file = &self.files[tree.file_index];
+            if file.len <= piece_len {
+                // the root of a file that fits in a piece covers only as many
+                // blocks as the file has, rounded up to a power of two
+                let block_count = crate::block_count(file.len as u32);
+                hashes
+                    .push((tree.pieces_root, block_count.next_power_of_two()));
+            } else {
+                let leaf_count = merkle::piece_leaf_count(self.piece_len);
+                hashes.extend(
+                    tree.piece_layer.iter().map(|hash| (*hash, leaf_count)),
+                );
+            }
+        }
+        PieceHashes::V2(hashes)
     }
 }
 
+/// The merkle hash tree of a file of a v2 or hybrid torrent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleTree {
+    /// The index of the file in [`Metainfo::files`].
+    pub file_index: FileIndex,
+    /// The root of the tree, which covers the whole file.
+    pub pieces_root: Sha256Hash,
+    /// The layer of the tree in which each hash covers a piece.
+    ///
+    /// This is empty for files that are no longer than a piece, as these are
+    /// covered by the pieces root alone, and for the files of hybrid torrents
+    /// created from their info dictionary, which doesn't have piece layers.
+    pub piece_layer: Vec<Sha256Hash>,
+}
+
+/// The expected hashes of all pieces in a torrent.
+#[derive(Clone, Debug)]
+pub(crate) enum PieceHashes {
+    /// The concatenation of the 20 byte SHA-1 hash of each piece.
+    V1(Vec<u8>),
+    /// The root of each piece's merkle subtree, along with the number of
+    /// leaves of the subtree.
+    V2(Vec<(Sha256Hash, usize)>),
+}
+
+impl PieceHashes {
+    /// Returns the expected hash of the piece.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the piece index is invalid.
+    pub fn get(&self, index: PieceIndex) -> PieceHash {
+        match self {
+            Self::V1(hashes) => {
+                let pos = index * 20;
+                let mut hash = [0; 20];
+                hash.copy_from_slice(&hashes[pos..pos + 20]);
+                PieceHash::V1(hash)
+            }
+            Self::V2(hashes) => {
+                let (root, leaf_count) = hashes[index];
+                PieceHash::V2 { root, leaf_count }
+            }
+        }
+    }
+}
+
+/// The expected hash of a piece.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PieceHash {
+    /// The SHA-1 hash of the piece.
+    V1(Sha1Hash),
+    /// The root of the piece's merkle subtree, whose leaves are the SHA-256
+    /// hashes of the piece's blocks, padded with zero hashes to `leaf_count`.
+    V2 { root: Sha256Hash, leaf_count: usize },
+}
+
+impl fmt::Display for PieceHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::V1(hash) => write!(f, "{}", hex::encode(hash)),
+            Self::V2 { root, .. } => write!(f, "{}", hex::encode(root)),
+        }
+    }
+}
+
+/// Verifies the v1 file structure of the info dictionary and returns its
+/// files.
+fn parse_files(info: &raw::Info) -> Result<Vec<FileInfo>> {
+    let mut files = Vec::new();
+    if let Some(len) = info.len {
+        if info.files.is_some() {
+            log::warn!("Metainfo cannot contain both `length` and `files`");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+        if len == 0 {
+            log::warn!("File length is 0");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+
+        // the path of this file is just the torrent name
+        files.push(FileInfo {
+            path: info.name.clone().into(),
+            len,
+            torrent_offset: 0,
+        });
+    } else if let Some(raw_files) = &info.files {
+        if raw_files.is_empty() {
+            log::warn!("Metainfo files must not be empty");
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+
+        files.reserve_exact(raw_files.len());
+
+        // and sum up the file offsets in the torrent
+        let mut torrent_offset = 0;
+        for file in raw_files.iter() {
+            // verify that the file length is non-zero
+            if file.len == 0 {
+                log::warn!("File {:?} length is 0", file.path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+
+            // verify that the path is not empty
+            let path: PathBuf = file.path.iter().collect();
+            if path == PathBuf::new() {
+                log::warn!("Path in metainfo is empty");
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+
+            // verify that the path is not absolute
+            if path.is_absolute() {
+                log::warn!("Path {:?} is absolute", path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+
+            // verify that the path is not the root
+            if path == Path::new("/") {
+                log::warn!("Path {:?} is root", path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+
+            // file is now verified, we can collect it
+            files.push(FileInfo {
+                path,
+                torrent_offset,
+                len: file.len,
+            });
+
+            // advance offset for next file
+            torrent_offset += file.len;
+        }
+    } else {
+        log::warn!("No `length` or `files` key present in metainfo");
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+    Ok(files)
+}
+
+/// A file in the file tree of a v2 torrent.
+struct TreeFile {
+    path: PathBuf,
+    len: u64,
+    pieces_root: Sha256Hash,
+}
+
+/// Collects the files in the file tree of a v2 torrent, in the order in which
+/// they are encoded.
+///
+/// Each directory in the tree is a dictionary keyed by the names of its
+/// entries. A file is a dictionary with a single empty key, whose value
+/// describes the file.
+fn parse_file_tree(
+    dir: &bencode::Dict,
+    path: &mut PathBuf,
+    files: &mut Vec<TreeFile>,
+) -> Result<()> {
+    for (name, entry) in dir.iter() {
+        let entry = entry.as_dict().ok_or_else(|| {
+            log::warn!("File tree entry in {:?} is not a dictionary", path);
+            MetainfoError::InvalidMetainfo
+        })?;
+
+        if name.is_empty() {
+            // the file's path is that of the dictionary it's in, so it can't
+            // be in the root of the tree
+            if path.as_os_str().is_empty() {
+                log::warn!("Path in metainfo is empty");
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+            let len = entry
+                .get(b"length")
+                .and_then(Value::as_int)
+                .and_then(|len| u64::try_from(len).ok())
+                .ok_or_else(|| {
+                    log::warn!("File {:?} has no valid length", path);
+                    MetainfoError::InvalidMetainfo
+                })?;
+            if len == 0 {
+                log::warn!("File {:?} length is 0", path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+            let pieces_root = entry
+                .get(b"pieces root")
+                .and_then(Value::as_bytes)
+                .filter(|root| root.len() == 32)
+                .ok_or_else(|| {
+                    log::warn!("File {:?} has no valid pieces root", path);
+                    MetainfoError::InvalidMetainfo
+                })?;
+            let mut root = [0; 32];
+            root.copy_from_slice(pieces_root);
+            files.push(TreeFile {
+                path: path.clone(),
+                len,
+                pieces_root: root,
+            });
+        } else {
+            // each key is a single path component, which mustn't be able to
+            // point outside the download directory
+            let name = std::str::from_utf8(name)
+                .ok()
+                .filter(|name| {
+                    *name != "." && *name != ".." && !name.contains('/')
+                })
+                .ok_or_else(|| {
+                    log::warn!("Invalid file name in {:?}", path);
+                    MetainfoError::InvalidMetainfo
+                })?;
+            path.push(name);
+            parse_file_tree(entry, path, files)?;
+            path.pop();
+        }
+    }
+    Ok(())
+}
+
+/// Returns the files of a v2-only torrent, each of which starts at a piece
+/// boundary.
+fn align_files(tree_files: &[TreeFile], piece_len: u32) -> Vec<FileInfo> {
+    let piece_len = piece_len as u64;
+    let mut torrent_offset = 0;
+    tree_files
+        .iter()
+        .map(|file| {
+            let info = FileInfo {
+                path: file.path.clone(),
+                len: file.len,
+                torrent_offset,
+            };
+            // the next file starts at the first piece after this one
+            torrent_offset +=
+                (file.len + piece_len - 1) / piece_len * piece_len;
+            info
+        })
+        .collect()
+}
+
+/// Verifies that the v1 files of a hybrid torrent are the same as those in
+/// its file tree, apart from the padding files
+/// ([BEP 47](https://www.bittorrent.org/beps/bep_0047.html)) that only the v1
+/// files have, and returns the index of each file of the tree in the v1
+/// files.
+fn match_hybrid_files(
+    info: &raw::Info,
+    files: &[FileInfo],
+    tree_files: &[TreeFile],
+) -> Result<Vec<FileIndex>> {
+    let is_pad_file = |index: FileIndex| {
+        info.files
+            .as_ref()
+            .and_then(|files| files[index].attr.as_ref())
+            .filter(|attr| attr.contains('p'))
+            .is_some()
+    };
+
+    let mut indices = Vec::with_capacity(tree_files.len());
+    let mut tree_files = tree_files.iter();
+    for (index, file) in files.iter().enumerate() {
+        if is_pad_file(index) {
+            continue;
+        }
+        match tree_files.next() {
+            Some(tree_file)
+                if tree_file.path == file.path && tree_file.len == file.len =>
+            {
+                indices.push(index);
+            }
+            _ => {
+                log::warn!("File {:?} is not in the file tree", file.path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+        }
+    }
+    if let Some(tree_file) = tree_files.next() {
+        log::warn!("File {:?} is not in the v1 files", tree_file.path);
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+    Ok(indices)
+}
+
+/// Returns the piece layer of the file, verified against the file's pieces
+/// root.
+///
+/// Files that fit in a single piece have no piece layer. If the piece layers
+/// are not known, only hybrid torrents may have files longer than a piece,
+/// as these can be verified with their v1 piece hashes.
+fn parse_piece_layer(
+    file: &TreeFile,
+    piece_len: u32,
+    piece_layers: Option<&bencode::Dict>,
+    is_hybrid: bool,
+) -> Result<Vec<Sha256Hash>> {
+    let piece_count = (file.len + piece_len as u64 - 1) / piece_len as u64;
+    if piece_count == 1 {
+        return Ok(Vec::new());
+    }
+
+    let piece_layers = match piece_layers {
+        Some(piece_layers) => piece_layers,
+        None if is_hybrid => return Ok(Vec::new()),
+        None => {
+            log::warn!("No piece layers for file {:?}", file.path);
+            return Err(MetainfoError::MissingPieceLayers);
+        }
+    };
+    let layer = piece_layers
+        .get(&file.pieces_root)
+        .and_then(Value::as_bytes)
+        .filter(|layer| layer.len() as u64 == piece_count * 32)
+        .ok_or_else(|| {
+            log::warn!("No valid piece layer for file {:?}", file.path);
+            MetainfoError::InvalidPieceLayers
+        })?;
+    let layer: Vec<_> = layer
+        .chunks_exact(32)
+        .map(|chunk| {
+            let mut hash = [0; 32];
+            hash.copy_from_slice(chunk);
+            hash
+        })
+        .collect();
+
+    // the layer is padded with the roots of subtrees past the end of the
+    // file, up to the width of the tree
+    let root = merkle::root(
+        layer.clone(),
+        layer.len().next_power_of_two(),
+        merkle::pad_hash(merkle::piece_leaf_count(piece_len)),
+    );
+    if root != file.pieces_root {
+        log::warn!("Piece layer of file {:?} doesn't match root", file.path);
+        return Err(MetainfoError::InvalidPieceLayers);
+    }
+    Ok(layer)
+}
+
+/// A piece layer that is needed to verify the pieces of a v2-only torrent but
+/// which is not in its info dictionary, and so has to be requested from
+/// peers when the torrent is created from a magnet link.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct MissingPieceLayer {
+    /// The root of the file's merkle tree.
+    pub pieces_root: Sha256Hash,
+    /// The index of the piece layer in the tree, counting from the leaves.
+    pub layer_index: u32,
+    /// The number of pieces in the file, which is the number of hashes in
+    /// the layer without the padding at its end.
+    pub piece_count: usize,
+}
+
+/// Returns the piece layers of the files longer than a piece, if the info
+/// dictionary is that of a v2-only torrent, as only these need the piece
+/// layers that are not part of the info dictionary.
+pub(crate) fn missing_piece_layers(
+    raw_info: &[u8],
+) -> Result<Vec<MissingPieceLayer>> {
+    let info: raw::Info = serde_bencode::from_bytes(raw_info)?;
+    let info_value = bencode::decode(raw_info).map_err(|e| {
+        log::warn!("Invalid info dictionary encoding: {}", e);
+        MetainfoError::InvalidMetainfo
+    })?;
+    let info_dict =
+        info_value.as_dict().ok_or(MetainfoError::InvalidMetainfo)?;
+    let is_v2 =
+        info_dict.get(b"meta version").and_then(Value::as_int) == Some(2);
+    let is_hybrid =
+        info.len.is_some() || info.files.is_some() || !info.pieces.is_empty();
+    if !is_v2 || is_hybrid {
+        return Ok(Vec::new());
+    }
+    if info.piece_len < BLOCK_LEN || !info.piece_len.is_power_of_two() {
+        log::warn!("Invalid v2 piece length {}", info.piece_len);
+        return Err(MetainfoError::InvalidMetainfo);
+    }
+
+    let file_tree = info_dict
+        .get(b"file tree")
+        .and_then(Value::as_dict)
+        .ok_or(MetainfoError::InvalidMetainfo)?;
+    let mut tree_files = Vec::new();
+    parse_file_tree(file_tree, &mut PathBuf::new(), &mut tree_files)?;
+    let piece_len = info.piece_len as u64;
+    Ok(tree_files
+        .into_iter()
+        .map(|file| MissingPieceLayer {
+            pieces_root: file.pieces_root,
+            layer_index: merkle::piece_layer_index(info.piece_len),
+            piece_count: ((file.len + piece_len - 1) / piece_len) as usize,
+        })
+        .filter(|layer| layer.piece_count > 1)
+        .collect())
+}
+
+/// Encodes the piece layers of the files, keyed by the files' pieces roots,
+/// in the format of the metainfo's `piece layers` dictionary.
+fn encode_piece_layers(
+    piece_layers: &[(Sha256Hash, Vec<Sha256Hash>)],
+) -> Vec<u8> {
+    // the keys of bencoded dictionaries are sorted
+    let mut piece_layers: Vec<_> = piece_layers.iter().collect();
+    piece_layers.sort_by_key(|(pieces_root, _)| *pieces_root);
+
+    let mut buf = b"d".to_vec();
+    for (pieces_root, layer) in piece_layers {
+        buf.extend_from_slice(b"32:");
+        buf.extend_from_slice(pieces_root);
+        buf.extend_from_slice(format!("{}:", layer.len() * 32).as_bytes());
+        for hash in layer {
+            buf.extend_from_slice(hash);
+        }
+    }
+    buf.push(b'e');
+    buf
+}
+
+/// Parses the URLs of a tier of trackers, skipping trackers whose protocol is
+/// not supported (such as WebSocket trackers).
+fn parse_tier(urls: &[String]) -> Result<Vec<TrackerUrl>> {
+    let mut tier = Vec::with_capacity(urls.len());
+    for url in urls {
+        match TrackerUrl::from_url(Url::parse(url)?) {
+            Some(tracker) => tier.push(tracker),
+            None => log::warn!("Skipping unsupported tracker {}", url),
+        }
+    }
+    Ok(tier)
+}
+
 impl fmt::Debug for Metainfo {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Metainfo")
             .field("name", &self.name)
             .field("info_hash", &self.info_hash)
+            .field("info_hash_v2", &self.info_hash_v2)
             .field("pieces", &"<pieces...>")
             .field("piece_len", &self.piece_len)
             .field("structure", &self.files)
+            .field("is_private", &self.is_private)
             .finish()
     }
 }
@@ -278,10 +934,6 @@ mod raw {
     //! [`Metainfo`], but with semantic requirements encoded in the type
     //! system.
 
-    use sha1::{Digest, Sha1};
-
-    use super::{Result, Sha1Hash};
-
     #[derive(Debug, Deserialize)]
     pub struct Metainfo {
         pub info: Info,
@@ -291,29 +943,36 @@ mod raw {
         pub announce_list: Vec<Vec<String>>,
     }
 
-    impl Metainfo {
-        /// Creates a SHA-1 hash of the encoded `info` field's value.
-        pub fn create_info_hash(&self) -> Result<Sha1Hash> {
-            let info = serde_bencode::to_bytes(&self.info)?;
-            let digest = Sha1::digest(&info);
-            let mut info_hash = [0; 20];
-            info_hash.copy_from_slice(&digest);
-            Ok(info_hash)
-        }
+    /// The metainfo of a new torrent, with the optional fields that we write
+    /// but don't parse.
+    #[derive(Debug, Serialize)]
+    pub struct NewMetainfo<'a> {
+        pub info: &'a Info,
+        pub announce: Option<String>,
+        #[serde(rename = "announce-list")]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub announce_list: Vec<Vec<String>>,
+        #[serde(rename = "url-list")]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub url_list: Vec<String>,
+        pub comment: Option<String>,
+        #[serde(rename = "created by")]
+        pub created_by: Option<String>,
+        #[serde(rename = "creation date")]
+        pub creation_date: Option<u64>,
     }
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct Info {
         pub name: String,
-        #[serde(with = "serde_bytes")]
+        // v2-only torrents don't have v1 piece hashes
+        #[serde(default, with = "serde_bytes")]
         pub pieces: Vec<u8>,
         #[serde(rename = "piece length")]
         pub piece_len: u32,
         #[serde(rename = "length")]
         pub len: Option<u64>,
         pub files: Option<Vec<File>>,
-        /// This is not currently used but needs to be kept in here so that we
-        /// can encode back a valid info hash for hashing.
         pub private: Option<u8>,
     }
 
@@ -322,8 +981,487 @@ mod raw {
         pub path: Vec<String>,
         #[serde(rename = "length")]
         pub len: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub attr: Option<String>,
     }
 }
 
-// TODO(https://github.com/mandreyel/cratetorrent/issues/8): add metainfo
+// TODO(https://github.com/mandreyel/cratetorrent/issues/8): add more metainfo
 // parsing tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a bencoded single file metainfo with the given tracker fields.
+    fn encode_metainfo(trackers: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(b'd');
+        buf.extend_from_slice(trackers.as_bytes());
+        buf.extend_from_slice(
+            b"4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:",
+        );
+        buf.extend_from_slice(&[0; 20]);
+        buf.extend_from_slice(b"ee");
+        buf
+    }
+
+    fn urls(trackers: &[Vec<TrackerUrl>]) -> Vec<Vec<String>> {
+        trackers
+            .iter()
+            .map(|tier| tier.iter().map(|t| t.url.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn should_parse_tracker_tiers() {
+        // the announce field is ignored if the announce list is present, and
+        // unsupported trackers are skipped
+        let metainfo = Metainfo::from_bytes(&encode_metainfo(
+            "8:announce16:http://a.com/ann\
+            13:announce-list\
+            l\
+            l16:http://b.com/ann16:udp://c.com:1337e\
+            l15:wss://d.com/anne\
+            l16:http://e.com/anne\
+            e",
+        ))
+        .unwrap();
+        assert_eq!(
+            urls(&metainfo.trackers),
+            vec![
+                vec!["http://b.com/ann", "udp://c.com:1337"],
+                vec!["http://e.com/ann"],
+            ]
+        );
+        assert_eq!(metainfo.trackers[0][1].protocol, NetProtocol::UDP);
+
+        let metainfo = Metainfo::from_bytes(&encode_metainfo(
+            "8:announce16:http://a.com/ann",
+        ))
+        .unwrap();
+        assert_eq!(urls(&metainfo.trackers), vec![vec!["http://a.com/ann"]]);
+
+        let metainfo = Metainfo::from_bytes(&encode_metainfo(
+            "8:announce15:wss://a.com/ann",
+        ))
+        .unwrap();
+        assert!(metainfo.trackers.is_empty());
+    }
+
+    #[test]
+    fn should_parse_private_flag() {
+        let metainfo = Metainfo::from_bytes(&encode_metainfo("")).unwrap();
+        assert!(!metainfo.is_private);
+
+        let mut buf = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e\
+            6:pieces20:"
+            .to_vec();
+        buf.extend_from_slice(&[0; 20]);
+        buf.extend_from_slice(b"7:privatei1eee");
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert!(metainfo.is_private);
+        // the flag is part of the info dictionary, and thus of the info hash
+        assert!(metainfo.raw_info.ends_with(b"7:privatei1ee"));
+    }
+
+    /// Returns the SHA-1 hash of the buffer.
+    fn sha1(buf: &[u8]) -> Sha1Hash {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&Sha1::digest(buf));
+        hash
+    }
+
+    /// Asserts that the metainfo's info dictionary is the `info` value of the
+    /// buffer, byte for byte, and that the info hash is derived from it.
+    fn assert_raw_info_preserved(buf: &[u8], metainfo: &Metainfo) {
+        let start = buf
+            .windows(7)
+            .position(|w| w == b"4:infod")
+            .expect("no info dictionary")
+            + 6;
+        let raw_info = &buf[start..start + metainfo.raw_info.len()];
+        assert_eq!(metainfo.raw_info, raw_info);
+        assert_eq!(metainfo.info_hash, sha1(raw_info));
+
+        // the info dictionary alone, as received via the metadata exchange,
+        // results in the same torrent
+        let from_info =
+            Metainfo::from_info_bytes(&metainfo.raw_info, Vec::new()).unwrap();
+        assert_eq!(from_info.info_hash, metainfo.info_hash);
+        assert_eq!(from_info.pieces, metainfo.pieces);
+        assert_eq!(from_info.files.len(), metainfo.files.len());
+    }
+
+    /// Tests a single file torrent laid out like those created for private
+    /// trackers, whose info dictionary has a `source` key so that the same
+    /// files have a different info hash on each tracker.
+    #[test]
+    fn should_preserve_unknown_keys_of_single_file_torrent() {
+        let mut buf = b"d8:announce35:http://tracker.example.org/announce\
+            10:created by13:mktorrent 1.1\
+            13:creation datei1609459200e\
+            4:infod\
+            6:lengthi40000e\
+            6:md5sum32:0123456789abcdef0123456789abcdef\
+            4:name10:debian.iso\
+            12:piece lengthi32768e\
+            6:pieces40:"
+            .to_vec();
+        buf.extend_from_slice(&[0xab; 40]);
+        buf.extend_from_slice(b"7:privatei1e6:source3:XYZee");
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.name, "debian.iso");
+        assert_eq!(metainfo.download_len(), 40000);
+        assert!(metainfo.is_private);
+        assert!(metainfo.raw_info.ends_with(b"6:source3:XYZe"));
+        assert_raw_info_preserved(&buf, &metainfo);
+    }
+
+    /// Tests a multi file torrent laid out like those created by libtorrent
+    /// based clients, with padding files, file attributes, UTF-8 names and
+    /// keys that are not sorted.
+    #[test]
+    fn should_preserve_unknown_keys_of_multi_file_torrent() {
+        let mut buf = b"d7:comment4:test\
+            13:announce-listll30:udp://tracker.example.org:1337ee\
+            4:infod\
+            5:filesl\
+            d6:lengthi20000e4:pathl5:a.txtee\
+            d4:attr1:p6:lengthi12768e4:pathl4:.pad5:12768ee\
+            d4:attr1:x6:lengthi100e4:pathl3:bin4:b.shee\
+            e\
+            4:name6:\xc3\xa1rbol\
+            10:name.utf-86:\xc3\xa1rbol\
+            12:piece lengthi32768e\
+            12:meta versioni1e\
+            6:pieces40:"
+            .to_vec();
+        buf.extend_from_slice(&[0xcd; 40]);
+        buf.extend_from_slice(b"e8:url-listl22:http://example.org/ws/ee");
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert_eq!(metainfo.name, "\u{e1}rbol");
+        assert_eq!(metainfo.files.len(), 3);
+        assert_eq!(metainfo.files[2].path, Path::new("bin").join("b.sh"));
+        assert_eq!(metainfo.trackers.len(), 1);
+        assert_raw_info_preserved(&buf, &metainfo);
+
+        // re-encoding the parsed dictionary would have lost the unknown keys
+        // and thus changed the info hash
+        let info: raw::Info = serde_bencode::from_bytes(&metainfo.raw_info)
+            .unwrap();
+        let reencoded = serde_bencode::to_bytes(&info).unwrap();
+        assert_ne!(sha1(&reencoded), metainfo.info_hash);
+    }
+
+    /// Parses the torrent file in the test fixtures directory, and asserts
+    /// that its info hash is the given one, which was computed with other
+    /// tools over the file's `info` value, and that the info dictionary is
+    /// preserved.
+    fn parse_fixture(buf: &[u8], info_hash: &str) -> Metainfo {
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        assert_eq!(hex::encode(metainfo.info_hash), info_hash);
+        assert_raw_info_preserved(buf, &metainfo);
+        metainfo
+    }
+
+    /// Tests a torrent created by mktorrent for a private tracker, whose
+    /// info dictionary has the `source` key.
+    #[test]
+    fn should_parse_mktorrent_fixture() {
+        let metainfo = parse_fixture(
+            include_bytes!(
+                "../tests/fixtures/mktorrent_private_source.torrent"
+            ),
+            "8cb9191530c09be1018fe88b6bb035c5b83d7eb7",
+        );
+        assert_eq!(metainfo.name, "ubuntu-20.04-server.iso");
+        assert_eq!(metainfo.download_len(), 100000);
+        assert_eq!(metainfo.piece_count(), 4);
+        assert!(metainfo.is_private);
+    }
+
+    /// Tests a torrent created by BitComet, whose info dictionary has the
+    /// files' `md5sum` and the `name.utf-8` and `path.utf-8` keys.
+    #[test]
+    fn should_parse_bitcomet_fixture() {
+        let metainfo = parse_fixture(
+            include_bytes!("../tests/fixtures/bitcomet_md5sum_utf8.torrent"),
+            "fdc4bfe7d5c1f269f1a4c25ee4174c50662a9fc8",
+        );
+        assert_eq!(metainfo.name, "Bosque");
+        assert_eq!(metainfo.files.len(), 2);
+        assert_eq!(metainfo.files[0].path, Path::new("\u{e1}rbol.txt"));
+        assert_eq!(
+            metainfo.files[1].path,
+            Path::new("docs").join("le\u{e9}me.txt")
+        );
+        assert_eq!(metainfo.download_len(), 35000);
+    }
+
+    /// Tests a torrent created by a libtorrent based client, with padding
+    /// files, file attributes and a web seed.
+    #[test]
+    fn should_parse_libtorrent_fixture() {
+        let metainfo = parse_fixture(
+            include_bytes!("../tests/fixtures/libtorrent_padding_attr.torrent"),
+            "ed3e8df7278e8d5ecb57283f2c34be680b69ffa5",
+        );
+        assert_eq!(metainfo.files.len(), 3);
+        assert_eq!(metainfo.files[2].path, Path::new("bin").join("run.sh"));
+        assert_eq!(metainfo.files[2].torrent_offset, 65536);
+        assert_eq!(metainfo.trackers.len(), 2);
+    }
+
+    /// Tests a hybrid torrent created by libtorrent, whose info dictionary has
+    /// both the v1 and v2 keys.
+    #[test]
+    fn should_parse_hybrid_fixture() {
+        let metainfo = parse_fixture(
+            include_bytes!("../tests/fixtures/libtorrent_hybrid.torrent"),
+            "4ceb8d935a499159f35c2bcf171c644e83d9889d",
+        );
+        assert!(metainfo.is_hybrid());
+        assert_eq!(
+            hex::encode(metainfo.info_hash_v2.unwrap()),
+            "0470d07046f6e649a98c7d4047f37c0e6346fc8c2ff067708b305b6adfd185b0"
+        );
+        assert_eq!(metainfo.piece_count(), 5);
+        assert_eq!(metainfo.merkle_trees.len(), 2);
+        assert_eq!(metainfo.merkle_trees[0].piece_layer.len(), 4);
+    }
+
+    #[test]
+    fn should_round_trip_created_torrent() {
+        let dir = std::env::temp_dir().join(format!(
+            "cratetorrent-metainfo-test-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        std::fs::write(dir.join("files").join("a"), vec![1; 50000]).unwrap();
+        std::fs::write(dir.join("files").join("b"), vec![2; 3]).unwrap();
+
+        let torrent = MetainfoBuilder::new(dir.join("files"))
+            .comment("round trip")
+            .build()
+            .unwrap();
+        assert_raw_info_preserved(&torrent.bytes, &torrent.metainfo);
+        let metainfo = Metainfo::from_bytes(&torrent.bytes).unwrap();
+        assert_eq!(metainfo.info_hash, torrent.metainfo.info_hash);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Returns the contents of the files in the v2 test torrents: a file
+    /// spanning 3 pieces and one that fits in a single piece.
+    fn v2_file_contents() -> (Vec<u8>, Vec<u8>) {
+        (vec![1; 2 * BLOCK_LEN as usize + 100], vec![2; 100])
+    }
+
+    /// Returns the file tree of the v2 test torrents, whose piece length is a
+    /// single block, and the piece layer of the first file.
+    fn encode_v2_file_tree() -> (Vec<u8>, Vec<Sha256Hash>) {
+        let (a, b) = v2_file_contents();
+        let layer: Vec<_> =
+            a.chunks(BLOCK_LEN as usize).map(merkle::hash).collect();
+        let root_a = merkle::root(layer.clone(), 4, [0; 32]);
+        let root_b = merkle::piece_root(std::iter::once(&b), 1);
+
+        let mut buf =
+            b"9:file treed1:ad0:d6:lengthi32868e11:pieces root32:".to_vec();
+        buf.extend_from_slice(&root_a);
+        buf.extend_from_slice(b"ee1:bd0:d6:lengthi100e11:pieces root32:");
+        buf.extend_from_slice(&root_b);
+        buf.extend_from_slice(b"eee");
+        (buf, layer)
+    }
+
+    /// Returns the bencoded piece layers of the v2 test torrents, along with
+    /// their key in the metainfo.
+    fn encode_piece_layers(root: &Sha256Hash, layer: &[Sha256Hash]) -> Vec<u8> {
+        let mut buf = b"12:piece layers".to_vec();
+        buf.extend_from_slice(&super::encode_piece_layers(&[(
+            *root,
+            layer.to_vec(),
+        )]));
+        buf
+    }
+
+    #[test]
+    fn should_parse_v2_torrent() {
+        let (file_tree, layer) = encode_v2_file_tree();
+        let mut info = b"d".to_vec();
+        info.extend_from_slice(&file_tree);
+        info.extend_from_slice(
+            b"12:meta versioni2e4:name1:t12:piece lengthi16384ee",
+        );
+        let root_a = merkle::root(layer.clone(), 4, [0; 32]);
+        let mut buf = b"d4:info".to_vec();
+        buf.extend_from_slice(&info);
+        buf.extend_from_slice(&encode_piece_layers(&root_a, &layer));
+        buf.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert!(metainfo.is_v2());
+        assert!(!metainfo.is_hybrid());
+        assert!(metainfo.is_piece_aligned());
+        assert!(metainfo.pieces.is_empty());
+        let info_hash_v2 = merkle::hash(&info);
+        assert_eq!(metainfo.raw_info, info);
+        assert_eq!(metainfo.info_hash_v2, Some(info_hash_v2));
+        assert_eq!(metainfo.info_hash[..], info_hash_v2[..20]);
+        assert_eq!(metainfo.hybrid_info_hash(), None);
+
+        // each file starts at a piece boundary
+        assert_eq!(metainfo.files.len(), 2);
+        assert_eq!(metainfo.files[0].path, Path::new("a"));
+        assert_eq!(metainfo.files[0].torrent_offset, 0);
+        assert_eq!(metainfo.files[1].path, Path::new("b"));
+        assert_eq!(metainfo.files[1].torrent_offset, 3 * BLOCK_LEN as u64);
+        assert_eq!(metainfo.piece_count(), 4);
+
+        assert_eq!(metainfo.merkle_trees.len(), 2);
+        assert_eq!(metainfo.merkle_trees[0].pieces_root, root_a);
+        assert_eq!(metainfo.merkle_trees[0].piece_layer, layer);
+        assert!(metainfo.merkle_trees[1].piece_layer.is_empty());
+
+        let hashes = metainfo.piece_hashes();
+        assert_eq!(
+            hashes.get(2),
+            PieceHash::V2 {
+                root: layer[2],
+                leaf_count: 1
+            }
+        );
+        assert_eq!(
+            hashes.get(3),
+            PieceHash::V2 {
+                root: metainfo.merkle_trees[1].pieces_root,
+                leaf_count: 1
+            }
+        );
+
+        // without the piece layers the pieces of the first file can't be
+        // verified, so they have to be requested from peers
+        assert!(matches!(
+            Metainfo::from_info_bytes(&info, Vec::new()),
+            Err(MetainfoError::MissingPieceLayers)
+        ));
+        assert_eq!(
+            missing_piece_layers(&info).unwrap(),
+            vec![MissingPieceLayer {
+                pieces_root: root_a,
+                layer_index: 0,
+                piece_count: 3,
+            }]
+        );
+        let from_info = Metainfo::from_info_and_piece_layers(
+            &info,
+            &[(root_a, layer.clone())],
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(from_info.merkle_trees, metainfo.merkle_trees);
+
+        // piece layers that don't match the root are rejected
+        let mut corrupt_layer = layer;
+        corrupt_layer[0][0] ^= 1;
+        assert!(matches!(
+            Metainfo::from_info_and_piece_layers(
+                &info,
+                &[(root_a, corrupt_layer)],
+                Vec::new(),
+            ),
+            Err(MetainfoError::InvalidPieceLayers)
+        ));
+    }
+
+    #[test]
+    fn should_reject_invalid_piece_layers() {
+        let (file_tree, mut layer) = encode_v2_file_tree();
+        let mut buf = b"d4:infod".to_vec();
+        buf.extend_from_slice(&file_tree);
+        buf.extend_from_slice(
+            b"12:meta versioni2e4:name1:t12:piece lengthi16384ee",
+        );
+        let root_a = merkle::root(layer.clone(), 4, [0; 32]);
+        layer[1][0] ^= 1;
+        buf.extend_from_slice(&encode_piece_layers(&root_a, &layer));
+        buf.push(b'e');
+        assert!(matches!(
+            Metainfo::from_bytes(&buf),
+            Err(MetainfoError::InvalidPieceLayers)
+        ));
+    }
+
+    #[test]
+    fn should_parse_hybrid_torrent() {
+        let (file_tree, layer) = encode_v2_file_tree();
+        // the v1 files are aligned with a padding file
+        let mut info = b"d5:filesl\
+            d6:lengthi32868e4:pathl1:aee\
+            d4:attr1:p6:lengthi16284e4:pathl4:.pad5:16284ee\
+            d6:lengthi100e4:pathl1:bee\
+            e"
+        .to_vec();
+        info.extend_from_slice(&file_tree);
+        info.extend_from_slice(
+            b"12:meta versioni2e4:name1:t12:piece lengthi16384e6:pieces80:",
+        );
+        info.extend_from_slice(&[0xab; 80]);
+        info.push(b'e');
+        let root_a = merkle::root(layer.clone(), 4, [0; 32]);
+        let mut buf = b"d4:info".to_vec();
+        buf.extend_from_slice(&info);
+        buf.extend_from_slice(&encode_piece_layers(&root_a, &layer));
+        buf.push(b'e');
+
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+        assert!(metainfo.is_hybrid());
+        assert!(!metainfo.is_piece_aligned());
+        // the torrent is identified by its v1 info hash in the v1 swarm and
+        // by its truncated v2 info hash in the v2 swarm
+        assert_eq!(metainfo.info_hash, sha1(&info));
+        let info_hash_v2 = merkle::hash(&info);
+        assert_eq!(metainfo.info_hash_v2, Some(info_hash_v2));
+        assert_eq!(
+            metainfo.hybrid_info_hash().unwrap()[..],
+            info_hash_v2[..20]
+        );
+
+        // the padding file is part of the v1 files only
+        assert_eq!(metainfo.files.len(), 3);
+        assert_eq!(metainfo.piece_count(), 4);
+        assert_eq!(metainfo.merkle_trees.len(), 2);
+        assert_eq!(metainfo.merkle_trees[0].file_index, 0);
+        assert_eq!(metainfo.merkle_trees[0].piece_layer, layer);
+        assert_eq!(metainfo.merkle_trees[1].file_index, 2);
+        assert_eq!(metainfo.piece_hashes().get(3), PieceHash::V1([0xab; 20]));
+
+        // hybrid torrents can be verified with their v1 hashes alone
+        assert!(missing_piece_layers(&info).unwrap().is_empty());
+        let from_info = Metainfo::from_info_bytes(&info, Vec::new()).unwrap();
+        assert_eq!(from_info.info_hash, metainfo.info_hash);
+        assert!(from_info.merkle_trees[0].piece_layer.is_empty());
+
+        // the v1 files must match the file tree
+        let mut mismatched = info.clone();
+        let pos = mismatched
+            .windows(11)
+            .position(|w| w == b"lengthi100e")
+            .unwrap();
+        mismatched[pos + 9] = b'1';
+        assert!(Metainfo::from_info_bytes(&mismatched, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn should_reject_metainfo_without_info() {
+        assert!(Metainfo::from_bytes(b"d8:announce3:urle").is_err());
+        assert!(Metainfo::from_bytes(b"d4:infoi1ee").is_err());
+        // trailing bytes after the metainfo
+        let mut buf = encode_metainfo("");
+        buf.push(b'x');
+        assert!(Metainfo::from_bytes(&buf).is_err());
+    }
+}
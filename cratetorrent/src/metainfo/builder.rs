@@ -0,0 +1,573 @@
+//! This module contains the builder with which new torrents are created from
+//! files on disk.
+//!
+//! The builder walks the file or directory, hashes its pieces on multiple
+//! threads, and encodes the resulting metainfo, which can be saved as
+//! a `.torrent` file and used to seed the files right away.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::Url;
+use sha1::{Digest, Sha1};
+
+use super::{raw, Metainfo, MetainfoError, Result};
+use crate::BLOCK_LEN;
+
+/// The piece length is picked so that the torrent has about this many pieces,
+/// which keeps the metainfo small while still letting peers share pieces
+/// early on.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+/// The smallest piece length that is picked automatically, which is also the
+/// smallest valid one, as a piece has to consist of whole blocks.
+const MIN_PIECE_LEN: u32 = BLOCK_LEN;
+
+/// The largest piece length that is picked automatically.
+const MAX_PIECE_LEN: u32 = 16 * 1024 * 1024;
+
+/// The newly created torrent.
+#[derive(Clone, Debug)]
+pub struct NewTorrent {
+    /// The bencoded metainfo, which is the content of the `.torrent` file.
+    pub bytes: Vec<u8>,
+    /// The metainfo parsed from the bytes.
+    pub metainfo: Metainfo,
+}
+
+/// Creates the metainfo of a new torrent from a file or directory.
+///
+/// The files are seeded from the parent directory of the path, so to seed the
+/// new torrent, the engine's download directory has to be the parent of the
+/// path passed to the builder, and the torrent has to be added in
+/// [`Mode::Seed`](crate::engine::Mode::Seed).
+///
+/// # Example
+///
+/// ```no_run
+/// use cratetorrent::metainfo::MetainfoBuilder;
+///
+/// let torrent = MetainfoBuilder::new("/tmp/artifacts")
+///     .tracker_tier(vec!["http://tracker.example.com/announce".parse()?])
+///     .comment("Nightly build")
+///     .build()?;
+/// std::fs::write("/tmp/artifacts.torrent", &torrent.bytes)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct MetainfoBuilder {
+    path: PathBuf,
+    piece_len: Option<u32>,
+    trackers: Vec<Vec<Url>>,
+    web_seeds: Vec<Url>,
+    is_private: bool,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<SystemTime>,
+    thread_count: usize,
+}
+
+impl MetainfoBuilder {
+    /// Creates a builder for a torrent of the file or the directory at the
+    /// path, which gives the torrent its name.
+    ///
+    /// By default, the piece length is picked automatically, the torrent
+    /// has no trackers and is not private, and the creator and creation date
+    /// are set to cratetorrent and the current time. The pieces are hashed on
+    /// as many threads as there are CPUs.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_len: None,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            is_private: false,
+            comment: None,
+            created_by: Some(format!(
+                "cratetorrent/{}",
+                env!("CARGO_PKG_VERSION")
+            )),
+            creation_date: Some(SystemTime::now()),
+            thread_count: num_cpus::get(),
+        }
+    }
+
+    /// Sets the piece length, which has to be a power of two and at least
+    /// 16 KiB.
+    pub fn piece_len(mut self, piece_len: u32) -> Self {
+        self.piece_len = Some(piece_len);
+        self
+    }
+
+    /// Adds a tier of trackers, after the tiers added before it. The trackers
+    /// of a tier are tried in order, as described in
+    /// [BEP 12](https://www.bittorrent.org/beps/bep_0012.html).
+    pub fn tracker_tier(mut self, tier: Vec<Url>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    /// Adds a web seed, from which clients may download the torrent over
+    /// HTTP, as described in
+    /// [BEP 19](https://www.bittorrent.org/beps/bep_0019.html).
+    pub fn web_seed(mut self, url: Url) -> Self {
+        self.web_seeds.push(url);
+        self
+    }
+
+    /// Sets whether the torrent is private, in which case clients only get
+    /// peers from its trackers.
+    pub fn private(mut self, is_private: bool) -> Self {
+        self.is_private = is_private;
+        self
+    }
+
+    /// Sets the free-form comment of the torrent.
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    /// Sets the name of the program that created the torrent, or omits it if
+    /// `None`.
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    /// Sets the creation date of the torrent, or omits it if `None`, such as
+    /// when the same files should always produce the same metainfo.
+    pub fn creation_date(mut self, creation_date: Option<SystemTime>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    /// Sets the number of threads on which the pieces are hashed.
+    pub fn thread_count(mut self, thread_count: usize) -> Self {
+        self.thread_count = thread_count.max(1);
+        self
+    }
+
+    /// Reads and hashes the files, and returns the encoded and the parsed
+    /// metainfo of the torrent.
+    ///
+    /// Empty files are left out, as a torrent can't contain them. This
+    /// fails if there are no other files, if the piece length is invalid, or
+    /// if the files can't be read.
+    pub fn build(self) -> Result<NewTorrent> {
+        let name = match self.path.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_string(),
+            None => {
+                log::warn!("Torrent path {:?} has no valid name", self.path);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+        };
+
+        let is_dir = fs::metadata(&self.path)?.is_dir();
+        let files = if is_dir {
+            let mut files = Vec::new();
+            walk_dir(&self.path, &mut Vec::new(), &mut files)?;
+            files
+        } else {
+            let len = fs::metadata(&self.path)?.len();
+            vec![SourceFile {
+                path: self.path.clone(),
+                components: Vec::new(),
+                len,
+            }]
+        };
+        let files: Vec<_> = files.into_iter().filter(|f| f.len > 0).collect();
+        if files.is_empty() {
+            log::warn!("No non-empty files in {:?}", self.path);
+            return Err(MetainfoError::InvalidMetainfo);
+        }
+
+        let total_len = files.iter().map(|f| f.len).sum();
+        let piece_len = match self.piece_len {
+            Some(piece_len) => {
+                if piece_len < MIN_PIECE_LEN || !piece_len.is_power_of_two() {
+                    log::warn!("Invalid piece length {}", piece_len);
+                    return Err(MetainfoError::InvalidMetainfo);
+                }
+                piece_len
+            }
+            None => auto_piece_len(total_len),
+        };
+
+        let raw_files = if is_dir {
+            Some(
+                files
+                    .iter()
+                    .map(|f| raw::File {
+                        path: f.components.clone(),
+                        len: f.len,
+                        attr: None,
+                    })
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let files = Arc::new(files);
+        let pieces =
+            hash_pieces(&files, total_len, piece_len, self.thread_count)?;
+
+        let info = raw::Info {
+            name,
+            pieces,
+            piece_len,
+            len: if is_dir { None } else { Some(total_len) },
+            files: raw_files,
+            private: if self.is_private { Some(1) } else { None },
+        };
+        let torrent = raw::NewMetainfo {
+            info: &info,
+            // older clients only read the first tracker
+            announce: self
+                .trackers
+                .first()
+                .and_then(|tier| tier.first())
+                .map(|url| url.to_string()),
+            announce_list: self
+                .trackers
+                .iter()
+                .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+                .collect(),
+            url_list: self
+                .web_seeds
+                .iter()
+                .map(|url| url.to_string())
+                .collect(),
+            comment: self.comment,
+            created_by: self.created_by,
+            creation_date: self.creation_date.map(|date| {
+                date.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            }),
+        };
+        let bytes = serde_bencode::to_bytes(&torrent)?;
+        let metainfo = Metainfo::from_bytes(&bytes)?;
+
+        Ok(NewTorrent { bytes, metainfo })
+    }
+}
+
+/// A file of the new torrent.
+struct SourceFile {
+    /// The path from which the file is read.
+    path: PathBuf,
+    /// The components of the file's path within the torrent, which are empty
+    /// in a single file torrent.
+    components: Vec<String>,
+    len: u64,
+}
+
+/// Collects the files in the directory and its subdirectories, in order of
+/// their paths so that the same directory always results in the same
+/// torrent.
+fn walk_dir(
+    dir: &Path,
+    components: &mut Vec<String>,
+    files: &mut Vec<SourceFile>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => {
+                log::warn!("File name {:?} is not valid UTF-8", name);
+                return Err(MetainfoError::InvalidMetainfo);
+            }
+        };
+        let path = entry.path();
+        // symlinks are followed
+        let metadata = fs::metadata(&path)?;
+        components.push(name);
+        if metadata.is_dir() {
+            walk_dir(&path, components, files)?;
+        } else {
+            files.push(SourceFile {
+                path,
+                components: components.clone(),
+                len: metadata.len(),
+            });
+        }
+        components.pop();
+    }
+    Ok(())
+}
+
+/// Returns the piece length with which the torrent has about
+/// `TARGET_PIECE_COUNT` pieces.
+fn auto_piece_len(total_len: u64) -> u32 {
+    let piece_len = (total_len / TARGET_PIECE_COUNT)
+        .max(1)
+        .next_power_of_two()
+        .min(MAX_PIECE_LEN as u64) as u32;
+    piece_len.max(MIN_PIECE_LEN)
+}
+
+/// Hashes the pieces of the files, returning the concatenation of their
+/// hashes.
+///
+/// The pieces are split into as many contiguous ranges as there are threads,
+/// each of which is hashed on its own thread. A piece may span multiple
+/// files.
+fn hash_pieces(
+    files: &Arc<Vec<SourceFile>>,
+    total_len: u64,
+    piece_len: u32,
+    thread_count: usize,
+) -> Result<Vec<u8>> {
+    let piece_len = piece_len as u64;
+    let piece_count = (total_len + piece_len - 1) / piece_len;
+    let thread_count = (thread_count as u64).min(piece_count).max(1);
+    let pieces_per_thread = (piece_count + thread_count - 1) / thread_count;
+
+    let threads: Vec<_> = (0..thread_count)
+        .map(|i| {
+            let files = Arc::clone(files);
+            let start = i * pieces_per_thread;
+            let end = ((i + 1) * pieces_per_thread).min(piece_count);
+            thread::spawn(move || -> io::Result<Vec<u8>> {
+                let mut reader = TorrentReader::new(&files);
+                let mut buf = vec![0; piece_len as usize];
+                let mut hashes =
+                    Vec::with_capacity((end - start) as usize * 20);
+                for index in start..end {
+                    let offset = index * piece_len;
+                    let len = piece_len.min(total_len - offset) as usize;
+                    reader.read_exact_at(offset, &mut buf[..len])?;
+                    hashes.extend_from_slice(&Sha1::digest(&buf[..len]));
+                }
+                Ok(hashes)
+            })
+        })
+        .collect();
+
+    let mut pieces = Vec::with_capacity(piece_count as usize * 20);
+    for thread in threads {
+        let hashes = thread.join().expect("hashing thread panicked")?;
+        pieces.extend_from_slice(&hashes);
+    }
+    Ok(pieces)
+}
+
+/// Reads the files of the torrent as if they were a single contiguous byte
+/// array, keeping the last read file open.
+struct TorrentReader<'a> {
+    files: &'a [SourceFile],
+    /// The index of the open file and the file itself.
+    open_file: Option<(usize, File)>,
+}
+
+impl<'a> TorrentReader<'a> {
+    fn new(files: &'a [SourceFile]) -> Self {
+        Self {
+            files,
+            open_file: None,
+        }
+    }
+
+    /// Fills the buffer with the bytes of the torrent starting at the offset.
+    fn read_exact_at(
+        &mut self,
+        mut offset: u64,
+        mut buf: &mut [u8],
+    ) -> io::Result<()> {
+        // find the file containing the first byte
+        let mut file_offset = 0;
+        let mut index = 0;
+        while file_offset + self.files[index].len <= offset {
+            file_offset += self.files[index].len;
+            index += 1;
+        }
+
+        while !buf.is_empty() {
+            let file = &self.files[index];
+            let pos = offset - file_offset;
+            let len = ((file.len - pos) as usize).min(buf.len());
+            let (chunk, rest) = buf.split_at_mut(len);
+
+            let reopen = match &self.open_file {
+                Some((open_index, _)) => *open_index != index,
+                None => true,
+            };
+            if reopen {
+                self.open_file = Some((index, File::open(&file.path)?));
+            }
+            if let Some((_, f)) = &mut self.open_file {
+                f.seek(SeekFrom::Start(pos))?;
+                f.read_exact(chunk)?;
+            }
+
+            buf = rest;
+            offset += len as u64;
+            file_offset += file.len;
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a new empty directory for a test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "cratetorrent-builder-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(seed)).collect()
+    }
+
+    #[test]
+    fn should_pick_piece_len() {
+        assert_eq!(auto_piece_len(1), MIN_PIECE_LEN);
+        assert_eq!(auto_piece_len(100 * 1024 * 1024), 128 * 1024);
+        assert_eq!(auto_piece_len(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(auto_piece_len(u64::MAX), MAX_PIECE_LEN);
+    }
+
+    #[test]
+    fn should_build_single_file_torrent() {
+        let dir = test_dir("single");
+        let path = dir.join("file.bin");
+        let content = data(3 * BLOCK_LEN as usize + 100, 7);
+        fs::write(&path, &content).unwrap();
+
+        let tracker: Url =
+            "http://tracker.example.com/announce".parse().unwrap();
+        let torrent = MetainfoBuilder::new(&path)
+            .piece_len(2 * BLOCK_LEN)
+            .tracker_tier(vec![tracker])
+            .private(true)
+            .comment("test")
+            .creation_date(None)
+            .build()
+            .unwrap();
+
+        let metainfo = &torrent.metainfo;
+        assert_eq!(metainfo.name, "file.bin");
+        assert!(!metainfo.is_archive());
+        assert!(metainfo.is_private);
+        assert_eq!(metainfo.download_len(), content.len() as u64);
+        assert_eq!(metainfo.piece_count(), 2);
+        let piece_len = 2 * BLOCK_LEN as usize;
+        assert_eq!(
+            &metainfo.pieces[..20],
+            &Sha1::digest(&content[..piece_len])[..]
+        );
+        assert_eq!(
+            &metainfo.pieces[20..],
+            &Sha1::digest(&content[piece_len..])[..]
+        );
+        assert_eq!(
+            metainfo.trackers[0][0].url.as_str(),
+            "http://tracker.example.com/announce"
+        );
+
+        let bytes = &torrent.bytes;
+        let contains = |s: &[u8]| bytes.windows(s.len()).any(|w| w == s);
+        assert!(contains(b"7:comment4:test"));
+        assert!(contains(b"10:created by"));
+        assert!(!contains(b"creation date"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_hash_pieces_across_files_in_parallel() {
+        let dir = test_dir("archive");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let a = data(BLOCK_LEN as usize + 10, 3);
+        let b = data(BLOCK_LEN as usize * 3, 5);
+        let c = data(77, 11);
+        fs::write(root.join("b"), &b).unwrap();
+        fs::write(root.join("a"), &a).unwrap();
+        fs::write(root.join("sub").join("c"), &c).unwrap();
+        // empty files can't be part of a torrent
+        fs::write(root.join("empty"), b"").unwrap();
+
+        let web_seed: Url = "http://example.com/files/".parse().unwrap();
+        let single_threaded = MetainfoBuilder::new(&root)
+            .piece_len(BLOCK_LEN)
+            .web_seed(web_seed)
+            .creation_date(None)
+            .thread_count(1)
+            .build()
+            .unwrap();
+        let multi_threaded = MetainfoBuilder::new(&root)
+            .piece_len(BLOCK_LEN)
+            .web_seed("http://example.com/files/".parse().unwrap())
+            .creation_date(None)
+            .thread_count(3)
+            .build()
+            .unwrap();
+        assert_eq!(single_threaded.bytes, multi_threaded.bytes);
+
+        let metainfo = multi_threaded.metainfo;
+        assert_eq!(metainfo.name, "root");
+        let paths: Vec<_> =
+            metainfo.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from("a"),
+                PathBuf::from("b"),
+                Path::new("sub").join("c"),
+            ]
+        );
+
+        let mut content = a;
+        content.extend_from_slice(&b);
+        content.extend_from_slice(&c);
+        let expected: Vec<u8> = content
+            .chunks(BLOCK_LEN as usize)
+            .flat_map(|piece| Sha1::digest(piece).to_vec())
+            .collect();
+        assert_eq!(metainfo.pieces, expected);
+        assert!(multi_threaded.bytes.windows(8).any(|w| w == b"url-list"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn should_reject_invalid_input() {
+        let dir = test_dir("invalid");
+        assert!(matches!(
+            MetainfoBuilder::new(&dir).build(),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+        fs::write(dir.join("file"), b"data").unwrap();
+        assert!(matches!(
+            MetainfoBuilder::new(&dir).piece_len(1000).build(),
+            Err(MetainfoError::InvalidMetainfo)
+        ));
+        assert!(matches!(
+            MetainfoBuilder::new(dir.join("missing")).build(),
+            Err(MetainfoError::Io(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+//! This module contains the IPv6 related networking helpers shared by the
+//! engine, the torrents and the trackers, as described in
+//! [BEP 7](http://bittorrent.org/beps/bep_0007.html).
+//!
+//! Listeners bound to the unspecified IPv6 address are dual-stack: they also
+//! accept IPv4 connections, whose addresses are IPv4-mapped IPv6 addresses
+//! (`::ffff:a.b.c.d`). So that a peer is known by the same address however it
+//! reaches us, such addresses are converted to plain IPv4 addresses wherever
+//! peer addresses enter the engine.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The backlog of pending connections of the listeners we bind.
+const LISTEN_BACKLOG: i32 = 128;
+
+/// Returns the address with an IPv4-mapped IPv6 address replaced by the IPv4
+/// address it maps, and any other address unchanged.
+pub(crate) fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(canonical_ip(addr.ip()), addr.port())
+}
+
+/// Returns the IPv4 address that the IPv6 address maps, if it's an
+/// IPv4-mapped address, or the address unchanged otherwise.
+pub(crate) fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                Ipv4Addr::new(a, b, c, d).into()
+            }
+            _ => ip.into(),
+        },
+        ip => ip,
+    }
+}
+
+/// Binds a TCP listener on the address.
+///
+/// If the address is the unspecified IPv6 address, the listener is
+/// dual-stack, regardless of the OS's default. If the host doesn't support
+/// IPv6, the listener falls back to the unspecified IPv4 address, on the same
+/// port.
+pub(crate) fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    match addr.ip() {
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            match bind_dual_stack_listener(addr) {
+                Ok(listener) => Ok(listener),
+                Err(e) => {
+                    log::warn!(
+                        "Cannot listen on {} ({}), falling back to IPv4",
+                        addr,
+                        e
+                    );
+                    TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port()))
+                }
+            }
+        }
+        _ => TcpListener::bind(addr),
+    }
+}
+
+/// Binds a TCP listener on the IPv6 address that accepts IPv4 connections
+/// too.
+fn bind_dual_stack_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket =
+        Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_only_v6(false)?;
+    // like the standard library's listeners, allow rebinding the port while
+    // the connections of a previous listener linger
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into_tcp_listener())
+}
+
+/// Returns the global IPv6 address of the host, if it has one.
+///
+/// This is announced to trackers, so that they can give our IPv6 address to
+/// IPv6 peers even when we announce over IPv4.
+pub(crate) fn global_ipv6() -> Option<Ipv6Addr> {
+    // connecting a UDP socket doesn't send anything, but makes the OS choose
+    // the local address through which it would reach the given global
+    // address (a public DNS server)
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect((
+            Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888),
+            53,
+        ))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        // only global unicast addresses (2000::/3) are reachable by peers
+        IpAddr::V6(ip) if ip.segments()[0] & 0xe000 == 0x2000 => Some(ip),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    #[test]
+    fn should_convert_ipv4_mapped_addresses() {
+        let mapped: SocketAddr = "[::ffff:192.168.0.10]:6881".parse().unwrap();
+        assert_eq!(
+            canonical_addr(mapped),
+            "192.168.0.10:6881".parse().unwrap()
+        );
+
+        // other addresses are left as is
+        for addr in &["192.168.0.10:6881", "[::1]:6881", "[::192.168.0.10]:1"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(canonical_addr(addr), addr);
+        }
+    }
+
+    /// Tests that a listener on the unspecified IPv6 address accepts both IPv4
+    /// and IPv6 connections.
+    #[test]
+    fn should_listen_on_both_ipv4_and_ipv6() {
+        let listener =
+            bind_tcp_listener((Ipv6Addr::UNSPECIFIED, 0).into()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(listener.local_addr().unwrap().is_ipv6());
+
+        for ip in &[
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            Ipv6Addr::LOCALHOST.into(),
+        ] {
+            let stream = TcpStream::connect((*ip, port)).unwrap();
+            let (_, addr) = listener.accept().unwrap();
+            let addr = canonical_addr(addr);
+            assert_eq!(addr, stream.local_addr().unwrap());
+            assert_eq!(addr.ip(), *ip);
+        }
+    }
+
+    #[test]
+    fn should_listen_on_ipv4_only() {
+        let listener =
+            bind_tcp_listener((Ipv4Addr::LOCALHOST, 0).into()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(TcpStream::connect((Ipv6Addr::LOCALHOST, port)).is_err());
+    }
+}
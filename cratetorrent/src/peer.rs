@@ -9,16 +9,17 @@
 //! one, due to making use of shared data in torrent.
 
 use std::{
-    collections::HashSet,
-    net::SocketAddr,
+    collections::{HashSet, VecDeque},
+    convert::TryFrom,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use futures::{
-    select,
+    future, select,
     stream::{Fuse, SplitSink},
-    SinkExt, StreamExt,
+    FutureExt, SinkExt, Stream, StreamExt,
 };
 use tokio::{
     net::TcpStream,
@@ -32,21 +33,37 @@ use tokio_util::codec::{Framed, FramedParts};
 
 use crate::{
     alert::Alert,
+    conf::EncryptionPolicy,
     counter::ThruputCounters,
     disk,
     download::{BlockStatus, PieceDownload},
     error::Error,
+    merkle,
+    rate_limiter::{self, RateLimits},
     torrent::{self, TorrentContext},
-    Bitfield, Block, BlockInfo, PeerId, PieceIndex,
+    Bitfield, Block, BlockInfo, PeerId, PieceIndex, Sha1Hash,
 };
 use codec::*;
 use error::*;
+use extension::*;
+use fast::{allowed_fast_set, ALLOWED_FAST_SET_LEN};
+use mse::MseStream;
+use pex::PexState;
 use state::*;
 
+pub(crate) use metadata::{Metadata, MetadataSession};
+pub(crate) use pex::{
+    PexPeer, PEX_FLAG_CONNECTABLE, PEX_FLAG_SEED, PEX_INTERVAL, UT_PEX,
+};
 pub use state::{ConnectionState, SessionState};
 
 mod codec;
 pub mod error;
+mod extension;
+mod fast;
+mod metadata;
+mod mse;
+mod pex;
 mod state;
 
 /// The most essential information of a peer session that is sent to torrent
@@ -76,10 +93,82 @@ pub(crate) enum Command {
         /// Tell the session to enter endgame mode.
         in_endgame: bool,
     },
+    /// Tells the session to choke the peer, as decided by the torrent's
+    /// choker.
+    Choke,
+    /// Tells the session to unchoke the peer, as decided by the torrent's
+    /// choker.
+    Unchoke,
+    /// Tells the session to re-evaluate whether we're interested in the
+    /// peer, as the pieces we want have changed.
+    UpdateInterest,
+    /// The torrent's peers that can be advertised to the peer via peer
+    /// exchange.
+    PexPeers(Vec<PexPeer>),
     /// Eventually shut down the peer session.
     Shutdown,
 }
 
+/// A connection initiated by a peer, whose handshake we received but haven't
+/// replied to yet, as we first need to find the torrent it's for.
+#[derive(Debug)]
+pub(crate) struct IncomingConnection {
+    /// The address of the peer.
+    pub addr: SocketAddr,
+    socket: Framed<MseStream, HandshakeCodec>,
+    handshake: Handshake,
+}
+
+impl IncomingConnection {
+    /// Sets up the connection of a peer that connected to us according to
+    /// our encryption policy, and waits for its handshake. If the connection
+    /// is encrypted, the torrent the peer wants has to be among
+    /// `info_hashes`.
+    ///
+    /// `None` is returned if the peer closes the connection or doesn't send
+    /// a valid handshake in time.
+    pub async fn receive_handshake(
+        socket: TcpStream,
+        addr: SocketAddr,
+        encryption: EncryptionPolicy,
+        info_hashes: &[Sha1Hash],
+    ) -> Option<Self> {
+        let receive = async {
+            let stream = mse::accept(socket, encryption, info_hashes).await?;
+            let mut socket = Framed::new(stream, HandshakeCodec);
+            let conn = match socket.next().await {
+                Some(handshake) => Some(Self {
+                    addr,
+                    socket,
+                    handshake: handshake?,
+                }),
+                None => None,
+            };
+            Ok::<_, PeerError>(conn)
+        };
+        match time::timeout(HANDSHAKE_TIMEOUT, receive).await {
+            Ok(Ok(Some(conn))) => Some(conn),
+            Ok(Ok(None)) => {
+                log::info!("Peer {} disconnected before handshake", addr);
+                None
+            }
+            Ok(Err(e)) => {
+                log::info!("Peer {} handshake failed: {}", addr, e);
+                None
+            }
+            Err(_) => {
+                log::info!("Peer {} handshake timed out", addr);
+                None
+            }
+        }
+    }
+
+    /// Returns the info hash of the torrent the peer wants to join.
+    pub fn info_hash(&self) -> &Sha1Hash {
+        &self.handshake.info_hash
+    }
+}
+
 /// Determines who initiated the connection.
 #[derive(Clone, Copy, PartialEq)]
 enum Direction {
@@ -102,10 +191,17 @@ enum Direction {
 /// beginning of the connection. From then on the session mechanisms are
 /// identical.
 ///
-/// # Important
+/// # Extensions
 ///
-/// For now only the BitTorrent v1 specification is implemented, without any
-/// extensions.
+/// Besides the BitTorrent v1 specification, the session supports the
+/// [extension protocol](http://bittorrent.org/beps/bep_0010.html). The
+/// messages of the individual extensions are handled by the handlers in the
+/// session's extension registry.
+///
+/// It also supports the [Fast extension](http://bittorrent.org/beps/bep_0006.html)
+/// if the peer does too, in which case requests that we won't serve are
+/// explicitly rejected and the peer may request the pieces in its allowed
+/// fast set even while choked.
 pub(crate) struct PeerSession {
     /// Shared information of the torrent.
     torrent: Arc<TorrentContext>,
@@ -122,6 +218,15 @@ pub(crate) struct PeerSession {
     /// Information about the peer.
     peer: PeerInfo,
 
+    /// The port on which the torrent listens for connections, advertised in
+    /// the extended handshake.
+    listen_port: u16,
+    /// The extensions that we support, to which the peer's extended messages
+    /// are dispatched.
+    extensions: Extensions,
+    /// The peers that we told the peer about via peer exchange.
+    pex: PexState,
+
     /// Most of the session's information and state is stored here, i.e. it's
     /// the "context" of the session.
     ctx: SessionContext,
@@ -152,9 +257,10 @@ pub(crate) struct PeerSession {
     /// will be wasted. Thus this method avoids bandwidth waste and cuts down
     /// overall download times.
     ///
-    /// Since the Fast extension is not supported (yet), this is emptied when
-    /// we're choked, as in that case we don't expect outstanding requests to be
-    /// served.
+    /// Unless the peer supports the Fast extension, this is emptied when we're
+    /// choked, as in that case we don't expect outstanding requests to be
+    /// served. With the Fast extension the peer rejects each request it won't
+    /// serve, upon which the request is removed and its block is freed.
     ///
     /// Note that if a reuest for a piece's block is in this queue, there _must_
     /// be a corresponding entry for the piece download in `downloads`.
@@ -169,6 +275,22 @@ pub(crate) struct PeerSession {
     /// or when the peer cancels it. If a peer sends a request and cancels it
     /// before the disk read is done, the read block is dropped.
     incoming_requests: HashSet<BlockInfo>,
+    /// The pieces that the peer may request even while choked, which we told
+    /// it in allowed fast messages. Only used with the Fast extension.
+    allowed_fast: HashSet<PieceIndex>,
+
+    /// The session's own rate limits. The torrent and engine limits are in
+    /// the torrent context.
+    rate_limits: RateLimits,
+    /// If we're downloading faster than allowed, we don't read from the
+    /// socket until this time.
+    next_read_time: Option<Instant>,
+    /// If we're uploading faster than allowed, we don't send blocks until
+    /// this time.
+    next_upload_time: Option<Instant>,
+    /// The blocks read from disk that are waiting to be sent to peer due to
+    /// the upload rate limit.
+    outgoing_blocks: VecDeque<Block>,
 }
 
 /// Information about the peer we're connected to.
@@ -188,6 +310,14 @@ pub(super) struct PeerInfo {
     /// This is equivalent to `self.pieces.count_ones()` and is updated every
     /// time the peer sends us an announcement of a new piece.
     pub piece_count: usize,
+    /// Whether the peer set the extension protocol bit in its handshake.
+    pub supports_extensions: bool,
+    /// Whether the peer set the Fast extension bit in its handshake. Since we
+    /// always set it, this means that the extension is used in the session.
+    pub supports_fast: bool,
+    /// The maximum number of outstanding requests the peer accepts, if it
+    /// told us in its extended handshake.
+    pub max_request_queue_len: Option<usize>,
 }
 
 impl PeerSession {
@@ -200,11 +330,24 @@ impl PeerSession {
     pub fn new(
         torrent: Arc<TorrentContext>,
         addr: SocketAddr,
+        listen_port: u16,
+        rate_limits: RateLimits,
     ) -> (Self, Sender) {
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let piece_count = torrent.storage.piece_count;
         let log_target =
             format!("cratetorrent::peer [{}][{}]", torrent.id, addr);
+        let mut extensions = Extensions::default();
+        extensions.register(Box::new(metadata::MetadataHandler::new(
+            Arc::clone(&torrent.raw_info),
+        )));
+        // peers of private torrents may only come from their trackers
+        if !torrent.is_private {
+            extensions.register(Box::new(pex::PexHandler::new(
+                addr,
+                torrent.cmd_tx.clone(),
+            )));
+        }
         (
             Self {
                 torrent,
@@ -215,69 +358,148 @@ impl PeerSession {
                     pieces: Bitfield::repeat(false, piece_count),
                     piece_count: 0,
                     id: Default::default(),
+                    supports_extensions: false,
+                    supports_fast: false,
+                    max_request_queue_len: None,
                 },
+                listen_port,
+                extensions,
+                pex: PexState::default(),
                 ctx: SessionContext {
                     log_target,
                     ..SessionContext::default()
                 },
                 outgoing_requests: HashSet::new(),
                 incoming_requests: HashSet::new(),
+                allowed_fast: HashSet::new(),
+                rate_limits,
+                next_read_time: None,
+                next_upload_time: None,
+                outgoing_blocks: VecDeque::new(),
             },
             cmd_tx,
         )
     }
 
+    /// Returns the session's own rate limits, which may be changed while the
+    /// session is running.
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+
     /// Starts an outbound peer session.
     ///
     /// This method tries to connect to the peer at the address given in the
     /// constructor, send a handshake, and start the session.
     /// It returns if the connection is closed or an error occurs.
-    pub async fn start_outbound(&mut self) -> Result<()> {
+    ///
+    /// The info hash is that of the swarm in which we found the peer, which
+    /// for hybrid torrents may be the truncated v2 info hash.
+    pub async fn start_outbound(&mut self, info_hash: Sha1Hash) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting outbound session");
+        let result = self.connect_and_start(info_hash).await;
+        self.report_setup_result(result)
+    }
 
+    /// Connects to the peer and starts the session.
+    async fn connect_and_start(&mut self, info_hash: Sha1Hash) -> Result<()> {
         // establish the TCP connection
         log::info!(target: &self.ctx.log_target, "Connecting to peer");
         self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = TcpStream::connect(self.peer.addr).await?;
-        log::info!(target: &self.ctx.log_target, "Connected to peer");
+        let socket =
+            mse::connect(self.peer.addr, &info_hash, self.torrent.encryption)
+                .await?;
+        log::info!(
+            target: &self.ctx.log_target,
+            "Connected to peer (encrypted: {})",
+            socket.is_encrypted()
+        );
 
         let socket = Framed::new(socket, HandshakeCodec);
-        self.start(socket, Direction::Outbound).await
+        self.start(socket, Direction::Outbound, info_hash, None)
+            .await
     }
 
-    /// Starts an inbound peer session from an existing TCP connection.
+    /// Starts an inbound peer session from a connection whose handshake was
+    /// already received.
     ///
-    /// The method waits for the peer to send its handshake, responds
-    /// with a handshake, and starts the session.
+    /// The method responds with a handshake and starts the session.
     /// It returns if the connection is closed or an error occurs.
-    pub async fn start_inbound(&mut self, socket: TcpStream) -> Result<()> {
+    pub async fn start_inbound(
+        &mut self,
+        conn: IncomingConnection,
+    ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting inbound session");
-        self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = Framed::new(socket, HandshakeCodec);
-        self.start(socket, Direction::Inbound).await
+        let info_hash = conn.handshake.info_hash;
+        let result = self
+            .start(
+                conn.socket,
+                Direction::Inbound,
+                info_hash,
+                Some(conn.handshake),
+            )
+            .await;
+        self.report_setup_result(result)
+    }
+
+    /// Tells the torrent about the error that stopped the session before it
+    /// could run, as in that case `start` doesn't send the final state update
+    /// with which the torrent removes the peer.
+    fn report_setup_result(&mut self, result: Result<()>) -> Result<()> {
+        if let Err(e) = &result {
+            self.report_error(e)?;
+            self.ctx.set_connection_state(ConnectionState::Disconnected);
+            self.torrent.cmd_tx.send(torrent::Command::PeerState {
+                addr: self.peer.addr,
+                info: self.session_info(),
+            })?;
+        }
+        result
+    }
+
+    /// Tells the torrent the error with which the session stopped, so that
+    /// it can ban the peer if it broke the protocol.
+    fn report_error(&self, error: &PeerError) -> Result<()> {
+        self.torrent.cmd_tx.send(torrent::Command::PeerError {
+            addr: self.peer.addr,
+            error: error.to_string(),
+            is_protocol_violation: error.is_protocol_violation(),
+        })?;
+        Ok(())
     }
 
     /// Helper method for the common steps of setting up a session.
+    ///
+    /// Our handshake is sent with `info_hash`, the info hash of the swarm in
+    /// which the session runs. If the peer's handshake was already received,
+    /// it's passed in `peer_handshake`, otherwise it's read from the socket.
     async fn start(
         &mut self,
-        mut socket: Framed<TcpStream, HandshakeCodec>,
+        mut socket: Framed<MseStream, HandshakeCodec>,
         direction: Direction,
+        info_hash: Sha1Hash,
+        peer_handshake: Option<Handshake>,
     ) -> Result<()> {
         self.ctx.set_connection_state(ConnectionState::Handshaking);
 
         // if this is an outbound connection, we have to send the first
         // handshake
         if direction == Direction::Outbound {
-            let handshake =
-                Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+            let handshake = self.handshake(info_hash);
             log::info!(target: &self.ctx.log_target, "Sending handshake");
             self.ctx.counters.protocol.up += handshake.len();
             socket.send(handshake).await?;
         }
 
         // receive peer's handshake
-        log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
-        if let Some(peer_handshake) = socket.next().await {
+        let peer_handshake = match peer_handshake {
+            Some(peer_handshake) => Some(Ok(peer_handshake)),
+            None => {
+                log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
+                socket.next().await
+            }
+        };
+        if let Some(peer_handshake) = peer_handshake {
             let peer_handshake = peer_handshake?;
             log::info!(target: &self.ctx.log_target, "Peer sent handshake");
             log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
@@ -287,8 +509,13 @@ impl PeerSession {
 
             self.ctx.counters.protocol.down += peer_handshake.len();
 
-            // verify that the advertised torrent info hash is the same as ours
-            if peer_handshake.info_hash != self.torrent.info_hash {
+            // verify that the advertised torrent info hash is the same as
+            // ours, which for hybrid torrents may also be the truncated v2
+            // info hash
+            if peer_handshake.info_hash != self.torrent.info_hash
+                && Some(peer_handshake.info_hash)
+                    != self.torrent.hybrid_info_hash
+            {
                 log::info!(target: &self.ctx.log_target, "Peer handshake invalid info hash");
                 // abort session, info hash is invalid
                 return Err(PeerError::InvalidInfoHash);
@@ -296,13 +523,16 @@ impl PeerSession {
 
             // set the peer's id
             self.peer.id = Some(peer_handshake.peer_id);
+            self.peer.supports_extensions =
+                peer_handshake.supports_extension_protocol();
+            self.peer.supports_fast = peer_handshake.supports_fast_extension();
+            if peer_handshake.supports_v2() {
+                log::debug!(target: &self.ctx.log_target, "Peer supports v2 protocol");
+            }
 
             // if this is an inbound connection, we reply with the handshake
             if direction == Direction::Inbound {
-                let handshake = Handshake::new(
-                    self.torrent.info_hash,
-                    self.torrent.client_id,
-                );
+                let handshake = self.handshake(info_hash);
                 log::info!(target: &self.ctx.log_target, "Sending handshake");
                 self.ctx.counters.protocol.up += handshake.len();
                 socket.send(handshake).await?;
@@ -338,6 +568,7 @@ impl PeerSession {
                     "Session stopped due to an error: {}",
                     e
                 );
+                self.report_error(&e)?;
                 self.ctx.set_connection_state(ConnectionState::Disconnected);
                 self.torrent.cmd_tx.send(torrent::Command::PeerState {
                     addr: self.peer.addr,
@@ -372,6 +603,15 @@ impl PeerSession {
             self.free_pending_blocks().await;
         }
 
+        // the peer's pieces are no longer available
+        if self.peer.piece_count > 0 {
+            self.torrent
+                .piece_picker
+                .write()
+                .await
+                .unregister_peer_pieces(&self.peer.pieces);
+        }
+
         // send a state update message to torrent to actualize possible download
         // stats changes
         self.ctx.set_connection_state(ConnectionState::Disconnected);
@@ -383,34 +623,68 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Returns our handshake with the given info hash, announcing support
+    /// for the extension protocol and the Fast extension, and for the v2
+    /// protocol if the torrent has v2 metadata.
+    fn handshake(&self, info_hash: Sha1Hash) -> Handshake {
+        let mut handshake = Handshake::new(info_hash, self.torrent.client_id);
+        handshake.set_extension_protocol();
+        handshake.set_fast_extension();
+        if !self.torrent.merkle_trees.is_empty() {
+            handshake.set_v2();
+        }
+        handshake
+    }
+
     /// Runs the session after connection to peer is established.
     ///
     /// This is the main session "loop" and performs the core of the session
     /// logic: exchange of messages, timeout logic, etc.
     async fn run(
         &mut self,
-        socket: Framed<TcpStream, PeerCodec>,
+        socket: Framed<MseStream, PeerCodec>,
     ) -> Result<()> {
         self.ctx.connected_time = Some(Instant::now());
 
         // split the sink and stream so that we can pass the sink while holding
         // a reference to the stream in the loop
-        let (mut sink, stream) = socket.split();
-        let mut stream = stream.fuse();
+        let (mut sink, mut stream) = socket.split();
+
+        // if both sides support the extension protocol, the extended
+        // handshake should be sent right after the BitTorrent handshake
+        if self.peer.supports_extensions {
+            self.send_extended_handshake(&mut sink).await?;
+        }
 
         // This is the beginning of the session, which is the only time
         // a peer is allowed to advertise their pieces. If we have pieces
-        // available, send a bitfield message.
+        // available, send a bitfield message. With the Fast extension the
+        // availability must always be sent, and having all or none of the
+        // pieces is sent in its own message.
         {
             let piece_picker_guard = self.torrent.piece_picker.read().await;
             let own_pieces = piece_picker_guard.own_pieces();
-            if own_pieces.any() {
+            let msg = if self.peer.supports_fast && own_pieces.all() {
+                Some(Message::HaveAll)
+            } else if self.peer.supports_fast && own_pieces.not_any() {
+                Some(Message::HaveNone)
+            } else if own_pieces.any() {
+                Some(Message::Bitfield(own_pieces.clone()))
+            } else {
+                None
+            };
+            if let Some(msg) = msg {
                 log::info!(target: &self.ctx.log_target, "Sending piece availability");
-                sink.send(Message::Bitfield(own_pieces.clone())).await?;
+                self.ctx.counters.protocol.up += msg.protocol_len();
+                sink.send(msg).await?;
                 log::info!(target: &self.ctx.log_target, "Sent piece availability");
             }
         }
 
+        if self.peer.supports_fast {
+            self.send_allowed_fast(&mut sink).await?;
+        }
+
         // used for collecting session stats every second
         let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
 
@@ -421,20 +695,39 @@ impl PeerSession {
                 now = tick_timer.select_next_some() => {
                     self.tick(&mut sink, now.into_std()).await?;
                 }
-                msg = stream.select_next_some() => {
-                    let msg = msg?;
+                msg = read_msg(&mut stream, self.next_read_time).fuse() => {
+                    let msg = match msg {
+                        Some(msg) => msg?,
+                        None => {
+                            log::info!(target: &self.ctx.log_target, "Peer closed connection");
+                            return Ok(());
+                        }
+                    };
 
                     // handle bitfield message separately as it may only be
-                    // received directly after the handshake (later once we
-                    // implement the FAST extension, there will be other piece
-                    // availability related messages to handle)
-                    if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
-                        if let Message::Bitfield(bitfield) = msg {
-                            self.handle_bitfield_msg(&mut sink, bitfield).await?;
-                        } else {
+                    // received directly after the handshake, as are the have
+                    // all and have none messages of the Fast extension
+                    // the extended handshake may be sent before the
+                    // bitfield, so it doesn't end the availability exchange
+                    if self.ctx.state.connection == ConnectionState::AvailabilityExchange
+                        && !matches!(msg, Message::Extended { .. })
+                    {
+                        match msg {
+                            Message::Bitfield(bitfield) => {
+                                self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                            }
+                            Message::HaveAll | Message::HaveNone
+                                if self.peer.supports_fast =>
+                            {
+                                let bitfield = Bitfield::repeat(
+                                    msg == Message::HaveAll,
+                                    self.torrent.storage.piece_count,
+                                );
+                                self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                            }
                             // it's not mandatory to send a bitfield message
                             // right after the handshake
-                            self.handle_msg(&mut sink, msg).await?;
+                            msg => self.handle_msg(&mut sink, msg).await?,
                         }
 
                         // if neither of us have any pieces, disconnect, there
@@ -468,12 +761,31 @@ impl PeerSession {
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
                         Command::Block(block)=> {
-                            self.send_block(&mut sink, block).await?;
+                            self.outgoing_blocks.push_back(block);
+                            self.send_blocks(&mut sink).await?;
                         }
                         Command::PieceCompletion { index, in_endgame } => {
                             self.ctx.in_endgame = in_endgame;
                             self.handle_piece_completion(&mut sink, index).await?;
                         }
+                        Command::Choke => {
+                            self.choke_peer(&mut sink).await?;
+                        }
+                        Command::Unchoke => {
+                            self.unchoke_peer(&mut sink).await?;
+                        }
+                        Command::UpdateInterest => {
+                            let is_interested = self
+                                .torrent
+                                .piece_picker
+                                .read()
+                                .await
+                                .is_interested(&self.peer.pieces);
+                            self.update_interest(&mut sink, is_interested).await?;
+                        }
+                        Command::PexPeers(peers) => {
+                            self.send_pex_msg(&mut sink, &peers).await?;
+                        }
                         Command::Shutdown => {
                             log::info!(
                                 target: &self.ctx.log_target,
@@ -483,6 +795,10 @@ impl PeerSession {
                         }
                     }
                 }
+                _ = delay_until(self.next_upload_time).fuse() => {
+                    self.next_upload_time = None;
+                    self.send_blocks(&mut sink).await?;
+                }
             }
         }
 
@@ -498,7 +814,7 @@ impl PeerSession {
     /// target request queue size.
     async fn tick(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         now: Instant,
     ) -> Result<()> {
         // if we haven't become interested in each other for too long,
@@ -514,8 +830,11 @@ impl PeerSession {
         }
 
         // resent requests if we have pending requests and more time has elapsed
-        // since the last request than the current timeout value
-        if !self.outgoing_requests.is_empty() {
+        // since the last request than the current timeout value (unless it's
+        // us who hold back the blocks due to the download rate limit)
+        if !self.outgoing_requests.is_empty()
+            && !self.is_download_throttled(now)
+        {
             self.check_request_timeout(sink).await?;
         }
 
@@ -578,7 +897,7 @@ impl PeerSession {
     /// Times out the peer if it hasn't sent a request in too long.
     async fn check_request_timeout(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
     ) -> Result<()> {
         if let Some(last_outgoing_request_time) =
             self.ctx.last_outgoing_request_time
@@ -649,11 +968,12 @@ impl PeerSession {
         }
     }
 
-    /// Handles a message expected in the `AvailabilityExchange` state
-    /// (currently only the bitfield message).
+    /// Handles a message expected in the `AvailabilityExchange` state: the
+    /// bitfield message, or the have all and have none messages of the Fast
+    /// extension, as which this is passed a bitfield with all or no pieces.
     async fn handle_bitfield_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         mut bitfield: Bitfield,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Handling peer Bitfield message");
@@ -702,7 +1022,7 @@ impl PeerSession {
     /// Handles messages from peer that are expected in the `Connected` state.
     async fn handle_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         msg: Message,
     ) -> Result<()> {
         // record protocol message size
@@ -715,6 +1035,14 @@ impl PeerSession {
                 );
                 return Err(PeerError::BitfieldNotAfterHandshake);
             }
+            Message::HaveAll | Message::HaveNone => {
+                self.check_fast_extension()?;
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Peer sent piece availability not after handshake"
+                );
+                return Err(PeerError::BitfieldNotAfterHandshake);
+            }
             Message::KeepAlive => {
                 log::info!(target: &self.ctx.log_target, "Peer sent keep alive");
             }
@@ -723,8 +1051,11 @@ impl PeerSession {
                     log::info!(target: &self.ctx.log_target, "Peer choked us");
                     // since we're choked we don't expect to receive blocks
                     // for our pending requests and free them for other peers to
-                    // download
-                    self.free_pending_blocks().await;
+                    // download, unless the peer supports the Fast extension,
+                    // in which case it rejects the requests it won't serve
+                    if !self.peer.supports_fast {
+                        self.free_pending_blocks().await;
+                    }
                     self.ctx.update_state(|state| state.is_choked = true);
                 }
             }
@@ -744,16 +1075,13 @@ impl PeerSession {
             }
             Message::Interested => {
                 if !self.ctx.state.is_peer_interested {
-                    // TODO(https://github.com/mandreyel/cratetorrent/issues/60):
-                    // we currently unchkoe peer unconditionally, but we should
-                    // implement the proper unchoke algorithm in `Torrent`
+                    // whether the peer is unchoked is decided by the torrent's
+                    // choker, which learns of the peer's interest from the
+                    // state update
                     log::info!(target: &self.ctx.log_target, "Peer became interested");
-                    log::info!(target: &self.ctx.log_target, "Unchoking peer");
                     self.ctx.update_state(|state| {
                         state.is_peer_interested = true;
-                        state.is_peer_choked = false;
                     });
-                    sink.send(Message::Unchoke).await?;
                 }
             }
             Message::NotInterested => {
@@ -776,12 +1104,23 @@ impl PeerSession {
                 };
                 self.handle_block_msg(block_info, data.into_owned()).await?;
 
+                // if we're downloading faster than allowed, the next read has
+                // to wait
+                self.next_read_time = rate_limiter::reserve(
+                    [
+                        &self.rate_limits.down,
+                        &self.torrent.rate_limits.down,
+                        &self.torrent.engine_rate_limits.down,
+                    ],
+                    block_info.len as u64,
+                );
+
                 // we may be able to make more requests now that a block has
                 // arrived
                 self.make_requests(sink).await?;
             }
             Message::Request(block_info) => {
-                self.handle_request_msg(block_info).await?;
+                self.handle_request_msg(sink, block_info).await?;
             }
             Message::Have { piece_index } => {
                 self.handle_have_msg(sink, piece_index).await?;
@@ -790,13 +1129,216 @@ impl PeerSession {
                 // before processing request validate block info
                 self.validate_block_info(&block_info)?;
                 log::info!(target: &self.ctx.log_target, "Peer cancelled block {}", block_info);
-                self.incoming_requests.remove(&block_info);
+                // with the Fast extension every request must be answered,
+                // either by the block or by a reject
+                if self.incoming_requests.remove(&block_info)
+                    && self.peer.supports_fast
+                {
+                    self.reject_request(sink, block_info).await?;
+                }
+            }
+            Message::SuggestPiece { piece_index } => {
+                self.check_fast_extension()?;
+                self.validate_piece_index(piece_index)?;
+                // suggestions are only advisory and we pick pieces by their
+                // rarity instead
+                log::info!(target: &self.ctx.log_target, "Peer suggested piece {}", piece_index);
+            }
+            Message::RejectRequest(block_info) => {
+                self.check_fast_extension()?;
+                self.handle_reject_request_msg(block_info).await?;
+            }
+            Message::AllowedFast { piece_index } => {
+                self.check_fast_extension()?;
+                self.validate_piece_index(piece_index)?;
+                // we only make requests while unchoked, so we don't make use
+                // of the pieces we're allowed to request while choked
+                log::info!(target: &self.ctx.log_target, "Peer allowed fast piece {}", piece_index);
+            }
+            Message::Extended { id, payload } => {
+                self.handle_extended_msg(sink, id, &payload).await?;
+            }
+            Message::HashRequest(request) => {
+                self.handle_hash_request_msg(sink, request).await?;
+            }
+            // we verify pieces with the piece layers from the metainfo, so
+            // hashes are only ever requested by metadata sessions
+            Message::Hashes { request, .. } => {
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Peer sent unrequested hashes of file {}",
+                    hex::encode(request.pieces_root)
+                );
+            }
+            Message::HashReject(request) => {
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Peer sent unrequested hash reject of file {}",
+                    hex::encode(request.pieces_root)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serves the peer's request for hashes of a file's piece layer, or
+    /// rejects it if we don't have the layer or the request is invalid.
+    ///
+    /// Only the piece layer, which is in the metainfo, is served, as we don't
+    /// keep the lower layers of the merkle trees.
+    async fn handle_hash_request_msg(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+        request: HashRequest,
+    ) -> Result<()> {
+        log::info!(
+            target: &self.ctx.log_target,
+            "Peer requested {} hash(es) of file {}",
+            request.len,
+            hex::encode(request.pieces_root)
+        );
+        let piece_len = self.torrent.storage.piece_len;
+        let hashes = self
+            .torrent
+            .merkle_trees
+            .iter()
+            .find(|tree| tree.pieces_root == request.pieces_root)
+            .filter(|tree| {
+                !tree.piece_layer.is_empty()
+                    && request.base_layer
+                        == merkle::piece_layer_index(piece_len)
+            })
+            .and_then(|tree| {
+                merkle::piece_layer_proof(
+                    &tree.piece_layer,
+                    piece_len,
+                    request.index,
+                    request.len,
+                    request.proof_layers,
+                )
+            });
+
+        let msg = match hashes {
+            Some(hashes) => Message::Hashes { request, hashes },
+            None => {
+                log::info!(target: &self.ctx.log_target, "Rejecting hash request");
+                Message::HashReject(request)
+            }
+        };
+        self.ctx.counters.protocol.up += msg.protocol_len();
+        sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// Sends our extended handshake, advertising the extensions we support.
+    async fn send_extended_handshake(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        let mut handshake = self.extensions.handshake();
+        handshake.v = Some(CLIENT_NAME.as_bytes().to_vec());
+        handshake.p = Some(self.listen_port.into());
+        handshake.reqq = Some(MAX_INCOMING_REQUEST_COUNT as i64);
+        handshake.yourip = Some(match self.peer.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        });
+        log::info!(target: &self.ctx.log_target, "Sending extended handshake");
+        log::trace!(
+            target: &self.ctx.log_target,
+            "Extended handshake: {:?}",
+            handshake
+        );
+        let msg = Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: handshake.encode()?,
+        };
+        self.ctx.counters.protocol.up += msg.protocol_len();
+        sink.send(msg).await?;
+        Ok(())
+    }
+
+    /// Handles the peer's extended handshake or passes the message on to the
+    /// handler of the extension, sending back any responses.
+    async fn handle_extended_msg(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<()> {
+        // we didn't advertise the extension protocol if the peer doesn't
+        // support it, so it shouldn't be sending these
+        if !self.peer.supports_extensions {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer sent extended message without extension support"
+            );
+            return Err(PeerError::ExtensionUnsupported);
+        }
+
+        if id == EXTENDED_HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::decode(payload)?;
+            log::info!(
+                target: &self.ctx.log_target,
+                "Peer sent extended handshake (client: {:?})",
+                handshake.client_name()
+            );
+            log::trace!(
+                target: &self.ctx.log_target,
+                "Peer extended handshake: {:?}",
+                handshake
+            );
+            if let Some(reqq) = handshake.reqq {
+                if reqq > 0 {
+                    self.peer.max_request_queue_len = Some(reqq as usize);
+                }
+            }
+            self.extensions.handle_handshake(&handshake);
+            self.torrent.cmd_tx.send(torrent::Command::PeerExtensions {
+                addr: self.peer.addr,
+                extensions: self.extensions.negotiated(),
+                listen_port: handshake
+                    .p
+                    .and_then(|port| u16::try_from(port).ok())
+                    .filter(|port| *port != 0),
+            })?;
+        } else {
+            log::debug!(
+                target: &self.ctx.log_target,
+                "Peer sent extended message {}",
+                id
+            );
+            for msg in self.extensions.handle_msg(id, payload)? {
+                self.ctx.counters.protocol.up += msg.protocol_len();
+                sink.send(msg).await?;
             }
         }
 
         Ok(())
     }
 
+    /// Tells the peer which of the torrent's peers were connected and
+    /// disconnected since our previous peer exchange message, if the peer
+    /// supports the extension.
+    async fn send_pex_msg(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+        peers: &[PexPeer],
+    ) -> Result<()> {
+        let id = match self.extensions.peer_id(UT_PEX) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        if let Some(payload) = self.pex.update(peers)? {
+            log::debug!(target: &self.ctx.log_target, "Sending peer exchange");
+            let msg = Message::Extended { id, payload };
+            self.ctx.counters.protocol.up += msg.protocol_len();
+            sink.send(msg).await?;
+        }
+        Ok(())
+    }
+
     /// Fills the session's download pipeline with the optimal number of
     /// requests.
     ///
@@ -804,7 +1346,7 @@ impl PeerSession {
     /// `Status::best_request_queue_len` or the relevant section in DESIGN.md.
     async fn make_requests(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
     ) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Making requests");
 
@@ -818,10 +1360,25 @@ impl PeerSession {
             return Ok(());
         }
 
+        // while we're over the download rate limit, more requests would only
+        // pile up unread on the socket, so the pipeline is only refilled once
+        // it has drained
+        if !self.outgoing_requests.is_empty()
+            && self.is_download_throttled(Instant::now())
+        {
+            log::debug!(target: &self.ctx.log_target, "Download rate limited, not making requests");
+            return Ok(());
+        }
+
         // TODO: optimize this by using the preallocated hashset in self
         let mut requests = Vec::new();
-        let target_request_queue_len =
-            self.ctx.target_request_queue_len.unwrap_or_default();
+        // the peer may have told us how many requests it's willing to keep
+        // outstanding, beyond which it would drop them
+        let target_request_queue_len = self
+            .ctx
+            .target_request_queue_len
+            .unwrap_or_default()
+            .min(self.peer.max_request_queue_len.unwrap_or(usize::MAX));
 
         // If we have active downloads, prefer to continue those. This will
         // result in less in-progress pieces.
@@ -865,8 +1422,12 @@ impl PeerSession {
 
             log::debug!(target: &self.ctx.log_target, "Trying to pick new piece");
 
-            if let Some(index) =
-                self.torrent.piece_picker.write().await.pick_piece()
+            if let Some(index) = self
+                .torrent
+                .piece_picker
+                .write()
+                .await
+                .pick_piece(&self.peer.pieces)
             {
                 log::info!(target: &self.ctx.log_target, "Picked piece {}", index);
 
@@ -944,9 +1505,10 @@ impl PeerSession {
             .await
             .get(&block_info.piece_index)
         {
-            Some(download) => {
-                download.write().await.received_block(&block_info)
-            }
+            Some(download) => download
+                .write()
+                .await
+                .received_block(&block_info, self.peer.addr),
             None => {
                 // silently ignore this block if we didn't expected it
                 //
@@ -1013,8 +1575,12 @@ impl PeerSession {
     /// we receive a message on the peer session's command port in
     /// [`Self::run`]. This is when the block is actually sent to peer, if by
     /// the request is not cancelled by then.
+    ///
+    /// With the Fast extension, requests that we don't serve are rejected
+    /// rather than dropped.
     async fn handle_request_msg(
         &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         block_info: BlockInfo,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Got request: {:?}", block_info);
@@ -1022,8 +1588,26 @@ impl PeerSession {
         // before processing request validate block info
         self.validate_block_info(&block_info)?;
 
-        // check if peer is not choked: if they are, they can't request blocks
-        if self.ctx.state.is_peer_choked {
+        // check if peer is not choked: if they are, they can't request blocks,
+        // other than those of the pieces in their allowed fast set
+        if self.ctx.state.is_peer_choked
+            && !self.allowed_fast.contains(&block_info.piece_index)
+        {
+            if self.peer.supports_fast {
+                log::info!(target: &self.ctx.log_target, "Rejecting request of choked peer");
+                return self.reject_request(sink, block_info).await;
+            }
+            // the peer may have sent the request before receiving our choke
+            // message, so such requests are dropped rather than treated as
+            // a protocol violation
+            let was_recently_choked = match self.ctx.last_peer_choke_time {
+                Some(t) => t.elapsed() < CHOKE_GRACE_PERIOD,
+                None => false,
+            };
+            if was_recently_choked {
+                log::info!(target: &self.ctx.log_target, "Dropping request of choked peer");
+                return Ok(());
+            }
             log::warn!(target: &self.ctx.log_target, "Choked peer sent request");
             return Err(PeerError::RequestWhileChoked);
         }
@@ -1035,6 +1619,20 @@ impl PeerSession {
             return Ok(());
         }
 
+        // we advertise the number of requests we queue in the extended
+        // handshake, beyond which requests are dropped
+        if self.incoming_requests.len() >= MAX_INCOMING_REQUEST_COUNT {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer exceeded request queue, dropping request {}",
+                block_info
+            );
+            if self.peer.supports_fast {
+                return self.reject_request(sink, block_info).await;
+            }
+            return Ok(());
+        }
+
         log::info!(target: &self.ctx.log_target, "Issuing disk IO read for block {}", block_info);
         self.incoming_requests.insert(block_info);
 
@@ -1049,11 +1647,121 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Tells the peer that its request for the block is not going to be
+    /// served. Only used with the Fast extension.
+    async fn reject_request(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+        block_info: BlockInfo,
+    ) -> Result<()> {
+        log::debug!(target: &self.ctx.log_target, "Rejecting request {}", block_info);
+        self.ctx.counters.protocol.up += MessageId::RejectRequest.header_len();
+        sink.send(Message::RejectRequest(block_info)).await?;
+        Ok(())
+    }
+
+    /// Handles the peer's rejection of our request, freeing the block for
+    /// other peer sessions (or this one, later) to download.
+    async fn handle_reject_request_msg(
+        &mut self,
+        block_info: BlockInfo,
+    ) -> Result<()> {
+        self.validate_block_info(&block_info)?;
+        log::info!(target: &self.ctx.log_target, "Peer rejected request {}", block_info);
+
+        // the request may have been freed already, e.g. when it timed out
+        if !self.outgoing_requests.remove(&block_info) {
+            return Ok(());
+        }
+        // the piece may have been completed by another peer in the meantime,
+        // in which case there is nothing to free
+        if let Some(download) = self
+            .torrent
+            .downloads
+            .read()
+            .await
+            .get(&block_info.piece_index)
+        {
+            download.write().await.free_block(&block_info);
+        }
+
+        Ok(())
+    }
+
+    /// Tells the peer which pieces it may request even while choked, which
+    /// are the pieces of its allowed fast set that we have.
+    ///
+    /// The set is only defined for IPv4 peers, so other peers aren't sent
+    /// one.
+    async fn send_allowed_fast(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        let ip = match self.peer.addr.ip() {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return Ok(()),
+        };
+        let set = allowed_fast_set(
+            ip,
+            &self.torrent.info_hash,
+            self.torrent.storage.piece_count,
+            ALLOWED_FAST_SET_LEN,
+        );
+        {
+            let piece_picker_guard = self.torrent.piece_picker.read().await;
+            let own_pieces = piece_picker_guard.own_pieces();
+            self.allowed_fast
+                .extend(set.into_iter().filter(|&index| own_pieces[index]));
+        }
+
+        for &piece_index in self.allowed_fast.iter() {
+            log::debug!(target: &self.ctx.log_target, "Allowing fast piece {}", piece_index);
+            self.ctx.counters.protocol.up +=
+                MessageId::AllowedFast.header_len();
+            sink.send(Message::AllowedFast { piece_index }).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error if the peer sent a Fast extension message without
+    /// having announced support for it.
+    fn check_fast_extension(&self) -> Result<()> {
+        if self.peer.supports_fast {
+            Ok(())
+        } else {
+            log::warn!(target: &self.ctx.log_target, "Peer sent Fast extension message without support");
+            Err(PeerError::FastExtensionNotNegotiated)
+        }
+    }
+
+    /// Returns whether we're holding off reading from the socket due to the
+    /// download rate limit.
+    fn is_download_throttled(&self, now: Instant) -> bool {
+        matches!(self.next_read_time, Some(t) if t > now)
+    }
+
+    /// Sends the blocks read from disk to peer until the upload rate limit is
+    /// reached, after which the rest are sent once the limit allows it.
+    async fn send_blocks(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        while self.next_upload_time.is_none() {
+            if let Some(block) = self.outgoing_blocks.pop_front() {
+                self.send_block(sink, block).await?;
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     /// Sends the block to peer if the peer still wants it (hasn't canceled the
     /// request).
     async fn send_block(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         block: Block,
     ) -> Result<()> {
         let info = block.info();
@@ -1081,6 +1789,16 @@ impl PeerSession {
         // update download stats
         self.ctx.update_upload_stats(info.len);
 
+        // if we're uploading faster than allowed, the next block has to wait
+        self.next_upload_time = rate_limiter::reserve(
+            [
+                &self.rate_limits.up,
+                &self.torrent.rate_limits.up,
+                &self.torrent.engine_rate_limits.up,
+            ],
+            info.len as u64,
+        );
+
         Ok(())
     }
 
@@ -1088,7 +1806,7 @@ impl PeerSession {
     /// to become interested in peer and start making requests.
     async fn handle_have_msg(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         piece_index: PieceIndex,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Peer has piece {}", piece_index);
@@ -1106,22 +1824,73 @@ impl PeerSession {
         self.peer.pieces.set(piece_index, true);
         self.peer.piece_count += 1;
 
-        // need to recalculate interest with each received piece
+        // need to recalculate interest with each received piece: we're
+        // interested if we already were or if we don't have this piece
         let is_interested = self
             .torrent
             .piece_picker
             .write()
             .await
-            .register_peer_piece(piece_index);
+            .register_peer_piece(piece_index)
+            || self.ctx.state.is_interested;
 
         // we may have become interested in peer
         self.update_interest(sink, is_interested).await
     }
 
+    /// Chokes the peer, if it's not already choked, and drops its pending
+    /// requests.
+    ///
+    /// With the Fast extension, the dropped requests are rejected, while
+    /// those of the pieces in the peer's allowed fast set are still served.
+    async fn choke_peer(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        if self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Choking peer");
+        self.ctx.counters.protocol.up += MessageId::Choke.header_len();
+        self.ctx.update_state(|state| state.is_peer_choked = true);
+        self.ctx.last_peer_choke_time = Some(Instant::now());
+        // the blocks of the requests whose disk reads are in progress are
+        // dropped once they are read (the allowed fast set is only non-empty
+        // with the Fast extension)
+        let allowed_fast = &self.allowed_fast;
+        let (kept, dropped): (HashSet<_>, HashSet<_>) = self
+            .incoming_requests
+            .drain()
+            .partition(|block| allowed_fast.contains(&block.piece_index));
+        self.incoming_requests = kept;
+        sink.send(Message::Choke).await?;
+        if self.peer.supports_fast {
+            for block_info in dropped {
+                self.reject_request(sink, block_info).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unchokes the peer, if it's choked, allowing it to request blocks.
+    async fn unchoke_peer(
+        &mut self,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
+    ) -> Result<()> {
+        if !self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Unchoking peer");
+        self.ctx.counters.protocol.up += MessageId::Unchoke.header_len();
+        self.ctx.update_state(|state| state.is_peer_choked = false);
+        sink.send(Message::Unchoke).await?;
+        Ok(())
+    }
+
     /// Checks whether we have become or stopped being interested in the peer.
     async fn update_interest(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         is_interested: bool,
     ) -> Result<()> {
         // we may have become interested in peer
@@ -1179,7 +1948,7 @@ impl PeerSession {
     /// that we need to cancel. If peer doesn't have the piece, we announce it.
     async fn handle_piece_completion(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<MseStream, PeerCodec>, Message>,
         piece_index: PieceIndex,
     ) -> Result<()> {
         // if peer doesn't have the piece, announce it
@@ -1216,6 +1985,542 @@ impl PeerSession {
     }
 }
 
+/// Reads the next message from the peer, but not before the given time, if
+/// any.
+async fn read_msg<S: Stream + Unpin>(
+    stream: &mut S,
+    read_time: Option<Instant>,
+) -> Option<S::Item> {
+    if let Some(t) = read_time {
+        time::delay_until(t.into()).await;
+    }
+    stream.next().await
+}
+
+/// Waits until the given time, or forever if there is none.
+async fn delay_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(t) => time::delay_until(t.into()).await,
+        None => future::pending().await,
+    }
+}
+
+/// A peer that connects to us has this long to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// After this timeout if the peers haven't become intereseted in each other,
 /// the connection is severed.
 const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests that a peer sends within this much time after we choked it are
+/// dropped, as they may have been sent before the peer received the choke
+/// message. Later requests while choked are a protocol violation.
+const CHOKE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The maximum number of outstanding block requests we accept from a peer.
+/// This is advertised to the peer in the extended handshake.
+const MAX_INCOMING_REQUEST_COUNT: usize = 250;
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::Ipv4Addr};
+
+    use futures::future::Either;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        alert::AlertReceiver, piece_picker::PiecePicker,
+        storage_info::StorageInfo, BlockData, FileInfo, TorrentId, BLOCK_LEN,
+    };
+
+    /// The number of pieces in the test torrent.
+    const PIECE_COUNT: usize = 16;
+    /// The length of the pieces of the test torrent, which have 4 blocks each.
+    const PIECE_LEN: u32 = 4 * BLOCK_LEN;
+
+    /// The rate limit in the rate limit tests, at which a block is
+    /// transferred every quarter second.
+    const RATE_LIMIT: u64 = 4 * BLOCK_LEN as u64;
+    /// The number of blocks transferred at the limited rate, which take about
+    /// 2 seconds.
+    const LIMITED_BLOCK_COUNT: usize = 9;
+    /// The number of blocks transferred after the rate limit is lifted.
+    const UNLIMITED_BLOCK_COUNT: usize = 24;
+
+    /// The setup of a session under test and of the peer it's connected to.
+    #[derive(Default)]
+    struct EnvConf {
+        /// Whether we have all pieces of the torrent, or none of them.
+        has_all_pieces: bool,
+        /// Whether the peer sets the Fast extension bit in its handshake.
+        supports_fast: bool,
+        /// Whether the peer sets the extension protocol bit in its
+        /// handshake.
+        supports_extensions: bool,
+        /// Whether the torrent is private.
+        is_private: bool,
+    }
+
+    /// A session under test, which runs in its own task and is connected to
+    /// a stand-in peer whose side of the connection is driven by the test.
+    struct Env {
+        /// The stand-in peer's end of the connection, after the handshakes.
+        peer: Framed<TcpStream, PeerCodec>,
+        /// The torrent of the session, whose rate limits are shared by the
+        /// session.
+        torrent: Arc<TorrentContext>,
+        /// The rate limits of the session.
+        rate_limits: RateLimits,
+        /// The channel on which the test sends the session commands, as its
+        /// torrent would.
+        session_tx: Sender,
+        /// The channel on which the session sends commands to the disk task.
+        disk_rx: UnboundedReceiver<disk::Command>,
+        /// The channel on which the session sends commands to its torrent.
+        torrent_rx: torrent::Receiver,
+        /// The session stops if it can't post alerts when it fails, so the
+        /// channel is kept open.
+        _alert_rx: AlertReceiver,
+    }
+
+    impl Env {
+        /// Starts an outbound session to a stand-in peer and performs the
+        /// handshakes.
+        async fn new(conf: EnvConf) -> Self {
+            let (torrent_tx, torrent_rx) = mpsc::unbounded_channel();
+            let (disk_tx, disk_rx) = mpsc::unbounded_channel();
+            let (alert_tx, alert_rx) = mpsc::unbounded_channel();
+            let download_len = PIECE_COUNT as u64 * PIECE_LEN as u64;
+            let torrent = Arc::new(TorrentContext {
+                id: TorrentId::new(),
+                info_hash: [1; 20],
+                hybrid_info_hash: None,
+                raw_info: Arc::new(b"d4:name4:teste".to_vec()),
+                merkle_trees: Vec::new(),
+                client_id: [2; 20],
+                encryption: EncryptionPolicy::Disabled,
+                is_private: conf.is_private,
+                cmd_tx: torrent_tx,
+                piece_picker: Arc::new(RwLock::new(PiecePicker::new(
+                    Bitfield::repeat(conf.has_all_pieces, PIECE_COUNT),
+                ))),
+                downloads: RwLock::new(HashMap::new()),
+                rate_limits: RateLimits::new(None, None),
+                engine_rate_limits: RateLimits::new(None, None),
+                alert_tx,
+                disk_tx,
+                storage: StorageInfo {
+                    piece_count: PIECE_COUNT,
+                    piece_len: PIECE_LEN,
+                    last_piece_len: PIECE_LEN,
+                    download_len,
+                    download_dir: "/tmp".into(),
+                    files: vec![FileInfo {
+                        path: "peer_session_test".into(),
+                        torrent_offset: 0,
+                        len: download_len,
+                    }],
+                    is_piece_aligned: false,
+                },
+            });
+
+            let mut listener =
+                TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let rate_limits = RateLimits::new(None, None);
+            let (mut session, session_tx) = PeerSession::new(
+                Arc::clone(&torrent),
+                addr,
+                6881,
+                rate_limits.clone(),
+            );
+            let info_hash = torrent.info_hash;
+            tokio::spawn(
+                async move { session.start_outbound(info_hash).await },
+            );
+
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = Framed::new(socket, HandshakeCodec);
+            let handshake = socket.next().await.unwrap().unwrap();
+            assert_eq!(handshake.info_hash, torrent.info_hash);
+            let mut handshake = Handshake::new(torrent.info_hash, [3; 20]);
+            if conf.supports_fast {
+                handshake.set_fast_extension();
+            }
+            if conf.supports_extensions {
+                handshake.set_extension_protocol();
+            }
+            socket.send(handshake).await.unwrap();
+
+            let parts = socket.into_parts();
+            let mut new_parts = FramedParts::new(parts.io, PeerCodec);
+            new_parts.read_buf = parts.read_buf;
+
+            Self {
+                peer: Framed::from_parts(new_parts),
+                torrent,
+                rate_limits,
+                session_tx,
+                disk_rx,
+                torrent_rx,
+                _alert_rx: alert_rx,
+            }
+        }
+
+        /// Sends the command to the session.
+        fn command(&self, cmd: Command) {
+            assert!(self.session_tx.send(cmd).is_ok(), "session stopped");
+        }
+
+        /// Sends the message to the session.
+        async fn send(&mut self, msg: Message) {
+            self.peer.send(msg).await.unwrap();
+        }
+
+        /// Returns the next message the session sent.
+        async fn recv(&mut self) -> Message {
+            time::timeout(Duration::from_secs(5), self.peer.next())
+                .await
+                .expect("no message from session")
+                .expect("session closed connection")
+                .unwrap()
+        }
+
+        /// Returns the next command the session sent the disk task.
+        async fn recv_disk(&mut self) -> disk::Command {
+            time::timeout(Duration::from_secs(5), self.disk_rx.recv())
+                .await
+                .expect("no disk command from session")
+                .unwrap()
+        }
+
+        /// Serves the blocks the session requests until it has written the
+        /// given number of blocks to disk, and returns how long it took.
+        async fn serve_blocks(&mut self, count: usize) -> Duration {
+            let start = Instant::now();
+            let mut written_count = 0;
+            while written_count < count {
+                // the session can't be sent blocks from inside the select,
+                // as that borrows the connection
+                let event = select! {
+                    msg = self.peer.next().fuse() => Either::Left(msg),
+                    cmd = self.disk_rx.recv().fuse() => Either::Right(cmd),
+                };
+                match event {
+                    Either::Left(Some(Ok(Message::Request(block_info)))) => {
+                        self.send(Message::Block {
+                            piece_index: block_info.piece_index,
+                            offset: block_info.offset,
+                            data: BlockData::Owned(vec![
+                                0;
+                                block_info.len as usize
+                            ]),
+                        })
+                        .await;
+                    }
+                    Either::Left(Some(Ok(_))) => (),
+                    Either::Left(_) => panic!("session closed connection"),
+                    Either::Right(Some(disk::Command::WriteBlock {
+                        ..
+                    })) => written_count += 1,
+                    Either::Right(cmd) => {
+                        panic!("expected block write, got {:?}", cmd)
+                    }
+                }
+            }
+            start.elapsed()
+        }
+
+        /// Requests the blocks from the session, serves the block reads, and
+        /// returns how long it took for the session to send all blocks.
+        async fn request_blocks(&mut self, blocks: &[BlockInfo]) -> Duration {
+            let start = Instant::now();
+            for block_info in blocks {
+                self.send(Message::Request(*block_info)).await;
+            }
+            for _ in blocks {
+                match self.recv_disk().await {
+                    disk::Command::ReadBlock {
+                        block_info,
+                        result_tx,
+                        ..
+                    } => {
+                        let data = vec![0; block_info.len as usize];
+                        let block = Block::new(block_info, data);
+                        assert!(result_tx.send(Command::Block(block)).is_ok());
+                    }
+                    cmd => panic!("expected block read, got {:?}", cmd),
+                }
+            }
+            let mut sent_count = 0;
+            while sent_count < blocks.len() {
+                if let Message::Block { .. } = self.recv().await {
+                    sent_count += 1;
+                }
+            }
+            start.elapsed()
+        }
+    }
+
+    /// Returns the blocks of the test torrent, in order.
+    fn blocks() -> impl Iterator<Item = BlockInfo> {
+        (0..PIECE_COUNT).flat_map(|piece_index| {
+            (0..PIECE_LEN / BLOCK_LEN).map(move |block_index| BlockInfo {
+                piece_index,
+                offset: block_index * BLOCK_LEN,
+                len: BLOCK_LEN,
+            })
+        })
+    }
+
+    /// Tests that the download rate limit, selected by `limits` from the
+    /// session's, its torrent's and the engine's, is applied when it's set
+    /// on a running session, and that downloads speed up when it's lifted.
+    async fn assert_download_rate_limited(limits: fn(&Env) -> &RateLimits) {
+        let mut env = Env::new(EnvConf::default()).await;
+        limits(&env).set_rates(Some(RATE_LIMIT), None);
+
+        env.send(Message::Bitfield(Bitfield::repeat(true, PIECE_COUNT)))
+            .await;
+        env.send(Message::Unchoke).await;
+
+        // the first block is read right away, and each of the others a
+        // quarter second after the previous one
+        let elapsed = env.serve_blocks(LIMITED_BLOCK_COUNT).await;
+        assert!(
+            elapsed >= Duration::from_millis(1750)
+                && elapsed < Duration::from_secs(3),
+            "limited download took {:?}",
+            elapsed
+        );
+
+        limits(&env).set_rates(None, None);
+        let elapsed = env.serve_blocks(UNLIMITED_BLOCK_COUNT).await;
+        assert!(
+            elapsed < Duration::from_secs(1),
+            "unlimited download took {:?}",
+            elapsed
+        );
+    }
+
+    /// Tests that the upload rate limit, selected by `limits` from the
+    /// session's, its torrent's and the engine's, is applied when it's set
+    /// on a running session, and that uploads speed up when it's lifted.
+    async fn assert_upload_rate_limited(limits: fn(&Env) -> &RateLimits) {
+        let mut env = Env::new(EnvConf {
+            has_all_pieces: true,
+            ..EnvConf::default()
+        })
+        .await;
+        limits(&env).set_rates(None, Some(RATE_LIMIT));
+
+        match env.recv().await {
+            Message::Bitfield(_) => (),
+            msg => panic!("expected bitfield, got {:?}", msg),
+        }
+        env.send(Message::Interested).await;
+        env.command(Command::Unchoke);
+        assert_eq!(env.recv().await, Message::Unchoke);
+
+        let blocks: Vec<_> = blocks()
+            .take(LIMITED_BLOCK_COUNT + UNLIMITED_BLOCK_COUNT)
+            .collect();
+        let (limited, unlimited) = blocks.split_at(LIMITED_BLOCK_COUNT);
+        // the first block is sent right away, and each of the others a
+        // quarter second after the previous one
+        let elapsed = env.request_blocks(limited).await;
+        assert!(
+            elapsed >= Duration::from_millis(1750)
+                && elapsed < Duration::from_secs(3),
+            "limited upload took {:?}",
+            elapsed
+        );
+
+        limits(&env).set_rates(None, None);
+        let elapsed = env.request_blocks(unlimited).await;
+        assert!(
+            elapsed < Duration::from_secs(1),
+            "unlimited upload took {:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn should_limit_download_rate_of_peer() {
+        assert_download_rate_limited(|env| &env.rate_limits).await;
+    }
+
+    #[tokio::test]
+    async fn should_limit_download_rate_of_torrent() {
+        assert_download_rate_limited(|env| &env.torrent.rate_limits).await;
+    }
+
+    #[tokio::test]
+    async fn should_limit_download_rate_of_engine() {
+        assert_download_rate_limited(|env| &env.torrent.engine_rate_limits)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn should_limit_upload_rate_of_peer() {
+        assert_upload_rate_limited(|env| &env.rate_limits).await;
+    }
+
+    #[tokio::test]
+    async fn should_limit_upload_rate_of_torrent() {
+        assert_upload_rate_limited(|env| &env.torrent.rate_limits).await;
+    }
+
+    #[tokio::test]
+    async fn should_limit_upload_rate_of_engine() {
+        assert_upload_rate_limited(|env| &env.torrent.engine_rate_limits).await;
+    }
+
+    /// Tests that when a peer with the Fast extension is choked, its pending
+    /// requests are rejected, except for those of the pieces in its allowed
+    /// fast set, which are still served.
+    #[tokio::test]
+    async fn should_reject_requests_on_choke_except_allowed_fast() {
+        let mut env = Env::new(EnvConf {
+            has_all_pieces: true,
+            supports_fast: true,
+            ..EnvConf::default()
+        })
+        .await;
+
+        assert_eq!(env.recv().await, Message::HaveAll);
+        let mut allowed_fast = HashSet::new();
+        for _ in 0..ALLOWED_FAST_SET_LEN {
+            match env.recv().await {
+                Message::AllowedFast { piece_index } => {
+                    allowed_fast.insert(piece_index);
+                }
+                msg => panic!("expected allowed fast message, got {:?}", msg),
+            }
+        }
+        env.send(Message::HaveNone).await;
+        env.send(Message::Interested).await;
+        env.command(Command::Unchoke);
+        assert_eq!(env.recv().await, Message::Unchoke);
+
+        // request a block of an allowed fast piece and one of another piece
+        let allowed = BlockInfo {
+            piece_index: *allowed_fast.iter().next().unwrap(),
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        let other = BlockInfo {
+            piece_index: (0..PIECE_COUNT)
+                .find(|index| !allowed_fast.contains(index))
+                .unwrap(),
+            offset: 0,
+            len: BLOCK_LEN,
+        };
+        env.send(Message::Request(other)).await;
+        env.send(Message::Request(allowed)).await;
+        let mut reads = Vec::new();
+        for _ in 0..2 {
+            match env.recv_disk().await {
+                disk::Command::ReadBlock {
+                    block_info,
+                    result_tx,
+                    ..
+                } => reads.push((block_info, result_tx)),
+                cmd => panic!("expected block read, got {:?}", cmd),
+            }
+        }
+        assert_eq!(reads[0].0, other);
+        assert_eq!(reads[1].0, allowed);
+
+        // choke the peer while the blocks are being read
+        env.command(Command::Choke);
+        assert_eq!(env.recv().await, Message::Choke);
+        assert_eq!(env.recv().await, Message::RejectRequest(other));
+
+        // of the blocks read afterwards, only the allowed fast one is sent
+        let data = vec![7; BLOCK_LEN as usize];
+        for (block_info, result_tx) in reads {
+            let block = Block::new(block_info, data.clone());
+            assert!(result_tx.send(Command::Block(block)).is_ok());
+        }
+        assert_eq!(
+            env.recv().await,
+            Message::Block {
+                piece_index: allowed.piece_index,
+                offset: 0,
+                data: BlockData::Owned(data),
+            }
+        );
+    }
+
+    /// Sends the session a peer exchange message with a peer, after the
+    /// extended handshakes, and returns the peers that the session passed on
+    /// to its torrent.
+    ///
+    /// The message is sent under the id with which sessions of public
+    /// torrents receive peer exchange messages, whether or not the session
+    /// advertised the extension.
+    async fn exchange_peers(is_private: bool) -> Vec<SocketAddr> {
+        let mut env = Env::new(EnvConf {
+            supports_extensions: true,
+            is_private,
+            ..EnvConf::default()
+        })
+        .await;
+
+        let handshake = match env.recv().await {
+            Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload,
+            } => ExtendedHandshake::decode(&payload).unwrap(),
+            msg => panic!("expected extended handshake, got {:?}", msg),
+        };
+        assert!(handshake.extension_id(metadata::UT_METADATA).is_some());
+        assert_eq!(handshake.extension_id(UT_PEX).is_some(), !is_private);
+
+        let mut peer_handshake = ExtendedHandshake::default();
+        peer_handshake.m.insert(UT_PEX.into(), 1);
+        env.send(Message::Extended {
+            id: EXTENDED_HANDSHAKE_ID,
+            payload: peer_handshake.encode().unwrap(),
+        })
+        .await;
+        // the peer exchange extension is registered after the metadata one
+        let mut payload = b"d5:added6:\x7f\x00\x00\x01\x1a\xe1".to_vec();
+        payload.extend_from_slice(b"7:added.f1:\x00e");
+        env.send(Message::Extended { id: 2, payload }).await;
+
+        // the session keeps sending its torrent other commands, and may also
+        // end the connection over the message, so the peers are only waited
+        // for a while
+        time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(torrent::Command::PexPeers { peers, .. }) =
+                    env.torrent_rx.recv().await
+                {
+                    return peers;
+                }
+            }
+        })
+        .await
+        .unwrap_or_default()
+    }
+
+    /// Tests that the session of a public torrent advertises peer exchange
+    /// and passes the peers it receives on to its torrent.
+    #[tokio::test]
+    async fn should_exchange_peers_of_public_torrent() {
+        assert_eq!(
+            exchange_peers(false).await,
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))]
+        );
+    }
+
+    /// Tests that the session of a private torrent neither advertises peer
+    /// exchange nor accepts peers through it.
+    #[tokio::test]
+    async fn should_not_exchange_peers_of_private_torrent() {
+        assert!(exchange_peers(true).await.is_empty());
+    }
+}
@@ -6,7 +6,7 @@ use std::{
 use bytes::{Buf, BufMut, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::{Bitfield, BlockData, BlockInfo};
+use crate::{Bitfield, BlockData, BlockInfo, Sha256Hash};
 
 /// The message sent at the beginning of a peer session by both sides of the
 /// connection.
@@ -43,8 +43,57 @@ impl Handshake {
     pub const fn len(&self) -> u64 {
         19 + 8 + 20 + 20
     }
+
+    /// Announces support for the extension protocol (BEP 10) by setting the
+    /// 20th bit from the right in the reserved field.
+    pub fn set_extension_protocol(&mut self) {
+        self.reserved[5] |= EXTENSION_PROTOCOL_BIT;
+    }
+
+    /// Returns whether the client sending the handshake supports the extension
+    /// protocol.
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[5] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    /// Announces support for the v2 protocol (BEP 52) by setting the 5th bit
+    /// from the right in the reserved field, which tells peers of hybrid
+    /// torrents that they may upgrade to it.
+    pub fn set_v2(&mut self) {
+        self.reserved[7] |= V2_BIT;
+    }
+
+    /// Returns whether the client sending the handshake supports the v2
+    /// protocol.
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[7] & V2_BIT != 0
+    }
+
+    /// Announces support for the Fast extension (BEP 6) by setting the 3rd
+    /// bit from the right in the reserved field.
+    pub fn set_fast_extension(&mut self) {
+        self.reserved[7] |= FAST_EXTENSION_BIT;
+    }
+
+    /// Returns whether the client sending the handshake supports the Fast
+    /// extension.
+    pub fn supports_fast_extension(&self) -> bool {
+        self.reserved[7] & FAST_EXTENSION_BIT != 0
+    }
 }
 
+/// The bit in the 6th byte of the reserved field that is set if the extension
+/// protocol is supported.
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+/// The bit in the last byte of the reserved field that is set if the v2
+/// protocol is supported.
+const V2_BIT: u8 = 0x10;
+
+/// The bit in the last byte of the reserved field that is set if the Fast
+/// extension is supported.
+const FAST_EXTENSION_BIT: u8 = 0x04;
+
 /// The protocol version 1 string included in the handshake.
 pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
@@ -56,6 +105,7 @@ pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 /// receiving and sending a handshake the codec should be switched to
 /// [`PeerCodec`], but care should be taken not to discard the underlying
 /// receive and send buffers.
+#[derive(Debug)]
 pub(crate) struct HandshakeCodec;
 
 impl Encoder<Handshake> for HandshakeCodec {
@@ -110,7 +160,7 @@ impl Decoder for HandshakeCodec {
         // won't advance `buf`'s cursor
         let mut tmp_buf = buf.bytes();
         let prot_len = tmp_buf.get_u8() as usize;
-        if prot_len != PROTOCOL_STRING.as_bytes().len() {
+        if prot_len != PROTOCOL_STRING.len() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Handshake must have the string \"BitTorrent protocol\"",
@@ -172,6 +222,84 @@ pub(crate) enum Message {
         data: BlockData,
     },
     Cancel(BlockInfo),
+    /// Advises the recipient that downloading the piece may be a good idea,
+    /// e.g. because the sender has it in its disk cache (BEP 6).
+    SuggestPiece {
+        piece_index: usize,
+    },
+    /// Sent in place of the bitfield when the sender has all pieces (BEP 6).
+    HaveAll,
+    /// Sent in place of the bitfield when the sender has no pieces (BEP 6).
+    HaveNone,
+    /// Tells the recipient that its request for the block is not going to be
+    /// served (BEP 6).
+    RejectRequest(BlockInfo),
+    /// Tells the recipient that it may request blocks of the piece even while
+    /// it's choked (BEP 6).
+    AllowedFast {
+        piece_index: usize,
+    },
+    /// A message of the extension protocol (BEP 10). The id is that of the
+    /// extended message: 0 for the extended handshake, or the id the
+    /// recipient assigned to the extension in its extended handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A request for a range of hashes of a file's merkle tree (BEP 52).
+    HashRequest(HashRequest),
+    /// The hashes requested in a hash request, followed by the uncle hashes
+    /// with which they can be verified against the file's root.
+    Hashes {
+        request: HashRequest,
+        hashes: Vec<Sha256Hash>,
+    },
+    /// Sent in response to a hash request that is not going to be served.
+    HashReject(HashRequest),
+}
+
+/// The fields that identify a range of hashes in a file's merkle tree, which
+/// are shared by the hash request, hashes, and hash reject messages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct HashRequest {
+    /// The root of the file's merkle tree.
+    pub pieces_root: Sha256Hash,
+    /// The layer of the tree, counting from the leaves, that the requested
+    /// hashes are in.
+    pub base_layer: u32,
+    /// The index of the first requested hash in the layer.
+    pub index: u32,
+    /// The number of requested hashes.
+    pub len: u32,
+    /// The number of layers above the requested hashes whose uncle hashes are
+    /// requested too.
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    /// Encodes the hash request fields in the network binary protocol's format
+    /// into the given buffer.
+    fn encode(&self, buf: &mut BytesMut) {
+        buf.extend_from_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.len);
+        buf.put_u32(self.proof_layers);
+    }
+
+    /// Decodes the hash request fields from the given buffer, which must hold
+    /// at least 48 bytes.
+    fn decode(buf: &mut BytesMut) -> Self {
+        let mut pieces_root = [0; 32];
+        buf.copy_to_slice(&mut pieces_root);
+        Self {
+            pieces_root,
+            base_layer: buf.get_u32(),
+            index: buf.get_u32(),
+            len: buf.get_u32(),
+            proof_layers: buf.get_u32(),
+        }
+    }
 }
 
 impl Message {
@@ -188,12 +316,24 @@ impl Message {
             Self::Request(_) => Some(MessageId::Request),
             Self::Block { .. } => Some(MessageId::Block),
             Self::Cancel(_) => Some(MessageId::Cancel),
+            Self::SuggestPiece { .. } => Some(MessageId::SuggestPiece),
+            Self::HaveAll => Some(MessageId::HaveAll),
+            Self::HaveNone => Some(MessageId::HaveNone),
+            Self::RejectRequest(_) => Some(MessageId::RejectRequest),
+            Self::AllowedFast { .. } => Some(MessageId::AllowedFast),
+            Self::Extended { .. } => Some(MessageId::Extended),
+            Self::HashRequest(_) => Some(MessageId::HashRequest),
+            Self::Hashes { .. } => Some(MessageId::Hashes),
+            Self::HashReject(_) => Some(MessageId::HashReject),
         }
     }
 
     /// Returns the length of the part of the message that constitutes the
     /// message header. For all but the block message this is simply the size of
     /// the message. For the block message this is the message header.
+    ///
+    /// Note that extended messages, like bitfields, are only counted by their
+    /// header.
     pub fn protocol_len(&self) -> u64 {
         if let Some(id) = self.id() {
             id.header_len()
@@ -219,6 +359,15 @@ pub(crate) enum MessageId {
     Request = 6,
     Block = 7,
     Cancel = 8,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
 }
 
 impl MessageId {
@@ -237,6 +386,15 @@ impl MessageId {
             Self::Request => 4 + 1 + 3 * 4,
             Self::Block => 4 + 1 + 2 * 4,
             Self::Cancel => 4 + 1 + 3 * 4,
+            Self::SuggestPiece => 4 + 1 + 4,
+            Self::HaveAll => 4 + 1,
+            Self::HaveNone => 4 + 1,
+            Self::RejectRequest => 4 + 1 + 3 * 4,
+            Self::AllowedFast => 4 + 1 + 4,
+            Self::Extended => 4 + 1 + 1,
+            Self::HashRequest => 4 + 1 + 32 + 4 * 4,
+            Self::Hashes => 4 + 1 + 32 + 4 * 4,
+            Self::HashReject => 4 + 1 + 32 + 4 * 4,
         }
     }
 }
@@ -256,6 +414,15 @@ impl TryFrom<u8> for MessageId {
             k if k == Request as u8 => Ok(Request),
             k if k == Block as u8 => Ok(Block),
             k if k == Cancel as u8 => Ok(Cancel),
+            k if k == SuggestPiece as u8 => Ok(SuggestPiece),
+            k if k == HaveAll as u8 => Ok(HaveAll),
+            k if k == HaveNone as u8 => Ok(HaveNone),
+            k if k == RejectRequest as u8 => Ok(RejectRequest),
+            k if k == AllowedFast as u8 => Ok(AllowedFast),
+            k if k == Extended as u8 => Ok(Extended),
+            k if k == HashRequest as u8 => Ok(HashRequest),
+            k if k == Hashes as u8 => Ok(Hashes),
+            k if k == HashReject as u8 => Ok(HashReject),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Unknown message id",
@@ -394,6 +561,106 @@ impl Encoder<Message> for PeerCodec {
                 // payload
                 block.encode(buf)?;
             }
+            SuggestPiece { piece_index } => {
+                // message length prefix:
+                // 1 byte message id and 4 byte piece index
+                let msg_len = 1 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::SuggestPiece as u8);
+                // payload
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                buf.put_u32(piece_index);
+            }
+            HaveAll => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveAll as u8);
+                // no payload
+            }
+            HaveNone => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveNone as u8);
+                // no payload
+            }
+            RejectRequest(block) => {
+                // message length prefix:
+                // 1 byte message id, 4 byte piece index, 4 byte offset, 4 byte
+                // length
+                let msg_len = 1 + 4 + 4 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::RejectRequest as u8);
+                // payload
+                block.encode(buf)?;
+            }
+            AllowedFast { piece_index } => {
+                // message length prefix:
+                // 1 byte message id and 4 byte piece index
+                let msg_len = 1 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::AllowedFast as u8);
+                // payload
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                buf.put_u32(piece_index);
+            }
+            Extended { id, payload } => {
+                // message length prefix:
+                // 1 byte message id, 1 byte extended message id, and n byte
+                // payload
+                let msg_len = 1 + 1 + payload.len() as u32;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::Extended as u8);
+                // payload
+                buf.put_u8(id);
+                buf.extend_from_slice(&payload);
+            }
+            HashRequest(request) => {
+                // message length prefix:
+                // 1 byte message id, 32 byte pieces root, 4 byte base layer,
+                // 4 byte index, 4 byte length, 4 byte proof layers
+                let msg_len = 1 + 32 + 4 * 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HashRequest as u8);
+                // payload
+                request.encode(buf);
+            }
+            Hashes { request, hashes } => {
+                // message length prefix:
+                // 1 byte message id, 48 byte hash request fields, and n * 32
+                // byte hashes
+                let msg_len = 1 + 32 + 4 * 4 + hashes.len() as u32 * 32;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::Hashes as u8);
+                // payload
+                request.encode(buf);
+                for hash in hashes.iter() {
+                    buf.extend_from_slice(hash);
+                }
+            }
+            HashReject(request) => {
+                // message length prefix:
+                // 1 byte message id and 48 byte hash request fields
+                let msg_len = 1 + 32 + 4 * 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HashReject as u8);
+                // payload
+                request.encode(buf);
+            }
         }
 
         Ok(())
@@ -520,6 +787,81 @@ impl Decoder for PeerCodec {
                     len,
                 })
             }
+            MessageId::SuggestPiece => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::SuggestPiece { piece_index }
+            }
+            MessageId::HaveAll => Message::HaveAll,
+            MessageId::HaveNone => Message::HaveNone,
+            MessageId::RejectRequest => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                let offset = buf.get_u32();
+                let len = buf.get_u32();
+                Message::RejectRequest(BlockInfo {
+                    piece_index,
+                    offset,
+                    len,
+                })
+            }
+            MessageId::AllowedFast => {
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::AllowedFast { piece_index }
+            }
+            MessageId::Extended => {
+                // the extended message id must be present
+                if msg_len < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Extended message must have an id",
+                    ));
+                }
+                let id = buf.get_u8();
+                let mut payload = vec![0; msg_len - 2];
+                buf.copy_to_slice(&mut payload);
+                Message::Extended { id, payload }
+            }
+            MessageId::HashRequest
+            | MessageId::Hashes
+            | MessageId::HashReject => {
+                // all hash messages start with the hash request fields, which
+                // in the hashes message are followed by whole hashes
+                let hashes_len = msg_len
+                    .checked_sub(1 + 32 + 4 * 4)
+                    .filter(|len| match msg_id {
+                        MessageId::Hashes => len % 32 == 0,
+                        _ => *len == 0,
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Invalid hash message length",
+                        )
+                    })?;
+                let request = HashRequest::decode(buf);
+                match msg_id {
+                    MessageId::HashRequest => Message::HashRequest(request),
+                    MessageId::HashReject => Message::HashReject(request),
+                    _ => {
+                        let hashes = (0..hashes_len / 32)
+                            .map(|_| {
+                                let mut hash = [0; 32];
+                                buf.copy_to_slice(&mut hash);
+                                hash
+                            })
+                            .collect();
+                        Message::Hashes { request, hashes }
+                    }
+                }
+            }
         };
 
         Ok(Some(msg))
@@ -555,7 +897,16 @@ mod tests {
             make_interested(),
             make_cancel(),
             make_block(),
+            make_suggest_piece(),
+            make_have_all(),
+            make_have_none(),
+            make_reject_request(),
+            make_allowed_fast(),
+            make_extended(),
             make_not_interested(),
+            make_hash_request(),
+            make_hashes(),
+            make_hash_reject(),
             make_choke(),
             make_choke(),
         ];
@@ -618,7 +969,16 @@ mod tests {
             make_interested(),
             make_cancel(),
             make_block(),
+            make_suggest_piece(),
+            make_have_all(),
+            make_have_none(),
+            make_reject_request(),
+            make_allowed_fast(),
+            make_extended(),
             make_not_interested(),
+            make_hash_request(),
+            make_hashes(),
+            make_hash_reject(),
             make_choke(),
             make_choke(),
         ];
@@ -659,6 +1019,44 @@ mod tests {
         assert_eq!(decoded, Some(handshake));
     }
 
+    /// Tests that the extension protocol bit is set in and read from the
+    /// correct position in the reserved field.
+    #[test]
+    fn test_handshake_extension_protocol_bit() {
+        let (mut handshake, _) = make_handshake();
+        assert!(!handshake.supports_extension_protocol());
+
+        handshake.set_extension_protocol();
+        assert!(handshake.supports_extension_protocol());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0]);
+    }
+
+    /// Tests that the v2 protocol bit is set in and read from the correct
+    /// position in the reserved field.
+    #[test]
+    fn test_handshake_v2_bit() {
+        let (mut handshake, _) = make_handshake();
+        assert!(!handshake.supports_v2());
+
+        handshake.set_v2();
+        assert!(handshake.supports_v2());
+        assert!(!handshake.supports_extension_protocol());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0, 0, 0x10]);
+    }
+
+    /// Tests that the Fast extension bit is set in and read from the correct
+    /// position in the reserved field.
+    #[test]
+    fn test_handshake_fast_extension_bit() {
+        let (mut handshake, _) = make_handshake();
+        assert!(!handshake.supports_fast_extension());
+
+        handshake.set_fast_extension();
+        assert!(handshake.supports_fast_extension());
+        assert!(!handshake.supports_v2());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+    }
+
     /// Tests that the decoding of various invalid handshake messages results in
     /// an error.
     #[test]
@@ -805,6 +1203,106 @@ mod tests {
         assert_message_codec(msg, expected_encoded);
     }
 
+    /// Tests the encoding and subsequent decoding of a valid 'suggest piece'
+    /// message.
+    #[test]
+    fn test_suggest_piece_codec() {
+        let (msg, expected_encoded) = make_suggest_piece();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'have all'
+    /// message.
+    #[test]
+    fn test_have_all_codec() {
+        let (msg, expected_encoded) = make_have_all();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'have none'
+    /// message.
+    #[test]
+    fn test_have_none_codec() {
+        let (msg, expected_encoded) = make_have_none();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'reject request'
+    /// message.
+    #[test]
+    fn test_reject_request_codec() {
+        let (msg, expected_encoded) = make_reject_request();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'allowed fast'
+    /// message.
+    #[test]
+    fn test_allowed_fast_codec() {
+        let (msg, expected_encoded) = make_allowed_fast();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'extended' message.
+    #[test]
+    fn test_extended_codec() {
+        let (msg, expected_encoded) = make_extended();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests that an extended message without the extended message id is
+    /// rejected.
+    #[test]
+    fn test_invalid_extended_decoding() {
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1);
+        encoded.put_u8(MessageId::Extended as u8);
+        assert!(PeerCodec.decode(&mut encoded).is_err());
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'hash request'
+    /// message.
+    #[test]
+    fn test_hash_request_codec() {
+        let (msg, expected_encoded) = make_hash_request();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'hashes' message.
+    #[test]
+    fn test_hashes_codec() {
+        let (msg, expected_encoded) = make_hashes();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests the encoding and subsequent decoding of a valid 'hash reject'
+    /// message.
+    #[test]
+    fn test_hash_reject_codec() {
+        let (msg, expected_encoded) = make_hash_reject();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests that hash messages whose length doesn't match their fields are
+    /// rejected.
+    #[test]
+    fn test_invalid_hash_message_decoding() {
+        // a hash request that is too short
+        let mut encoded = BytesMut::new();
+        encoded.put_u32(1 + 32);
+        encoded.put_u8(MessageId::HashRequest as u8);
+        encoded.extend_from_slice(&[0; 32]);
+        assert!(PeerCodec.decode(&mut encoded).is_err());
+
+        // a hashes message with a partial hash
+        let (_, encoded) = make_hashes();
+        let mut encoded = BytesMut::from(&encoded[..]);
+        encoded.extend_from_slice(&[0; 5]);
+        let msg_len = encoded.len() as u32 - 4;
+        encoded[..4].copy_from_slice(&msg_len.to_be_bytes());
+        assert!(PeerCodec.decode(&mut encoded).is_err());
+    }
+
     /// Helper function that asserts that a message is encoded and subsequently
     /// decoded correctly.
     fn assert_message_codec(msg: Message, expected_encoded: Bytes) {
@@ -859,8 +1357,25 @@ mod tests {
         )
     }
 
-    /// Helper used to create 'choke', 'unchoke', 'interested', and 'not
-    /// interested' encoded messages that all have the same format.
+    /// Returns `HaveAll` and its expected encoded variant.
+    fn make_have_all() -> (Message, Bytes) {
+        (
+            Message::HaveAll,
+            make_empty_msg_encoded_payload(MessageId::HaveAll),
+        )
+    }
+
+    /// Returns `HaveNone` and its expected encoded variant.
+    fn make_have_none() -> (Message, Bytes) {
+        (
+            Message::HaveNone,
+            make_empty_msg_encoded_payload(MessageId::HaveNone),
+        )
+    }
+
+    /// Helper used to create 'choke', 'unchoke', 'interested', 'not
+    /// interested', 'have all', and 'have none' encoded messages that all have
+    /// the same format.
     fn make_empty_msg_encoded_payload(id: MessageId) -> Bytes {
         // 1 byte message id
         let msg_len = 1;
@@ -898,19 +1413,49 @@ mod tests {
     fn make_have() -> (Message, Bytes) {
         let piece_index = 42;
         let msg = Message::Have { piece_index };
-        let encoded = {
-            // 1 byte message id and 4 byte piece index
-            let msg_len = 1 + 4;
-            // 4 byte message length prefix and message length
-            let buf_len = 4 + msg_len;
-            let mut buf = BytesMut::with_capacity(buf_len);
-            buf.put_u32(msg_len as u32);
-            buf.put_u8(MessageId::Have as u8);
-            // ok to unwrap, only used in tests
-            buf.put_u32(piece_index.try_into().unwrap());
-            buf
-        };
-        (msg, encoded.into())
+        let encoded =
+            make_piece_index_encoded_msg_payload(MessageId::Have, piece_index);
+        (msg, encoded)
+    }
+
+    /// Returns `SuggestPiece` and its expected encoded variant.
+    fn make_suggest_piece() -> (Message, Bytes) {
+        let piece_index = 42;
+        let msg = Message::SuggestPiece { piece_index };
+        let encoded = make_piece_index_encoded_msg_payload(
+            MessageId::SuggestPiece,
+            piece_index,
+        );
+        (msg, encoded)
+    }
+
+    /// Returns `AllowedFast` and its expected encoded variant.
+    fn make_allowed_fast() -> (Message, Bytes) {
+        let piece_index = 42;
+        let msg = Message::AllowedFast { piece_index };
+        let encoded = make_piece_index_encoded_msg_payload(
+            MessageId::AllowedFast,
+            piece_index,
+        );
+        (msg, encoded)
+    }
+
+    /// Helper used to create 'have', 'suggest piece', and 'allowed fast'
+    /// encoded messages that have the same format.
+    fn make_piece_index_encoded_msg_payload(
+        id: MessageId,
+        piece_index: usize,
+    ) -> Bytes {
+        // 1 byte message id and 4 byte piece index
+        let msg_len = 1 + 4;
+        // 4 byte message length prefix and message length
+        let buf_len = 4 + msg_len;
+        let mut buf = BytesMut::with_capacity(buf_len);
+        buf.put_u32(msg_len as u32);
+        buf.put_u8(id as u8);
+        // ok to unwrap, only used in tests
+        buf.put_u32(piece_index.try_into().unwrap());
+        buf.into()
     }
 
     /// Returns `Request` and its expected encoded variant.
@@ -980,8 +1525,113 @@ mod tests {
         (msg, encoded)
     }
 
-    /// Helper used to create 'request' and 'cancel' encoded messages that have
-    /// the same format.
+    /// Returns `RejectRequest` and its expected encoded variant.
+    fn make_reject_request() -> (Message, Bytes) {
+        let piece_index = 42;
+        let offset = 0x4000;
+        let len = BLOCK_LEN;
+        let msg = Message::RejectRequest(BlockInfo {
+            piece_index,
+            offset,
+            len,
+        });
+        let encoded = make_block_info_encoded_msg_payload(
+            MessageId::RejectRequest,
+            piece_index,
+            offset,
+            len,
+        );
+        (msg, encoded)
+    }
+
+    /// Returns `Extended` and its expected encoded variant.
+    fn make_extended() -> (Message, Bytes) {
+        let id = 3;
+        let payload = b"d8:msg_typei0e5:piecei0ee".to_vec();
+        let encoded = {
+            // 1 byte message id, 1 byte extended message id, and n byte
+            // payload
+            let msg_len = 1 + 1 + payload.len();
+            // 4 byte message length prefix and message length
+            let buf_len = 4 + msg_len;
+            let mut buf = BytesMut::with_capacity(buf_len);
+            buf.put_u32(msg_len as u32);
+            buf.put_u8(MessageId::Extended as u8);
+            buf.put_u8(id);
+            buf.extend_from_slice(&payload);
+            buf
+        };
+        let msg = Message::Extended { id, payload };
+        (msg, encoded.into())
+    }
+
+    /// Returns the hash request fields used by the hash message test cases.
+    fn hash_request() -> HashRequest {
+        HashRequest {
+            pieces_root: [7; 32],
+            base_layer: 2,
+            index: 4,
+            len: 2,
+            proof_layers: 3,
+        }
+    }
+
+    /// Returns `HashRequest` and its expected encoded variant.
+    fn make_hash_request() -> (Message, Bytes) {
+        let request = hash_request();
+        let encoded = make_hash_msg_encoded_payload(
+            MessageId::HashRequest,
+            &request,
+            &[],
+        );
+        (Message::HashRequest(request), encoded)
+    }
+
+    /// Returns `Hashes` and its expected encoded variant.
+    fn make_hashes() -> (Message, Bytes) {
+        let request = hash_request();
+        let hashes = vec![[1; 32], [2; 32], [3; 32], [4; 32], [5; 32]];
+        let encoded =
+            make_hash_msg_encoded_payload(MessageId::Hashes, &request, &hashes);
+        (Message::Hashes { request, hashes }, encoded)
+    }
+
+    /// Returns `HashReject` and its expected encoded variant.
+    fn make_hash_reject() -> (Message, Bytes) {
+        let request = hash_request();
+        let encoded =
+            make_hash_msg_encoded_payload(MessageId::HashReject, &request, &[]);
+        (Message::HashReject(request), encoded)
+    }
+
+    /// Helper used to create 'hash request', 'hashes' and 'hash reject'
+    /// encoded messages that share the same fields.
+    fn make_hash_msg_encoded_payload(
+        id: MessageId,
+        request: &HashRequest,
+        hashes: &[Sha256Hash],
+    ) -> Bytes {
+        // 1 byte message id, 32 byte pieces root, 4 byte base layer, 4 byte
+        // index, 4 byte length, 4 byte proof layers, and n * 32 byte hashes
+        let msg_len = 1 + 32 + 4 * 4 + hashes.len() * 32;
+        // 4 byte message length prefix and message length
+        let buf_len = 4 + msg_len;
+        let mut buf = BytesMut::with_capacity(buf_len);
+        buf.put_u32(msg_len as u32);
+        buf.put_u8(id as u8);
+        buf.extend_from_slice(&request.pieces_root);
+        buf.put_u32(request.base_layer);
+        buf.put_u32(request.index);
+        buf.put_u32(request.len);
+        buf.put_u32(request.proof_layers);
+        for hash in hashes {
+            buf.extend_from_slice(hash);
+        }
+        buf.into()
+    }
+
+    /// Helper used to create 'request', 'cancel', and 'reject request' encoded
+    /// messages that have the same format.
     fn make_block_info_encoded_msg_payload(
         id: MessageId,
         piece_index: usize,
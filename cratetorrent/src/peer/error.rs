@@ -1,5 +1,6 @@
 use std::fmt;
 
+pub use serde_bencode::Error as BencodeError;
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
 pub(crate) type Result<T, E = PeerError> = std::result::Result<T, E>;
@@ -11,6 +12,9 @@ pub(crate) type Result<T, E = PeerError> = std::result::Result<T, E>;
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum PeerError {
+    /// Holds bencode serialization or deserialization related errors of
+    /// extended messages.
+    Bencode(BencodeError),
     /// The bitfield message was not sent after the handshake. According to the
     /// protocol, it should only be accepted after the handshake and when
     /// received at any other time, connection is severed.
@@ -30,14 +34,59 @@ pub enum PeerError {
     InvalidPieceIndex,
     /// Peer's torrent info hash did not match ours.
     InvalidInfoHash,
+    /// The peer doesn't support the extension protocol or the extension we
+    /// need.
+    ExtensionUnsupported,
+    /// The peer sent a message of the Fast extension without announcing
+    /// support for it in its handshake.
+    FastExtensionNotNegotiated,
+    /// The peer sent an extended message that is semantically invalid.
+    InvalidExtendedMessage,
+    /// The peer rejected our request for a piece of the torrent metadata.
+    MetadataRejected,
+    /// The metadata the peer sent is invalid: either its advertised size or
+    /// its hash doesn't match the torrent's info hash.
+    InvalidMetadata,
+    /// The peer rejected our request for hashes of a file's piece layer.
+    HashesRejected,
+    /// The hashes the peer sent don't match the file's pieces root.
+    InvalidHashes,
+    /// The peer's stream encryption handshake is invalid.
+    InvalidEncryptionHandshake,
+    /// The peer's connection is not allowed by our encryption policy: it's
+    /// encrypted while encryption is disabled, or the other way around.
+    EncryptionPolicyMismatch,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
 
+impl PeerError {
+    /// Returns whether the error is due to the peer breaking the protocol,
+    /// in which case the peer is banned.
+    ///
+    /// Errors that may also happen to well-behaved peers, such as IO errors
+    /// or connecting to a peer that no longer has the torrent, are not
+    /// violations.
+    pub fn is_protocol_violation(&self) -> bool {
+        use PeerError::*;
+        matches!(
+            self,
+            Bencode(_)
+                | BitfieldNotAfterHandshake
+                | RequestWhileChoked
+                | InvalidBlockInfo
+                | InvalidPieceIndex
+                | InvalidExtendedMessage
+                | FastExtensionNotNegotiated
+        )
+    }
+}
+
 impl fmt::Display for PeerError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use PeerError::*;
         match self {
+            Bencode(e) => write!(fmt, "{}", e),
             BitfieldNotAfterHandshake => {
                 write!(fmt, "received unexpected bitfield")
             }
@@ -49,11 +98,32 @@ impl fmt::Display for PeerError {
             InvalidBlockInfo => write!(fmt, "invalid block info"),
             InvalidPieceIndex => write!(fmt, "invalid piece index"),
             InvalidInfoHash => write!(fmt, "invalid info hash"),
+            ExtensionUnsupported => write!(fmt, "extension not supported"),
+            FastExtensionNotNegotiated => {
+                write!(fmt, "fast extension not negotiated")
+            }
+            InvalidExtendedMessage => write!(fmt, "invalid extended message"),
+            MetadataRejected => write!(fmt, "metadata request rejected"),
+            InvalidMetadata => write!(fmt, "invalid metadata"),
+            HashesRejected => write!(fmt, "hash request rejected"),
+            InvalidHashes => write!(fmt, "invalid hashes"),
+            InvalidEncryptionHandshake => {
+                write!(fmt, "invalid encryption handshake")
+            }
+            EncryptionPolicyMismatch => {
+                write!(fmt, "encryption policy mismatch")
+            }
             Io(e) => write!(fmt, "{}", e),
         }
     }
 }
 
+impl From<BencodeError> for PeerError {
+    fn from(e: BencodeError) -> Self {
+        Self::Bencode(e)
+    }
+}
+
 impl From<IoError> for PeerError {
     fn from(e: IoError) -> Self {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
@@ -0,0 +1,361 @@
+//! This module contains the types of the [extension
+//! protocol](http://bittorrent.org/beps/bep_0010.html).
+//!
+//! Extensions are negotiated with the extended handshake, which is sent as an
+//! extended message with id 0 after the BitTorrent handshake, if both sides
+//! set the extension protocol bit in their handshake.
+//!
+//! Each extension that we support is implemented as an [`ExtensionHandler`],
+//! registered with the peer session's [`Extensions`] registry, which
+//! dispatches the peer's extended messages to the handler of the extension.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    bencode,
+    peer::{codec::Message, error::*},
+};
+
+/// The id of the extended handshake message.
+pub(crate) const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// The client name and version advertised in the extended handshake.
+pub(crate) const CLIENT_NAME: &str =
+    concat!("cratetorrent ", env!("CARGO_PKG_VERSION"));
+
+/// The bencoded dictionary sent in the extended handshake.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    /// Maps the names of the extensions supported by the sender to the
+    /// extended message ids with which it wishes to receive them. An id of
+    /// 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// The length of the torrent's info dictionary, sent by peers supporting
+    /// the metadata extension that have the metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+    /// The name and version of the sender's client.
+    ///
+    /// This is kept as raw bytes as not all clients send valid UTF-8 here, and
+    /// we don't want to reject the whole handshake because of it.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    pub v: Option<Vec<u8>>,
+    /// The TCP port on which the sender listens for connections.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// The number of outstanding requests the sender accepts without dropping
+    /// any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// The IP address of the receiver as seen by the sender, in compact form
+    /// (4 bytes for IPv4 and 16 bytes for IPv6).
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_bytes"
+    )]
+    pub yourip: Option<Vec<u8>>,
+}
+
+impl ExtendedHandshake {
+    /// Decodes the handshake from the payload of the extended message.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        Ok(serde_bencode::from_bytes(buf)?)
+    }
+
+    /// Encodes the handshake into the payload of the extended message.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(serde_bencode::to_bytes(self)?)
+    }
+
+    /// Returns the message id with which the sender of the handshake wishes to
+    /// receive messages of the extension, or `None` if the extension is not
+    /// supported or disabled.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&id) if id > 0 && id <= u8::MAX as i64 => Some(id as u8),
+            _ => None,
+        }
+    }
+
+    /// Returns the sender's client name, if it sent one.
+    pub fn client_name(&self) -> Option<String> {
+        self.v
+            .as_ref()
+            .map(|v| String::from_utf8_lossy(v).into_owned())
+    }
+}
+
+/// The implementation of an extension's messages, driven by the peer session.
+pub(crate) trait ExtensionHandler: Send {
+    /// The name under which the extension is advertised in the `m`
+    /// dictionary of the extended handshake.
+    fn name(&self) -> &'static str;
+
+    /// Adds the extension specific fields to our extended handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Handles the payload of a message of this extension sent by the peer,
+    /// returning the payloads of the messages to send back to the peer, if
+    /// any.
+    fn handle_msg(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+}
+
+/// The registry of the extensions supported in a peer session.
+///
+/// We receive the messages of each registered extension under the id that
+/// corresponds to the order of registration, starting from 1, as 0 is
+/// reserved for the extended handshake. The peer may choose different ids for
+/// the same extensions, which are learned from its extended handshake.
+#[derive(Default)]
+pub(crate) struct Extensions {
+    handlers: Vec<Box<dyn ExtensionHandler>>,
+    /// The ids with which the peer wishes to receive the messages of the
+    /// extensions it supports, by extension name.
+    peer_ids: HashMap<String, u8>,
+}
+
+impl Extensions {
+    /// Registers the handler of an extension.
+    pub fn register(&mut self, handler: Box<dyn ExtensionHandler>) {
+        debug_assert!(self.handlers.iter().all(|h| h.name() != handler.name()));
+        self.handlers.push(handler);
+    }
+
+    /// Returns our extended handshake, advertising the registered extensions.
+    ///
+    /// The fields that don't concern the extensions themselves are left empty
+    /// for the caller to fill in.
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::default();
+        for (id, handler) in self.ids_and_handlers() {
+            handshake.m.insert(handler.name().into(), id.into());
+            handler.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// Records the extensions supported by the peer and the ids under which it
+    /// wishes to receive their messages.
+    ///
+    /// The peer may send the handshake more than once, in which case only the
+    /// extensions present in the newer handshake are updated.
+    pub fn handle_handshake(&mut self, handshake: &ExtendedHandshake) {
+        for name in handshake.m.keys() {
+            match handshake.extension_id(name) {
+                Some(id) => {
+                    self.peer_ids.insert(name.clone(), id);
+                }
+                None => {
+                    self.peer_ids.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Returns the id with which the peer wishes to receive the messages of
+    /// the extension, or `None` if the peer doesn't support it.
+    pub fn peer_id(&self, name: &str) -> Option<u8> {
+        self.peer_ids.get(name).copied()
+    }
+
+    /// Passes the extended message to the handler of the extension registered
+    /// under the id, returning the messages that the handler wishes to send
+    /// back to the peer.
+    pub fn handle_msg(
+        &mut self,
+        id: u8,
+        payload: &[u8],
+    ) -> Result<Vec<Message>> {
+        let index = (id as usize)
+            .checked_sub(1)
+            .ok_or(PeerError::InvalidExtendedMessage)?;
+        let handler = self
+            .handlers
+            .get_mut(index)
+            .ok_or(PeerError::InvalidExtendedMessage)?;
+        let responses = handler.handle_msg(payload)?;
+        // the peer can only be sent messages of extensions it supports, and
+        // if it does, it must have registered an id for it
+        Ok(match self.peer_ids.get(handler.name()) {
+            Some(&id) => responses
+                .into_iter()
+                .map(|payload| Message::Extended { id, payload })
+                .collect(),
+            None => Vec::new(),
+        })
+    }
+
+    /// Returns the names of the extensions supported by both sides of the
+    /// connection.
+    pub fn negotiated(&self) -> Vec<String> {
+        self.handlers
+            .iter()
+            .map(|h| h.name())
+            .filter(|name| self.peer_ids.contains_key(*name))
+            .map(String::from)
+            .collect()
+    }
+
+    fn ids_and_handlers(
+        &self,
+    ) -> impl Iterator<Item = (u8, &Box<dyn ExtensionHandler>)> {
+        self.handlers
+            .iter()
+            .enumerate()
+            .map(|(index, handler)| (index as u8 + 1, handler))
+    }
+}
+
+/// Returns the length of the bencoded value at the start of the buffer, or
+/// `None` if the buffer doesn't start with a complete, structurally valid
+/// value.
+///
+/// This is needed where a bencoded dictionary is followed by raw data in the
+/// same message, as is the case with metadata extension messages.
+pub(crate) fn bencode_value_len(buf: &[u8]) -> Option<usize> {
+    bencode::decode_prefix(buf).ok().map(|(_, len)| len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_handshake() {
+        let mut handshake = ExtendedHandshake::default();
+        handshake.m.insert("ut_metadata".into(), 3);
+        handshake.metadata_size = Some(31235);
+
+        let encoded = handshake.encode().unwrap();
+        assert_eq!(
+            encoded,
+            b"d1:md11:ut_metadatai3ee13:metadata_sizei31235ee".to_vec()
+        );
+        assert_eq!(ExtendedHandshake::decode(&encoded).unwrap(), handshake);
+    }
+
+    #[test]
+    fn should_ignore_unknown_handshake_fields() {
+        let handshake = ExtendedHandshake::decode(
+            b"d1:md6:ut_pexi0e11:ut_metadatai2ee1:v5:hello4:reqqi250ee",
+        )
+        .unwrap();
+        assert_eq!(handshake.extension_id("ut_metadata"), Some(2));
+        // disabled extension
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        // unknown extension
+        assert_eq!(handshake.extension_id("lt_donthave"), None);
+        assert_eq!(handshake.metadata_size, None);
+    }
+
+    #[test]
+    fn should_encode_full_handshake() {
+        let handshake = ExtendedHandshake {
+            v: Some(b"cratetorrent 0.1.0".to_vec()),
+            p: Some(6881),
+            reqq: Some(250),
+            yourip: Some(vec![127, 0, 0, 1]),
+            ..Default::default()
+        };
+        let encoded = handshake.encode().unwrap();
+        assert_eq!(
+            encoded,
+            b"d1:mde1:pi6881e4:reqqi250e1:v18:cratetorrent 0.1.0\
+            6:yourip4:\x7f\0\0\x01e"
+                .to_vec()
+        );
+    }
+
+    /// Echoes the payload of each message back to the peer.
+    struct EchoHandler(&'static str);
+
+    impl ExtensionHandler for EchoHandler {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+            handshake.metadata_size = Some(42);
+        }
+
+        fn handle_msg(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn should_dispatch_to_registered_extensions() {
+        let mut extensions = Extensions::default();
+        extensions.register(Box::new(EchoHandler("ut_foo")));
+        extensions.register(Box::new(EchoHandler("ut_bar")));
+
+        let handshake = extensions.handshake();
+        assert_eq!(handshake.extension_id("ut_foo"), Some(1));
+        assert_eq!(handshake.extension_id("ut_bar"), Some(2));
+        assert_eq!(handshake.metadata_size, Some(42));
+        assert!(extensions.negotiated().is_empty());
+
+        // the peer doesn't support our extensions yet, so responses are
+        // dropped
+        assert!(extensions.handle_msg(1, b"hi").unwrap().is_empty());
+
+        let mut peer_handshake = ExtendedHandshake::default();
+        peer_handshake.m.insert("ut_bar".into(), 5);
+        peer_handshake.m.insert("ut_baz".into(), 6);
+        extensions.handle_handshake(&peer_handshake);
+        assert_eq!(extensions.negotiated(), vec!["ut_bar".to_string()]);
+        assert_eq!(extensions.peer_id("ut_bar"), Some(5));
+        assert_eq!(extensions.peer_id("ut_foo"), None);
+        assert_eq!(
+            extensions.handle_msg(2, b"hi").unwrap(),
+            vec![Message::Extended {
+                id: 5,
+                payload: b"hi".to_vec()
+            }]
+        );
+        assert!(extensions.handle_msg(1, b"hi").unwrap().is_empty());
+
+        // ids that we didn't assign are invalid
+        assert!(matches!(
+            extensions.handle_msg(3, b"hi"),
+            Err(PeerError::InvalidExtendedMessage)
+        ));
+        assert!(matches!(
+            extensions.handle_msg(0, b"hi"),
+            Err(PeerError::InvalidExtendedMessage)
+        ));
+
+        // a later handshake may disable an extension
+        let mut peer_handshake = ExtendedHandshake::default();
+        peer_handshake.m.insert("ut_bar".into(), 0);
+        extensions.handle_handshake(&peer_handshake);
+        assert!(extensions.negotiated().is_empty());
+    }
+
+    #[test]
+    fn should_return_bencode_value_len() {
+        assert_eq!(bencode_value_len(b"i42e"), Some(4));
+        assert_eq!(bencode_value_len(b"4:spamrest"), Some(6));
+        assert_eq!(bencode_value_len(b"le"), Some(2));
+        assert_eq!(
+            bencode_value_len(b"d8:msg_typei1e5:piecei0eeRAWDATA"),
+            Some(25)
+        );
+        assert_eq!(bencode_value_len(b"d1:ald1:xi1eeee1:y"), Some(15));
+
+        // incomplete or invalid values
+        assert_eq!(bencode_value_len(b""), None);
+        assert_eq!(bencode_value_len(b"i42"), None);
+        assert_eq!(bencode_value_len(b"5:spam"), None);
+        assert_eq!(bencode_value_len(b"d8:msg_typei1e"), None);
+        assert_eq!(bencode_value_len(b"e"), None);
+        assert_eq!(bencode_value_len(b"x"), None);
+        assert_eq!(bencode_value_len(b"99999999999999999999999:"), None);
+    }
+}
@@ -0,0 +1,93 @@
+//! This module implements the parts of the [Fast
+//! extension](http://bittorrent.org/beps/bep_0006.html) that are not simple
+//! message handling: the allowed fast set.
+//!
+//! The allowed fast set is a small set of pieces that a peer may request even
+//! while it's choked, which lets new peers, that have nothing to trade yet,
+//! get their first pieces quickly. The set is derived from the peer's IP
+//! address and the info hash, so that a peer can't get a larger set by
+//! reconnecting, and peers in the same network get the same set.
+
+use std::net::Ipv4Addr;
+
+use sha1::{Digest, Sha1};
+
+use crate::{PieceIndex, Sha1Hash};
+
+/// The number of pieces we allow a peer to request while choked.
+pub(super) const ALLOWED_FAST_SET_LEN: usize = 10;
+
+/// Returns the allowed fast set of at most `len` pieces of the torrent with
+/// the given info hash and piece count, for the peer with the given IP
+/// address, in the order in which they are generated.
+pub(super) fn allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &Sha1Hash,
+    piece_count: usize,
+    len: usize,
+) -> Vec<PieceIndex> {
+    // the set can't have more pieces than the torrent, otherwise we would
+    // never stop looking for new ones
+    let len = len.min(piece_count);
+    let mut set = Vec::with_capacity(len);
+
+    // peers in the same /24 network get the same set
+    let ip = u32::from(ip) & 0xffff_ff00;
+    let mut hash = Vec::with_capacity(4 + info_hash.len());
+    hash.extend_from_slice(&ip.to_be_bytes());
+    hash.extend_from_slice(info_hash);
+
+    while set.len() < len {
+        hash = Sha1::digest(&hash).to_vec();
+        for chunk in hash.chunks_exact(4) {
+            if set.len() == len {
+                break;
+            }
+            let mut y = [0; 4];
+            y.copy_from_slice(chunk);
+            let index = u32::from_be_bytes(y) as usize % piece_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the allowed fast set against the example in the specification.
+    #[test]
+    fn should_compute_allowed_fast_set() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+
+        // the last octet of the address doesn't matter
+        assert_eq!(
+            allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313, 9),
+            allowed_fast_set(ip, &info_hash, 1313, 9)
+        );
+    }
+
+    /// Tests that the set of a torrent with fewer pieces than the set length
+    /// has all the torrent's pieces.
+    #[test]
+    fn should_cap_allowed_fast_set_at_piece_count() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let mut set = allowed_fast_set(ip, &[0xaa; 20], 3, 10);
+        set.sort_unstable();
+        assert_eq!(set, vec![0, 1, 2]);
+        assert!(allowed_fast_set(ip, &[0xaa; 20], 0, 10).is_empty());
+    }
+}
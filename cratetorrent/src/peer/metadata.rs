@@ -0,0 +1,840 @@
+//! This module implements the [metadata exchange
+//! extension](http://bittorrent.org/beps/bep_0009.html) (`ut_metadata`), which
+//! is used to download a torrent's info dictionary from peers when starting
+//! a torrent from a magnet link.
+//!
+//! Torrents that have the metadata serve it to peers via
+//! a [`MetadataHandler`], while torrents started from a magnet link download
+//! it with a [`MetadataSession`].
+//!
+//! The piece layers of v2-only torrents are not part of the metadata, so the
+//! session also requests them from the peer, with the hash request messages of
+//! [BEP 52](https://www.bittorrent.org/beps/bep_0052.html).
+
+use std::{io, net::SocketAddr, sync::Arc};
+
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio_util::codec::{Framed, FramedParts};
+
+use crate::{
+    conf::EncryptionPolicy,
+    merkle, metainfo,
+    peer::{codec::*, error::*, extension::*, mse},
+    PeerId, Sha1Hash, Sha256Hash, TorrentId,
+};
+
+/// The name of the extension in the extended handshake.
+pub(crate) const UT_METADATA: &str = "ut_metadata";
+
+/// The extended message id with which we wish to receive metadata messages.
+pub(crate) const UT_METADATA_ID: u8 = 1;
+
+/// The metadata is exchanged in pieces of 16 KiB, except for the last piece,
+/// which may be smaller.
+const METADATA_PIECE_LEN: usize = 0x4000;
+
+/// The upper bound on the metadata size we accept from peers. Even torrents
+/// with very many pieces or files have metadata well below this, so this
+/// protects us from allocating absurd amounts of memory on a peer's say-so.
+const MAX_METADATA_LEN: usize = 8 * 1024 * 1024;
+
+/// The messages of the metadata extension.
+#[derive(Debug, PartialEq)]
+pub(crate) enum MetadataMsg {
+    /// Requests a piece of the metadata.
+    Request { piece: usize },
+    /// A piece of the metadata, along with the full metadata size.
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// The peer doesn't have the requested piece of the metadata.
+    Reject { piece: usize },
+}
+
+/// The bencoded dictionary at the start of each metadata message.
+#[derive(Debug, Serialize, Deserialize)]
+struct RawMetadataMsg {
+    msg_type: i64,
+    piece: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+impl MetadataMsg {
+    /// Decodes the message from the payload of an extended message.
+    ///
+    /// Returns `None` if the message type is not known, as such messages
+    /// must be ignored according to the specification.
+    pub fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        // data messages have the raw metadata piece appended after the
+        // dictionary, so only decode the dictionary part
+        let dict_len =
+            bencode_value_len(buf).ok_or(PeerError::InvalidExtendedMessage)?;
+        let raw: RawMetadataMsg = serde_bencode::from_bytes(&buf[..dict_len])?;
+        let piece = to_usize(raw.piece)?;
+        let msg = match raw.msg_type {
+            0 => Self::Request { piece },
+            1 => Self::Data {
+                piece,
+                total_size: raw
+                    .total_size
+                    .map(to_usize)
+                    .transpose()?
+                    .ok_or(PeerError::InvalidExtendedMessage)?,
+                data: buf[dict_len..].to_vec(),
+            },
+            2 => Self::Reject { piece },
+            _ => return Ok(None),
+        };
+        Ok(Some(msg))
+    }
+
+    /// Encodes the message into the payload of an extended message.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let (msg_type, piece, total_size, data) = match self {
+            Self::Request { piece } => (0, *piece, None, None),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (1, *piece, Some(*total_size as i64), Some(data)),
+            Self::Reject { piece } => (2, *piece, None, None),
+        };
+        let mut buf = serde_bencode::to_bytes(&RawMetadataMsg {
+            msg_type,
+            piece: piece as i64,
+            total_size,
+        })?;
+        if let Some(data) = data {
+            buf.extend_from_slice(data);
+        }
+        Ok(buf)
+    }
+}
+
+fn to_usize(n: i64) -> Result<usize> {
+    if n < 0 {
+        Err(PeerError::InvalidExtendedMessage)
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Serves the torrent's metadata to peers that request it.
+pub(crate) struct MetadataHandler {
+    /// The bencoded info dictionary of the torrent.
+    metadata: Arc<Vec<u8>>,
+}
+
+impl MetadataHandler {
+    pub fn new(metadata: Arc<Vec<u8>>) -> Self {
+        Self { metadata }
+    }
+}
+
+impl ExtensionHandler for MetadataHandler {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtendedHandshake) {
+        handshake.metadata_size = Some(self.metadata.len() as i64);
+    }
+
+    fn handle_msg(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let response = match MetadataMsg::decode(payload)? {
+            Some(MetadataMsg::Request { piece }) => {
+                let offset = piece.saturating_mul(METADATA_PIECE_LEN);
+                if offset < self.metadata.len() {
+                    let end =
+                        (offset + METADATA_PIECE_LEN).min(self.metadata.len());
+                    MetadataMsg::Data {
+                        piece,
+                        total_size: self.metadata.len(),
+                        data: self.metadata[offset..end].to_vec(),
+                    }
+                } else {
+                    MetadataMsg::Reject { piece }
+                }
+            }
+            // we never request the metadata in a regular peer session, so
+            // there is nothing to do with data or rejections
+            _ => return Ok(Vec::new()),
+        };
+        Ok(vec![response.encode()?])
+    }
+}
+
+/// The metadata downloaded from a peer.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Metadata {
+    /// The raw bencoded info dictionary.
+    pub info: Vec<u8>,
+    /// The piece layers of the files of a v2-only torrent, keyed by the
+    /// files' pieces roots, which are empty for other torrents.
+    pub piece_layers: Vec<(Sha256Hash, Vec<Sha256Hash>)>,
+}
+
+/// A connection with a peer whose sole purpose is downloading the torrent's
+/// metadata.
+///
+/// Since without the metadata we don't know anything about the torrent's
+/// pieces, a full [`PeerSession`](super::PeerSession) cannot be run yet. This
+/// session performs the handshakes, downloads all metadata pieces from the
+/// peer, and verifies them against the info hash. If the torrent is v2-only,
+/// it then downloads the piece layers and verifies them against the files'
+/// pieces roots.
+pub(crate) struct MetadataSession {
+    /// The info hash of the torrent whose metadata we're downloading.
+    info_hash: Sha1Hash,
+    /// Our client id, sent in the handshake.
+    client_id: PeerId,
+    /// Whether the connection is encrypted.
+    encryption: EncryptionPolicy,
+    /// The address of the peer.
+    addr: SocketAddr,
+    log_target: String,
+}
+
+impl MetadataSession {
+    pub fn new(
+        torrent_id: TorrentId,
+        info_hash: Sha1Hash,
+        client_id: PeerId,
+        encryption: EncryptionPolicy,
+        addr: SocketAddr,
+    ) -> Self {
+        Self {
+            info_hash,
+            client_id,
+            encryption,
+            addr,
+            log_target: format!(
+                "cratetorrent::peer::metadata [{}][{}]",
+                torrent_id, addr
+            ),
+        }
+    }
+
+    /// Connects to the peer and downloads the torrent's info dictionary,
+    /// returning its raw bencoded form, along with the piece layers of v2-only
+    /// torrents, once it is verified.
+    pub async fn run(self) -> Result<Metadata> {
+        log::info!(target: &self.log_target, "Connecting to peer");
+        let socket =
+            mse::connect(self.addr, &self.info_hash, self.encryption).await?;
+        let mut socket = Framed::new(socket, HandshakeCodec);
+
+        let mut handshake = Handshake::new(self.info_hash, self.client_id);
+        handshake.set_extension_protocol();
+        // the torrent may turn out to be v2-only, in which case we request
+        // hashes from the peer
+        handshake.set_v2();
+        log::info!(target: &self.log_target, "Sending handshake");
+        socket.send(handshake).await?;
+
+        let peer_handshake = match socket.next().await {
+            Some(handshake) => handshake?,
+            None => {
+                log::info!(target: &self.log_target, "No handshake received");
+                return Err(
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                );
+            }
+        };
+        if peer_handshake.info_hash != self.info_hash {
+            log::info!(target: &self.log_target, "Peer handshake invalid info hash");
+            return Err(PeerError::InvalidInfoHash);
+        }
+        if !peer_handshake.supports_extension_protocol() {
+            log::info!(target: &self.log_target, "Peer doesn't support extensions");
+            return Err(PeerError::ExtensionUnsupported);
+        }
+
+        // switch to the peer message codec, keeping the buffers of the
+        // handshake codec
+        let old_parts = socket.into_parts();
+        let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+        new_parts.read_buf = old_parts.read_buf;
+        new_parts.write_buf = old_parts.write_buf;
+        let mut socket = Framed::from_parts(new_parts);
+
+        let mut handshake = ExtendedHandshake::default();
+        handshake
+            .m
+            .insert(UT_METADATA.into(), UT_METADATA_ID.into());
+        log::info!(target: &self.log_target, "Sending extended handshake");
+        socket
+            .send(Message::Extended {
+                id: EXTENDED_HANDSHAKE_ID,
+                payload: handshake.encode()?,
+            })
+            .await?;
+
+        // the id with which the peer wishes to receive metadata messages and
+        // the metadata download, both set after the peer's extended handshake
+        let mut peer_ut_metadata_id = None;
+        let mut metadata: Option<MetadataBuf> = None;
+
+        while let Some(msg) = socket.next().await {
+            let (id, payload) = match msg? {
+                Message::Extended { id, payload } => (id, payload),
+                // the peer may send any of the regular messages, but we can't
+                // do anything with them until we have the metadata
+                _ => continue,
+            };
+
+            if id == EXTENDED_HANDSHAKE_ID {
+                // the extended handshake may be sent again later to update
+                // some fields, but once we have the metadata size we don't
+                // need anything else from it
+                if metadata.is_some() {
+                    continue;
+                }
+
+                let handshake = ExtendedHandshake::decode(&payload)?;
+                log::debug!(
+                    target: &self.log_target,
+                    "Peer extended handshake: {:?}",
+                    handshake
+                );
+                let ut_metadata_id = handshake
+                    .extension_id(UT_METADATA)
+                    .ok_or(PeerError::ExtensionUnsupported)?;
+                let len = handshake
+                    .metadata_size
+                    .ok_or(PeerError::ExtensionUnsupported)?;
+                if len <= 0 || len as usize > MAX_METADATA_LEN {
+                    log::warn!(
+                        target: &self.log_target,
+                        "Peer advertised invalid metadata size {}",
+                        len
+                    );
+                    return Err(PeerError::InvalidMetadata);
+                }
+
+                let buf = MetadataBuf::new(len as usize);
+                log::info!(
+                    target: &self.log_target,
+                    "Requesting {} metadata piece(s) ({} bytes)",
+                    buf.piece_count(),
+                    len
+                );
+                // the metadata is small enough to request all pieces at once
+                for piece in 0..buf.piece_count() {
+                    socket
+                        .send(Message::Extended {
+                            id: ut_metadata_id,
+                            payload: MetadataMsg::Request { piece }.encode()?,
+                        })
+                        .await?;
+                }
+
+                peer_ut_metadata_id = Some(ut_metadata_id);
+                metadata = Some(buf);
+            } else if id == UT_METADATA_ID {
+                let msg = match MetadataMsg::decode(&payload)? {
+                    Some(msg) => msg,
+                    None => continue,
+                };
+                match msg {
+                    MetadataMsg::Data {
+                        piece,
+                        total_size,
+                        data,
+                    } => {
+                        // we can't receive data before having requested it
+                        let buf = metadata
+                            .as_mut()
+                            .ok_or(PeerError::InvalidExtendedMessage)?;
+                        if total_size != buf.len() {
+                            return Err(PeerError::InvalidMetadata);
+                        }
+                        log::debug!(
+                            target: &self.log_target,
+                            "Got metadata piece {}",
+                            piece
+                        );
+                        buf.insert(piece, &data)?;
+
+                        if buf.is_complete() {
+                            let info = self.verify(buf.take())?;
+                            let piece_layers = self
+                                .download_piece_layers(&mut socket, &info)
+                                .await?;
+                            return Ok(Metadata { info, piece_layers });
+                        }
+                    }
+                    MetadataMsg::Reject { piece } => {
+                        log::info!(
+                            target: &self.log_target,
+                            "Peer rejected metadata piece {}",
+                            piece
+                        );
+                        return Err(PeerError::MetadataRejected);
+                    }
+                    MetadataMsg::Request { piece } => {
+                        // we don't have the metadata ourselves
+                        if let Some(id) = peer_ut_metadata_id {
+                            socket
+                                .send(Message::Extended {
+                                    id,
+                                    payload: MetadataMsg::Reject { piece }
+                                        .encode()?,
+                                })
+                                .await?;
+                        }
+                    }
+                }
+            }
+        }
+
+        log::info!(target: &self.log_target, "Peer closed connection");
+        Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+    }
+
+    /// Verifies that the downloaded metadata matches the info hash, which for
+    /// v2-only torrents is the truncated SHA-256 hash of the metadata.
+    fn verify(&self, buf: Vec<u8>) -> Result<Vec<u8>> {
+        if Sha1::digest(&buf)[..] == self.info_hash[..]
+            || merkle::hash(&buf)[..20] == self.info_hash[..]
+        {
+            log::info!(target: &self.log_target, "Downloaded valid metadata");
+            Ok(buf)
+        } else {
+            log::warn!(target: &self.log_target, "Peer sent invalid metadata");
+            Err(PeerError::InvalidMetadata)
+        }
+    }
+}
+
+impl MetadataSession {
+    /// Requests the piece layers that the torrent of the metadata needs but
+    /// which are not in it, which is the case for v2-only torrents, and
+    /// returns them once they're verified against the files' pieces roots.
+    async fn download_piece_layers(
+        &self,
+        socket: &mut Framed<mse::MseStream, PeerCodec>,
+        info: &[u8],
+    ) -> Result<Vec<(Sha256Hash, Vec<Sha256Hash>)>> {
+        let missing = match metainfo::missing_piece_layers(info) {
+            Ok(missing) => missing,
+            Err(e) => {
+                // the engine reports the invalid metadata when it creates the
+                // torrent from it
+                log::info!(target: &self.log_target, "Metadata is invalid: {}", e);
+                return Ok(Vec::new());
+            }
+        };
+        if missing.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // each layer is requested in batches of as many hashes as peers serve
+        // at a time, along with the uncle hashes up to the pieces root with
+        // which the batch is verified
+        let mut layers = Vec::with_capacity(missing.len());
+        let mut requests = Vec::new();
+        for (file, layer) in missing.iter().enumerate() {
+            let width = layer.piece_count.next_power_of_two();
+            let len = width.min(merkle::MAX_HASH_REQUEST_LEN as usize);
+            let proof_layers = (width / len).trailing_zeros();
+            for index in (0..layer.piece_count).step_by(len) {
+                requests.push((
+                    file,
+                    HashRequest {
+                        pieces_root: layer.pieces_root,
+                        base_layer: layer.layer_index,
+                        index: index as u32,
+                        len: len as u32,
+                        proof_layers,
+                    },
+                ));
+            }
+            layers.push(vec![[0; 32]; width]);
+        }
+        log::info!(
+            target: &self.log_target,
+            "Requesting {} piece layer(s) in {} hash request(s)",
+            missing.len(),
+            requests.len()
+        );
+        for (_, request) in requests.iter() {
+            socket.send(Message::HashRequest(*request)).await?;
+        }
+
+        while !requests.is_empty() {
+            let msg = match socket.next().await {
+                Some(msg) => msg?,
+                None => {
+                    log::info!(target: &self.log_target, "Peer closed connection");
+                    return Err(
+                        io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                    );
+                }
+            };
+            match msg {
+                Message::Hashes { request, hashes } => {
+                    let pos = match requests
+                        .iter()
+                        .position(|(_, r)| *r == request)
+                    {
+                        Some(pos) => pos,
+                        // unrequested hashes are ignored
+                        None => continue,
+                    };
+                    let (file, request) = requests.swap_remove(pos);
+                    let layer = &mut layers[file];
+                    let hashes = merkle::verify_piece_layer_proof(
+                        &request.pieces_root,
+                        layer.len(),
+                        request.index,
+                        request.len,
+                        &hashes,
+                    )
+                    .ok_or_else(|| {
+                        log::warn!(target: &self.log_target, "Peer sent invalid hashes");
+                        PeerError::InvalidHashes
+                    })?;
+                    let start = request.index as usize;
+                    layer[start..start + hashes.len()].copy_from_slice(hashes);
+                }
+                Message::HashReject(request) => {
+                    if requests.iter().any(|(_, r)| *r == request) {
+                        log::info!(target: &self.log_target, "Peer rejected hash request");
+                        return Err(PeerError::HashesRejected);
+                    }
+                }
+                // the peer may send any of the regular messages, but we can't
+                // do anything with them without a torrent
+                _ => continue,
+            }
+        }
+
+        log::info!(target: &self.log_target, "Downloaded valid piece layers");
+        Ok(missing
+            .into_iter()
+            .zip(layers)
+            .map(|(missing, mut layer)| {
+                // the hashes past the end of the file are only padding
+                layer.truncate(missing.piece_count);
+                (missing.pieces_root, layer)
+            })
+            .collect())
+    }
+}
+
+/// The buffer into which the metadata pieces are downloaded.
+struct MetadataBuf {
+    buf: Vec<u8>,
+    /// Which pieces have been received.
+    received: Vec<bool>,
+    missing_count: usize,
+}
+
+impl MetadataBuf {
+    fn new(len: usize) -> Self {
+        let piece_count = (len + METADATA_PIECE_LEN - 1) / METADATA_PIECE_LEN;
+        Self {
+            buf: vec![0; len],
+            received: vec![false; piece_count],
+            missing_count: piece_count,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn piece_count(&self) -> usize {
+        self.received.len()
+    }
+
+    /// Copies the piece into the buffer, provided that the piece index and
+    /// length are valid.
+    fn insert(&mut self, piece: usize, data: &[u8]) -> Result<()> {
+        if piece >= self.piece_count() {
+            return Err(PeerError::InvalidMetadata);
+        }
+        let offset = piece * METADATA_PIECE_LEN;
+        let piece_len = (self.len() - offset).min(METADATA_PIECE_LEN);
+        if data.len() != piece_len {
+            return Err(PeerError::InvalidMetadata);
+        }
+
+        if !self.received[piece] {
+            self.buf[offset..offset + piece_len].copy_from_slice(data);
+            self.received[piece] = true;
+            self.missing_count -= 1;
+        }
+
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.missing_count == 0
+    }
+
+    /// Moves the downloaded metadata out of the buffer.
+    fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn should_encode_and_decode_messages() {
+        let msgs: Vec<(MetadataMsg, &[u8])> = vec![
+            (
+                MetadataMsg::Request { piece: 0 },
+                b"d8:msg_typei0e5:piecei0ee",
+            ),
+            (
+                MetadataMsg::Data {
+                    piece: 1,
+                    total_size: 16390,
+                    data: b"metadata".to_vec(),
+                },
+                b"d8:msg_typei1e5:piecei1e10:total_sizei16390eemetadata",
+            ),
+            (
+                MetadataMsg::Reject { piece: 3 },
+                b"d8:msg_typei2e5:piecei3ee",
+            ),
+        ];
+
+        for (msg, encoded) in msgs {
+            assert_eq!(msg.encode().unwrap(), encoded);
+            assert_eq!(MetadataMsg::decode(encoded).unwrap(), Some(msg));
+        }
+    }
+
+    #[test]
+    fn should_ignore_unknown_message_type() {
+        assert_eq!(
+            MetadataMsg::decode(b"d8:msg_typei9e5:piecei0ee").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_messages() {
+        // negative piece index
+        assert!(MetadataMsg::decode(b"d8:msg_typei0e5:piecei-1ee").is_err());
+        // data without total size
+        assert!(MetadataMsg::decode(b"d8:msg_typei1e5:piecei0eedata").is_err());
+        // truncated dictionary
+        assert!(MetadataMsg::decode(b"d8:msg_typei0e5:piece").is_err());
+    }
+
+    #[test]
+    fn should_serve_metadata_pieces() {
+        let metadata: Vec<u8> =
+            (0..METADATA_PIECE_LEN + 100).map(|i| i as u8).collect();
+        let mut handler = MetadataHandler::new(Arc::new(metadata.clone()));
+
+        let mut handshake = ExtendedHandshake::default();
+        handler.extend_handshake(&mut handshake);
+        assert_eq!(handshake.metadata_size, Some(metadata.len() as i64));
+
+        let request = |piece| MetadataMsg::Request { piece }.encode().unwrap();
+        let decode = |mut responses: Vec<Vec<u8>>| {
+            assert_eq!(responses.len(), 1);
+            MetadataMsg::decode(&responses.pop().unwrap())
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            decode(handler.handle_msg(&request(0)).unwrap()),
+            MetadataMsg::Data {
+                piece: 0,
+                total_size: metadata.len(),
+                data: metadata[..METADATA_PIECE_LEN].to_vec(),
+            }
+        );
+        assert_eq!(
+            decode(handler.handle_msg(&request(1)).unwrap()),
+            MetadataMsg::Data {
+                piece: 1,
+                total_size: metadata.len(),
+                data: metadata[METADATA_PIECE_LEN..].to_vec(),
+            }
+        );
+        assert_eq!(
+            decode(handler.handle_msg(&request(2)).unwrap()),
+            MetadataMsg::Reject { piece: 2 }
+        );
+
+        // rejections are not answered
+        let reject = MetadataMsg::Reject { piece: 0 }.encode().unwrap();
+        assert!(handler.handle_msg(&reject).unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_assemble_metadata_pieces() {
+        let len = 2 * METADATA_PIECE_LEN + 100;
+        let mut buf = MetadataBuf::new(len);
+        assert_eq!(buf.piece_count(), 3);
+
+        // invalid piece index and lengths
+        assert!(buf.insert(3, &[0; 100]).is_err());
+        assert!(buf.insert(0, &[0; 100]).is_err());
+        assert!(buf.insert(2, &[0; METADATA_PIECE_LEN]).is_err());
+
+        buf.insert(2, &[2; 100]).unwrap();
+        buf.insert(0, &[0; METADATA_PIECE_LEN]).unwrap();
+        assert!(!buf.is_complete());
+        buf.insert(1, &[1; METADATA_PIECE_LEN]).unwrap();
+        assert!(buf.is_complete());
+
+        let buf = buf.take();
+        assert_eq!(buf.len(), len);
+        assert!(buf[..METADATA_PIECE_LEN].iter().all(|b| *b == 0));
+        assert!(buf[METADATA_PIECE_LEN..2 * METADATA_PIECE_LEN]
+            .iter()
+            .all(|b| *b == 1));
+        assert!(buf[2 * METADATA_PIECE_LEN..].iter().all(|b| *b == 2));
+    }
+
+    /// Tests downloading the metadata from a peer that serves it over
+    /// loopback.
+    #[tokio::test]
+    async fn should_download_metadata_from_peer() {
+        // metadata that spans more than a single piece
+        let metadata: Vec<u8> =
+            (0..METADATA_PIECE_LEN + 1000).map(|i| i as u8).collect();
+        let info_hash = sha1_hash(&metadata);
+        let addr = spawn_metadata_seed(metadata.clone(), info_hash).await;
+
+        let session = MetadataSession::new(
+            TorrentId::new(),
+            info_hash,
+            [1; 20],
+            EncryptionPolicy::Disabled,
+            addr,
+        );
+        let downloaded = session.run().await.unwrap();
+        assert_eq!(downloaded.info, metadata);
+        assert!(downloaded.piece_layers.is_empty());
+    }
+
+    /// Tests that metadata not matching the info hash is rejected.
+    #[tokio::test]
+    async fn should_reject_metadata_with_invalid_hash() {
+        let metadata = b"d4:name4:testee".to_vec();
+        let info_hash = sha1_hash(&metadata);
+        let mut other_metadata = metadata.clone();
+        other_metadata[10] = b'x';
+        let addr = spawn_metadata_seed(other_metadata, info_hash).await;
+
+        let session = MetadataSession::new(
+            TorrentId::new(),
+            info_hash,
+            [1; 20],
+            EncryptionPolicy::Disabled,
+            addr,
+        );
+        assert!(matches!(
+            session.run().await,
+            Err(PeerError::InvalidMetadata)
+        ));
+    }
+
+    fn sha1_hash(buf: &[u8]) -> Sha1Hash {
+        let mut hash = [0; 20];
+        hash.copy_from_slice(&Sha1::digest(buf));
+        hash
+    }
+
+    /// Spawns a minimal peer that accepts a single connection and serves the
+    /// metadata to it via the metadata extension.
+    async fn spawn_metadata_seed(
+        metadata: Vec<u8>,
+        info_hash: Sha1Hash,
+    ) -> SocketAddr {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut socket = Framed::new(socket, HandshakeCodec);
+            let peer_handshake = socket.next().await.unwrap().unwrap();
+            assert!(peer_handshake.supports_extension_protocol());
+            let mut handshake = Handshake::new(info_hash, [2; 20]);
+            handshake.set_extension_protocol();
+            socket.send(handshake).await.unwrap();
+
+            let parts = socket.into_parts();
+            let mut new_parts = FramedParts::new(parts.io, PeerCodec);
+            new_parts.read_buf = parts.read_buf;
+            let mut socket = Framed::from_parts(new_parts);
+
+            // serve metadata under a different id than the one we use, to
+            // make sure the ids are not mixed up
+            let seed_ut_metadata_id = 7;
+            let mut handshake = ExtendedHandshake::default();
+            handshake.m.insert(UT_METADATA.into(), seed_ut_metadata_id);
+            handshake.metadata_size = Some(metadata.len() as i64);
+            socket
+                .send(Message::Extended {
+                    id: EXTENDED_HANDSHAKE_ID,
+                    payload: handshake.encode().unwrap(),
+                })
+                .await
+                .unwrap();
+
+            let mut peer_ut_metadata_id = None;
+            while let Some(Ok(msg)) = socket.next().await {
+                match msg {
+                    Message::Extended {
+                        id: EXTENDED_HANDSHAKE_ID,
+                        payload,
+                    } => {
+                        let handshake =
+                            ExtendedHandshake::decode(&payload).unwrap();
+                        peer_ut_metadata_id =
+                            handshake.extension_id(UT_METADATA);
+                    }
+                    Message::Extended { id, payload }
+                        if id == seed_ut_metadata_id as u8 =>
+                    {
+                        let piece = match MetadataMsg::decode(&payload) {
+                            Ok(Some(MetadataMsg::Request { piece })) => piece,
+                            _ => panic!("expected metadata request"),
+                        };
+                        let offset = piece * METADATA_PIECE_LEN;
+                        let end =
+                            (offset + METADATA_PIECE_LEN).min(metadata.len());
+                        let msg = MetadataMsg::Data {
+                            piece,
+                            total_size: metadata.len(),
+                            data: metadata[offset..end].to_vec(),
+                        };
+                        // the downloader may disconnect at any point
+                        let msg = Message::Extended {
+                            id: peer_ut_metadata_id.unwrap(),
+                            payload: msg.encode().unwrap(),
+                        };
+                        if socket.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        addr
+    }
+}
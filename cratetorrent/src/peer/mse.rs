@@ -0,0 +1,714 @@
+//! This module implements [Message Stream
+//! Encryption](https://wiki.vuze.com/w/Message_Stream_Encryption) (MSE, also
+//! known as PE), which obfuscates BitTorrent connections so that they can't
+//! be told apart from random traffic, e.g. by ISPs that throttle BitTorrent.
+//!
+//! The MSE handshake precedes the BitTorrent handshake: the two sides agree on
+//! a shared secret via a Diffie-Hellman key exchange, identify the torrent by
+//! a hash of its info hash, and select whether the rest of the connection is
+//! encrypted with RC4 or sent as plaintext. Afterwards the connection is used
+//! through an [`MseStream`], which transparently encrypts and decrypts it, so
+//! that the codecs on top of it need not know about encryption.
+
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use bytes::{Buf, BytesMut};
+use futures::ready;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time,
+};
+
+use crate::{
+    conf::EncryptionPolicy,
+    peer::{codec::PROTOCOL_STRING, error::*},
+    Sha1Hash,
+};
+use dh::{KeyPair, KEY_LEN};
+use rc4::Rc4;
+
+mod dh;
+mod rc4;
+
+/// The MSE handshake has to complete within this time.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum length of the random paddings in the handshake.
+const MAX_PAD_LEN: usize = 512;
+
+/// The verification constant, whose encrypted form is used to find the start
+/// of the encrypted part of the handshake.
+const VC: [u8; 8] = [0; 8];
+
+/// The `crypto_provide` and `crypto_select` bit of plaintext connections.
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+/// The `crypto_provide` and `crypto_select` bit of RC4 connections.
+const CRYPTO_RC4: u32 = 0x02;
+
+/// Connects to the peer and, if the policy allows it, performs the MSE
+/// handshake as the initiating side.
+///
+/// If encryption is enabled but not required, and the peer doesn't speak MSE,
+/// the connection is retried without it.
+pub(crate) async fn connect(
+    addr: SocketAddr,
+    info_hash: &Sha1Hash,
+    policy: EncryptionPolicy,
+) -> Result<MseStream> {
+    let crypto_provide = match policy {
+        EncryptionPolicy::Disabled => {
+            return Ok(MseStream::plaintext(
+                TcpStream::connect(addr).await?,
+                BytesMut::new(),
+            ));
+        }
+        EncryptionPolicy::Enabled => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+        EncryptionPolicy::Required => CRYPTO_RC4,
+    };
+
+    let socket = TcpStream::connect(addr).await?;
+    let result = time::timeout(
+        HANDSHAKE_TIMEOUT,
+        initiate(socket, info_hash, crypto_provide),
+    )
+    .await
+    .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()));
+    match result {
+        Err(e) if policy == EncryptionPolicy::Enabled => {
+            log::info!(
+                "MSE handshake with {} failed ({}), retrying in plaintext",
+                addr,
+                e
+            );
+            Ok(MseStream::plaintext(
+                TcpStream::connect(addr).await?,
+                BytesMut::new(),
+            ))
+        }
+        result => result,
+    }
+}
+
+/// Sets up a connection accepted from a peer: if it starts with an MSE
+/// handshake, and the policy allows it, the handshake is completed as the
+/// receiving side, otherwise the connection is plaintext.
+///
+/// The torrent the peer wants is identified among the given info hashes.
+pub(crate) async fn accept(
+    socket: TcpStream,
+    policy: EncryptionPolicy,
+    info_hashes: &[Sha1Hash],
+) -> Result<MseStream> {
+    let mut reader = HandshakeReader::new(socket);
+    // the BitTorrent handshake starts with the length prefixed protocol
+    // string, while the MSE handshake with a public key that is
+    // indistinguishable from random bytes
+    let prefix_len = 1 + PROTOCOL_STRING.len();
+    reader.fill(prefix_len).await?;
+    let is_plaintext = reader.buf[0] as usize == PROTOCOL_STRING.len()
+        && &reader.buf[1..prefix_len] == PROTOCOL_STRING.as_bytes();
+
+    match (is_plaintext, policy) {
+        (true, EncryptionPolicy::Required) => {
+            Err(PeerError::EncryptionPolicyMismatch)
+        }
+        (true, _) => Ok(MseStream::plaintext(reader.socket, reader.buf)),
+        (false, EncryptionPolicy::Disabled) => {
+            Err(PeerError::EncryptionPolicyMismatch)
+        }
+        (false, _) => respond(reader, policy, info_hashes, &random_pad()).await,
+    }
+}
+
+/// Performs the initiating side (A) of the MSE handshake.
+async fn initiate(
+    socket: TcpStream,
+    info_hash: &Sha1Hash,
+    crypto_provide: u32,
+) -> Result<MseStream> {
+    let mut reader = HandshakeReader::new(socket);
+    let keys = KeyPair::new();
+
+    // 1. A->B: Ya, PadA
+    let mut msg = keys.public.to_vec();
+    msg.extend_from_slice(&random_pad());
+    reader.socket.write_all(&msg).await?;
+
+    // 2. B->A: Yb, PadB
+    let peer_public = reader.read_key().await?;
+    let secret = keys.shared_secret(&peer_public);
+    let mut enc = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut dec = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    // ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    //
+    // the BitTorrent handshake is sent afterwards rather than as the initial
+    // payload (IA), and there is no PadC, as the peer only learns about our
+    // choice after it has decrypted the rest of the message anyway
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    msg.extend_from_slice(&xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&crypto_provide.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    enc.apply(&mut encrypted);
+    msg.extend_from_slice(&encrypted);
+    reader.socket.write_all(&msg).await?;
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    //
+    // PadB is of unknown length, so we look for the encrypted VC after it
+    let mut encrypted_vc = VC;
+    dec.apply(&mut encrypted_vc);
+    reader.skip_until(&encrypted_vc, MAX_PAD_LEN).await?;
+    let crypto_select = reader.read_decrypted(4, &mut dec).await?.get_u32();
+    let pad_len = reader.read_decrypted(2, &mut dec).await?.get_u16();
+    if pad_len as usize > MAX_PAD_LEN {
+        return Err(PeerError::InvalidEncryptionHandshake);
+    }
+    // the padding is encrypted too, so it has to be decrypted for the cipher
+    // to stay in sync with the peer's, even though it's discarded
+    reader.read_decrypted(pad_len as usize, &mut dec).await?;
+
+    // the peer must select exactly one of the methods we provided
+    if crypto_select != CRYPTO_PLAINTEXT && crypto_select != CRYPTO_RC4
+        || crypto_select & crypto_provide == 0
+    {
+        return Err(PeerError::EncryptionPolicyMismatch);
+    }
+    Ok(reader.into_stream(crypto_select, enc, dec, BytesMut::new()))
+}
+
+/// Performs the receiving side (B) of the MSE handshake, sending `pad_d` as
+/// the padding of the last message.
+async fn respond(
+    mut reader: HandshakeReader,
+    policy: EncryptionPolicy,
+    info_hashes: &[Sha1Hash],
+    pad_d: &[u8],
+) -> Result<MseStream> {
+    let keys = KeyPair::new();
+
+    // 1. A->B: Ya, PadA
+    let peer_public = reader.read_key().await?;
+    let secret = keys.shared_secret(&peer_public);
+
+    // 2. B->A: Yb, PadB
+    let mut msg = keys.public.to_vec();
+    msg.extend_from_slice(&random_pad());
+    reader.socket.write_all(&msg).await?;
+
+    // 3. A->B: HASH('req1', S), HASH('req2', SKEY) xor HASH('req3', S),
+    // ENCRYPT(VC, crypto_provide, len(PadC), PadC, len(IA)), ENCRYPT(IA)
+    //
+    // PadA is of unknown length, so we look for the first hash after it
+    reader
+        .skip_until(&hash(&[b"req1", &secret]), MAX_PAD_LEN)
+        .await?;
+    let mut req2 = [0; 20];
+    req2.copy_from_slice(&reader.read(20).await?);
+    let req2 = xor(&req2, &hash(&[b"req3", &secret]));
+    let info_hash = info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", *info_hash]) == req2)
+        .ok_or(PeerError::InvalidInfoHash)?;
+    let mut dec = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut enc = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let vc = reader.read_decrypted(VC.len(), &mut dec).await?;
+    if vc != VC[..] {
+        return Err(PeerError::InvalidEncryptionHandshake);
+    }
+    let crypto_provide = reader.read_decrypted(4, &mut dec).await?.get_u32();
+    let pad_len = reader.read_decrypted(2, &mut dec).await?.get_u16();
+    if pad_len as usize > MAX_PAD_LEN {
+        return Err(PeerError::InvalidEncryptionHandshake);
+    }
+    let mut pad = reader.read(pad_len as usize).await?;
+    dec.apply(&mut pad);
+    let ia_len = reader.read_decrypted(2, &mut dec).await?.get_u16();
+    // the initial payload is the start of the payload stream, usually the
+    // BitTorrent handshake
+    let mut ia = reader.read(ia_len as usize).await?;
+    dec.apply(&mut ia);
+
+    // 4. B->A: ENCRYPT(VC, crypto_select, len(padD), padD)
+    //
+    // we prefer encryption if the peer offers it, since that's why the peer
+    // (or we) enabled MSE in the first place
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0
+        && policy != EncryptionPolicy::Required
+    {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(PeerError::EncryptionPolicyMismatch);
+    };
+    let mut msg = VC.to_vec();
+    msg.extend_from_slice(&crypto_select.to_be_bytes());
+    msg.extend_from_slice(&(pad_d.len() as u16).to_be_bytes());
+    msg.extend_from_slice(pad_d);
+    enc.apply(&mut msg);
+    reader.socket.write_all(&msg).await?;
+
+    Ok(reader.into_stream(crypto_select, enc, dec, ia))
+}
+
+/// A connection with a peer, which is either encrypted with RC4 or plaintext,
+/// depending on the outcome of the MSE handshake.
+pub(crate) struct MseStream {
+    socket: TcpStream,
+    /// Decrypts the bytes read from the socket, if the connection is
+    /// encrypted. The ciphers are boxed as their state is large and most
+    /// connections aren't encrypted.
+    read_cipher: Option<Box<Rc4>>,
+    /// Encrypts the bytes written to the socket, if the connection is
+    /// encrypted.
+    write_cipher: Option<Box<Rc4>>,
+    /// The payload that was read during the handshake, in plaintext, which is
+    /// returned before reading from the socket.
+    read_buf: BytesMut,
+    /// The encrypted bytes that are not yet written to the socket. Since the
+    /// cipher can't encrypt the same bytes twice, all bytes passed to
+    /// a write are accepted and buffered here until they are sent.
+    write_buf: BytesMut,
+}
+
+impl MseStream {
+    fn plaintext(socket: TcpStream, read_buf: BytesMut) -> Self {
+        Self {
+            socket,
+            read_cipher: None,
+            write_cipher: None,
+            read_buf,
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Returns whether the connection is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.read_cipher.is_some()
+    }
+
+    /// Writes the buffered encrypted bytes to the socket.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(
+                Pin::new(&mut self.socket).poll_write(cx, &self.write_buf)
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl std::fmt::Debug for MseStream {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("MseStream")
+            .field("socket", &self.socket)
+            .field("is_encrypted", &self.is_encrypted())
+            .finish()
+    }
+}
+
+impl AsyncRead for MseStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let n = buf.len().min(this.read_buf.len());
+            buf[..n].copy_from_slice(&this.read_buf[..n]);
+            this.read_buf.advance(n);
+            return Poll::Ready(Ok(n));
+        }
+
+        let n = ready!(Pin::new(&mut this.socket).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for MseStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.socket).poll_write(cx, buf);
+        }
+
+        // the bytes of the previous write are sent first, so that the buffer
+        // doesn't grow unbounded
+        ready!(this.poll_write_buf(cx))?;
+        this.write_buf.extend_from_slice(buf);
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut this.write_buf);
+        }
+        // try to send the bytes right away, but they're accepted either way
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_shutdown(cx)
+    }
+}
+
+/// Reads the messages of the MSE handshake, which are not framed, so bytes
+/// may be read ahead of the current message and are buffered.
+struct HandshakeReader {
+    socket: TcpStream,
+    buf: BytesMut,
+}
+
+impl HandshakeReader {
+    fn new(socket: TcpStream) -> Self {
+        Self {
+            socket,
+            buf: BytesMut::with_capacity(2 * MAX_PAD_LEN),
+        }
+    }
+
+    /// Reads from the socket until at least `len` bytes are buffered.
+    async fn fill(&mut self, len: usize) -> Result<()> {
+        while self.buf.len() < len {
+            if self.socket.read_buf(&mut self.buf).await? == 0 {
+                return Err(
+                    io::Error::from(io::ErrorKind::UnexpectedEof).into()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the next `len` bytes.
+    async fn read(&mut self, len: usize) -> Result<BytesMut> {
+        self.fill(len).await?;
+        Ok(self.buf.split_to(len))
+    }
+
+    /// Returns the peer's public key.
+    async fn read_key(&mut self) -> Result<[u8; KEY_LEN]> {
+        let mut key = [0; KEY_LEN];
+        key.copy_from_slice(&self.read(KEY_LEN).await?);
+        Ok(key)
+    }
+
+    /// Returns the next `len` bytes, decrypted.
+    async fn read_decrypted(
+        &mut self,
+        len: usize,
+        cipher: &mut Rc4,
+    ) -> Result<BytesMut> {
+        let mut bytes = self.read(len).await?;
+        cipher.apply(&mut bytes);
+        Ok(bytes)
+    }
+
+    /// Skips the bytes up to and including `pattern`, which has to start
+    /// within `max_skip` bytes.
+    async fn skip_until(
+        &mut self,
+        pattern: &[u8],
+        max_skip: usize,
+    ) -> Result<()> {
+        loop {
+            if let Some(pos) =
+                self.buf.windows(pattern.len()).position(|w| w == pattern)
+            {
+                if pos > max_skip {
+                    break;
+                }
+                self.buf.advance(pos + pattern.len());
+                return Ok(());
+            }
+            if self.buf.len() >= max_skip + pattern.len() {
+                break;
+            }
+            let len = self.buf.len() + 1;
+            self.fill(len).await?;
+        }
+        Err(PeerError::InvalidEncryptionHandshake)
+    }
+
+    /// Turns the connection into a stream once the handshake is done, with
+    /// `payload` being the part of the payload stream received during the
+    /// handshake.
+    fn into_stream(
+        mut self,
+        crypto_select: u32,
+        enc: Rc4,
+        mut dec: Rc4,
+        mut payload: BytesMut,
+    ) -> MseStream {
+        // the bytes read past the handshake are also part of the payload
+        // stream, and are encrypted if the connection is
+        if crypto_select == CRYPTO_RC4 {
+            dec.apply(&mut self.buf);
+            payload.unsplit(self.buf);
+            MseStream {
+                socket: self.socket,
+                read_cipher: Some(Box::new(dec)),
+                write_cipher: Some(Box::new(enc)),
+                read_buf: payload,
+                write_buf: BytesMut::new(),
+            }
+        } else {
+            payload.unsplit(self.buf);
+            MseStream::plaintext(self.socket, payload)
+        }
+    }
+}
+
+/// Returns the SHA-1 hash of the concatenated parts.
+fn hash(parts: &[&[u8]]) -> Sha1Hash {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut hash = [0; 20];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+fn xor(a: &Sha1Hash, b: &Sha1Hash) -> Sha1Hash {
+    let mut result = [0; 20];
+    for (r, (a, b)) in result.iter_mut().zip(a.iter().zip(b.iter())) {
+        *r = a ^ b;
+    }
+    result
+}
+
+/// Returns random padding of random length.
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::peer::codec::{Handshake, HandshakeCodec};
+
+    const INFO_HASH: Sha1Hash = [7; 20];
+
+    /// Connects a peer with the policy `outbound` to one with the policy
+    /// `inbound` on localhost, and has them exchange BitTorrent handshakes.
+    /// Returns whether the connection is encrypted on both sides, or `None`
+    /// if either side failed.
+    async fn handshake(
+        outbound: EncryptionPolicy,
+        inbound: EncryptionPolicy,
+    ) -> Option<bool> {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the listener accepts twice in case the outbound side falls back to
+        // plaintext
+        let inbound = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let stream = match accept(
+                    socket,
+                    inbound,
+                    &[[1; 20], INFO_HASH],
+                )
+                .await
+                {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let is_encrypted = stream.is_encrypted();
+                let mut socket = Framed::new(stream, HandshakeCodec);
+                let peer_handshake = socket.next().await?.ok()?;
+                assert_eq!(peer_handshake.info_hash, INFO_HASH);
+                socket.send(Handshake::new(INFO_HASH, [2; 20])).await.ok()?;
+                return Some(is_encrypted);
+            }
+            None
+        });
+
+        let outbound = async {
+            let stream = connect(addr, &INFO_HASH, outbound).await.ok()?;
+            let is_encrypted = stream.is_encrypted();
+            let mut socket = Framed::new(stream, HandshakeCodec);
+            socket.send(Handshake::new(INFO_HASH, [1; 20])).await.ok()?;
+            let peer_handshake = socket.next().await?.ok()?;
+            assert_eq!(peer_handshake.peer_id, [2; 20]);
+            Some(is_encrypted)
+        }
+        .await;
+
+        let inbound = if outbound.is_some() {
+            inbound.await.unwrap()
+        } else {
+            None
+        };
+        match (outbound, inbound) {
+            (Some(outbound), Some(inbound)) => {
+                assert_eq!(outbound, inbound);
+                Some(outbound)
+            }
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn should_connect_according_to_policies() {
+        use EncryptionPolicy::*;
+        // (outbound, inbound, expected outcome)
+        let cases = [
+            (Disabled, Disabled, Some(false)),
+            (Disabled, Enabled, Some(false)),
+            (Disabled, Required, None),
+            (Enabled, Disabled, Some(false)),
+            (Enabled, Enabled, Some(true)),
+            (Enabled, Required, Some(true)),
+            (Required, Disabled, None),
+            (Required, Enabled, Some(true)),
+            (Required, Required, Some(true)),
+        ];
+        for (outbound, inbound, expected) in cases.iter() {
+            assert_eq!(
+                handshake(*outbound, *inbound).await,
+                *expected,
+                "outbound: {:?}, inbound: {:?}",
+                outbound,
+                inbound
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn should_select_plaintext_if_peer_only_provides_it() {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbound = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let stream =
+                accept(socket, EncryptionPolicy::Enabled, &[INFO_HASH])
+                    .await
+                    .unwrap();
+            let mut socket = Framed::new(stream, HandshakeCodec);
+            socket.next().await.unwrap().unwrap()
+        });
+
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let stream = initiate(socket, &INFO_HASH, CRYPTO_PLAINTEXT)
+            .await
+            .unwrap();
+        assert!(!stream.is_encrypted());
+        let mut socket = Framed::new(stream, HandshakeCodec);
+        let handshake = Handshake::new(INFO_HASH, [1; 20]);
+        socket.send(handshake).await.unwrap();
+        assert_eq!(inbound.await.unwrap(), handshake);
+    }
+
+    #[tokio::test]
+    async fn should_decrypt_payload_after_peer_padding() {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbound = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut reader = HandshakeReader::new(socket);
+            reader.fill(1 + PROTOCOL_STRING.len()).await.unwrap();
+            let stream = respond(
+                reader,
+                EncryptionPolicy::Required,
+                &[INFO_HASH],
+                &[3; MAX_PAD_LEN],
+            )
+            .await
+            .unwrap();
+            let mut socket = Framed::new(stream, HandshakeCodec);
+            let peer_handshake = socket.next().await.unwrap().unwrap();
+            socket
+                .send(Handshake::new(INFO_HASH, [2; 20]))
+                .await
+                .unwrap();
+            peer_handshake
+        });
+
+        let stream = connect(addr, &INFO_HASH, EncryptionPolicy::Required)
+            .await
+            .unwrap();
+        assert!(stream.is_encrypted());
+        let mut socket = Framed::new(stream, HandshakeCodec);
+        let handshake = Handshake::new(INFO_HASH, [1; 20]);
+        socket.send(handshake).await.unwrap();
+        // the peer's handshake is only readable if its padding was decrypted
+        // along with the rest of its message
+        let peer_handshake = socket.next().await.unwrap().unwrap();
+        assert_eq!(peer_handshake, Handshake::new(INFO_HASH, [2; 20]));
+        assert_eq!(inbound.await.unwrap(), handshake);
+    }
+
+    #[tokio::test]
+    async fn should_reject_unknown_info_hash() {
+        let mut listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let inbound = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            accept(socket, EncryptionPolicy::Required, &[[1; 20]]).await
+        });
+
+        let result =
+            connect(addr, &INFO_HASH, EncryptionPolicy::Required).await;
+        assert!(result.is_err());
+        assert!(matches!(
+            inbound.await.unwrap(),
+            Err(PeerError::InvalidInfoHash)
+        ));
+    }
+}
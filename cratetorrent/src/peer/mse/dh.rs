@@ -0,0 +1,259 @@
+//! The Diffie-Hellman key exchange of the MSE handshake.
+//!
+//! The exchange is done in the 768 bit group defined by the specification, so
+//! rather than pulling in a general purpose big integer library, this module
+//! implements just the modular exponentiation in that group, using Montgomery
+//! multiplication.
+
+use rand::Rng;
+
+/// The length of the public keys and the shared secret, in bytes.
+pub(super) const KEY_LEN: usize = 96;
+
+/// The number of 64 bit limbs in a number of the group.
+const LIMB_COUNT: usize = KEY_LEN / 8;
+
+/// A number in the group, in little endian order of limbs.
+type Num = [u64; LIMB_COUNT];
+
+/// The prime modulus of the group.
+const P: Num = [
+    0x0000000000090563,
+    0xf44c42e9a63a3621,
+    0xe485b576625e7ec6,
+    0x4fe1356d6d51c245,
+    0x302b0a6df25f1437,
+    0xef9519b3cd3a431b,
+    0x514a08798e3404dd,
+    0x020bbea63b139b22,
+    0x29024e088a67cc74,
+    0xc4c6628b80dc1cd1,
+    0xc90fdaa22168c234,
+    0xffffffffffffffff,
+];
+
+/// The generator of the group.
+const G: u64 = 2;
+
+/// An ephemeral key pair of one side of the handshake.
+pub(super) struct KeyPair {
+    /// The private key, which the specification recommends to be 160 bits.
+    secret: [u8; 20],
+    /// The public key sent to the peer: `G^secret mod P`.
+    pub public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// Generates a new random key pair.
+    pub fn new() -> Self {
+        let secret: [u8; 20] = rand::thread_rng().gen();
+        let mut g = [0; LIMB_COUNT];
+        g[0] = G;
+        let public = to_be_bytes(&Montgomery::new().pow(&g, &secret));
+        Self { secret, public }
+    }
+
+    /// Returns the secret shared with the peer whose public key is given.
+    pub fn shared_secret(&self, peer_public: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+        let mut y = from_be_bytes(peer_public);
+        // the peer's key should be reduced, but it costs nothing to make sure
+        if !is_less(&y, &P) {
+            sub_assign(&mut y, &P);
+        }
+        to_be_bytes(&Montgomery::new().pow(&y, &self.secret))
+    }
+}
+
+/// Arithmetic in the Montgomery domain modulo `P`, with `R = 2^768`.
+struct Montgomery {
+    /// `-P^-1 mod 2^64`.
+    p_inv: u64,
+    /// `R mod P`, which is 1 in the Montgomery domain.
+    one: Num,
+    /// `R^2 mod P`, used to convert numbers into the Montgomery domain.
+    r2: Num,
+}
+
+impl Montgomery {
+    fn new() -> Self {
+        // Newton's iteration doubles the number of correct low bits of the
+        // inverse with each step, starting from the one bit that is correct
+        // for any odd number
+        let mut inv = 1u64;
+        for _ in 0..6 {
+            inv = inv.wrapping_mul(2u64.wrapping_sub(P[0].wrapping_mul(inv)));
+        }
+
+        // since P > 2^767, R mod P is R - P, which is the two's complement of
+        // P in 768 bits
+        let mut one = [0; LIMB_COUNT];
+        sub_assign(&mut one, &P);
+
+        // doubling R mod P another 768 times yields R^2 mod P
+        let mut r2 = one;
+        for _ in 0..LIMB_COUNT * 64 {
+            let carry = r2[LIMB_COUNT - 1] >> 63;
+            for i in (1..LIMB_COUNT).rev() {
+                r2[i] = (r2[i] << 1) | (r2[i - 1] >> 63);
+            }
+            r2[0] <<= 1;
+            if carry != 0 || !is_less(&r2, &P) {
+                sub_assign(&mut r2, &P);
+            }
+        }
+
+        Self {
+            p_inv: inv.wrapping_neg(),
+            one,
+            r2,
+        }
+    }
+
+    /// Returns `base^exp mod P`, where `base` is less than `P` and `exp` is
+    /// a big endian number of any length.
+    fn pow(&self, base: &Num, exp: &[u8]) -> Num {
+        let base = self.mul(base, &self.r2);
+        let mut result = self.one;
+        for byte in exp {
+            for bit in (0..8).rev() {
+                result = self.mul(&result, &result);
+                if (byte >> bit) & 1 == 1 {
+                    result = self.mul(&result, &base);
+                }
+            }
+        }
+        // convert back from the Montgomery domain
+        let mut one = [0; LIMB_COUNT];
+        one[0] = 1;
+        self.mul(&result, &one)
+    }
+
+    /// Returns `a * b * R^-1 mod P`, where `a` and `b` are less than `P`.
+    fn mul(&self, a: &Num, b: &Num) -> Num {
+        // the product is accumulated in two extra limbs, and shifted right by
+        // a limb in each round, after adding the multiple of P that zeroes the
+        // lowest limb
+        let mut t = [0u64; LIMB_COUNT + 2];
+        for b_limb in b.iter() {
+            let mut carry = 0u128;
+            for (t_limb, a_limb) in t.iter_mut().zip(a.iter()) {
+                let sum =
+                    *t_limb as u128 + *a_limb as u128 * *b_limb as u128 + carry;
+                *t_limb = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMB_COUNT] as u128 + carry;
+            t[LIMB_COUNT] = sum as u64;
+            t[LIMB_COUNT + 1] = (sum >> 64) as u64;
+
+            let m = t[0].wrapping_mul(self.p_inv);
+            let mut carry = (t[0] as u128 + m as u128 * P[0] as u128) >> 64;
+            for i in 1..LIMB_COUNT {
+                let sum = t[i] as u128 + m as u128 * P[i] as u128 + carry;
+                t[i - 1] = sum as u64;
+                carry = sum >> 64;
+            }
+            let sum = t[LIMB_COUNT] as u128 + carry;
+            t[LIMB_COUNT - 1] = sum as u64;
+            t[LIMB_COUNT] = t[LIMB_COUNT + 1] + (sum >> 64) as u64;
+            t[LIMB_COUNT + 1] = 0;
+        }
+
+        // the result is less than 2P, so a single subtraction reduces it
+        let mut result = [0; LIMB_COUNT];
+        result.copy_from_slice(&t[..LIMB_COUNT]);
+        if t[LIMB_COUNT] != 0 || !is_less(&result, &P) {
+            sub_assign(&mut result, &P);
+        }
+        result
+    }
+}
+
+/// Returns whether `a < b`.
+fn is_less(a: &Num, b: &Num) -> bool {
+    a.iter().rev().cmp(b.iter().rev()) == std::cmp::Ordering::Less
+}
+
+/// Subtracts `b` from `a`, wrapping around on underflow.
+fn sub_assign(a: &mut Num, b: &Num) {
+    let mut borrow = false;
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        let (diff, borrow1) = a.overflowing_sub(*b);
+        let (diff, borrow2) = diff.overflowing_sub(borrow as u64);
+        *a = diff;
+        borrow = borrow1 || borrow2;
+    }
+}
+
+fn from_be_bytes(bytes: &[u8; KEY_LEN]) -> Num {
+    let mut num = [0; LIMB_COUNT];
+    for (limb, chunk) in num.iter_mut().zip(bytes.chunks_exact(8).rev()) {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(chunk);
+        *limb = u64::from_be_bytes(buf);
+    }
+    num
+}
+
+fn to_be_bytes(num: &Num) -> [u8; KEY_LEN] {
+    let mut bytes = [0; KEY_LEN];
+    for (chunk, limb) in bytes.chunks_exact_mut(8).rev().zip(num.iter()) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: u64) -> Num {
+        let mut num = [0; LIMB_COUNT];
+        num[0] = n;
+        num
+    }
+
+    #[test]
+    fn should_raise_to_small_powers() {
+        let m = Montgomery::new();
+        assert_eq!(m.pow(&num(2), &[]), num(1));
+        assert_eq!(m.pow(&num(2), &[10]), num(1024));
+        assert_eq!(m.pow(&num(3), &[0, 40]), num(3u64.pow(40)));
+        // powers of two below P are just shifted
+        let mut expected = [0; LIMB_COUNT];
+        expected[LIMB_COUNT - 1] = 1 << 62;
+        assert_eq!(m.pow(&num(2), &[2, 254]), expected);
+    }
+
+    #[test]
+    fn should_reduce_modulo_prime() {
+        let m = Montgomery::new();
+        // by Fermat's little theorem, a^(P-1) mod P = 1
+        let mut exp = to_be_bytes(&P);
+        exp[KEY_LEN - 1] -= 1;
+        assert_eq!(m.pow(&num(2), &exp), num(1));
+        assert_eq!(m.pow(&num(12345), &exp), num(1));
+        // and thus a^P mod P = a
+        let a = from_be_bytes(&[0x5a; KEY_LEN]);
+        assert_eq!(m.pow(&a, &to_be_bytes(&P)), a);
+    }
+
+    #[test]
+    fn should_agree_on_shared_secret() {
+        let a = KeyPair::new();
+        let b = KeyPair::new();
+        assert_ne!(a.public, b.public);
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+    }
+
+    #[test]
+    fn should_convert_bytes() {
+        let mut bytes = [0; KEY_LEN];
+        bytes[0] = 0xff;
+        bytes[KEY_LEN - 1] = 1;
+        let n = from_be_bytes(&bytes);
+        assert_eq!(n[0], 1);
+        assert_eq!(n[LIMB_COUNT - 1], 0xff << 56);
+        assert_eq!(to_be_bytes(&n), bytes);
+    }
+}
@@ -0,0 +1,72 @@
+//! The RC4 stream cipher with which MSE encrypts the connection.
+
+/// An RC4 keystream, which encrypts and decrypts alike.
+#[derive(Clone)]
+pub(super) struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// Creates the cipher from the key, discarding the first 1024 bytes of
+    /// the keystream, as required by MSE.
+    pub fn new(key: &[u8]) -> Self {
+        let mut rc4 = Self::with_key(key);
+        rc4.apply(&mut [0; 1024]);
+        rc4
+    }
+
+    /// Creates the cipher from the key without discarding any of the
+    /// keystream.
+    fn with_key(key: &[u8]) -> Self {
+        let mut s = [0; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts the buffer in place.
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for b in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize]
+                .wrapping_add(self.s[self.j as usize])
+                as usize];
+            *b ^= k;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_match_test_vector() {
+        let mut rc4 = Rc4::with_key(b"Key");
+        let mut buf = *b"Plaintext";
+        rc4.apply(&mut buf);
+        assert_eq!(buf, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    #[test]
+    fn should_decrypt_what_it_encrypted() {
+        let mut enc = Rc4::new(b"secret key");
+        let mut dec = Rc4::new(b"secret key");
+        let mut buf = *b"BitTorrent protocol";
+        enc.apply(&mut buf);
+        assert_ne!(&buf, b"BitTorrent protocol");
+        dec.apply(&mut buf[..5]);
+        dec.apply(&mut buf[5..]);
+        assert_eq!(&buf, b"BitTorrent protocol");
+    }
+}
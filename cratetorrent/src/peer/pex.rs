@@ -0,0 +1,373 @@
+//! This module implements the [peer exchange
+//! extension](http://bittorrent.org/beps/bep_0011.html) (`ut_pex`), with which
+//! connected peers periodically tell each other which peers they connected to
+//! and disconnected from since their previous message.
+//!
+//! The torrent periodically sends each session the peers that can be
+//! advertised, and the session tells its peer what changed since the last
+//! message with a [`PexState`]. The peers received from the peer are passed to
+//! the torrent by the [`PexHandler`].
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    peer::{error::*, extension::*},
+    torrent,
+};
+
+/// The name of the extension in the extended handshake.
+pub(crate) const UT_PEX: &str = "ut_pex";
+
+/// Peer exchange messages are sent this often, which is the most frequently
+/// allowed by the specification.
+pub(crate) const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The maximum number of added and of dropped peers in a single message. The
+/// rest of our changes are sent in the next message, while the excess peers
+/// in the messages we receive are ignored.
+const MAX_PEX_PEERS: usize = 50;
+
+/// The flag of a peer that is a seed.
+pub(crate) const PEX_FLAG_SEED: u8 = 0x02;
+/// The flag of a peer that accepts incoming connections, which we know if we
+/// connected to it.
+pub(crate) const PEX_FLAG_CONNECTABLE: u8 = 0x10;
+
+/// The length of an IPv4 peer in compact form.
+const COMPACT_PEER_LEN: usize = 6;
+/// The length of an IPv6 peer in compact form.
+const COMPACT_PEER6_LEN: usize = 18;
+
+/// A peer that we advertise to other peers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct PexPeer {
+    /// The address on which the peer accepts connections.
+    pub addr: SocketAddr,
+    /// The `PEX_FLAG_*` flags of the peer.
+    pub flags: u8,
+}
+
+/// The bencoded dictionary of a peer exchange message. The peers are in
+/// compact form, and each added peer has a byte of flags.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct PexMsg {
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+    #[serde(rename = "added.f", default, with = "serde_bytes")]
+    added_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added6: Vec<u8>,
+    #[serde(
+        rename = "added6.f",
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    added6_flags: Vec<u8>,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        with = "serde_bytes"
+    )]
+    dropped6: Vec<u8>,
+}
+
+impl PexMsg {
+    fn add_peer(&mut self, peer: &PexPeer) {
+        match peer.addr.ip() {
+            IpAddr::V4(_) => {
+                encode_compact_peer(&mut self.added, &peer.addr);
+                self.added_flags.push(peer.flags);
+            }
+            IpAddr::V6(_) => {
+                encode_compact_peer(&mut self.added6, &peer.addr);
+                self.added6_flags.push(peer.flags);
+            }
+        }
+    }
+
+    fn drop_peer(&mut self, addr: &SocketAddr) {
+        match addr.ip() {
+            IpAddr::V4(_) => encode_compact_peer(&mut self.dropped, addr),
+            IpAddr::V6(_) => encode_compact_peer(&mut self.dropped6, addr),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.dropped.is_empty()
+            && self.added6.is_empty()
+            && self.dropped6.is_empty()
+    }
+
+    /// Returns at most `MAX_PEX_PEERS` of the added peers.
+    fn added_peers(&self) -> Result<Vec<SocketAddr>> {
+        let mut peers = decode_compact_peers(&self.added, COMPACT_PEER_LEN)?;
+        peers.extend(decode_compact_peers(&self.added6, COMPACT_PEER6_LEN)?);
+        peers.truncate(MAX_PEX_PEERS);
+        Ok(peers)
+    }
+}
+
+/// Passes the peers that the peer tells us about to the torrent.
+pub(crate) struct PexHandler {
+    /// The address of the peer, which is the source of the peers.
+    addr: SocketAddr,
+    torrent_tx: torrent::Sender,
+}
+
+impl PexHandler {
+    pub fn new(addr: SocketAddr, torrent_tx: torrent::Sender) -> Self {
+        Self { addr, torrent_tx }
+    }
+}
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    fn handle_msg(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let msg: PexMsg = serde_bencode::from_bytes(payload)?;
+        let peers = msg.added_peers()?;
+        if !peers.is_empty() {
+            self.torrent_tx.send(torrent::Command::PexPeers {
+                addr: self.addr,
+                peers,
+            })?;
+        }
+        // dropped peers are not removed from the torrent's peers to which we
+        // can connect, as the peer may have disconnected them for reasons that
+        // don't concern us
+        Ok(Vec::new())
+    }
+}
+
+/// Keeps track of the peers that we advertised to the peer, so that each
+/// message only contains the changes since the previous one.
+#[derive(Debug, Default)]
+pub(crate) struct PexState {
+    advertised: HashSet<SocketAddr>,
+}
+
+impl PexState {
+    /// Returns the payload of the message that tells the peer about the
+    /// changes in our peers, or `None` if there are no changes.
+    ///
+    /// At most `MAX_PEX_PEERS` added and dropped peers are included, the rest
+    /// are left for the next message.
+    pub fn update(&mut self, peers: &[PexPeer]) -> Result<Option<Vec<u8>>> {
+        let mut msg = PexMsg::default();
+
+        let added: Vec<_> = peers
+            .iter()
+            .filter(|peer| !self.advertised.contains(&peer.addr))
+            .take(MAX_PEX_PEERS)
+            .collect();
+        for peer in added {
+            msg.add_peer(peer);
+            self.advertised.insert(peer.addr);
+        }
+
+        let current: HashSet<_> = peers.iter().map(|peer| peer.addr).collect();
+        let dropped: Vec<_> = self
+            .advertised
+            .iter()
+            .filter(|addr| !current.contains(addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        for addr in dropped.iter() {
+            msg.drop_peer(addr);
+            self.advertised.remove(addr);
+        }
+
+        if msg.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(serde_bencode::to_bytes(&msg)?))
+        }
+    }
+}
+
+/// Appends the address and port of the peer in network byte order.
+fn encode_compact_peer(buf: &mut Vec<u8>, addr: &SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Decodes the concatenation of compact peers of the given length, skipping
+/// the ones that we can't connect to.
+fn decode_compact_peers(buf: &[u8], len: usize) -> Result<Vec<SocketAddr>> {
+    let peers = buf.chunks_exact(len);
+    if !peers.remainder().is_empty() {
+        return Err(PeerError::InvalidExtendedMessage);
+    }
+    Ok(peers
+        .filter_map(|peer| {
+            let (ip, port) = peer.split_at(len - 2);
+            let ip: IpAddr = if ip.len() == 4 {
+                Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).into()
+            } else {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(ip);
+                Ipv6Addr::from(octets).into()
+            };
+            let port = u16::from_be_bytes([port[0], port[1]]);
+            if ip.is_unspecified() || port == 0 {
+                None
+            } else {
+                Some(SocketAddr::new(ip, port))
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn peer(n: u8, flags: u8) -> PexPeer {
+        PexPeer {
+            addr: SocketAddr::new(Ipv4Addr::new(10, 0, 0, n).into(), 6881),
+            flags,
+        }
+    }
+
+    fn decode(payload: &[u8]) -> PexMsg {
+        serde_bencode::from_bytes(payload).unwrap()
+    }
+
+    #[test]
+    fn should_encode_and_decode_msg() {
+        let ip6 = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0x1ae1);
+        let mut msg = PexMsg::default();
+        msg.add_peer(&peer(1, PEX_FLAG_SEED));
+        msg.add_peer(&PexPeer {
+            addr: ip6,
+            flags: PEX_FLAG_CONNECTABLE,
+        });
+        msg.drop_peer(&peer(2, 0).addr);
+
+        let encoded = serde_bencode::to_bytes(&msg).unwrap();
+        let mut expected = b"d5:added6:\x0a\0\0\x01\x1a\xe1\
+            7:added.f1:\x02\
+            6:added618:"
+            .to_vec();
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(b"\x1a\xe18:added6.f1:\x10");
+        expected.extend_from_slice(b"7:dropped6:\x0a\0\0\x02\x1a\xe1e");
+        assert_eq!(encoded, expected);
+
+        let decoded = decode(&encoded);
+        assert_eq!(decoded, msg);
+        assert_eq!(decoded.added_peers().unwrap(), vec![peer(1, 0).addr, ip6]);
+    }
+
+    #[test]
+    fn should_reject_invalid_compact_peers() {
+        let msg = decode(b"d5:added5:\x0a\0\0\x01\x1ae");
+        assert!(matches!(
+            msg.added_peers(),
+            Err(PeerError::InvalidExtendedMessage)
+        ));
+        // peers we can't connect to are skipped
+        let msg = decode(b"d5:added12:\0\0\0\0\x1a\xe1\x0a\0\0\x01\0\0e");
+        assert!(msg.added_peers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_only_send_changes() {
+        let mut state = PexState::default();
+        assert_eq!(state.update(&[]).unwrap(), None);
+
+        let payload = state.update(&[peer(1, 0), peer(2, PEX_FLAG_SEED)]);
+        let msg = decode(&payload.unwrap().unwrap());
+        assert_eq!(msg.added.len(), 2 * COMPACT_PEER_LEN);
+        assert_eq!(msg.added_flags, vec![0, PEX_FLAG_SEED]);
+        assert!(msg.dropped.is_empty());
+
+        // nothing changed
+        assert_eq!(state.update(&[peer(1, 0), peer(2, 0)]).unwrap(), None);
+
+        let payload = state.update(&[peer(2, 0), peer(3, 0)]);
+        let msg = decode(&payload.unwrap().unwrap());
+        assert_eq!(
+            decode_compact_peers(&msg.added, COMPACT_PEER_LEN).unwrap(),
+            vec![peer(3, 0).addr]
+        );
+        assert_eq!(
+            decode_compact_peers(&msg.dropped, COMPACT_PEER_LEN).unwrap(),
+            vec![peer(1, 0).addr]
+        );
+    }
+
+    #[test]
+    fn should_cap_peers_per_msg() {
+        let peers: Vec<_> =
+            (1..=MAX_PEX_PEERS as u8 + 10).map(|n| peer(n, 0)).collect();
+        let mut state = PexState::default();
+
+        let msg = decode(&state.update(&peers).unwrap().unwrap());
+        assert_eq!(msg.added.len(), MAX_PEX_PEERS * COMPACT_PEER_LEN);
+        // the rest of the peers are sent in the next message
+        let msg = decode(&state.update(&peers).unwrap().unwrap());
+        assert_eq!(msg.added.len(), 10 * COMPACT_PEER_LEN);
+        assert_eq!(state.update(&peers).unwrap(), None);
+
+        let msg = decode(&state.update(&[]).unwrap().unwrap());
+        assert_eq!(msg.dropped.len(), MAX_PEX_PEERS * COMPACT_PEER_LEN);
+        let msg = decode(&state.update(&[]).unwrap().unwrap());
+        assert_eq!(msg.dropped.len(), 10 * COMPACT_PEER_LEN);
+    }
+
+    #[test]
+    fn should_pass_received_peers_to_torrent() {
+        let (torrent_tx, mut torrent_rx) = mpsc::unbounded_channel();
+        let addr = peer(100, 0).addr;
+        let mut handler = PexHandler::new(addr, torrent_tx);
+
+        let peers: Vec<_> =
+            (1..=MAX_PEX_PEERS as u8 + 10).map(|n| peer(n, 0)).collect();
+        let payload = PexState::default().update(&peers).unwrap().unwrap();
+        // the state caps the message itself, so make the message longer
+        let mut msg = decode(&payload);
+        for n in MAX_PEX_PEERS as u8 + 1..=MAX_PEX_PEERS as u8 + 10 {
+            msg.add_peer(&peer(n, 0));
+        }
+        let payload = serde_bencode::to_bytes(&msg).unwrap();
+
+        assert!(handler.handle_msg(&payload).unwrap().is_empty());
+        match torrent_rx.try_recv() {
+            Ok(torrent::Command::PexPeers {
+                addr: from,
+                peers: received,
+            }) => {
+                assert_eq!(from, addr);
+                assert_eq!(received.len(), MAX_PEX_PEERS);
+                assert_eq!(received[0], peers[0].addr);
+            }
+            _ => panic!("torrent should receive peers"),
+        }
+
+        // messages without added peers are not passed on
+        let payload = b"d5:added0:7:added.f0:7:dropped6:\x0a\0\0\x01\x1a\xe1e";
+        assert!(handler.handle_msg(payload).unwrap().is_empty());
+        assert!(torrent_rx.try_recv().is_err());
+    }
+}
@@ -139,6 +139,8 @@ pub(super) struct SessionContext {
     /// The time the BitTorrent connection was established (i.e. after
     /// handshaking)
     pub connected_time: Option<Instant>,
+    /// The last time we choked the peer.
+    pub last_peer_choke_time: Option<Instant>,
 
     /// The log header to use for logging.
     pub log_target: String,
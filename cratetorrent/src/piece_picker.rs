@@ -1,5 +1,27 @@
-use crate::{Bitfield, PieceIndex};
-
+use std::ops::Range;
+
+use rand::{seq::IteratorRandom, Rng};
+
+use crate::{storage_info::FilePriority, Bitfield, PieceIndex};
+
+/// Until we have this many pieces, we pick pieces at random rather than the
+/// rarest ones. This way we get pieces to share with others sooner, as rare
+/// pieces tend to be available from fewer, and often slower, peers.
+const RANDOM_PICK_PIECE_COUNT: usize = 4;
+
+/// The number of distinct piece priorities.
+const PRIORITY_COUNT: usize = 4;
+
+/// Picks the pieces to download, highest priority and rarest first.
+///
+/// The picker keeps track of how many peers have each piece, and the free
+/// pieces (the ones we want but neither have nor are downloading) are grouped
+/// into buckets by their priority and this frequency, so that the rarest
+/// pieces can be found without scanning all pieces.
+///
+/// For streaming, the picker may instead pick pieces in order, and a range of
+/// priority pieces (e.g. the ones after the playback position of a video) can
+/// be set, which are picked before any other piece.
 pub(crate) struct PiecePicker {
     /// Represents the pieces that we have downloaded.
     ///
@@ -10,11 +32,23 @@ pub(crate) struct PiecePicker {
     ///
     /// The vector is pre-allocated to the number of pieces in the torrent.
     pieces: Vec<Piece>,
-    /// A cache for the number of pieces we haven't received yet (but may have
-    /// picked).
+    /// The indices of the free pieces, grouped by the pieces' priority and
+    /// then frequency, so that the bucket at `buckets[p][n]` contains the free
+    /// pieces of priority `p` that `n` peers have. Skipped pieces are never
+    /// free, so their buckets are always empty.
+    ///
+    /// The order of pieces within a bucket is arbitrary.
+    buckets: [Vec<Vec<PieceIndex>>; PRIORITY_COUNT],
+    /// A cache for the number of wanted pieces we haven't received yet (but
+    /// may have picked).
     missing_count: usize,
     /// A cache for the number of pieces that can be picked.
     free_count: usize,
+    /// If set, pieces are picked in order rather than rarest first.
+    is_sequential: bool,
+    /// The pieces that are picked, in order, before any other piece. Empty if
+    /// there are no priority pieces.
+    priority_pieces: Range<PieceIndex>,
 }
 
 /// Metadata about a piece relevant for the piece picker.
@@ -32,29 +66,128 @@ pub(crate) struct Piece {
     /// wouldn't be able to download multiple pieces simultaneously (an
     /// important optimizaiton step).
     pub is_pending: bool,
+    /// The priority of the piece, which is that of the highest priority file
+    /// the piece overlaps with. Skipped pieces are not picked.
+    pub priority: FilePriority,
+    /// The position of the piece in its frequency bucket, if the piece is
+    /// free.
+    bucket_pos: Option<usize>,
 }
 
 impl PiecePicker {
     /// Creates a new piece picker with the given own_pieces we already have.
+    ///
+    /// All pieces have normal priority.
     pub fn new(own_pieces: Bitfield) -> Self {
         let mut pieces = Vec::new();
         pieces.resize_with(own_pieces.len(), Piece::default);
         let missing_count = own_pieces.count_zeros();
+        // initially no peer has any pieces, so all free pieces are in the
+        // first bucket of their priority
+        let mut free_pieces = Vec::with_capacity(missing_count);
+        let free_indices = own_pieces
+            .iter()
+            .enumerate()
+            .filter(|(_, have_piece)| !**have_piece)
+            .map(|(index, _)| index);
+        for index in free_indices {
+            pieces[index].bucket_pos = Some(free_pieces.len());
+            free_pieces.push(index);
+        }
+        let mut buckets: [Vec<Vec<PieceIndex>>; PRIORITY_COUNT] =
+            Default::default();
+        buckets[FilePriority::Normal as usize].push(free_pieces);
         Self {
             own_pieces,
             pieces,
+            buckets,
             missing_count,
             free_count: missing_count,
+            is_sequential: false,
+            priority_pieces: 0..0,
         }
     }
 
+    /// Sets whether pieces are picked in order rather than rarest first.
+    pub fn set_sequential(&mut self, is_sequential: bool) {
+        self.is_sequential = is_sequential;
+    }
+
+    /// Sets the priority of each piece. Pieces whose priority is
+    /// [`FilePriority::Skip`] are not picked and are not needed for the
+    /// download to complete.
+    ///
+    /// Pieces that are already being downloaded are not affected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of priorities differs from the number of pieces.
+    pub fn set_piece_priorities(&mut self, priorities: &[FilePriority]) {
+        assert_eq!(
+            priorities.len(),
+            self.pieces.len(),
+            "there must be a priority for each piece"
+        );
+        for (index, priority) in priorities.iter().copied().enumerate() {
+            let piece = self.pieces[index];
+            if piece.priority == priority {
+                continue;
+            }
+
+            let is_wanted = priority != FilePriority::Skip;
+            let have_piece = self.own_pieces[index];
+            if !have_piece {
+                let was_wanted = piece.priority != FilePriority::Skip;
+                if was_wanted && !is_wanted {
+                    self.missing_count -= 1;
+                } else if !was_wanted && is_wanted {
+                    self.missing_count += 1;
+                }
+            }
+
+            // the piece is moved to the buckets of its new priority
+            if piece.bucket_pos.is_some() {
+                self.remove_from_bucket(index);
+                self.free_count -= 1;
+            }
+            self.pieces[index].priority = priority;
+            if is_wanted && !have_piece && !piece.is_pending {
+                self.add_to_bucket(index);
+                self.free_count += 1;
+            }
+        }
+    }
+
+    /// Returns whether the peer has any piece we want but don't have.
+    pub fn is_interested(&self, peer_pieces: &Bitfield) -> bool {
+        (0..peer_pieces.len()).any(|index| {
+            peer_pieces[index]
+                && !self.own_pieces[index]
+                && self.pieces[index].priority != FilePriority::Skip
+        })
+    }
+
+    /// Sets the pieces that are picked before all others, in order. An empty
+    /// range clears the priority pieces.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is past the last piece.
+    pub fn set_priority_pieces(&mut self, pieces: Range<PieceIndex>) {
+        assert!(
+            pieces.end <= self.own_pieces.len(),
+            "priority pieces must be valid piece indices"
+        );
+        self.priority_pieces = pieces;
+    }
+
     /// Returns an immutable reference to a bitfield of the pieces we own.
     pub fn own_pieces(&self) -> &Bitfield {
         &self.own_pieces
     }
 
-    /// Returns the number of missing pieces that are needed to complete the
-    /// download.
+    /// Returns the number of missing wanted pieces that are needed to complete
+    /// the download.
     pub fn missing_piece_count(&self) -> usize {
         self.missing_count
     }
@@ -65,32 +198,100 @@ impl PiecePicker {
         self.free_count == 0
     }
 
-    /// Returns the first piece that we don't yet have and isn't already being
-    /// downloaded, or None, if no piece can be picked at this time.
-    pub fn pick_piece(&mut self) -> Option<PieceIndex> {
+    /// Returns the next piece that the peer has but we don't yet have and
+    /// isn't already being downloaded, or None, if no piece can be picked at
+    /// this time.
+    ///
+    /// Priority pieces are picked first, in order. Then, in sequential mode,
+    /// the first free piece is picked, and otherwise the rarest one of the
+    /// highest priority, with ties between equally rare pieces broken at
+    /// random. While we have only a few pieces, a random piece of the highest
+    /// priority is picked instead of the rarest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the peer's bitfield has a different length than ours.
+    pub fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
         log::trace!("Picking next piece");
+        assert_eq!(
+            peer_pieces.len(),
+            self.own_pieces.len(),
+            "peer's bitfield must be the same length as ours"
+        );
+
+        let is_pickable = |index: &PieceIndex| {
+            self.pieces[*index].bucket_pos.is_some() && peer_pieces[*index]
+        };
+        let index =
+            self.priority_pieces.clone().find(is_pickable).or_else(|| {
+                if self.is_sequential {
+                    (0..self.pieces.len()).find(is_pickable)
+                } else {
+                    self.pick_rarest_piece(peer_pieces)
+                }
+            });
 
-        for index in 0..self.own_pieces.len() {
-            // only consider this piece if we don't have it and if we are not
-            // already downloading it (whether it's not pending)
-            debug_assert!(index < self.pieces.len());
-            let piece = &mut self.pieces[index];
-            if !self.own_pieces[index]
-                && piece.frequency > 0
-                && !piece.is_pending
-            {
+        match index {
+            Some(index) => {
                 // set pending flag on piece so that this piece is not picked
                 // again (see note on field)
-                piece.is_pending = true;
+                self.remove_from_bucket(index);
+                self.pieces[index].is_pending = true;
                 self.free_count -= 1;
                 log::trace!("Picked piece {}", index);
-                return Some(index);
+                Some(index)
+            }
+            None => {
+                log::trace!("Could not pick piece");
+                None
             }
         }
+    }
+
+    /// Marks a piece we don't have as being downloaded, as if it had been
+    /// picked. This is used for the pieces that were partially downloaded
+    /// before the torrent was restarted, whose downloads are resumed.
+    pub fn mark_pending(&mut self, index: PieceIndex) {
+        debug_assert!(!self.own_pieces[index]);
+        if self.pieces[index].bucket_pos.is_some() {
+            self.remove_from_bucket(index);
+            self.free_count -= 1;
+        }
+        self.pieces[index].is_pending = true;
+    }
 
-        // no piece could be picked
-        log::trace!("Could not pick piece");
-        None
+    /// Returns the rarest free piece of the highest priority that the peer
+    /// has, or a random one while we have only a few pieces.
+    fn pick_rarest_piece(&self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        let mut rng = rand::thread_rng();
+        let is_random_pick =
+            self.own_pieces.count_ones() < RANDOM_PICK_PIECE_COUNT;
+        self.buckets.iter().rev().find_map(|buckets| {
+            // pieces that no peer has are in the first bucket and can't be
+            // picked
+            if is_random_pick {
+                return buckets
+                    .iter()
+                    .skip(1)
+                    .flatten()
+                    .copied()
+                    .filter(|index| peer_pieces[*index])
+                    .choose(&mut rng);
+            }
+            buckets.iter().skip(1).find_map(|bucket| {
+                // start at a random position in the bucket so that peers
+                // don't all download the same pieces
+                if bucket.is_empty() {
+                    return None;
+                }
+                let start = rng.gen_range(0..bucket.len());
+                bucket[start..]
+                    .iter()
+                    .chain(bucket[..start].iter())
+                    .copied()
+                    .find(|index| peer_pieces[*index])
+            })
+        })
     }
 
     /// Registers the avilability of a peer's pieces and returns whether we're
@@ -111,15 +312,15 @@ impl PiecePicker {
         );
 
         let mut interested = false;
-        for (index, (have_piece, peer_has_piece)) in
-            self.own_pieces.iter().zip(pieces.iter()).enumerate()
-        {
+        for index in 0..pieces.len() {
             // increase frequency count for this piece if peer has it
-            if *peer_has_piece {
-                self.pieces[index].frequency += 1;
-                // if we don't have at least one piece peer has, we're
-                // interested
-                if !have_piece {
+            if pieces[index] {
+                self.change_frequency(index, |frequency| frequency + 1);
+                // if we don't have at least one piece peer has that we want,
+                // we're interested
+                if !self.own_pieces[index]
+                    && self.pieces[index].priority != FilePriority::Skip
+                {
                     interested = true;
                 }
             }
@@ -128,7 +329,8 @@ impl PiecePicker {
         interested
     }
 
-    /// Increments the availability of a piece.
+    /// Increments the availability of a piece and returns whether we're
+    /// interested in it.
     ///
     /// This should be called when a peer sends us a `have` message of a new
     /// piece.
@@ -139,10 +341,33 @@ impl PiecePicker {
     /// ensured at the protocol level (in [`crate::peer::PeerSession`]).
     pub fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
         log::trace!("Registering newly available piece {}", index);
-        let is_interested =
-            self.own_pieces.get(index).expect("invalid piece index");
-        self.pieces[index].frequency += 1;
-        *is_interested
+        let have_piece =
+            *self.own_pieces.get(index).expect("invalid piece index");
+        self.change_frequency(index, |frequency| frequency + 1);
+        !have_piece && self.pieces[index].priority != FilePriority::Skip
+    }
+
+    /// Decrements the availability of the pieces of a peer.
+    ///
+    /// This should be called when a peer disconnects.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the peer's bitfield has a different length than ours.
+    pub fn unregister_peer_pieces(&mut self, pieces: &Bitfield) {
+        log::trace!("Unregistering piece availability: {}", pieces);
+        assert_eq!(
+            pieces.len(),
+            self.own_pieces.len(),
+            "peer's bitfield must be the same length as ours"
+        );
+        for index in 0..pieces.len() {
+            if pieces[index] {
+                self.change_frequency(index, |frequency| {
+                    frequency.saturating_sub(1)
+                });
+            }
+        }
     }
 
     /// Tells the piece picker that we have downloaded the piece at the given
@@ -157,15 +382,20 @@ impl PiecePicker {
         // we assert here as this method is only called by internal methods on
         // piece completion, meaning the piece must exist (we can't download an
         // invalid piece)
-        let mut have_piece =
-            self.own_pieces.get_mut(index).expect("invalid piece index");
-        // we must not already have this piece as otherwise the free/missing
-        // count logic is thrown off
-        assert!(!*have_piece);
-
-        // register owned piece
-        *have_piece = true;
-        self.missing_count -= 1;
+        {
+            let mut have_piece =
+                self.own_pieces.get_mut(index).expect("invalid piece index");
+            // we must not already have this piece as otherwise the
+            // free/missing count logic is thrown off
+            assert!(!*have_piece);
+
+            // register owned piece
+            *have_piece = true;
+        }
+        // the piece may have been skipped after it was picked
+        if self.pieces[index].priority != FilePriority::Skip {
+            self.missing_count -= 1;
+        }
 
         // This is an edge-case and shouldn't normally happen, but we guard
         // against it anyway in case there are changes in other parts of the
@@ -173,20 +403,90 @@ impl PiecePicker {
         // If the piece was received without it having previously been picked,
         // we need to decrease the free piece count here, as it is normally done
         // in the `pick_piece` method.
-        let piece = &mut self.pieces[index];
-        if !piece.is_pending {
+        if self.pieces[index].bucket_pos.is_some() {
+            self.remove_from_bucket(index);
             self.free_count -= 1;
-            // also set that this piece is no longer pending (even though we
-            // won't be downloading it anymore, later we may re-download a piece
-            // in which case not resetting the flag would cause us to never pick
-            // the piece again)
-            piece.is_pending = false;
+        }
+    }
+
+    /// Tells the piece picker that a piece we had is missing after all, such
+    /// as when its data on disk fails the hash check, so that it is
+    /// downloaded again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if we don't have the piece.
+    pub fn lost_piece(&mut self, index: PieceIndex) {
+        log::trace!("Registering lost piece {}", index);
+        {
+            let mut have_piece =
+                self.own_pieces.get_mut(index).expect("invalid piece index");
+            assert!(*have_piece);
+            *have_piece = false;
+        }
+        if self.pieces[index].priority != FilePriority::Skip {
+            self.missing_count += 1;
+            self.add_to_bucket(index);
+            self.free_count += 1;
         }
     }
 
     pub fn pieces(&self) -> &[Piece] {
         &self.pieces
     }
+
+    /// Updates the frequency of the piece, moving it to its new bucket if
+    /// it's free.
+    fn change_frequency(
+        &mut self,
+        index: PieceIndex,
+        f: impl FnOnce(usize) -> usize,
+    ) {
+        let is_free = self.pieces[index].bucket_pos.is_some();
+        if is_free {
+            self.remove_from_bucket(index);
+        }
+        let piece = &mut self.pieces[index];
+        piece.frequency = f(piece.frequency);
+        if is_free {
+            self.add_to_bucket(index);
+        }
+    }
+
+    /// Adds the piece to the bucket of its priority and frequency.
+    fn add_to_bucket(&mut self, index: PieceIndex) {
+        let Piece {
+            frequency,
+            priority,
+            ..
+        } = self.pieces[index];
+        let buckets = &mut self.buckets[priority as usize];
+        if buckets.len() <= frequency {
+            buckets.resize_with(frequency + 1, Vec::new);
+        }
+        let bucket = &mut buckets[frequency];
+        self.pieces[index].bucket_pos = Some(bucket.len());
+        bucket.push(index);
+    }
+
+    /// Removes the piece from its bucket, if it's in one.
+    fn remove_from_bucket(&mut self, index: PieceIndex) {
+        let pos = match self.pieces[index].bucket_pos.take() {
+            Some(pos) => pos,
+            None => return,
+        };
+        let Piece {
+            frequency,
+            priority,
+            ..
+        } = self.pieces[index];
+        let bucket = &mut self.buckets[priority as usize][frequency];
+        bucket.swap_remove(pos);
+        // the last piece of the bucket took the removed piece's place
+        if let Some(moved) = bucket.get(pos) {
+            self.pieces[*moved].bucket_pos = Some(pos);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -208,11 +508,9 @@ mod tests {
         let mut picked = HashSet::with_capacity(piece_count);
 
         // pick all pieces one by one
-        for index in 0..piece_count {
-            let pick = piece_picker.pick_piece();
-            // for now we assert that we pick pieces in sequential order, but
-            // later, when we add different algorithms, this line has to change
-            assert_eq!(pick, Some(index));
+        for _ in 0..piece_count {
+            let pick = piece_picker.pick_piece(&available_pieces);
+            assert!(pick.is_some());
             let pick = pick.unwrap();
             // assert that this piece hasn't been picked before
             assert!(!picked.contains(&pick));
@@ -222,6 +520,7 @@ mod tests {
 
         // assert that we picked all pieces
         assert_eq!(picked.len(), piece_count);
+        assert_eq!(piece_picker.pick_piece(&available_pieces), None);
     }
 
     /// Tests registering a received piece causes the piece picker to not pick
@@ -245,12 +544,29 @@ mod tests {
         // request pieces to pick next and make sure the ones we already have
         // are not picked
         for _ in 0..piece_count - owned_pieces.len() {
-            let pick = piece_picker.pick_piece().unwrap();
+            let pick = piece_picker.pick_piece(&available_pieces).unwrap();
             // assert that it's not a piece we already have
             assert!(owned_pieces.iter().all(|owned| *owned != pick));
         }
     }
 
+    #[test]
+    fn should_pick_lost_piece_again() {
+        let piece_count = 4;
+        let mut piece_picker =
+            PiecePicker::new(Bitfield::repeat(true, piece_count));
+        let available_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&available_pieces);
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+        assert_eq!(piece_picker.pick_piece(&available_pieces), None);
+
+        piece_picker.lost_piece(2);
+        assert!(!piece_picker.own_pieces[2]);
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+        assert_eq!(piece_picker.pick_piece(&available_pieces), Some(2));
+        assert!(piece_picker.all_pieces_picked());
+    }
+
     #[test]
     fn should_count_missing_pieces() {
         // empty piece picker
@@ -284,34 +600,179 @@ mod tests {
         let piece_count = 15;
         let mut piece_picker = PiecePicker::empty(piece_count);
         // NOTE: need to register frequency before we pick any pieces
-        piece_picker.register_peer_pieces(&Bitfield::repeat(true, piece_count));
+        let available_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&available_pieces);
 
         assert_eq!(piece_picker.free_count, piece_count);
 
         // picked and received 2 pieces
-        for i in 0..2 {
-            assert!(piece_picker.pick_piece().is_some());
-            piece_picker.received_piece(i);
+        for _ in 0..2 {
+            let index = piece_picker.pick_piece(&available_pieces).unwrap();
+            piece_picker.received_piece(index);
         }
         assert_eq!(piece_picker.free_count, 13);
 
         // pick 3 pieces
+        let mut picked = Vec::new();
         for _ in 0..3 {
-            assert!(piece_picker.pick_piece().is_some());
+            picked.push(piece_picker.pick_piece(&available_pieces).unwrap());
         }
         assert_eq!(piece_picker.free_count, 10);
 
         // received 1 of the above picked pieces: shouldn't change outcome
-        piece_picker.received_piece(2);
+        piece_picker.received_piece(picked[0]);
         assert_eq!(piece_picker.free_count, 10);
 
         // pick rest of the pieces
         for _ in 0..10 {
-            assert!(piece_picker.pick_piece().is_some());
+            assert!(piece_picker.pick_piece(&available_pieces).is_some());
         }
         assert!(piece_picker.all_pieces_picked());
     }
 
+    /// Tests that once we have a few pieces, the rarest pieces are picked
+    /// first, and that only the pieces of the peer are picked.
+    #[test]
+    fn should_pick_rarest_pieces_of_peer() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        for index in 0..RANDOM_PICK_PIECE_COUNT {
+            piece_picker.received_piece(index);
+        }
+
+        // all pieces are available from one peer, and pieces 10 and 12 from
+        // two more peers, while piece 11 from one more
+        let seed_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&seed_pieces);
+        let mut peer_pieces = Bitfield::repeat(false, piece_count);
+        peer_pieces.set(10, true);
+        peer_pieces.set(11, true);
+        peer_pieces.set(12, true);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        peer_pieces.set(11, false);
+        piece_picker.register_peer_pieces(&peer_pieces);
+
+        // the peer that doesn't have the rarest pieces gets the least rare of
+        // its pieces
+        let mut picked: Vec<_> = (0..2)
+            .map(|_| piece_picker.pick_piece(&peer_pieces).unwrap())
+            .collect();
+        picked.sort_unstable();
+        assert_eq!(picked, vec![10, 12]);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+
+        // the seed gets the rarest pieces first
+        let mut picked: Vec<_> = (0..9)
+            .map(|_| piece_picker.pick_piece(&seed_pieces).unwrap())
+            .collect();
+        assert_eq!(picked.pop(), Some(11));
+        picked.sort_unstable();
+        assert_eq!(picked, (4..10).chain(13..15).collect::<Vec<_>>());
+        assert!(piece_picker.all_pieces_picked());
+    }
+
+    /// Tests that pieces of disconnected peers are no longer considered
+    /// available.
+    #[test]
+    fn should_unregister_peer_pieces() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut peer_pieces = Bitfield::repeat(false, piece_count);
+        peer_pieces.set(3, true);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        assert_eq!(piece_picker.pieces()[3].frequency, 1);
+
+        piece_picker.unregister_peer_pieces(&peer_pieces);
+        assert_eq!(piece_picker.pieces()[3].frequency, 0);
+        // even if the peer's bitfield says otherwise, we can't pick pieces
+        // no peer has
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+
+        piece_picker.register_peer_pieces(&peer_pieces);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(3));
+    }
+
+    /// Tests that in sequential mode pieces are picked in order.
+    #[test]
+    fn should_pick_pieces_sequentially() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        piece_picker.set_sequential(true);
+        piece_picker.received_piece(1);
+        let mut peer_pieces = Bitfield::repeat(true, piece_count);
+        peer_pieces.set(3, false);
+        piece_picker.register_peer_pieces(&peer_pieces);
+
+        let picked: Vec<_> = (0..4)
+            .map(|_| piece_picker.pick_piece(&peer_pieces).unwrap())
+            .collect();
+        assert_eq!(picked, vec![0, 2, 4, 5]);
+    }
+
+    /// Tests that priority pieces are picked first, in order, in both rarest
+    /// first and sequential mode.
+    #[test]
+    fn should_pick_priority_pieces_first() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let peer_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        piece_picker.set_priority_pieces(8..11);
+
+        let picked: Vec<_> = (0..3)
+            .map(|_| piece_picker.pick_piece(&peer_pieces).unwrap())
+            .collect();
+        assert_eq!(picked, vec![8, 9, 10]);
+
+        // once the priority pieces are picked, the rest are picked as usual
+        piece_picker.set_sequential(true);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(0));
+
+        // moving the window makes its pieces picked next
+        piece_picker.set_priority_pieces(5..7);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(5));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(6));
+        piece_picker.set_priority_pieces(0..0);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(1));
+    }
+
+    /// Tests that higher priority pieces are picked first and that skipped
+    /// pieces are not picked nor needed for completion.
+    #[test]
+    fn should_pick_pieces_by_priority() {
+        let piece_count = 6;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let peer_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        use FilePriority::*;
+        piece_picker
+            .set_piece_priorities(&[Low, High, Skip, Normal, High, Skip]);
+        assert_eq!(piece_picker.missing_piece_count(), 4);
+
+        let mut picked: Vec<_> = (0..2)
+            .map(|_| piece_picker.pick_piece(&peer_pieces).unwrap())
+            .collect();
+        picked.sort_unstable();
+        assert_eq!(picked, vec![1, 4]);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(3));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(0));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+        assert!(piece_picker.all_pieces_picked());
+
+        for index in [0, 1, 3, 4].iter() {
+            piece_picker.received_piece(*index);
+        }
+        assert_eq!(piece_picker.missing_piece_count(), 0);
+        assert!(!piece_picker.is_interested(&peer_pieces));
+
+        // a skipped piece that is wanted again can be picked
+        piece_picker
+            .set_piece_priorities(&[Low, High, Low, Normal, High, Skip]);
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+        assert!(piece_picker.is_interested(&peer_pieces));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(2));
+    }
+
     /// Tests that the piece picker correctly determines whether we are
     /// interested in a variety of piece sets.
     // TODO: break this up into smaller tests
@@ -2,10 +2,14 @@
 
 pub use crate::{
     alert::{Alert, AlertReceiver},
-    conf::Conf,
-    engine::{self, EngineHandle, Mode, TorrentParams},
+    conf::{Conf, DhtConf},
+    engine::{
+        self, EngineHandle, MagnetParams, Mode, ReadAhead, TorrentParams,
+    },
     error::Error,
+    magnet::MagnetLink,
     metainfo::Metainfo,
+    storage_info::FilePriority,
     TorrentId,
 };
 // this is needed for `AlertReceiver::next`
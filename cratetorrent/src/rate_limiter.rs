@@ -0,0 +1,180 @@
+//! This module contains the token bucket rate limiters that cap the payload
+//! transfer rates of the engine, of torrents and of peers.
+//!
+//! Each transfer takes as many tokens from the buckets of all levels as the
+//! number of bytes transferred. A bucket may go into debt, in which case the
+//! next transfer in that direction has to wait until the bucket is refilled.
+//! Thus transfers are never dropped, just delayed, and the wait is the longest
+//! of any level's.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A token bucket limiting the transfer rate in one direction.
+///
+/// It's cheap to clone, and clones share the same bucket, so that the limit
+/// may be changed while the limiter is in use.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter(Arc<Mutex<Bucket>>);
+
+#[derive(Debug)]
+struct Bucket {
+    /// The number of bytes per second that may be transferred, or `None` if
+    /// there is no limit.
+    rate: Option<u64>,
+    /// The number of bytes that may be transferred right now, which is
+    /// negative if the bucket is in debt.
+    tokens: f64,
+    /// The last time the bucket was refilled.
+    last_refill_time: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with the given rate, in bytes per second. If the
+    /// rate is `None` or 0, transfers are not limited.
+    pub fn new(rate: Option<u64>) -> Self {
+        let rate = rate.filter(|rate| *rate > 0);
+        Self(Arc::new(Mutex::new(Bucket {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            last_refill_time: Instant::now(),
+        })))
+    }
+
+    /// Changes the rate of the limiter, which takes effect with the next
+    /// transfer.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let rate = rate.filter(|rate| *rate > 0);
+        let mut bucket = self.0.lock().unwrap();
+        if let Some(rate) = rate {
+            // a lower limit shouldn't allow the burst of the previous one
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        } else {
+            bucket.tokens = 0.0;
+        }
+        bucket.rate = rate;
+    }
+
+    /// Takes the given number of bytes from the bucket and returns how long
+    /// the next transfer has to wait for the bucket to get out of debt, if at
+    /// all.
+    fn reserve(&self, now: Instant, len: u64) -> Option<Duration> {
+        let mut bucket = self.0.lock().unwrap();
+        let rate = bucket.rate? as f64;
+
+        // at most a second's worth of bytes is saved up, so that the limit is
+        // only exceeded in short bursts
+        let elapsed = now.saturating_duration_since(bucket.last_refill_time);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        bucket.last_refill_time = bucket.last_refill_time.max(now);
+
+        bucket.tokens -= len as f64;
+        if bucket.tokens < 0.0 {
+            Some(Duration::from_secs_f64(-bucket.tokens / rate))
+        } else {
+            None
+        }
+    }
+}
+
+/// The download and upload rate limiters of the engine, a torrent, or a peer.
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimits {
+    pub down: RateLimiter,
+    pub up: RateLimiter,
+}
+
+impl RateLimits {
+    /// Creates the limiters with the given download and upload rates, in
+    /// bytes per second.
+    pub fn new(down: Option<u64>, up: Option<u64>) -> Self {
+        Self {
+            down: RateLimiter::new(down),
+            up: RateLimiter::new(up),
+        }
+    }
+
+    /// Changes the download and upload rates.
+    pub fn set_rates(&self, down: Option<u64>, up: Option<u64>) {
+        self.down.set_rate(down);
+        self.up.set_rate(up);
+    }
+}
+
+/// Takes the transferred bytes from all limiters and returns the time until
+/// which the next transfer in the same direction has to wait, if any.
+pub(crate) fn reserve<'a>(
+    limiters: impl IntoIterator<Item = &'a RateLimiter>,
+    len: u64,
+) -> Option<Instant> {
+    let now = Instant::now();
+    limiters
+        .into_iter()
+        .filter_map(|limiter| limiter.reserve(now, len))
+        .max()
+        .map(|wait| now + wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_not_limit_without_rate() {
+        let limiter = RateLimiter::new(None);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve(now, u32::MAX as u64), None);
+        let limiter = RateLimiter::new(Some(0));
+        assert_eq!(limiter.reserve(now, u32::MAX as u64), None);
+    }
+
+    #[test]
+    fn should_wait_for_bucket_to_get_out_of_debt() {
+        let limiter = RateLimiter::new(Some(1000));
+        let now = limiter.0.lock().unwrap().last_refill_time;
+
+        // the first second's worth of bytes may be transferred right away
+        assert_eq!(limiter.reserve(now, 1000), None);
+        // after which transfers have to wait for the bucket to be refilled
+        assert_eq!(limiter.reserve(now, 500), Some(Duration::from_millis(500)));
+        assert_eq!(
+            limiter.reserve(now + Duration::from_millis(500), 500),
+            Some(Duration::from_millis(500))
+        );
+        // the bucket doesn't save up more than a second's worth of bytes
+        let now = now + Duration::from_secs(10);
+        assert_eq!(limiter.reserve(now, 1000), None);
+        assert!(limiter.reserve(now, 1).is_some());
+    }
+
+    #[test]
+    fn should_change_rate() {
+        let limiter = RateLimiter::new(Some(1000));
+        let now = limiter.0.lock().unwrap().last_refill_time;
+
+        limiter.set_rate(Some(100));
+        assert_eq!(limiter.reserve(now, 100), None);
+        assert_eq!(limiter.reserve(now, 100), Some(Duration::from_secs(1)));
+
+        // lifting the limit clears the debt
+        limiter.set_rate(None);
+        assert_eq!(limiter.reserve(now, 1000), None);
+    }
+
+    #[test]
+    fn should_wait_for_most_limited_level() {
+        let fast = RateLimiter::new(Some(1000));
+        let slow = RateLimiter::new(Some(100));
+        let unlimited = RateLimiter::new(None);
+        let start = Instant::now();
+        let until = reserve(vec![&fast, &slow, &unlimited], 200)
+            .expect("transfer should be limited");
+        // the slow limiter goes 100 bytes into debt, which it pays off in
+        // a second
+        assert!(until >= start + Duration::from_millis(900));
+        assert!(until <= Instant::now() + Duration::from_secs(1));
+    }
+}
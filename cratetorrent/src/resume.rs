@@ -0,0 +1,427 @@
+//! This module contains the resume data of torrents, which is their state
+//! saved to disk so that a torrent doesn't have to download its pieces again
+//! when it is restarted.
+//!
+//! Along with the pieces we have, the resume data records the size and
+//! modification time of each file at the time of saving. When the data is
+//! loaded, these are checked against the files on disk: the pieces
+//! overlapping with a file that has changed since are considered missing, as
+//! we can no longer vouch for their contents.
+
+use std::{
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde_bytes::ByteBuf;
+
+use crate::{
+    block_count, storage_info::StorageInfo, Bitfield, PeerId, PieceIndex,
+    Sha1Hash, BLOCK_LEN,
+};
+
+pub use serde_bencode::Error as BencodeError;
+pub use std::io::Error as IoError;
+
+pub(crate) type Result<T, E = ResumeError> = std::result::Result<T, E>;
+
+/// Error type returned when saving or loading resume data.
+#[derive(Debug)]
+pub(crate) enum ResumeError {
+    /// Holds bencode serialization or deserialization related errors.
+    Bencode(BencodeError),
+    /// The resume data is of another torrent or doesn't match the torrent's
+    /// layout.
+    Invalid,
+    /// Holds IO related errors.
+    Io(IoError),
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ResumeError::*;
+        match self {
+            Bencode(e) => e.fmt(fmt),
+            Invalid => write!(fmt, "resume data doesn't match torrent"),
+            Io(e) => e.fmt(fmt),
+        }
+    }
+}
+
+impl From<BencodeError> for ResumeError {
+    fn from(e: BencodeError) -> Self {
+        Self::Bencode(e)
+    }
+}
+
+impl From<IoError> for ResumeError {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// The state of a torrent that is saved across restarts.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ResumeData {
+    /// The pieces we have.
+    pub own_pieces: Bitfield,
+    /// The state of each file of the torrent at the time of saving.
+    pub files: Vec<FileState>,
+    /// The pieces of which only some blocks were downloaded. The blocks are
+    /// saved to disk along with the resume data.
+    pub partial_pieces: Vec<PartialPiece>,
+    /// The total number of payload bytes downloaded.
+    pub downloaded: u64,
+    /// The total number of payload bytes uploaded.
+    pub uploaded: u64,
+    /// The peers we knew of, which are connected first after a restart.
+    pub peers: Vec<SocketAddr>,
+    /// The peer id of a private torrent, which has to stay the same across
+    /// restarts.
+    pub peer_id: Option<PeerId>,
+    /// The key sent in the torrent's announces.
+    pub announce_key: Option<u32>,
+}
+
+/// The length and modification time of a file on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FileState {
+    pub len: u64,
+    /// The modification time of the file, in nanoseconds since the UNIX
+    /// epoch.
+    pub mtime: u64,
+}
+
+impl FileState {
+    /// Returns the state of the file at the path, or the default (zero)
+    /// state if the file doesn't exist.
+    fn read(path: &Path) -> Self {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Self::default(),
+        };
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default();
+        Self {
+            len: metadata.len(),
+            mtime,
+        }
+    }
+}
+
+/// A piece of which only some blocks were downloaded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PartialPiece {
+    pub index: PieceIndex,
+    /// The offsets of the downloaded blocks within the piece.
+    pub blocks: Vec<u32>,
+}
+
+impl ResumeData {
+    /// Returns the path of the torrent's resume data in the directory.
+    pub fn path(dir: &Path, info_hash: &Sha1Hash) -> PathBuf {
+        dir.join(format!("{}.resume", hex::encode(info_hash)))
+    }
+
+    /// Loads the torrent's resume data from the directory, if it exists and
+    /// is valid, and invalidates the pieces of the files that changed since
+    /// the data was saved.
+    pub fn load(
+        dir: &Path,
+        info_hash: &Sha1Hash,
+        storage: &StorageInfo,
+    ) -> Option<Self> {
+        let path = Self::path(dir, info_hash);
+        let buf = match fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!("Cannot read resume data {:?}: {}", path, e);
+                }
+                return None;
+            }
+        };
+        match Self::from_bytes(&buf, info_hash, storage) {
+            Ok(mut data) => {
+                data.check_files(storage);
+                log::info!(
+                    "Restored {} piece(s) from resume data {:?}",
+                    data.own_pieces.count_ones(),
+                    path
+                );
+                Some(data)
+            }
+            Err(e) => {
+                log::warn!("Invalid resume data {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    /// Saves the torrent's resume data in the directory, creating the
+    /// directory if it doesn't exist.
+    pub fn save(&self, dir: &Path, info_hash: &Sha1Hash) -> Result<()> {
+        let path = Self::path(dir, info_hash);
+        log::debug!("Saving resume data to {:?}", path);
+        fs::create_dir_all(dir)?;
+        fs::write(path, self.to_bytes(info_hash)?)?;
+        Ok(())
+    }
+
+    /// Removes the torrent's resume data from the directory, if it exists.
+    pub fn remove(dir: &Path, info_hash: &Sha1Hash) -> std::io::Result<()> {
+        match fs::remove_file(Self::path(dir, info_hash)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the bencoded resume data.
+    fn to_bytes(&self, info_hash: &Sha1Hash) -> Result<Vec<u8>> {
+        let raw = RawResumeData {
+            info_hash: ByteBuf::from(info_hash.to_vec()),
+            pieces: ByteBuf::from(self.own_pieces.as_slice().to_vec()),
+            files: self.files.clone(),
+            partial_pieces: self.partial_pieces.clone(),
+            downloaded: self.downloaded,
+            uploaded: self.uploaded,
+            peers: self.peers.iter().map(|addr| addr.to_string()).collect(),
+            peer_id: self
+                .peer_id
+                .map(|peer_id| ByteBuf::from(peer_id.to_vec())),
+            announce_key: self.announce_key,
+        };
+        Ok(serde_bencode::to_bytes(&raw)?)
+    }
+
+    /// Restores the resume data saved with [`Self::to_bytes`], checking that
+    /// it is of the torrent with the info hash and storage layout.
+    ///
+    /// Peer addresses that can't be parsed are dropped.
+    fn from_bytes(
+        buf: &[u8],
+        info_hash: &Sha1Hash,
+        storage: &StorageInfo,
+    ) -> Result<Self> {
+        let raw: RawResumeData = serde_bencode::from_bytes(buf)?;
+        let piece_count = storage.piece_count;
+        if raw.info_hash.as_slice() != info_hash
+            || raw.files.len() != storage.files.len()
+        {
+            return Err(ResumeError::Invalid);
+        }
+
+        // the bitfield is padded to a whole number of bytes
+        let mut own_pieces = Bitfield::from_vec(raw.pieces.into_vec());
+        if own_pieces.len() < piece_count || own_pieces.len() >= piece_count + 8
+        {
+            return Err(ResumeError::Invalid);
+        }
+        own_pieces.truncate(piece_count);
+
+        for piece in raw.partial_pieces.iter() {
+            if piece.index >= piece_count || own_pieces[piece.index] {
+                return Err(ResumeError::Invalid);
+            }
+            let piece_len = storage.piece_len(piece.index);
+            let is_valid_block = |offset: &u32| {
+                let block_index = offset / BLOCK_LEN;
+                block_index * BLOCK_LEN == *offset
+                    && (block_index as usize) < block_count(piece_len)
+            };
+            if !piece.blocks.iter().all(is_valid_block) {
+                return Err(ResumeError::Invalid);
+            }
+        }
+
+        Ok(Self {
+            own_pieces,
+            files: raw.files,
+            partial_pieces: raw.partial_pieces,
+            downloaded: raw.downloaded,
+            uploaded: raw.uploaded,
+            peers: raw.peers.iter().filter_map(|s| s.parse().ok()).collect(),
+            peer_id: match raw.peer_id {
+                Some(buf) => {
+                    if buf.len() != 20 {
+                        return Err(ResumeError::Invalid);
+                    }
+                    let mut peer_id = [0; 20];
+                    peer_id.copy_from_slice(&buf);
+                    Some(peer_id)
+                }
+                None => None,
+            },
+            announce_key: raw.announce_key,
+        })
+    }
+
+    /// Considers the pieces that overlap with files that changed on disk
+    /// since the resume data was saved as missing, along with the blocks of
+    /// such pieces that were partially downloaded.
+    fn check_files(&mut self, storage: &StorageInfo) {
+        for (index, (file, saved_state)) in
+            storage.files.iter().zip(&self.files).enumerate()
+        {
+            let state = FileState::read(&storage.download_dir.join(&file.path));
+            if state == *saved_state {
+                continue;
+            }
+            log::info!(
+                "File {:?} changed since resume data was saved",
+                file.path
+            );
+            let pieces = storage.pieces_intersecting_file(index);
+            for piece in pieces.clone() {
+                self.own_pieces.set(piece, false);
+            }
+            self.partial_pieces
+                .retain(|piece| !pieces.contains(&piece.index));
+        }
+    }
+}
+
+/// Returns the current state of each file of the torrent on disk.
+pub(crate) fn file_states(storage: &StorageInfo) -> Vec<FileState> {
+    storage
+        .files
+        .iter()
+        .map(|file| FileState::read(&storage.download_dir.join(&file.path)))
+        .collect()
+}
+
+/// The bencoded representation of [`ResumeData`].
+#[derive(Debug, Serialize, Deserialize)]
+struct RawResumeData {
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    /// The bitfield of the pieces we have.
+    pieces: ByteBuf,
+    files: Vec<FileState>,
+    #[serde(rename = "partial-pieces")]
+    partial_pieces: Vec<PartialPiece>,
+    downloaded: u64,
+    uploaded: u64,
+    /// The addresses of peers, as `ip:port` strings.
+    peers: Vec<String>,
+    #[serde(
+        rename = "peer-id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    peer_id: Option<ByteBuf>,
+    #[serde(
+        rename = "announce-key",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    announce_key: Option<u32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileInfo;
+
+    fn storage(download_dir: PathBuf) -> StorageInfo {
+        let files = vec![
+            FileInfo {
+                path: "a".into(),
+                len: 3 * BLOCK_LEN as u64,
+                torrent_offset: 0,
+            },
+            FileInfo {
+                path: "b".into(),
+                len: 3 * BLOCK_LEN as u64,
+                torrent_offset: 3 * BLOCK_LEN as u64,
+            },
+        ];
+        StorageInfo {
+            piece_count: 3,
+            piece_len: 2 * BLOCK_LEN,
+            last_piece_len: 2 * BLOCK_LEN,
+            download_len: 6 * BLOCK_LEN as u64,
+            download_dir,
+            files,
+            is_piece_aligned: false,
+        }
+    }
+
+    fn resume_data(storage: &StorageInfo) -> ResumeData {
+        let mut own_pieces = Bitfield::repeat(false, storage.piece_count);
+        own_pieces.set(0, true);
+        own_pieces.set(2, true);
+        ResumeData {
+            own_pieces,
+            files: file_states(storage),
+            partial_pieces: vec![PartialPiece {
+                index: 1,
+                blocks: vec![BLOCK_LEN],
+            }],
+            downloaded: 1000,
+            uploaded: 500,
+            peers: vec!["10.0.0.1:6881".parse().unwrap()],
+            peer_id: Some(*b"cbt-0123456789abcdef"),
+            announce_key: Some(0xdead_beef),
+        }
+    }
+
+    #[test]
+    fn should_encode_and_decode_resume_data() {
+        let storage = storage(PathBuf::from("/tmp/cratetorrent-nonexistent"));
+        let data = resume_data(&storage);
+        let info_hash = [1; 20];
+
+        let buf = data.to_bytes(&info_hash).unwrap();
+        let decoded =
+            ResumeData::from_bytes(&buf, &info_hash, &storage).unwrap();
+        assert_eq!(decoded, data);
+
+        // the data of another torrent is rejected
+        assert!(matches!(
+            ResumeData::from_bytes(&buf, &[2; 20], &storage),
+            Err(ResumeError::Invalid)
+        ));
+        // as is data whose layout doesn't match the torrent's
+        let mut other_storage = storage.clone();
+        other_storage.files.pop();
+        assert!(matches!(
+            ResumeData::from_bytes(&buf, &info_hash, &other_storage),
+            Err(ResumeError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn should_invalidate_pieces_of_changed_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("cratetorrent-resume-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let storage = storage(dir.clone());
+        for file in storage.files.iter() {
+            fs::write(dir.join(&file.path), vec![0; file.len as usize])
+                .unwrap();
+        }
+        let info_hash = [1; 20];
+        resume_data(&storage).save(&dir, &info_hash).unwrap();
+
+        // nothing changed, so all pieces are restored
+        let data = ResumeData::load(&dir, &info_hash, &storage).unwrap();
+        assert_eq!(data, resume_data(&storage));
+
+        // the second file changed, so the pieces overlapping with it are no
+        // longer trusted
+        fs::write(dir.join("b"), b"changed").unwrap();
+        let data = ResumeData::load(&dir, &info_hash, &storage).unwrap();
+        assert!(data.own_pieces[0]);
+        assert!(!data.own_pieces[2]);
+        assert!(data.partial_pieces.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
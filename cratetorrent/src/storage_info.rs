@@ -1,6 +1,6 @@
-use std::{ops::Range, path::PathBuf};
+use std::{cmp::Ordering, ops::Range, path::PathBuf};
 
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use crate::{engine::ReadAhead, metainfo::Metainfo, FileIndex, PieceIndex};
 
 /// Information about a torrent's file.
 #[derive(Clone, Debug)]
@@ -60,6 +60,26 @@ impl FileInfo {
     }
 }
 
+/// The download priority of a file.
+///
+/// Pieces are downloaded in the order of the highest priority file they
+/// overlap with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FilePriority {
+    /// The file is not downloaded and is not created on disk, except for the
+    /// bytes of the pieces it shares with files that are downloaded.
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for FilePriority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
 /// Represents the location of a range of bytes within a file.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct FileSlice {
@@ -98,6 +118,11 @@ pub struct StorageInfo {
     pub download_dir: PathBuf,
     /// All files in torrent.
     pub files: Vec<FileInfo>,
+    /// Whether each file starts at a piece boundary, as in v2-only torrents.
+    ///
+    /// In this case no piece spans multiple files, and the last piece of each
+    /// file may be shorter than the nominal piece length.
+    pub is_piece_aligned: bool,
 }
 
 impl StorageInfo {
@@ -106,8 +131,14 @@ impl StorageInfo {
         let piece_count = metainfo.piece_count();
         let download_len = metainfo.download_len();
         let piece_len = metainfo.piece_len;
+        // if files are aligned to pieces, the download length doesn't include
+        // the gaps between files, so the end of the last file is used instead
+        let torrent_len = metainfo
+            .files
+            .last()
+            .map_or(0, FileInfo::torrent_end_offset);
         let last_piece_len =
-            download_len - piece_len as u64 * (piece_count - 1) as u64;
+            torrent_len - piece_len as u64 * (piece_count - 1) as u64;
         let last_piece_len = last_piece_len as u32;
 
         // if this is an archive, download files into torrent's own dir
@@ -124,6 +155,7 @@ impl StorageInfo {
             download_len,
             download_dir,
             files: metainfo.files.clone(),
+            is_piece_aligned: metainfo.is_piece_aligned(),
         }
     }
 
@@ -145,6 +177,90 @@ impl StorageInfo {
         self.files_intersecting_bytes(piece_offset..piece_end)
     }
 
+    /// Returns the priority of each piece, which is the highest priority of
+    /// the files the piece overlaps with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of priorities is not the same as the number of
+    /// files.
+    pub fn piece_priorities(
+        &self,
+        file_priorities: &[FilePriority],
+    ) -> Vec<FilePriority> {
+        assert_eq!(
+            file_priorities.len(),
+            self.files.len(),
+            "there must be a priority for each file"
+        );
+        let mut priorities = vec![FilePriority::Skip; self.piece_count];
+        for (index, priority) in file_priorities.iter().enumerate() {
+            let pieces = self.pieces_intersecting_file(index);
+            for piece_priority in &mut priorities[pieces] {
+                *piece_priority = (*piece_priority).max(*priority);
+            }
+        }
+        priorities
+    }
+
+    /// Returns the pieces that overlap with the file, which is an empty range
+    /// for empty files.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file index is invalid.
+    pub fn pieces_intersecting_file(
+        &self,
+        index: FileIndex,
+    ) -> Range<PieceIndex> {
+        let file = &self.files[index];
+        if file.len == 0 {
+            return 0..0;
+        }
+        let piece_len = self.piece_len as u64;
+        let first_piece = file.torrent_offset / piece_len;
+        let last_piece = (file.torrent_end_offset() - 1) / piece_len;
+        first_piece as PieceIndex..last_piece as PieceIndex + 1
+    }
+
+    /// Returns the pieces that overlap with the read-ahead window, or `None` if
+    /// the window is empty or is outside the torrent.
+    pub fn read_ahead_pieces(
+        &self,
+        read_ahead: &ReadAhead,
+    ) -> Option<Range<PieceIndex>> {
+        let byte_range = match read_ahead {
+            ReadAhead::File { index, offset, len } => {
+                let file = self.files.get(*index)?;
+                let start = file.torrent_offset + offset;
+                start..(start + len).min(file.torrent_end_offset())
+            }
+            ReadAhead::Bytes(range) => {
+                let torrent_len =
+                    self.files.last().map_or(0, FileInfo::torrent_end_offset);
+                range.start..range.end.min(torrent_len)
+            }
+        };
+        if byte_range.start >= byte_range.end {
+            return None;
+        }
+
+        let files = self.files_intersecting_bytes(byte_range.clone());
+        log::debug!(
+            "Read-ahead window {:?} intersects files {:?}",
+            byte_range,
+            files
+        );
+        if files.is_empty() {
+            return None;
+        }
+
+        let piece_len = self.piece_len as u64;
+        let first_piece = byte_range.start / piece_len;
+        let last_piece = (byte_range.end - 1) / piece_len;
+        Some(first_piece as PieceIndex..last_piece as PieceIndex + 1)
+    }
+
     /// Returns the files that overlap with the given left-inclusive range of
     /// bytes, where `bytes.start` is the offset and `bytes.end` is one past the
     /// last byte offset.
@@ -223,6 +339,24 @@ impl StorageInfo {
         assert!(index < self.piece_count, "piece index out of range");
         if index == self.piece_count - 1 {
             self.last_piece_len
+        } else if self.is_piece_aligned {
+            // the piece ends at the end of its file if it's the file's last
+            // piece
+            let piece_offset = self.torrent_piece_offset(index);
+            // the comparator never returns `Equal`, so this is always the
+            // index of the first file that ends after the piece's offset
+            let file_index = self
+                .files
+                .binary_search_by(|f| {
+                    if f.torrent_end_offset() <= piece_offset {
+                        Ordering::Less
+                    } else {
+                        Ordering::Greater
+                    }
+                })
+                .unwrap_or_else(|index| index);
+            let file_end = self.files[file_index].torrent_end_offset();
+            (self.piece_len as u64).min(file_end - piece_offset) as u32
         } else {
             self.piece_len
         }
@@ -321,6 +455,7 @@ mod tests {
             download_len,
             download_dir: PathBuf::from("/"),
             files,
+            is_piece_aligned: false,
         };
         // all 4 pieces are in the same file
         assert_eq!(info.files_intersecting_piece(0), 0..1);
@@ -401,6 +536,7 @@ mod tests {
             download_len,
             download_dir: PathBuf::from("/"),
             files,
+            is_piece_aligned: false,
         };
         // piece 0 intersects with files 0 and 1
         assert_eq!(info.files_intersecting_piece(0), 0..2);
@@ -430,6 +566,7 @@ mod tests {
             download_len,
             download_dir: PathBuf::from("/"),
             files,
+            is_piece_aligned: false,
         };
         assert_eq!(info.files_intersecting_bytes(0..0), 0..1);
         assert_eq!(info.files_intersecting_bytes(0..1), 0..1);
@@ -467,6 +604,7 @@ mod tests {
             download_len,
             download_dir: PathBuf::from("/"),
             files,
+            is_piece_aligned: false,
         };
 
         // bytes only in the first file
@@ -492,4 +630,174 @@ mod tests {
         // bytes not intersecting any files
         assert_eq!(info.files_intersecting_bytes(30..38), 0..0);
     }
+
+    #[test]
+    fn test_read_ahead_pieces() {
+        let files = vec![
+            FileInfo {
+                path: PathBuf::from("/bogus0"),
+                torrent_offset: 0,
+                len: 10,
+            },
+            FileInfo {
+                path: PathBuf::from("/bogus1"),
+                torrent_offset: 10,
+                len: 10,
+            },
+        ];
+        let info = StorageInfo {
+            piece_count: 5,
+            piece_len: 4,
+            last_piece_len: 4,
+            download_len: 20,
+            download_dir: PathBuf::from("/"),
+            files,
+            is_piece_aligned: false,
+        };
+
+        // a window within a file
+        let read_ahead = ReadAhead::File {
+            index: 0,
+            offset: 1,
+            len: 4,
+        };
+        assert_eq!(info.read_ahead_pieces(&read_ahead), Some(0..2));
+        // a file window doesn't extend past the end of the file
+        let read_ahead = ReadAhead::File {
+            index: 1,
+            offset: 2,
+            len: 100,
+        };
+        assert_eq!(info.read_ahead_pieces(&read_ahead), Some(3..5));
+        // a byte window may span files
+        let read_ahead = ReadAhead::Bytes(8..13);
+        assert_eq!(info.read_ahead_pieces(&read_ahead), Some(2..4));
+        // windows outside the torrent are ignored
+        let read_ahead = ReadAhead::File {
+            index: 2,
+            offset: 0,
+            len: 4,
+        };
+        assert_eq!(info.read_ahead_pieces(&read_ahead), None);
+        let read_ahead = ReadAhead::File {
+            index: 1,
+            offset: 10,
+            len: 4,
+        };
+        assert_eq!(info.read_ahead_pieces(&read_ahead), None);
+        assert_eq!(info.read_ahead_pieces(&ReadAhead::Bytes(20..30)), None);
+    }
+
+    #[test]
+    fn test_piece_priorities() {
+        let files = vec![
+            FileInfo {
+                path: PathBuf::from("/bogus0"),
+                torrent_offset: 0,
+                len: 6,
+            },
+            FileInfo {
+                path: PathBuf::from("/bogus1"),
+                torrent_offset: 6,
+                len: 0,
+            },
+            FileInfo {
+                path: PathBuf::from("/bogus2"),
+                torrent_offset: 6,
+                len: 6,
+            },
+            FileInfo {
+                path: PathBuf::from("/bogus3"),
+                torrent_offset: 12,
+                len: 6,
+            },
+        ];
+        let info = StorageInfo {
+            piece_count: 5,
+            piece_len: 4,
+            last_piece_len: 2,
+            download_len: 18,
+            download_dir: PathBuf::from("/"),
+            files,
+            is_piece_aligned: false,
+        };
+
+        // a piece shared by two files gets the higher priority of the two
+        let priorities = info.piece_priorities(&[
+            FilePriority::Low,
+            FilePriority::High,
+            FilePriority::Skip,
+            FilePriority::Normal,
+        ]);
+        assert_eq!(
+            priorities,
+            vec![
+                FilePriority::Low,
+                FilePriority::Low,
+                FilePriority::Skip,
+                FilePriority::Normal,
+                FilePriority::Normal,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_piece_aligned_files() {
+        // pieces: (index:first byte offset)
+        // ---------------------------------------------
+        // |0:0    |1:4    |2:8    |3:12   |4:16   |5:20
+        // ---------------------------------------------
+        // files: (index:first byte offset,last byte offset)
+        // ---------------------------------------------
+        // |0:0,5      |   |1:8,10 |2:12,20             |
+        // ---------------------------------------------
+        let files = vec![
+            FileInfo {
+                path: PathBuf::from("/0"),
+                torrent_offset: 0,
+                len: 6,
+            },
+            FileInfo {
+                path: PathBuf::from("/1"),
+                torrent_offset: 8,
+                len: 3,
+            },
+            FileInfo {
+                path: PathBuf::from("/2"),
+                torrent_offset: 12,
+                len: 9,
+            },
+        ];
+        let info = StorageInfo {
+            piece_count: 6,
+            piece_len: 4,
+            last_piece_len: 1,
+            download_len: 18,
+            download_dir: PathBuf::from("/"),
+            files,
+            is_piece_aligned: true,
+        };
+
+        // the last piece of each file ends with the file
+        let piece_lens: Vec<_> = (0..6).map(|i| info.piece_len(i)).collect();
+        assert_eq!(piece_lens, vec![4, 2, 3, 4, 4, 1]);
+        assert_eq!(
+            piece_lens.iter().map(|l| *l as u64).sum::<u64>(),
+            info.download_len
+        );
+
+        // no piece spans multiple files
+        assert_eq!(info.files_intersecting_piece(0), 0..1);
+        assert_eq!(info.files_intersecting_piece(1), 0..1);
+        assert_eq!(info.files_intersecting_piece(2), 1..2);
+        assert_eq!(info.files_intersecting_piece(3), 2..3);
+        assert_eq!(info.files_intersecting_piece(5), 2..3);
+        assert_eq!(info.pieces_intersecting_file(2), 3..6);
+
+        // the read-ahead window may cover the gaps between files
+        assert_eq!(
+            info.read_ahead_pieces(&ReadAhead::Bytes(0..100)),
+            Some(0..6)
+        );
+    }
 }
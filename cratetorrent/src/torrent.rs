@@ -1,43 +1,65 @@
 use std::{
     collections::HashMap,
+    io,
     net::SocketAddr,
+    ops::Range,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use futures::{
+    future::{self, FutureExt},
     select,
     stream::{Fuse, StreamExt},
 };
+use rand::{distributions::Alphanumeric, seq::SliceRandom, Rng};
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, RwLock,
     },
     task, time,
 };
 
 use crate::{
     alert::{Alert, AlertSender},
-    conf::TorrentConf,
-    counter::ThruputCounters,
+    conf::{EncryptionPolicy, TorrentConf},
+    counter::{Counter, ThruputCounters},
+    dht,
     disk::{
         self,
         error::{ReadError, WriteError},
     },
     download::PieceDownload,
+    engine::ReadAhead,
     error::Error,
-    peer::{self, ConnectionState, PeerSession, SessionState, SessionTick},
+    metainfo::MerkleTree,
+    net,
+    peer::{
+        self, ConnectionState, IncomingConnection, PeerSession, PexPeer,
+        SessionState, SessionTick, PEX_FLAG_CONNECTABLE, PEX_FLAG_SEED,
+        PEX_INTERVAL, UT_PEX,
+    },
     piece_picker::PiecePicker,
-    storage_info::StorageInfo,
+    rate_limiter::RateLimits,
+    resume::{self, PartialPiece, ResumeData},
+    storage_info::{FilePriority, StorageInfo},
     tracker::{Announce, Event, Tracker},
     Bitfield, BlockInfo, PeerId, PieceIndex, Sha1Hash, TorrentId,
 };
+use choker::{Candidate, Choker, CHOKE_INTERVAL};
 use error::*;
-use stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use peer_list::{PeerList, Swarm};
+use stats::{
+    PeerSource, Peers, PieceStats, ThruputStats, TorrentState, TorrentStats,
+    TrackerStats,
+};
 
+mod choker;
 pub mod error;
+mod peer_list;
 pub mod stats;
 
 /// The channel for communicating with torrent.
@@ -59,11 +81,65 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         error: ReadError,
     },
+    /// A block of a partially downloaded piece could not be restored from
+    /// disk when the torrent was resumed.
+    RestoreError {
+        block_info: BlockInfo,
+        error: ReadError,
+    },
     /// A message sent only once, after the peer has been connected.
     PeerConnected { addr: SocketAddr, id: PeerId },
+    /// Sent when the peer's extended handshake is received, with the
+    /// extensions that both sides of the connection support.
+    PeerExtensions {
+        addr: SocketAddr,
+        extensions: Vec<String>,
+        /// The port on which the peer accepts connections, if it told us.
+        listen_port: Option<u16>,
+    },
+    /// The peers that a peer told us about via peer exchange.
+    PexPeers {
+        addr: SocketAddr,
+        peers: Vec<SocketAddr>,
+    },
+    /// Sent by a peer session that stopped due to an error, before its last
+    /// state update.
+    PeerError {
+        addr: SocketAddr,
+        error: String,
+        /// Whether the peer broke the protocol, in which case it's banned.
+        is_protocol_violation: bool,
+    },
     /// Peer sessions periodically send this message when they have a state
     /// change.
     PeerState { addr: SocketAddr, info: SessionTick },
+    /// A peer connected to us and sent a handshake for this torrent, either
+    /// via the engine's listener or the torrent's own.
+    IncomingPeer(IncomingConnection),
+    /// Sets the download priority of each file.
+    SetFilePriorities(Vec<FilePriority>),
+    /// Checks the pieces on disk in the range, or all pieces if `None`,
+    /// against their hashes.
+    Recheck(Option<Range<PieceIndex>>),
+    /// Sent by the disk task with the result of checking a piece on disk
+    /// against its hash.
+    PieceChecked { index: PieceIndex, is_valid: bool },
+    /// Sets the read-ahead window, whose pieces are downloaded first. If
+    /// `None`, the window is cleared.
+    SetReadAhead(Option<ReadAhead>),
+    /// Disconnects all peers, stops listening for new ones and tells the
+    /// trackers that we stopped, until the torrent is resumed.
+    Pause,
+    /// Resumes a paused torrent.
+    Resume,
+    /// Replaces the torrent's configuration.
+    SetConf(TorrentConf),
+    /// Adds peers that we can connect to.
+    AddPeers(Vec<SocketAddr>),
+    /// Announces to the trackers and the DHT regardless of when we last did.
+    Reannounce,
+    /// Returns the torrent's current stats via the sender.
+    GetStats(oneshot::Sender<TorrentStats>),
     /// Gracefully shut down the torrent.
     ///
     /// This command tells all active peer sessions of torrent to do the same,
@@ -91,9 +167,25 @@ pub(crate) struct TorrentContext {
     /// The info hash of the torrent, derived from its metainfo. This is used to
     /// identify the torrent with other peers and trackers.
     pub info_hash: Sha1Hash,
+    /// The truncated v2 info hash of a hybrid torrent, with which it is
+    /// identified in the v2 swarm.
+    pub hybrid_info_hash: Option<Sha1Hash>,
+    /// The bencoded info dictionary of the torrent's metainfo, which is served
+    /// to peers requesting the metadata.
+    pub raw_info: Arc<Vec<u8>>,
+    /// The merkle trees of the files of v2 and hybrid torrents, from which
+    /// hash requests of peers are served.
+    pub merkle_trees: Vec<MerkleTree>,
     /// The arbitrary client id, chosen by the user of this library. This is
     /// advertised to peers and trackers.
+    ///
+    /// Private torrents have an id of their own, derived from the client id.
     pub client_id: PeerId,
+    /// Whether connections with peers are encrypted.
+    pub encryption: EncryptionPolicy,
+    /// Whether the torrent is private, in which case its peers may only come
+    /// from its trackers.
+    pub is_private: bool,
 
     /// A copy of the torrent channel sender. This is not used by torrent iself,
     /// but by the peer session tasks to which an arc copy of this torrent
@@ -116,6 +208,11 @@ pub(crate) struct TorrentContext {
     // For mvp it should do.
     pub downloads: RwLock<HashMap<PieceIndex, RwLock<PieceDownload>>>,
 
+    /// The torrent's rate limits, shared by all its peers.
+    pub rate_limits: RateLimits,
+    /// The engine's rate limits, shared by the peers of all torrents.
+    pub engine_rate_limits: RateLimits,
+
     /// The channel on which to post alerts to user.
     pub alert_tx: AlertSender,
 
@@ -131,15 +228,41 @@ pub(crate) struct Params {
     pub id: TorrentId,
     pub disk_tx: disk::Sender,
     pub info_hash: Sha1Hash,
+    pub hybrid_info_hash: Option<Sha1Hash>,
+    pub raw_info: Vec<u8>,
+    pub merkle_trees: Vec<MerkleTree>,
     pub storage_info: StorageInfo,
+    /// The download priority of each file in the torrent.
+    pub file_priorities: Vec<FilePriority>,
     pub own_pieces: Bitfield,
-    pub trackers: Vec<Tracker>,
+    /// The torrent's trackers, grouped into tiers.
+    pub trackers: Vec<Vec<Tracker>>,
     pub client_id: PeerId,
-    pub listen_addr: SocketAddr,
+    /// If set, the torrent listens for new peers on this address, instead of
+    /// getting them from the engine's listener.
+    pub listen_addr: Option<SocketAddr>,
+    /// The address of the engine's listener.
+    pub engine_listen_addr: SocketAddr,
+    pub encryption: EncryptionPolicy,
+    pub is_private: bool,
     pub conf: TorrentConf,
+    pub dht_tx: Option<dht::Sender>,
     pub alert_tx: AlertSender,
+    pub engine_rate_limits: RateLimits,
+    /// The directory in which the torrent's resume data is saved, if any.
+    pub resume_dir: Option<PathBuf>,
+    /// The state restored from the torrent's resume data, if any.
+    pub resume_data: Option<ResumeData>,
 }
 
+/// The torrent looks up peers in the DHT and announces itself there this
+/// often, as DHT nodes forget peers that don't re-announce themselves.
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The length of the part of a peer id that identifies the client, such as
+/// `-CT0100-`, which is kept in the peer ids of private torrents.
+const PEER_ID_PREFIX_LEN: usize = 8;
+
 /// Represents a torrent upload or download.
 ///
 /// This is the main entity responsible for the high-level management of
@@ -149,8 +272,8 @@ pub(crate) struct Params {
 pub(crate) struct Torrent {
     /// The peers in this torrent.
     peers: HashMap<SocketAddr, PeerSessionEntry>,
-    /// The peers returned by tracker to which we can connect.
-    available_peers: Vec<SocketAddr>,
+    /// All peers we know of, from which the peers we connect to are picked.
+    peer_list: PeerList,
     /// Information that is shared with peer sessions.
     ctx: Arc<TorrentContext>,
     /// The port on which other entities in the engine send this torrent
@@ -159,11 +282,55 @@ pub(crate) struct Torrent {
     /// The channel has to be wrapped in a `stream::Fuse` so that we can
     /// `select!` on it in the torrent event loop.
     cmd_rx: Fuse<Receiver>,
-    /// The trackers we can announce to.
-    trackers: Vec<TrackerEntry>,
-
-    /// The address on which torrent should listen for new peers.
-    listen_addr: SocketAddr,
+    /// The trackers we can announce to, grouped into tiers in order of
+    /// preference. The trackers within each tier are shuffled when the torrent
+    /// is created.
+    trackers: Vec<Vec<TrackerEntry>>,
+    /// The tier of the tracker that responded to our last announce, which is
+    /// the first tracker of its tier.
+    current_tier: Option<usize>,
+    /// The random key sent in all announces, with which trackers can identify
+    /// us even if our IP address changes.
+    announce_key: u32,
+
+    /// The engine's DHT node, if the DHT is enabled.
+    dht_tx: Option<dht::Sender>,
+    /// The channel passed to the DHT node with each lookup, on which it sends
+    /// the peers it finds.
+    dht_peer_tx: dht::PeerSender,
+    dht_peer_rx: Fuse<dht::PeerReceiver>,
+    /// The channel passed to the DHT node with the lookups of the v2 swarm of
+    /// hybrid torrents, so that its peers are connected with the truncated v2
+    /// info hash.
+    hybrid_dht_peer_tx: dht::PeerSender,
+    hybrid_dht_peer_rx: Fuse<dht::PeerReceiver>,
+    last_dht_announce_time: Option<Instant>,
+
+    /// The last time the peers were sent to the peer sessions for peer
+    /// exchange.
+    last_pex_time: Option<Instant>,
+
+    /// Decides which peers we upload to.
+    choker: Choker,
+    /// The last time the choker was run. If `None`, it is run in the next
+    /// tick.
+    last_choke_time: Option<Instant>,
+
+    /// The address on which the torrent listens for new peers, if it doesn't
+    /// share the engine's listener.
+    listen_addr: Option<SocketAddr>,
+    /// The address of the engine's listener, which peers are told to connect
+    /// to if the torrent can't listen on its own address.
+    engine_listen_addr: SocketAddr,
+    /// The port on which peers can connect to us, which is announced to
+    /// trackers and peers.
+    listen_port: u16,
+    /// Whether peers can connect to us over IPv6, in which case our IPv6
+    /// address is announced to trackers.
+    listens_on_ipv6: bool,
+    /// Accepts the connections of new peers if the torrent has its own listen
+    /// address. This is not set while the torrent is paused.
+    listener: Option<TcpListener>,
 
     /// The time the torrent was first started.
     start_time: Option<Instant>,
@@ -172,9 +339,10 @@ pub(crate) struct Torrent {
     /// This is a separate field as `Instant::now() - start_time` cannot be
     /// relied upon due to the fact that it is possible to pause a torrent, in
     /// which case we don't want to record the run time.
-    // TODO: pausing a torrent is not actually at this point, but this is done
-    // in expectation of that feature
     run_duration: Duration,
+    /// The time up to which the run duration was last updated, which is the
+    /// time of the last tick, or that of the last pause or resume.
+    last_tick_time: Option<Instant>,
 
     /// In the last part of the download the torrent is in what's called the
     /// endgame. This is the stage when all pieces have been picked but not all
@@ -189,6 +357,17 @@ pub(crate) struct Torrent {
     /// Measures various transfer statistics.
     counters: ThruputCounters,
 
+    /// The ongoing check of the pieces on disk, if any.
+    recheck: Option<Recheck>,
+
+    /// Whether the torrent is paused, in which case it has no peers, it
+    /// doesn't look for new ones, and its run duration is not counted.
+    is_paused: bool,
+
+    /// The directory in which the torrent's resume data is saved when it is
+    /// stopped or completes.
+    resume_dir: Option<PathBuf>,
+
     /// The configuration of this particular torrent.
     conf: TorrentConf,
 
@@ -213,19 +392,85 @@ impl Torrent {
             id,
             disk_tx,
             info_hash,
+            hybrid_info_hash,
+            raw_info,
+            merkle_trees,
             storage_info,
+            file_priorities,
             own_pieces,
             trackers,
             client_id,
             listen_addr,
+            engine_listen_addr,
+            encryption,
+            is_private,
             conf,
+            dht_tx,
             alert_tx,
+            engine_rate_limits,
+            resume_dir,
+            resume_data,
         } = params;
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let piece_picker = PiecePicker::new(own_pieces);
+        let (dht_peer_tx, dht_peer_rx) = mpsc::unbounded_channel();
+        let (hybrid_dht_peer_tx, hybrid_dht_peer_rx) =
+            mpsc::unbounded_channel();
+        let mut piece_picker = PiecePicker::new(own_pieces);
+        piece_picker.set_sequential(conf.sequential_download);
+        piece_picker.set_piece_priorities(
+            &storage_info.piece_priorities(&file_priorities),
+        );
+        let mut downloads = HashMap::new();
+        let mut counters = ThruputCounters::default();
+
+        // private trackers tell apart the peers of a torrent by their peer id
+        // and key, so these have to be unique and the same in all announces,
+        // even across restarts. Thus private torrents don't use the peer id
+        // shared by all torrents of the engine
+        let client_id = if is_private {
+            resume_data
+                .as_ref()
+                .and_then(|resume_data| resume_data.peer_id)
+                .unwrap_or_else(|| private_peer_id(&client_id))
+        } else {
+            client_id
+        };
+        let announce_key = resume_data
+            .as_ref()
+            .and_then(|resume_data| resume_data.announce_key)
+            .unwrap_or_else(rand::random);
+
+        if let Some(resume_data) = resume_data {
+            // peers continue the downloads of the partially downloaded
+            // pieces, whose saved blocks the disk task reads back (the blocks
+            // it fails to read are freed again)
+            for piece in resume_data.partial_pieces {
+                piece_picker.mark_pending(piece.index);
+                let mut download = PieceDownload::new(
+                    piece.index,
+                    storage_info.piece_len(piece.index),
+                );
+                for offset in piece.blocks {
+                    download.restore_block(offset);
+                }
+                downloads.insert(piece.index, RwLock::new(download));
+            }
+            counters.payload.down = Counter::with_total(resume_data.downloaded);
+            counters.payload.up = Counter::with_total(resume_data.uploaded);
+        }
         let cmd_rx = cmd_rx.fuse();
-        let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+        let trackers = trackers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<_> =
+                    tier.into_iter().map(TrackerEntry::new).collect();
+                tier.shuffle(&mut rand::thread_rng());
+                tier
+            })
+            .collect();
+        let rate_limits =
+            RateLimits::new(conf.download_rate_limit, conf.upload_rate_limit);
         let completed_pieces = if conf.alerts.completed_pieces {
             Some(Vec::new())
         } else {
@@ -235,25 +480,52 @@ impl Torrent {
         (
             Self {
                 peers: HashMap::new(),
-                available_peers: Vec::new(),
+                peer_list: PeerList::default(),
                 ctx: Arc::new(TorrentContext {
                     id,
                     cmd_tx: cmd_tx.clone(),
                     piece_picker: Arc::new(RwLock::new(piece_picker)),
-                    downloads: RwLock::new(HashMap::new()),
+                    downloads: RwLock::new(downloads),
+                    rate_limits,
+                    engine_rate_limits,
                     info_hash,
+                    hybrid_info_hash,
+                    raw_info: Arc::new(raw_info),
+                    merkle_trees,
                     client_id,
+                    encryption,
+                    is_private,
                     alert_tx,
                     disk_tx,
                     storage: storage_info,
                 }),
                 start_time: None,
                 run_duration: Duration::default(),
+                last_tick_time: None,
                 cmd_rx,
                 trackers,
+                current_tier: None,
+                announce_key,
+                // private torrents may only get peers from their trackers
+                dht_tx: if is_private { None } else { dht_tx },
+                dht_peer_tx,
+                dht_peer_rx: dht_peer_rx.fuse(),
+                hybrid_dht_peer_tx,
+                hybrid_dht_peer_rx: hybrid_dht_peer_rx.fuse(),
+                last_dht_announce_time: None,
+                last_pex_time: None,
+                choker: Choker::default(),
+                last_choke_time: None,
                 in_endgame: false,
-                counters: Default::default(),
+                counters,
+                recheck: None,
+                is_paused: false,
+                resume_dir,
                 listen_addr,
+                engine_listen_addr,
+                listen_port: engine_listen_addr.port(),
+                listens_on_ipv6: engine_listen_addr.is_ipv6(),
+                listener: None,
                 conf,
                 completed_pieces,
             },
@@ -265,35 +537,38 @@ impl Torrent {
     pub async fn start(&mut self, peers: &[SocketAddr]) -> Result<()> {
         log::info!("Starting torrent");
 
-        self.available_peers.extend_from_slice(peers);
+        let now = Instant::now();
+        for addr in peers {
+            self.peer_list.add(*addr, PeerSource::Manual, now);
+        }
+
+        if self.conf.recheck_on_add {
+            self.start_recheck(None).await;
+        }
 
         // record the torrent starttime
         self.start_time = Some(Instant::now());
 
-        // if the torrent is a seed, don't send the started event, just an
-        // empty announce
-        let tracker_event =
-            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
-                None
-            } else {
-                Some(Event::Started)
-            };
-        if let Err(e) = self
-            .announce_to_trackers(Instant::now(), tracker_event)
-            .await
-        {
-            // this is a torrent error, not a tracker error, as that is handled
-            // inside the function
-            self.ctx
-                .alert_tx
-                .send(Alert::Error(Error::Torrent {
-                    id: self.ctx.id,
-                    error: e,
-                }))
-                .ok();
-        }
-
-        if let Err(e) = self.run().await {
+        // the trackers are told the port on which we listen, so we have to
+        // listen before announcing
+        let result = match self.listen().await {
+            Ok(()) => {
+                if let Err(e) = self.announce_started(Instant::now()).await {
+                    // this is a torrent error, not a tracker error, as that is
+                    // handled inside the function
+                    self.ctx
+                        .alert_tx
+                        .send(Alert::Error(Error::Torrent {
+                            id: self.ctx.id,
+                            error: e,
+                        }))
+                        .ok();
+                }
+                self.run().await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
             // send alert of torrent failure to user
             self.ctx
                 .alert_tx
@@ -307,47 +582,78 @@ impl Torrent {
         Ok(())
     }
 
+    /// Starts listening for new peers on the torrent's listen address, if it
+    /// has one.
+    async fn listen(&mut self) -> Result<()> {
+        let listen_addr = match self.listen_addr {
+            Some(listen_addr) => listen_addr,
+            None => return Ok(()),
+        };
+        let listener =
+            TcpListener::from_std(net::bind_tcp_listener(listen_addr)?)?;
+        // the bind port may have been 0, so we need to get the actual port in
+        // use, which is then reused when the torrent is resumed
+        let listen_addr = listener.local_addr()?;
+        log::info!("Listening for peers on {}", listen_addr);
+        self.listen_addr = Some(listen_addr);
+        self.listen_port = listen_addr.port();
+        self.listens_on_ipv6 = listen_addr.is_ipv6();
+        self.listener = Some(listener);
+        Ok(())
+    }
+
     /// Starts the torrent and runs until an error is encountered.
     async fn run(&mut self) -> Result<()> {
         let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
-        let mut last_tick_time = None;
-
-        let mut listener = TcpListener::bind(&self.listen_addr).await?;
-        // the bind port may have been 0, so we need to get the actual port in
-        // use
-        self.listen_addr = listener.local_addr()?;
-        let mut incoming = listener.incoming().fuse();
 
         // the torrent loop is triggered every second by the loop timer and by
         // disk IO events
         loop {
             select! {
                 tick_time = tick_timer.select_next_some() => {
-                    self.tick(&mut last_tick_time, tick_time.into_std()).await?;
+                    self.tick(tick_time.into_std()).await?;
                 }
-                peer_conn_result = incoming.select_next_some() => {
-                    let socket = match peer_conn_result {
-                        Ok(socket) => socket,
+                peer_conn_result = accept(&mut self.listener).fuse() => {
+                    let (socket, addr) = match peer_conn_result {
+                        Ok(conn) => conn,
                         Err(e) => {
                             log::info!("Error accepting peer connection: {}", e);
                             continue;
                         }
                     };
-                    let addr = match socket.peer_addr() {
-                        Ok(addr) => addr,
-                        Err(e) => {
-                            log::info!("Error getting socket address of peer: {}", e);
-                            continue;
-                        }
-                    };
+                    // peers connecting over IPv4 to a dual-stack listener are
+                    // known by their IPv4 address
+                    let addr = net::canonical_addr(addr);
                     log::info!("New connection {:?}", addr);
 
-                    // start inbound session
-                    let (session, tx) = PeerSession::new(
-                        Arc::clone(&self.ctx),
-                        addr,
-                    );
-                    self.peers.insert(addr, PeerSessionEntry::start_inbound(socket, session, tx));
+                    // the handshake is received in its own task so as not to
+                    // block the torrent, after which the connection is handled
+                    // the same as those routed to us by the engine
+                    let cmd_tx = self.ctx.cmd_tx.clone();
+                    let encryption = self.ctx.encryption;
+                    let info_hashes: Vec<_> =
+                        std::iter::once(self.ctx.info_hash)
+                            .chain(self.ctx.hybrid_info_hash)
+                            .collect();
+                    task::spawn(async move {
+                        if let Some(conn) =
+                            IncomingConnection::receive_handshake(
+                                socket,
+                                addr,
+                                encryption,
+                                &info_hashes,
+                            )
+                            .await
+                        {
+                            cmd_tx.send(Command::IncomingPeer(conn)).ok();
+                        }
+                    });
+                }
+                peers = self.dht_peer_rx.select_next_some() => {
+                    self.add_dht_peers(peers, Swarm::Main);
+                }
+                peers = self.hybrid_dht_peer_rx.select_next_some() => {
+                    self.add_dht_peers(peers, Swarm::Hybrid);
                 }
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
@@ -359,10 +665,86 @@ impl Torrent {
                                     addr, String::from_utf8_lossy(&id)
                                 );
                                 peer.id = Some(id);
+                                self.peer_list.connected(addr, Instant::now());
+                            }
+                        }
+                        Command::PeerExtensions {
+                            addr,
+                            extensions,
+                            listen_port,
+                        } => {
+                            if let Some(peer) = self.peers.get_mut(&addr) {
+                                log::debug!(
+                                    "Peer {} supports extensions {:?}",
+                                    addr, extensions
+                                );
+                                peer.extensions = extensions;
+                                peer.listen_port = listen_port;
                             }
                         }
+                        Command::PexPeers { addr, peers } => {
+                            log::debug!(
+                                "Received peers from peer {}: {:?}",
+                                addr, peers
+                            );
+                            self.add_peers(peers, PeerSource::Pex, Instant::now());
+                        }
+                        Command::PeerError {
+                            addr,
+                            error,
+                            is_protocol_violation,
+                        } => {
+                            self.handle_peer_error(
+                                addr,
+                                error,
+                                is_protocol_violation,
+                            );
+                        }
                         Command::PeerState { addr, info } => {
-                            self.handle_peer_state_change(addr, info);
+                            self.handle_peer_state_change(
+                                addr,
+                                info,
+                                Instant::now(),
+                            );
+                        }
+                        Command::IncomingPeer(conn) => {
+                            self.handle_incoming_peer(conn, Instant::now());
+                        }
+                        Command::SetFilePriorities(priorities) => {
+                            self.set_file_priorities(priorities).await?;
+                        }
+                        Command::SetReadAhead(read_ahead) => {
+                            self.set_read_ahead(read_ahead).await;
+                        }
+                        Command::Recheck(pieces) => {
+                            self.start_recheck(pieces).await;
+                        }
+                        Command::Pause => {
+                            self.pause(Instant::now()).await?;
+                        }
+                        Command::Resume => {
+                            self.resume(Instant::now()).await?;
+                        }
+                        Command::SetConf(conf) => {
+                            self.set_conf(conf).await;
+                        }
+                        Command::AddPeers(peers) => {
+                            log::info!("Adding peers {:?}", peers);
+                            self.add_peers(
+                                peers,
+                                PeerSource::Manual,
+                                Instant::now(),
+                            );
+                        }
+                        Command::Reannounce => {
+                            self.reannounce(Instant::now()).await?;
+                        }
+                        Command::GetStats(result_tx) => {
+                            // the caller may no longer be waiting for the stats
+                            result_tx.send(self.build_stats().await).ok();
+                        }
+                        Command::PieceChecked { index, is_valid } => {
+                            self.handle_piece_check(index, is_valid).await?;
                         }
                         Command::PieceCompletion(write_result) => {
                             log::debug!("Disk write result {:?}", write_result);
@@ -378,6 +760,23 @@ impl Torrent {
                                 }
                             }
                         }
+                        Command::RestoreError { block_info, error } => {
+                            log::warn!(
+                                "Failed to restore block {}: {}",
+                                block_info,
+                                error
+                            );
+                            // download the block again
+                            if let Some(download) = self
+                                .ctx
+                                .downloads
+                                .read()
+                                .await
+                                .get(&block_info.piece_index)
+                            {
+                                download.write().await.free_block(&block_info);
+                            }
+                        }
                         Command::ReadError { block_info, error } => {
                             log::error!(
                                 "Failed to read from disk {}: {}",
@@ -402,33 +801,335 @@ impl Torrent {
         Ok(())
     }
 
-    /// The torrent tick, as in "the tick of a clock", which runs every second
-    /// to perform periodic updates.
+    /// Updates the priorities of the torrent's pieces from those of its
+    /// files.
     ///
-    /// This is when we update statistics and report them to the user, when new
-    /// peers are connected, and when perioric announces are made.
-    async fn tick(
+    /// Peer sessions re-evaluate their interest, as we may no longer want the
+    /// pieces of some peers, or may now want the pieces of others. If only
+    /// skipped pieces are left to download, the download is complete.
+    async fn set_file_priorities(
         &mut self,
-        last_tick_time: &mut Option<Instant>,
-        now: Instant,
+        priorities: Vec<FilePriority>,
+    ) -> Result<()> {
+        if priorities.len() != self.ctx.storage.files.len() {
+            log::warn!(
+                "Ignoring {} file priorities for {} files",
+                priorities.len(),
+                self.ctx.storage.files.len()
+            );
+            self.ctx
+                .alert_tx
+                .send(Alert::Error(Error::Torrent {
+                    id: self.ctx.id,
+                    error: TorrentError::InvalidFilePriorities,
+                }))
+                .ok();
+            return Ok(());
+        }
+
+        let piece_priorities = self.ctx.storage.piece_priorities(&priorities);
+        let (prev_missing_piece_count, missing_piece_count) = {
+            let mut piece_picker = self.ctx.piece_picker.write().await;
+            let prev_missing_piece_count = piece_picker.missing_piece_count();
+            piece_picker.set_piece_priorities(&piece_priorities);
+            (prev_missing_piece_count, piece_picker.missing_piece_count())
+        };
+        log::info!("Updated file priorities (left: {})", missing_piece_count);
+
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                // this may be after the peer session had already stopped
+                tx.send(peer::Command::UpdateInterest).ok();
+            }
+        }
+
+        if prev_missing_piece_count > 0 && missing_piece_count == 0 {
+            self.complete_download().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the pieces of the read-ahead window the first to be picked.
+    ///
+    /// Pieces that are already being downloaded are not affected, but since
+    /// peers pick a new piece whenever they finish one, the window's pieces
+    /// are downloaded soon after it's set.
+    async fn set_read_ahead(&mut self, read_ahead: Option<ReadAhead>) {
+        let pieces = match read_ahead {
+            Some(read_ahead) => {
+                match self.ctx.storage.read_ahead_pieces(&read_ahead) {
+                    Some(pieces) => pieces,
+                    None => {
+                        log::warn!(
+                            "Ignoring read-ahead {:?} outside torrent",
+                            read_ahead
+                        );
+                        return;
+                    }
+                }
+            }
+            None => 0..0,
+        };
+        log::info!("Setting read-ahead pieces to {:?}", pieces);
+        self.ctx
+            .piece_picker
+            .write()
+            .await
+            .set_priority_pieces(pieces);
+    }
+
+    /// Pauses the torrent.
+    ///
+    /// All peers are disconnected, but they're kept so that they're
+    /// reconnected when the torrent is resumed, and we stop listening for new
+    /// ones. The blocks of the incomplete pieces are written to disk, along
+    /// with the resume data if there is a resume directory, and the trackers
+    /// are told that we stopped.
+    async fn pause(&mut self, now: Instant) -> Result<()> {
+        if self.is_paused {
+            return Ok(());
+        }
+        log::info!("Pausing torrent");
+        self.update_run_duration(now);
+        self.is_paused = true;
+        self.listener = None;
+
+        self.stop_peers().await;
+        self.peers.clear();
+        self.peer_list.disconnect_all();
+
+        let partial_pieces = self.flush_partial_pieces().await;
+        self.save_resume_data(partial_pieces).await;
+        self.announce_to_trackers(now, Some(Event::Stopped)).await
+    }
+
+    /// Resumes a paused torrent, reconnecting the peers we had when it was
+    /// paused and announcing to the trackers that we started again.
+    ///
+    /// If the torrent's own listen address can no longer be bound, e.g.
+    /// because another process took the port while the torrent was paused,
+    /// peers are told to connect to the engine's listener instead. The
+    /// address is tried again the next time the torrent is resumed.
+    async fn resume(&mut self, now: Instant) -> Result<()> {
+        if !self.is_paused {
+            return Ok(());
+        }
+        log::info!("Resuming torrent");
+        if let Err(e) = self.listen().await {
+            log::warn!(
+                "Cannot listen for peers on {:?}, falling back to engine \
+                listener on {}: {}",
+                self.listen_addr,
+                self.engine_listen_addr,
+                e
+            );
+            self.listen_port = self.engine_listen_addr.port();
+            self.listens_on_ipv6 = self.engine_listen_addr.is_ipv6();
+            self.ctx
+                .alert_tx
+                .send(Alert::Error(Error::Torrent {
+                    id: self.ctx.id,
+                    error: e,
+                }))
+                .ok();
+        }
+        self.is_paused = false;
+        // the time spent paused is not counted
+        self.last_tick_time = Some(now);
+
+        self.connect_peers(now);
+        // don't make the peers wait for the next choker round once they're
+        // connected
+        self.last_choke_time = None;
+        self.announce_started(now).await?;
+        // the DHT may have forgotten us while we were paused
+        self.last_dht_announce_time = None;
+        self.announce_to_dht(now);
+        Ok(())
+    }
+
+    /// Replaces the torrent's configuration.
+    ///
+    /// Most settings are read when they're used, so only the ones that are
+    /// applied up front need updating here.
+    async fn set_conf(&mut self, conf: TorrentConf) {
+        log::info!("Updating torrent configuration: {:?}", conf);
+        self.ctx
+            .piece_picker
+            .write()
+            .await
+            .set_sequential(conf.sequential_download);
+        if conf.alerts.completed_pieces != self.completed_pieces.is_some() {
+            self.completed_pieces = if conf.alerts.completed_pieces {
+                Some(Vec::new())
+            } else {
+                None
+            };
+        }
+        if conf.upload_slot_count != self.conf.upload_slot_count {
+            self.last_choke_time = None;
+        }
+        self.ctx
+            .rate_limits
+            .set_rates(conf.download_rate_limit, conf.upload_rate_limit);
+        for peer in self.peers.values() {
+            peer.rate_limits.set_rates(
+                conf.peer_download_rate_limit,
+                conf.peer_upload_rate_limit,
+            );
+        }
+        self.conf = conf;
+    }
+
+    /// Starts checking the pieces on disk in the range, or all pieces if
+    /// `None`, against their hashes.
+    ///
+    /// The torrent keeps running during the check, and the pieces we have
+    /// are updated as their results come in. Only one check may run at
+    /// a time.
+    async fn start_recheck(&mut self, pieces: Option<Range<PieceIndex>>) {
+        if self.recheck.is_some() {
+            log::warn!("Ignoring recheck while one is in progress");
+            return;
+        }
+        let piece_count = self.ctx.storage.piece_count;
+        let pieces = pieces.unwrap_or(0..piece_count);
+        if pieces.start >= pieces.end || pieces.end > piece_count {
+            log::warn!("Ignoring recheck of invalid pieces {:?}", pieces);
+            return;
+        }
+
+        log::info!("Rechecking pieces {:?}", pieces);
+        if self
+            .ctx
+            .disk_tx
+            .send(disk::Command::CheckPieces {
+                id: self.ctx.id,
+                pieces: pieces.clone(),
+            })
+            .is_err()
+        {
+            log::error!("Cannot start recheck, disk task stopped");
+            return;
+        }
+        self.recheck = Some(Recheck {
+            piece_count: pieces.len(),
+            checked_piece_count: 0,
+            prev_missing_piece_count: self
+                .ctx
+                .piece_picker
+                .read()
+                .await
+                .missing_piece_count(),
+        });
+    }
+
+    /// Updates the pieces we have with the result of checking a piece on
+    /// disk, finishing the recheck once all its pieces are checked.
+    async fn handle_piece_check(
+        &mut self,
+        index: PieceIndex,
+        is_valid: bool,
     ) -> Result<()> {
-        // calculate how long torrent has been running
+        let recheck = match &mut self.recheck {
+            Some(recheck) => recheck,
+            None => {
+                log::warn!("Piece {} checked without a recheck", index);
+                return Ok(());
+            }
+        };
+        recheck.checked_piece_count += 1;
+        let is_recheck_done =
+            recheck.checked_piece_count == recheck.piece_count;
+
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        let have_piece = piece_picker.own_pieces()[index];
+        if piece_picker.pieces()[index].is_pending {
+            // the piece is being downloaded, so it will be checked once
+            // complete
+            log::debug!("Piece {} is being downloaded, ignoring check", index);
+        } else if is_valid && !have_piece {
+            log::debug!("Piece {} found on disk", index);
+            piece_picker.received_piece(index);
+            // let peers know we have the piece
+            for peer in self.peers.values() {
+                if let Some(tx) = &peer.tx {
+                    tx.send(peer::Command::PieceCompletion {
+                        index,
+                        in_endgame: self.in_endgame,
+                    })
+                    .ok();
+                }
+            }
+        } else if !is_valid && have_piece {
+            log::debug!("Piece {} missing or corrupt on disk", index);
+            piece_picker.lost_piece(index);
+        }
+
+        if !is_recheck_done {
+            return Ok(());
+        }
+
+        let own_piece_count = piece_picker.own_pieces().count_ones();
+        let missing_piece_count = piece_picker.missing_piece_count();
+        self.in_endgame =
+            missing_piece_count > 0 && piece_picker.all_pieces_picked();
+        drop(piece_picker);
+        let recheck = self.recheck.take().expect("recheck missing");
+        log::info!(
+            "Recheck complete (have: {}, left: {})",
+            own_piece_count,
+            missing_piece_count
+        );
+        self.ctx
+            .alert_tx
+            .send(Alert::RecheckComplete {
+                id: self.ctx.id,
+                own_piece_count,
+            })
+            .ok();
+
+        // we may now want pieces of peers that we didn't before, or not
+        // want any
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                tx.send(peer::Command::UpdateInterest).ok();
+            }
+        }
 
-        let elapsed_since_last_tick = last_tick_time
-            .or(self.start_time)
-            .map(|t| now.saturating_duration_since(t))
-            .unwrap_or_default();
-        self.run_duration += elapsed_since_last_tick;
-        *last_tick_time = Some(now);
+        if recheck.prev_missing_piece_count > 0 && missing_piece_count == 0 {
+            self.complete_download().await?;
+        }
 
-        // check if we can connect some peers
-        // NOTE: do this before announcing as we don't want to block new
-        // connections with the potentially long running announce requests
-        self.connect_peers();
+        Ok(())
+    }
 
-        // check if we need to announce to some trackers
-        let event = None;
-        self.announce_to_trackers(now, event).await?;
+    /// The torrent tick, as in "the tick of a clock", which runs every second
+    /// to perform periodic updates.
+    ///
+    /// This is when we update statistics and report them to the user, when new
+    /// peers are connected, and when perioric announces are made.
+    async fn tick(&mut self, now: Instant) -> Result<()> {
+        self.update_run_duration(now);
+
+        // a paused torrent has no peers and doesn't look for new ones
+        if !self.is_paused {
+            // check if we can connect some peers
+            // NOTE: do this before announcing as we don't want to block new
+            // connections with the potentially long running announce requests
+            self.connect_peers(now);
+
+            // decide which peers we upload to
+            self.run_choker(now).await;
+
+            // check if we need to announce to some trackers
+            let event = None;
+            self.announce_to_trackers(now, event).await?;
+            self.announce_to_dht(now);
+            self.send_pex_peers(now);
+        }
+        self.scrape_trackers(now).await;
 
         log::debug!(
             "Stats: \
@@ -465,8 +1166,23 @@ impl Torrent {
             }
         }
 
+        if let Some(recheck) = &self.recheck {
+            self.ctx
+                .alert_tx
+                .send(Alert::RecheckProgress {
+                    id: self.ctx.id,
+                    checked_piece_count: recheck.checked_piece_count,
+                    piece_count: recheck.piece_count,
+                })
+                .ok();
+        }
+
         // send periodic stats update to api user
-        let stats = self.build_stats().await;
+        let mut stats = self.build_stats().await;
+        stats.pieces.latest_completed = self
+            .completed_pieces
+            .as_mut()
+            .map(|p| std::mem::replace(p, Vec::new()));
         self.ctx
             .alert_tx
             .send(Alert::TorrentStats {
@@ -480,181 +1196,673 @@ impl Torrent {
         Ok(())
     }
 
-    /// Attempts to connect available peers, if we have any.
-    fn connect_peers(&mut self) {
+    /// Adds the time elapsed since the last update to the run duration, unless
+    /// the torrent is paused.
+    fn update_run_duration(&mut self, now: Instant) {
+        if !self.is_paused {
+            let elapsed_since_last_tick = self
+                .last_tick_time
+                .or(self.start_time)
+                .map(|t| now.saturating_duration_since(t))
+                .unwrap_or_default();
+            self.run_duration += elapsed_since_last_tick;
+        }
+        self.last_tick_time = Some(now);
+    }
+
+    /// Attempts to connect the best available peers in the peer list, if we
+    /// have any.
+    fn connect_peers(&mut self, now: Instant) {
         let connect_count = self
             .conf
             .max_connected_peer_count
-            .saturating_sub(self.peers.len())
-            .min(self.available_peers.len());
-        if connect_count == 0 {
+            .saturating_sub(self.peers.len());
+        let addrs = self.peer_list.pick_candidates(now, connect_count);
+        if addrs.is_empty() {
             log::trace!("Cannot connect to peers");
             return;
         }
 
-        log::debug!("Connecting {} peer(s)", connect_count);
-        for addr in self.available_peers.drain(0..connect_count) {
+        log::debug!("Connecting {} peer(s)", addrs.len());
+        for addr in addrs {
             log::info!("Connecting to peer {}", addr);
-            let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
-            self.peers
-                .insert(addr, PeerSessionEntry::start_outbound(session, tx));
+            // peers found in the v2 swarm of hybrid torrents may not know the
+            // torrent by its v1 info hash
+            let info_hash = match self.peer_list.swarm(&addr) {
+                Swarm::Main => self.ctx.info_hash,
+                Swarm::Hybrid => {
+                    self.ctx.hybrid_info_hash.unwrap_or(self.ctx.info_hash)
+                }
+            };
+            let (session, tx) = PeerSession::new(
+                Arc::clone(&self.ctx),
+                addr,
+                self.listen_port,
+                Self::peer_rate_limits(&self.conf),
+            );
+            self.peers.insert(
+                addr,
+                PeerSessionEntry::start_outbound(session, tx, info_hash),
+            );
         }
     }
 
-    /// Chacks whether we need to announce to any trackers of if we need to request
-    /// peers.
-    async fn announce_to_trackers(
-        &mut self,
-        now: Instant,
-        event: Option<Event>,
-    ) -> Result<()> {
-        // calculate transfer statistics in advance
-        let uploaded = self.counters.payload.up.total();
-        let downloaded = self.counters.payload.down.total();
-        let left = self.ctx.storage.download_len - downloaded;
+    /// Starts a session with a peer that connected to us, unless the torrent
+    /// is paused or the peer is banned, in which case the connection is
+    /// dropped.
+    fn handle_incoming_peer(&mut self, conn: IncomingConnection, now: Instant) {
+        let addr = conn.addr;
+        if self.is_paused {
+            log::info!("Rejecting connection from {} while paused", addr);
+            return;
+        }
+        if self.peers.contains_key(&addr) {
+            log::info!("Rejecting duplicate connection from {}", addr);
+            return;
+        }
+        if !self.peer_list.accept(addr, now) {
+            log::info!("Rejecting connection from banned peer {}", addr);
+            return;
+        }
 
-        // skip trackers that errored too often
-        // TODO: introduce a retry timeout
-        let tracker_error_threshold = self.conf.tracker_error_threshold;
-        for tracker in self
-            .trackers
-            .iter_mut()
-            .filter(|t| t.error_count < tracker_error_threshold)
-        {
-            // Check if the torrent's peer count has fallen below the minimum.
-            // But don't request new peers otherwise or if we're about to stop
-            // torrent.
-            let peer_count = self.peers.len() + self.available_peers.len();
-            let needed_peer_count = if peer_count
-                >= self.conf.min_requested_peer_count
-                || event == Some(Event::Stopped)
-            {
-                None
-            } else {
-                debug_assert!(self.conf.max_connected_peer_count >= peer_count);
-                let needed = self.conf.max_connected_peer_count - peer_count;
-                // Download at least this numbe of peers, even if we don't need
-                // as many. This is because later we may be able to connect to
-                // more peers and in that case we don't want to wait till the
-                // next tracker request.
-                Some(self.conf.min_requested_peer_count.max(needed))
-            };
+        let (session, tx) = PeerSession::new(
+            Arc::clone(&self.ctx),
+            addr,
+            self.listen_port,
+            Self::peer_rate_limits(&self.conf),
+        );
+        self.peers
+            .insert(addr, PeerSessionEntry::start_inbound(conn, session, tx));
+    }
+
+    /// Returns the rate limits of a new peer session.
+    fn peer_rate_limits(conf: &TorrentConf) -> RateLimits {
+        RateLimits::new(
+            conf.peer_download_rate_limit,
+            conf.peer_upload_rate_limit,
+        )
+    }
+
+    /// Runs the choker if it's time to do so, and tells the sessions of the
+    /// peers whose choke state changed to choke or unchoke them.
+    async fn run_choker(&mut self, now: Instant) {
+        if let Some(last_choke_time) = self.last_choke_time {
+            if now.saturating_duration_since(last_choke_time) < CHOKE_INTERVAL {
+                return;
+            }
+        }
+        self.last_choke_time = Some(now);
+
+        // while downloading we reciprocate the peers that upload to us, while
+        // seeding we prefer the peers we can upload to the fastest
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+        let candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.state.connection == ConnectionState::Connected
+            })
+            .map(|(addr, peer)| Candidate {
+                addr: *addr,
+                is_interested: peer.state.is_peer_interested,
+                is_choked: peer.state.is_peer_choked,
+                rate: if is_seed {
+                    peer.thruput.payload.up.rate
+                } else {
+                    peer.thruput.payload.down.rate
+                },
+            })
+            .collect();
+        let decisions =
+            self.choker
+                .run(now, &candidates, self.conf.upload_slot_count);
+
+        let choke = decisions.choke.into_iter().map(|addr| (addr, true));
+        let unchoke = decisions.unchoke.into_iter().map(|addr| (addr, false));
+        for (addr, is_choked) in choke.chain(unchoke) {
+            if let Some(peer) = self.peers.get_mut(&addr) {
+                if let Some(tx) = &peer.tx {
+                    let cmd = if is_choked {
+                        peer::Command::Choke
+                    } else {
+                        peer::Command::Unchoke
+                    };
+                    if tx.send(cmd).is_ok() {
+                        // the session reports its new state too, but until
+                        // then our copy of it should be up to date
+                        peer.state.is_peer_choked = is_choked;
+                    }
+                }
+            }
+        }
+    }
 
-            // we can override the normal annoucne interval if we need peers or
-            // if we have an event to announce
-            if event.is_some()
-                || (needed_peer_count > Some(0)
-                    && tracker.can_announce(now, self.conf.announce_interval))
-                || tracker.should_announce(now, self.conf.announce_interval)
+    /// Starts a DHT lookup of the torrent's peers that also announces us as
+    /// a peer, if the DHT is enabled and it's time to do so.
+    fn announce_to_dht(&mut self, now: Instant) {
+        let dht_tx = match &self.dht_tx {
+            Some(dht_tx) => dht_tx,
+            None => return,
+        };
+        if let Some(last_announce_time) = self.last_dht_announce_time {
+            if now.saturating_duration_since(last_announce_time)
+                < DHT_ANNOUNCE_INTERVAL
             {
-                let params = Announce {
-                    tracker_id: tracker.id.clone(),
-                    info_hash: self.ctx.info_hash,
-                    peer_id: self.ctx.client_id,
-                    port: self.listen_addr.port(),
-                    peer_count: needed_peer_count,
-                    uploaded,
-                    downloaded,
-                    left,
-                    ip: None,
+                return;
+            }
+        }
+
+        log::debug!("Announcing torrent {} to DHT", self.ctx.id);
+        // hybrid torrents are also looked up in the v2 swarm
+        let lookups = std::iter::once((self.ctx.info_hash, &self.dht_peer_tx))
+            .chain(
+                self.ctx
+                    .hybrid_info_hash
+                    .map(|info_hash| (info_hash, &self.hybrid_dht_peer_tx)),
+            );
+        for (info_hash, peer_tx) in lookups {
+            let cmd = dht::Command::GetPeers {
+                info_hash,
+                announce_port: Some(self.listen_port),
+                peer_tx: peer_tx.clone(),
+            };
+            if dht_tx.send(cmd).is_err() {
+                // the DHT is not essential for the torrent, so just stop
+                // using it
+                log::warn!("DHT node is no longer running");
+                self.dht_tx = None;
+                break;
+            }
+        }
+        self.last_dht_announce_time = Some(now);
+    }
+
+    /// Sends each peer session that negotiated peer exchange the peers it can
+    /// advertise to its peer, if it's time to do so.
+    ///
+    /// Only connected peers are advertised, by the address on which they
+    /// accept connections, so peers that connected to us are only included if
+    /// they told us their listen port.
+    fn send_pex_peers(&mut self, now: Instant) {
+        if self.ctx.is_private {
+            return;
+        }
+        if let Some(last_pex_time) = self.last_pex_time {
+            if now.saturating_duration_since(last_pex_time) < PEX_INTERVAL {
+                return;
+            }
+        }
+        self.last_pex_time = Some(now);
+
+        let piece_count = self.ctx.storage.piece_count;
+        let pex_peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.state.connection == ConnectionState::Connected
+            })
+            .filter_map(|(addr, peer)| {
+                let mut flags = 0;
+                if peer.piece_count == piece_count {
+                    flags |= PEX_FLAG_SEED;
+                }
+                if peer.is_outbound {
+                    flags |= PEX_FLAG_CONNECTABLE;
+                }
+                Some(PexPeer {
+                    addr: peer.listen_addr(*addr)?,
+                    flags,
+                })
+            })
+            .collect();
+
+        for (addr, peer) in self.peers.iter() {
+            if !peer.extensions.iter().any(|e| e == UT_PEX) {
+                continue;
+            }
+            if let Some(tx) = &peer.tx {
+                // peers are not told about themselves
+                let own_addr = peer.listen_addr(*addr);
+                let peers = pex_peers
+                    .iter()
+                    .filter(|p| Some(p.addr) != own_addr)
+                    .copied()
+                    .collect();
+                tx.send(peer::Command::PexPeers(peers)).ok();
+            }
+        }
+    }
+
+    /// Adds the peers found via the DHT in the given swarm that we don't
+    /// already know of to the peers we can connect to.
+    fn add_dht_peers(&mut self, peers: Vec<SocketAddr>, swarm: Swarm) {
+        log::debug!("Received peers from DHT: {:?}", peers);
+        let now = Instant::now();
+        for addr in peers {
+            self.peer_list
+                .add_in_swarm(addr, PeerSource::Dht, swarm, now);
+        }
+    }
+
+    /// Adds the peers that we don't already know of to the peer list, from
+    /// which the peers we connect to are picked.
+    fn add_peers(
+        &mut self,
+        peers: Vec<SocketAddr>,
+        source: PeerSource,
+        now: Instant,
+    ) {
+        for addr in peers {
+            self.peer_list.add(addr, source, now);
+        }
+    }
+
+    /// Chacks whether we need to announce to the trackers or if we need to
+    /// request peers, and if so, announces to the first tracker that responds.
+    ///
+    /// As described in [BEP 12](https://www.bittorrent.org/beps/bep_0012.html),
+    /// trackers are tried tier by tier, in order, and a tracker that responds
+    /// is moved to the front of its tier.
+    async fn announce_to_trackers(
+        &mut self,
+        now: Instant,
+        event: Option<Event>,
+    ) -> Result<()> {
+        let needed_peer_count = self.needed_peer_count(event);
+
+        // the announce interval is determined by the tracker that last
+        // responded, or if none did, by the first tracker we'd try
+        let tracker = match self.current_tracker() {
+            Some((tier_index, tracker_index)) => {
+                &self.trackers[tier_index][tracker_index]
+            }
+            None => return Ok(()),
+        };
+        // we can override the normal annoucne interval if we need peers or
+        // if we have an event to announce
+        if event.is_none()
+            && !(needed_peer_count > Some(0)
+                && tracker.can_announce(now, self.conf.announce_interval))
+            && !tracker.should_announce(now, self.conf.announce_interval)
+        {
+            return Ok(());
+        }
+
+        self.announce_to_first_tracker(now, needed_peer_count, event)
+            .await
+    }
+
+    /// Announces to the trackers and the DHT regardless of when we last did,
+    /// such as when the user wants new peers right away.
+    async fn reannounce(&mut self, now: Instant) -> Result<()> {
+        if self.is_paused {
+            log::warn!("Ignoring reannounce while paused");
+            return Ok(());
+        }
+        log::info!("Reannouncing torrent");
+        let needed_peer_count = self.needed_peer_count(None);
+        self.announce_to_first_tracker(now, needed_peer_count, None)
+            .await?;
+        self.last_dht_announce_time = None;
+        self.announce_to_dht(now);
+        Ok(())
+    }
+
+    /// Announces to the trackers that we started the torrent, which is
+    /// a regular announce if we're seeding.
+    async fn announce_started(&mut self, now: Instant) -> Result<()> {
+        // if the torrent is a seed, don't send the started event, just an
+        // empty announce
+        let event =
+            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
+                None
+            } else {
+                Some(Event::Started)
+            };
+        self.announce_to_trackers(now, event).await
+    }
+
+    /// Returns the number of peers to request in an announce, if the
+    /// torrent's peer count has fallen below the minimum.
+    ///
+    /// New peers are not requested otherwise or if we're about to stop the
+    /// torrent.
+    fn needed_peer_count(&self, event: Option<Event>) -> Option<usize> {
+        let peer_count = self.peers.len() + self.peer_list.available_count();
+        if peer_count >= self.conf.min_requested_peer_count
+            || event == Some(Event::Stopped)
+        {
+            None
+        } else {
+            // the configuration may have changed since the peers were
+            // connected, so there may be more of them than the maximum
+            let needed = self
+                .conf
+                .max_connected_peer_count
+                .saturating_sub(peer_count);
+            // Download at least this numbe of peers, even if we don't need
+            // as many. This is because later we may be able to connect to
+            // more peers and in that case we don't want to wait till the
+            // next tracker request.
+            Some(self.conf.min_requested_peer_count.max(needed))
+        }
+    }
+
+    /// Announces to the trackers tier by tier until one of them responds.
+    async fn announce_to_first_tracker(
+        &mut self,
+        now: Instant,
+        needed_peer_count: Option<usize>,
+        event: Option<Event>,
+    ) -> Result<()> {
+        // skip trackers that errored too often
+        // TODO: introduce a retry timeout
+        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        self.current_tier = None;
+        for tier_index in 0..self.trackers.len() {
+            for tracker_index in 0..self.trackers[tier_index].len() {
+                let tracker = &self.trackers[tier_index][tracker_index];
+                if tracker.error_count >= tracker_error_threshold {
+                    continue;
+                }
+                let params = self.announce_params(
+                    tracker.id.clone(),
+                    needed_peer_count,
                     event,
-                };
-                // TODO: We probably don't want to block the torrent event loop
-                // here waiting on the tracker response. Instead, poll the
-                // future in the event loop select call, or spawn the tracker
-                // announce on a separate task and return the result as
-                // an mpsc message.
-                match tracker.client.announce(params).await {
-                    Ok(resp) => {
-                        log::info!(
-                            "Announced to tracker {}, response: {:?}",
-                            tracker.client,
-                            resp
-                        );
-                        if let Some(tracker_id) = resp.tracker_id {
-                            tracker.id = Some(tracker_id);
-                        }
-                        if let Some(failure_reason) = resp.failure_reason {
-                            log::warn!(
-                                "Error contacting tracker {}: {}",
-                                tracker.client,
-                                failure_reason
-                            );
-                        }
-                        if let Some(warning_message) = resp.warning_message {
-                            log::warn!(
-                                "Warning from tracker {}: {}",
-                                tracker.client,
-                                warning_message
-                            );
-                        }
-                        if let Some(interval) = resp.interval {
-                            log::info!(
-                                "Tracker {} interval: {} s",
-                                tracker.client,
-                                interval.as_secs()
-                            );
-                            tracker.interval = Some(interval);
-                        }
-                        if let Some(min_interval) = resp.min_interval {
-                            log::info!(
-                                "Tracker {} min min_interval: {} s",
-                                tracker.client,
-                                min_interval.as_secs()
-                            );
-                            tracker.min_interval = Some(min_interval);
-                        }
+                );
+                if self
+                    .announce_to_tracker(tier_index, tracker_index, params, now)
+                    .await?
+                {
+                    self.trackers[tier_index][..=tracker_index].rotate_right(1);
+                    self.current_tier = Some(tier_index);
+                    return Ok(());
+                }
+            }
+        }
 
-                        if let (Some(seeder_count), Some(leecher_count)) =
-                            (resp.seeder_count, resp.leecher_count)
-                        {
-                            log::debug!(
-                                "Torrent seeds: {} and leeches: {}",
-                                seeder_count,
-                                leecher_count
-                            );
-                        }
+        Ok(())
+    }
 
-                        if !resp.peers.is_empty() {
-                            log::debug!(
-                                "Received peers from tracker {}: {:?}",
-                                tracker.client,
-                                resp.peers
-                            );
-                            self.available_peers.extend(resp.peers.into_iter());
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!(
-                            "Error announcing to tracker {}: {}",
-                            tracker.client,
-                            e
-                        );
-                        tracker.error_count += 1;
-                        self.ctx.alert_tx.send(Alert::Error(
-                            Error::Tracker {
-                                id: self.ctx.id,
-                                error: e,
-                            },
-                        ))?;
+    /// Returns the tier and index of the tracker whose announce interval we
+    /// follow: the one that responded to the last announce, or if none did,
+    /// the first tracker that we haven't given up on.
+    fn current_tracker(&self) -> Option<(usize, usize)> {
+        if let Some(tier_index) = self.current_tier {
+            return Some((tier_index, 0));
+        }
+        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        self.trackers
+            .iter()
+            .enumerate()
+            .find_map(|(tier_index, tier)| {
+                tier.iter()
+                    .position(|t| t.error_count < tracker_error_threshold)
+                    .map(|tracker_index| (tier_index, tracker_index))
+            })
+    }
+
+    /// Returns the parameters of an announce to a tracker.
+    fn announce_params(
+        &self,
+        tracker_id: Option<String>,
+        peer_count: Option<usize>,
+        event: Option<Event>,
+    ) -> Announce {
+        // calculate transfer statistics
+        let uploaded = self.counters.payload.up.total();
+        let downloaded = self.counters.payload.down.total();
+        let left = self.ctx.storage.download_len - downloaded;
+        Announce {
+            tracker_id,
+            info_hash: self.ctx.info_hash,
+            peer_id: self.ctx.client_id,
+            key: self.announce_key,
+            port: self.listen_port,
+            peer_count,
+            uploaded,
+            downloaded,
+            left,
+            ip: None,
+            ipv6: if self.listens_on_ipv6 {
+                net::global_ipv6()
+            } else {
+                None
+            },
+            event,
+        }
+    }
+
+    /// Announces to the tracker at the given position, returning whether it
+    /// responded successfully.
+    async fn announce_to_tracker(
+        &mut self,
+        tier_index: usize,
+        tracker_index: usize,
+        params: Announce,
+        now: Instant,
+    ) -> Result<bool> {
+        // hybrid torrents are also announced in the v2 swarm, in which they
+        // are identified by their truncated v2 info hash
+        let hybrid_params =
+            self.ctx.hybrid_info_hash.map(|info_hash| Announce {
+                info_hash,
+                ..params.clone()
+            });
+        let tracker = &mut self.trackers[tier_index][tracker_index];
+        tracker.last_announce_time = Some(now);
+        // TODO: We probably don't want to block the torrent event loop
+        // here waiting on the tracker response. Instead, poll the
+        // future in the event loop select call, or spawn the tracker
+        // announce on a separate task and return the result as
+        // an mpsc message.
+        match tracker.client.announce(params).await {
+            Ok(resp) => {
+                log::info!(
+                    "Announced to tracker {}, response: {:?}",
+                    tracker.client,
+                    resp
+                );
+                tracker.last_error = resp.failure_reason.clone();
+                if let Some(tracker_id) = resp.tracker_id {
+                    tracker.id = Some(tracker_id);
+                }
+                if let Some(failure_reason) = resp.failure_reason {
+                    // the tracker refused our request, so try the next one
+                    log::warn!(
+                        "Error contacting tracker {}: {}",
+                        tracker.client,
+                        failure_reason
+                    );
+                    return Ok(false);
+                }
+                if let Some(warning_message) = resp.warning_message {
+                    log::warn!(
+                        "Warning from tracker {}: {}",
+                        tracker.client,
+                        warning_message
+                    );
+                }
+                if let Some(interval) = resp.interval {
+                    log::info!(
+                        "Tracker {} interval: {} s",
+                        tracker.client,
+                        interval.as_secs()
+                    );
+                    tracker.interval = Some(interval);
+                }
+                if let Some(min_interval) = resp.min_interval {
+                    log::info!(
+                        "Tracker {} min min_interval: {} s",
+                        tracker.client,
+                        min_interval.as_secs()
+                    );
+                    tracker.min_interval = Some(min_interval);
+                }
+
+                if let (Some(seeder_count), Some(leecher_count)) =
+                    (resp.seeder_count, resp.leecher_count)
+                {
+                    log::debug!(
+                        "Torrent seeds: {} and leeches: {}",
+                        seeder_count,
+                        leecher_count
+                    );
+                    tracker.seeder_count = Some(seeder_count);
+                    tracker.leecher_count = Some(leecher_count);
+                }
+
+                if !resp.peers.is_empty() {
+                    log::debug!(
+                        "Received peers from tracker {}: {:?}",
+                        tracker.client,
+                        resp.peers
+                    );
+                    for addr in resp.peers {
+                        self.peer_list.add(addr, PeerSource::Tracker, now);
                     }
                 }
-                tracker.last_announce_time = Some(now);
+                if let Some(params) = hybrid_params {
+                    self.announce_hybrid_to_tracker(
+                        tier_index,
+                        tracker_index,
+                        params,
+                        now,
+                    )
+                    .await;
+                }
+                Ok(true)
+            }
+            Err(e) => {
+                log::warn!(
+                    "Error announcing to tracker {}: {}",
+                    tracker.client,
+                    e
+                );
+                tracker.error_count += 1;
+                tracker.last_error = Some(e.to_string());
+                self.ctx.alert_tx.send(Alert::Error(Error::Tracker {
+                    id: self.ctx.id,
+                    error: e,
+                }))?;
+                Ok(false)
             }
         }
+    }
 
-        Ok(())
+    /// Announces a hybrid torrent in the v2 swarm to the tracker at the given
+    /// position, after it was announced in the v1 swarm.
+    ///
+    /// The tracker's state is only updated from the v1 announce, so this
+    /// merely adds the peers of the v2 swarm.
+    async fn announce_hybrid_to_tracker(
+        &mut self,
+        tier_index: usize,
+        tracker_index: usize,
+        params: Announce,
+        now: Instant,
+    ) {
+        let tracker = &mut self.trackers[tier_index][tracker_index];
+        match tracker.client.announce(params).await {
+            Ok(resp) => {
+                if let Some(failure_reason) = resp.failure_reason {
+                    log::warn!(
+                        "Error announcing v2 swarm to tracker {}: {}",
+                        tracker.client,
+                        failure_reason
+                    );
+                    return;
+                }
+                log::debug!(
+                    "Received v2 swarm peers from tracker {}: {:?}",
+                    tracker.client,
+                    resp.peers
+                );
+                for addr in resp.peers {
+                    self.peer_list.add_in_swarm(
+                        addr,
+                        PeerSource::Tracker,
+                        Swarm::Hybrid,
+                        now,
+                    );
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Error announcing v2 swarm to tracker {}: {}",
+                    tracker.client,
+                    e
+                );
+            }
+        }
+    }
+
+    /// Requests the statistics of the torrent's swarm from the trackers that
+    /// are due to be scraped.
+    async fn scrape_trackers(&mut self, now: Instant) {
+        let scrape_interval = match self.conf.scrape_interval {
+            Some(scrape_interval) => scrape_interval,
+            None => return,
+        };
+        let info_hash = self.ctx.info_hash;
+        let tracker_error_threshold = self.conf.tracker_error_threshold;
+        for tracker in self.trackers.iter_mut().flatten().filter(|t| {
+            t.error_count < tracker_error_threshold
+                && t.client.supports_scrape()
+                && t.should_scrape(now, scrape_interval)
+        }) {
+            // TODO: like announces, this blocks the torrent event loop
+            match tracker.client.scrape(info_hash).await {
+                Ok(resp) => {
+                    log::debug!(
+                        "Scraped tracker {}, response: {:?}",
+                        tracker.client,
+                        resp
+                    );
+                    tracker.seeder_count = Some(resp.seeder_count);
+                    tracker.leecher_count = Some(resp.leecher_count);
+                    tracker.completed_count = Some(resp.completed_count);
+                    tracker.last_error = None;
+                }
+                Err(e) => {
+                    // unlike announce errors, these are not counted towards
+                    // the tracker's error threshold, as we can do without
+                    // the swarm statistics
+                    log::warn!(
+                        "Error scraping tracker {}: {}",
+                        tracker.client,
+                        e
+                    );
+                    tracker.last_error = Some(e.to_string());
+                }
+            }
+            tracker.last_scrape_time = Some(now);
+        }
     }
 
     /// Returns high-level statistics about the torrent for sending to the user.
-    async fn build_stats(&mut self) -> TorrentStats {
-        let missing_piece_count =
-            self.ctx.piece_picker.read().await.missing_piece_count();
+    ///
+    /// The pieces completed since the last stats are not included, as only
+    /// the periodic stats update reports them.
+    async fn build_stats(&self) -> TorrentStats {
+        let (own_piece_count, missing_piece_count) = {
+            let piece_picker = self.ctx.piece_picker.read().await;
+            (
+                piece_picker.own_pieces().count_ones(),
+                piece_picker.missing_piece_count(),
+            )
+        };
         let piece_count = self.ctx.storage.piece_count;
-        let completed_pieces = self
-            .completed_pieces
-            .as_mut()
-            .map(|p| std::mem::replace(p, Vec::new()));
+        let state = if self.is_paused {
+            TorrentState::Paused
+        } else if self.recheck.is_some() {
+            TorrentState::Checking
+        } else if missing_piece_count == 0 {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
         let peers = if self.conf.alerts.peers {
             let peers = self
                 .peers
@@ -665,24 +1873,95 @@ impl Torrent {
                     state: entry.state,
                     piece_count: entry.piece_count,
                     thruput: entry.thruput,
+                    extensions: entry.extensions.clone(),
                 })
                 .collect();
             Peers::Full(peers)
         } else {
             Peers::Count(self.peers.len())
         };
+        let known_peers = if self.conf.alerts.known_peers {
+            Some(self.peer_list.stats())
+        } else {
+            None
+        };
 
         TorrentStats {
+            state,
             start_time: self.start_time,
             run_duration: self.run_duration,
             pieces: PieceStats {
                 total: piece_count,
-                complete: piece_count - missing_piece_count,
+                complete: own_piece_count,
+                missing: missing_piece_count,
                 pending: self.ctx.downloads.read().await.len(),
-                latest_completed: completed_pieces,
+                latest_completed: None,
             },
             thruput: ThruputStats::from(&self.counters),
             peers,
+            trackers: self.build_tracker_stats(),
+            known_peers,
+        }
+    }
+
+    /// Returns the state of each tracker, for sending to the user.
+    fn build_tracker_stats(&self) -> Vec<TrackerStats> {
+        let current_tracker = self.current_tracker();
+        let mut stats = Vec::new();
+        for (tier_index, tier) in self.trackers.iter().enumerate() {
+            for (tracker_index, t) in tier.iter().enumerate() {
+                // only the current tracker is announced to
+                let next_announce_time =
+                    if current_tracker == Some((tier_index, tracker_index)) {
+                        t.next_announce_time(self.conf.announce_interval)
+                    } else {
+                        None
+                    };
+                stats.push(TrackerStats {
+                    url: t.client.url().to_string(),
+                    tier: tier_index,
+                    seeder_count: t.seeder_count,
+                    leecher_count: t.leecher_count,
+                    completed_count: t.completed_count,
+                    last_announce_time: t.last_announce_time,
+                    next_announce_time,
+                    last_error: t.last_error.clone(),
+                });
+            }
+        }
+        stats
+    }
+
+    /// Records the error with which a peer session stopped, banning the peer
+    /// if it broke the protocol.
+    fn handle_peer_error(
+        &mut self,
+        addr: SocketAddr,
+        error: String,
+        is_protocol_violation: bool,
+    ) {
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.has_failed = true;
+        }
+        if is_protocol_violation {
+            self.peer_list.ban(addr, error);
+            self.disconnect_banned_peers();
+        } else {
+            self.peer_list.record_error(addr, error);
+        }
+    }
+
+    /// Tells the sessions of the peers whose IP address is banned to shut
+    /// down.
+    fn disconnect_banned_peers(&self) {
+        for (addr, peer) in self.peers.iter() {
+            if !self.peer_list.is_banned(addr) {
+                continue;
+            }
+            if let Some(tx) = &peer.tx {
+                log::info!("Disconnecting banned peer {}", addr);
+                tx.send(peer::Command::Shutdown).ok();
+            }
         }
     }
 
@@ -691,16 +1970,32 @@ impl Torrent {
     ///
     /// It simply updates the minimum copy of the peer's state that is kept in
     /// torrent in order to perform various pieces of logic (the choke
-    /// algorithm and detailed reporting to user, neither of which is done at
-    /// the moment).
+    /// algorithm and detailed reporting to user). When the session ends, the
+    /// peer list is told whether it failed, which is the case if it stopped
+    /// due to an error or before the handshake completed.
     fn handle_peer_state_change(
         &mut self,
         addr: SocketAddr,
         info: SessionTick,
+        now: Instant,
     ) {
+        let unchoked_count = self
+            .peers
+            .values()
+            .filter(|peer| !peer.state.is_peer_choked)
+            .count();
         if let Some(peer) = self.peers.get_mut(&addr) {
             log::debug!("Updating peer {} state", addr);
 
+            // if the peer became interested while we have a free upload slot,
+            // don't make it wait for the next choker round
+            if !peer.state.is_peer_interested
+                && info.state.is_peer_interested
+                && unchoked_count < self.conf.upload_slot_count
+            {
+                self.last_choke_time = None;
+            }
+
             peer.state = info.state;
             peer.piece_count = info.piece_count;
             peer.thruput = ThruputStats::from(&info.counters);
@@ -710,7 +2005,9 @@ impl Torrent {
 
             // if we disconnected peer, remove it
             if peer.state.connection == ConnectionState::Disconnected {
+                let has_failed = peer.has_failed || peer.id.is_none();
                 self.peers.remove(&addr);
+                self.peer_list.disconnected(addr, now, has_failed);
             }
         } else {
             log::debug!("Tried updating non-existent peer {}", addr);
@@ -779,44 +2076,64 @@ impl Torrent {
 
             // if the torrent is fully downloaded, stop the download loop
             if missing_piece_count == 0 {
-                log::info!(
-                    "Finished torrent download, exiting. \
-                    Peak download rate: {} b/s, wasted: {} b",
-                    self.counters.payload.down.peak(),
-                    self.counters.waste.total(),
-                );
-
-                // notify user of torrent completion
-                self.ctx
-                    .alert_tx
-                    .send(Alert::TorrentComplete(self.ctx.id))
-                    .ok();
-
-                // tell trackers we've finished
-                self.announce_to_trackers(
-                    Instant::now(),
-                    Some(Event::Completed),
-                )
-                .await?;
+                self.complete_download().await?;
             }
         } else {
-            // TODO(https://github.com/mandreyel/cratetorrent/issues/61):
-            // implement parole mode for the peers that sent corrupt data
             log::warn!("Piece {} is invalid", piece.index);
-            // mark all blocks free to be requested in piece
+            // the peers that sent the blocks of the piece are to blame, and
+            // all blocks are freed to be requested again
             if let Some(piece) =
                 self.ctx.downloads.read().await.get(&piece.index)
             {
-                piece.write().await.free_all_blocks();
+                let mut piece = piece.write().await;
+                let peers: Vec<_> = piece.peers().copied().collect();
+                self.peer_list.corrupt_piece(&peers);
+                piece.free_all_blocks();
             }
+            self.disconnect_banned_peers();
         }
 
         Ok(())
     }
 
+    /// Notifies the user and the trackers that all wanted pieces of the
+    /// torrent are downloaded.
+    async fn complete_download(&mut self) -> Result<()> {
+        log::info!(
+            "Finished torrent download, exiting. \
+            Peak download rate: {} b/s, wasted: {} b",
+            self.counters.payload.down.peak(),
+            self.counters.waste.total(),
+        );
+
+        // notify user of torrent completion
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentComplete(self.ctx.id))
+            .ok();
+
+        let partial_pieces = self.flush_partial_pieces().await;
+        self.save_resume_data(partial_pieces).await;
+
+        // tell trackers we've finished
+        self.announce_to_trackers(Instant::now(), Some(Event::Completed))
+            .await
+    }
+
     /// Shuts down torrent and all peer sessions, and also announces torrent's
     /// exit to tracker.
     async fn shutdown(&mut self) -> Result<()> {
+        self.stop_peers().await;
+        let partial_pieces = self.flush_partial_pieces().await;
+        self.save_resume_data(partial_pieces).await;
+
+        // tell trackers we're leaving
+        self.announce_to_trackers(Instant::now(), Some(Event::Stopped))
+            .await
+    }
+
+    /// Tells all peer sessions to shut down and waits for them to do so.
+    async fn stop_peers(&mut self) {
         // send shutdown command to all connected peers
         for peer in self.peers.values() {
             if let Some(tx) = &peer.tx {
@@ -837,11 +2154,100 @@ impl Torrent {
                 log::error!("Peer session error: {}", e);
             }
         }
+    }
 
-        // tell trackers we're leaving
-        self.announce_to_trackers(Instant::now(), Some(Event::Stopped))
-            .await
+    /// Writes the blocks of the pieces that are not yet complete, which are
+    /// only in the disk write buffer, to disk, and returns them.
+    async fn flush_partial_pieces(&self) -> Vec<PartialPiece> {
+        let (result_tx, result_rx) = oneshot::channel();
+        if self
+            .ctx
+            .disk_tx
+            .send(disk::Command::FlushPartialPieces {
+                id: self.ctx.id,
+                result_tx,
+            })
+            .is_ok()
+        {
+            result_rx.await.unwrap_or_default()
+        } else {
+            Vec::new()
+        }
     }
+
+    /// Saves the state of the torrent, including the flushed blocks of its
+    /// incomplete pieces, in the resume directory, if there is one, so that
+    /// it can be restored when the torrent is created again.
+    ///
+    /// Errors are only logged, as failing to save the state doesn't affect
+    /// the torrent itself.
+    // TODO: pieces that are still being written to disk when the state is
+    // saved may change a file after its state was taken, in which case the
+    // file's pieces are downloaded again after a restart
+    async fn save_resume_data(&self, partial_pieces: Vec<PartialPiece>) {
+        let resume_dir = match &self.resume_dir {
+            Some(resume_dir) => resume_dir,
+            None => return,
+        };
+
+        let resume_data = ResumeData {
+            own_pieces: self.ctx.piece_picker.read().await.own_pieces().clone(),
+            files: resume::file_states(&self.ctx.storage),
+            partial_pieces,
+            downloaded: self.counters.payload.down.total(),
+            uploaded: self.counters.payload.up.total(),
+            peers: self.peer_list.resume_addrs(),
+            peer_id: if self.ctx.is_private {
+                Some(self.ctx.client_id)
+            } else {
+                None
+            },
+            announce_key: Some(self.announce_key),
+        };
+        match resume_data.save(resume_dir, &self.ctx.info_hash) {
+            Ok(()) => log::info!("Saved torrent {} resume data", self.ctx.id),
+            Err(e) => log::warn!(
+                "Failed to save torrent {} resume data: {}",
+                self.ctx.id,
+                e
+            ),
+        }
+    }
+}
+
+/// Returns the peer id of a private torrent, which keeps the client
+/// identifying prefix of the client id, but is otherwise random.
+fn private_peer_id(client_id: &PeerId) -> PeerId {
+    let mut peer_id = *client_id;
+    for (b, c) in peer_id[PEER_ID_PREFIX_LEN..]
+        .iter_mut()
+        .zip(rand::thread_rng().sample_iter(Alphanumeric))
+    {
+        *b = c;
+    }
+    peer_id
+}
+
+/// Accepts the next peer connection, or never returns if there is no
+/// listener, such as while the torrent is paused.
+async fn accept(
+    listener: &mut Option<TcpListener>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => future::pending().await,
+    }
+}
+
+/// The state of an ongoing check of the pieces on disk.
+struct Recheck {
+    /// The number of pieces being checked.
+    piece_count: usize,
+    /// The number of pieces whose result came in.
+    checked_piece_count: usize,
+    /// The number of missing pieces before the check, used to tell whether
+    /// the check completed the download.
+    prev_missing_piece_count: usize,
 }
 
 /// A peer in the torrent. Contains additional metadata needed by torrent to
@@ -860,33 +2266,53 @@ struct PeerSessionEntry {
     state: SessionState,
     /// The number of pieces that the peer has available.
     piece_count: usize,
+    /// The extensions negotiated with the peer in the extended handshake.
+    extensions: Vec<String>,
+    /// Whether we connected to the peer, rather than the peer to us.
+    is_outbound: bool,
+    /// The port on which the peer accepts connections, if it told us in its
+    /// extended handshake.
+    listen_port: Option<u16>,
+    /// Whether the session reported an error, in which case it failed.
+    has_failed: bool,
 
     /// Most recent throughput statistics of this peer.
     thruput: ThruputStats,
+    /// The rate limits of the session, shared with it so that they can be
+    /// changed while it's running.
+    rate_limits: RateLimits,
 
     /// The peer session task's join handle, used during shutdown.
     join_handle: Option<task::JoinHandle<peer::error::Result<()>>>,
 }
 
 impl PeerSessionEntry {
-    fn start_outbound(mut session: PeerSession, tx: peer::Sender) -> Self {
+    fn start_outbound(
+        mut session: PeerSession,
+        tx: peer::Sender,
+        info_hash: Sha1Hash,
+    ) -> Self {
+        let rate_limits = session.rate_limits().clone();
         let join_handle =
-            task::spawn(async move { session.start_outbound().await });
-        Self::new(tx, join_handle)
+            task::spawn(async move { session.start_outbound(info_hash).await });
+        Self::new(tx, true, rate_limits, join_handle)
     }
 
     fn start_inbound(
-        socket: TcpStream,
+        conn: IncomingConnection,
         mut session: PeerSession,
         tx: peer::Sender,
     ) -> Self {
+        let rate_limits = session.rate_limits().clone();
         let join_handle =
-            task::spawn(async move { session.start_inbound(socket).await });
-        Self::new(tx, join_handle)
+            task::spawn(async move { session.start_inbound(conn).await });
+        Self::new(tx, false, rate_limits, join_handle)
     }
 
     fn new(
         tx: peer::Sender,
+        is_outbound: bool,
+        rate_limits: RateLimits,
         join_handle: task::JoinHandle<peer::error::Result<()>>,
     ) -> Self {
         Self {
@@ -897,10 +2323,26 @@ impl PeerSessionEntry {
                 ..Default::default()
             },
             piece_count: 0,
+            extensions: Vec::new(),
+            is_outbound,
+            listen_port: None,
+            has_failed: false,
             thruput: Default::default(),
+            rate_limits,
             join_handle: Some(join_handle),
         }
     }
+
+    /// Returns the address on which the peer at `addr` accepts connections,
+    /// if known. This is the same address if we connected to the peer.
+    fn listen_addr(&self, addr: SocketAddr) -> Option<SocketAddr> {
+        if self.is_outbound {
+            Some(addr)
+        } else {
+            self.listen_port
+                .map(|port| SocketAddr::new(addr.ip(), port))
+        }
+    }
 }
 
 /// Contains the tracker client as well as additional metadata about the
@@ -921,6 +2363,15 @@ struct TrackerEntry {
     /// Each time we fail to requet from tracker, this counter is incremented.
     /// If it fails too often, we stop requesting from tracker.
     error_count: usize,
+    /// The error of the last announce or scrape request, if it failed.
+    last_error: Option<String>,
+    /// The last scrape time is kept here so that we don't scrape too often.
+    last_scrape_time: Option<Instant>,
+    /// The swarm statistics reported by the tracker in the last announce or
+    /// scrape response. The completed count is only sent in the latter.
+    seeder_count: Option<usize>,
+    leecher_count: Option<usize>,
+    completed_count: Option<usize>,
 }
 
 impl TrackerEntry {
@@ -932,9 +2383,26 @@ impl TrackerEntry {
             interval: None,
             min_interval: None,
             error_count: 0,
+            last_error: None,
+            last_scrape_time: None,
+            seeder_count: None,
+            leecher_count: None,
+            completed_count: None,
         }
     }
 
+    /// Returns when we should next announce to the tracker, based on when we
+    /// last announced, or `None` if we haven't announced yet.
+    fn next_announce_time(
+        &self,
+        default_announce_interval: Duration,
+    ) -> Option<Instant> {
+        self.last_announce_time.map(|last_announce_time| {
+            last_announce_time
+                + self.interval.unwrap_or(default_announce_interval)
+        })
+    }
+
     /// Determines whether we should announce to the tracker at the given time,
     /// based on when we last announced.
     ///
@@ -945,10 +2413,20 @@ impl TrackerEntry {
         t: Instant,
         default_announce_interval: Duration,
     ) -> bool {
-        if let Some(last_announce_time) = self.last_announce_time {
-            let min_next_announce_time = last_announce_time
-                + self.interval.unwrap_or(default_announce_interval);
-            t > min_next_announce_time
+        if let Some(next_announce_time) =
+            self.next_announce_time(default_announce_interval)
+        {
+            t > next_announce_time
+        } else {
+            true
+        }
+    }
+
+    /// Determines whether we should scrape the tracker at the given time,
+    /// based on when we last scraped it.
+    fn should_scrape(&self, t: Instant, scrape_interval: Duration) -> bool {
+        if let Some(last_scrape_time) = self.last_scrape_time {
+            t >= last_scrape_time + scrape_interval
         } else {
             true
         }
@@ -976,3 +2454,513 @@ impl TrackerEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs, iter,
+        net::{Ipv4Addr, TcpListener as StdTcpListener},
+        path::Path,
+    };
+
+    use mockito::{mock, Matcher};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::{
+        alert::AlertReceiver,
+        engine,
+        metainfo::{NetProtocol, PieceHashes, TrackerUrl},
+        rate_limiter::{self, RateLimiter},
+        storage_info::FileInfo,
+        BLOCK_LEN,
+    };
+
+    /// The number of pieces in the test torrent.
+    const PIECE_COUNT: usize = 4;
+    /// The length of the pieces of the test torrent, which have 4 blocks each.
+    const PIECE_LEN: u32 = 4 * BLOCK_LEN;
+
+    /// The environment of a torrent under test: the parameters with which
+    /// it's created, which tests may change before starting it, and the
+    /// channels of the disk task and alerts.
+    struct Env {
+        params: Params,
+        engine_rx: engine::Receiver,
+        alert_rx: AlertReceiver,
+    }
+
+    impl Env {
+        /// Spawns a disk task and returns the parameters of a torrent without
+        /// trackers, peers or resume data.
+        ///
+        /// Tests are run in parallel, so the name of the test is included in
+        /// the path of the torrent's file, which must be unique.
+        fn new(test_name: &str) -> Self {
+            let download_dir = Path::new("/tmp");
+            let path = PathBuf::from(format!("torrent_test_{}", test_name));
+            let download_path = download_dir.join(&path);
+            if download_path.is_file() {
+                fs::remove_file(&download_path)
+                    .expect("cannot clean up previous torrent test file");
+            }
+
+            let download_len = PIECE_COUNT as u64 * PIECE_LEN as u64;
+            let storage_info = StorageInfo {
+                piece_count: PIECE_COUNT,
+                piece_len: PIECE_LEN,
+                last_piece_len: PIECE_LEN,
+                download_len,
+                download_dir: download_dir.to_path_buf(),
+                files: vec![FileInfo {
+                    path,
+                    torrent_offset: 0,
+                    len: download_len,
+                }],
+                is_piece_aligned: false,
+            };
+
+            let (engine_tx, engine_rx) = mpsc::unbounded_channel();
+            let (_, disk_tx) = disk::spawn(engine_tx).unwrap();
+            let (alert_tx, alert_rx) = mpsc::unbounded_channel();
+            let params = Params {
+                id: TorrentId::new(),
+                disk_tx,
+                info_hash: [1; 20],
+                hybrid_info_hash: None,
+                raw_info: Vec::new(),
+                merkle_trees: Vec::new(),
+                storage_info,
+                file_priorities: vec![FilePriority::Normal],
+                own_pieces: Bitfield::repeat(false, PIECE_COUNT),
+                trackers: Vec::new(),
+                client_id: *b"-CT0100-000000000000",
+                listen_addr: None,
+                engine_listen_addr: (Ipv4Addr::LOCALHOST, 6881).into(),
+                encryption: EncryptionPolicy::Disabled,
+                is_private: false,
+                conf: TorrentConf::default(),
+                dht_tx: None,
+                alert_tx,
+                engine_rate_limits: RateLimits::new(None, None),
+                resume_dir: None,
+                resume_data: None,
+            };
+            Self {
+                params,
+                engine_rx,
+                alert_rx,
+            }
+        }
+
+        /// Creates the torrent, allocates it on disk, and starts it in its
+        /// own task.
+        async fn start(self) -> RunningTorrent {
+            let Self {
+                params,
+                engine_rx,
+                alert_rx,
+            } = self;
+            let (torrent, tx) = Torrent::new(params);
+            RunningTorrent::start(torrent, tx, engine_rx, alert_rx).await
+        }
+    }
+
+    /// Returns a tracker at the path of the mock HTTP server, whose
+    /// responses the test sets up.
+    fn mock_tracker(path: &str) -> Tracker {
+        Tracker::new(TrackerUrl {
+            url: format!("{}{}", mockito::server_url(), path)
+                .parse()
+                .unwrap(),
+            protocol: NetProtocol::HTTP,
+        })
+    }
+
+    /// A torrent running in its own task.
+    struct RunningTorrent {
+        id: TorrentId,
+        tx: Sender,
+        disk_tx: disk::Sender,
+        storage_info: StorageInfo,
+        /// The disk task stops if it can't notify the engine, so the channel
+        /// is kept open.
+        _engine_rx: engine::Receiver,
+        alert_rx: AlertReceiver,
+        join_handle: task::JoinHandle<Torrent>,
+    }
+
+    impl RunningTorrent {
+        /// Allocates the torrent on disk and starts it in its own task.
+        async fn start(
+            mut torrent: Torrent,
+            tx: Sender,
+            mut engine_rx: engine::Receiver,
+            alert_rx: AlertReceiver,
+        ) -> Self {
+            let id = torrent.ctx.id;
+            let disk_tx = torrent.ctx.disk_tx.clone();
+            let storage_info = torrent.ctx.storage.clone();
+            disk_tx
+                .send(disk::Command::NewTorrent {
+                    id,
+                    storage_info: storage_info.clone(),
+                    file_priorities: vec![FilePriority::Normal],
+                    piece_hashes: PieceHashes::V1(vec![0; PIECE_COUNT * 20]),
+                    partial_pieces: Vec::new(),
+                    torrent_tx: tx.clone(),
+                })
+                .unwrap();
+            match engine_rx.recv().await {
+                Some(engine::Command::TorrentAllocation {
+                    result: Ok(()),
+                    ..
+                }) => (),
+                _ => panic!("torrent not allocated"),
+            }
+
+            let join_handle = task::spawn(async move {
+                torrent.start(&[]).await.unwrap();
+                torrent
+            });
+            Self {
+                id,
+                tx,
+                disk_tx,
+                storage_info,
+                _engine_rx: engine_rx,
+                alert_rx,
+                join_handle,
+            }
+        }
+
+        /// Sends the command to the torrent.
+        fn command(&self, cmd: Command) {
+            assert!(self.tx.send(cmd).is_ok(), "torrent stopped");
+        }
+
+        /// Returns the torrent's stats. Since commands are handled in order,
+        /// this also waits for the torrent to handle the previous commands.
+        async fn stats(&self) -> TorrentStats {
+            let (result_tx, result_rx) = oneshot::channel();
+            self.command(Command::GetStats(result_tx));
+            result_rx.await.expect("torrent stopped")
+        }
+
+        /// Shuts down the torrent and returns it once it stopped.
+        async fn shutdown(self) -> Torrent {
+            self.command(Command::Shutdown);
+            self.join_handle.await.unwrap()
+        }
+    }
+
+    /// Tests that pausing a torrent writes the blocks of its incomplete
+    /// pieces to disk, even if it has no resume directory.
+    #[tokio::test]
+    async fn should_flush_partial_pieces_on_pause() {
+        let torrent = Env::new("flush_partial_pieces_on_pause").start().await;
+
+        // a block of an incomplete piece, as if a peer downloaded it
+        let block_info = BlockInfo {
+            piece_index: 1,
+            offset: BLOCK_LEN,
+            len: BLOCK_LEN,
+        };
+        let data = vec![7; BLOCK_LEN as usize];
+        torrent
+            .disk_tx
+            .send(disk::Command::WriteBlock {
+                id: torrent.id,
+                block_info,
+                data: data.clone(),
+            })
+            .unwrap();
+
+        torrent.command(Command::Pause);
+        assert_eq!(torrent.stats().await.state, TorrentState::Paused);
+
+        let file = &torrent.storage_info.files[0];
+        let path = torrent.storage_info.download_dir.join(&file.path);
+        let offset = (PIECE_LEN + BLOCK_LEN) as usize;
+        let buf = fs::read(path).unwrap();
+        assert_eq!(&buf[offset..offset + BLOCK_LEN as usize], &data[..]);
+
+        torrent.shutdown().await;
+    }
+
+    /// Tests that if the torrent's listen address is taken while it's paused,
+    /// resuming it posts an alert and announces the engine's listen port
+    /// instead of stopping the torrent.
+    #[tokio::test]
+    async fn should_fall_back_to_engine_listener_on_resume() {
+        // find a free port, which is taken after the torrent is paused
+        let listen_addr = StdTcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // the torrent announces that it started and stopped on its own port,
+        // then that it started again on the engine's port
+        let path = "/resume_listener_fallback";
+        let own_port_announces = mock("GET", path)
+            .match_query(Matcher::UrlEncoded(
+                "port".into(),
+                listen_addr.port().to_string(),
+            ))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect(2)
+            .create();
+        let engine_port_announces = mock("GET", path)
+            .match_query(Matcher::UrlEncoded("port".into(), "6881".into()))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect(1)
+            .create();
+
+        let mut env = Env::new("fall_back_to_engine_listener_on_resume");
+        env.params.listen_addr = Some(listen_addr);
+        env.params.trackers = vec![vec![mock_tracker(path)]];
+        let mut torrent = env.start().await;
+
+        torrent.command(Command::Pause);
+        assert_eq!(torrent.stats().await.state, TorrentState::Paused);
+
+        let _taken = StdTcpListener::bind(listen_addr).unwrap();
+        torrent.command(Command::Resume);
+        assert_ne!(torrent.stats().await.state, TorrentState::Paused);
+        // the stats may have been posted before the error
+        let failed_listen = iter::from_fn(|| torrent.alert_rx.try_recv().ok())
+            .any(|alert| {
+                matches!(
+                    alert,
+                    Alert::Error(Error::Torrent {
+                        error: TorrentError::Io(_),
+                        ..
+                    })
+                )
+            });
+        assert!(failed_listen, "no alert of the failed listen");
+
+        own_port_announces.assert();
+        engine_port_announces.assert();
+
+        torrent.shutdown().await;
+    }
+
+    /// Tests that a peer that a tracker returned for the v2 swarm of a hybrid
+    /// torrent is sent the truncated v2 info hash in the handshake, as it may
+    /// not know the torrent by its v1 info hash.
+    #[tokio::test]
+    async fn should_connect_v2_swarm_peers_with_v2_info_hash() {
+        let v2_info_hash = [2; 20];
+        let mut peer_listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let peer_port = peer_listener.local_addr().unwrap().port();
+
+        // the peer is only in the v2 swarm
+        let path = "/hybrid_swarm_peers";
+        let v1_announces = mock("GET", path)
+            .match_query(Matcher::UrlEncoded(
+                "info_hash".into(),
+                String::from_utf8(vec![1; 20]).unwrap(),
+            ))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect_at_least(1)
+            .create();
+        let mut v2_body = b"d8:intervali1800e5:peers6:".to_vec();
+        v2_body.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+        v2_body.extend_from_slice(&peer_port.to_be_bytes());
+        v2_body.push(b'e');
+        let v2_announces = mock("GET", path)
+            .match_query(Matcher::UrlEncoded(
+                "info_hash".into(),
+                String::from_utf8(v2_info_hash.to_vec()).unwrap(),
+            ))
+            .with_body(v2_body)
+            .expect_at_least(1)
+            .create();
+
+        let mut env = Env::new("connect_v2_swarm_peers_with_v2_info_hash");
+        env.params.hybrid_info_hash = Some(v2_info_hash);
+        env.params.trackers = vec![vec![mock_tracker(path)]];
+        let torrent = env.start().await;
+
+        let (mut socket, _) =
+            time::timeout(Duration::from_secs(5), peer_listener.accept())
+                .await
+                .expect("torrent didn't connect to v2 swarm peer")
+                .unwrap();
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake).await.unwrap();
+        assert_eq!(&handshake[..20], b"\x13BitTorrent protocol");
+        assert_eq!(handshake[28..48], v2_info_hash);
+
+        // the session waits for the peer's handshake until the connection is
+        // closed
+        drop(socket);
+        torrent.shutdown().await;
+        v1_announces.assert();
+        v2_announces.assert();
+    }
+
+    /// Tests that changing the torrent's configuration updates the rate
+    /// limits of the torrent and of its running peer sessions.
+    #[tokio::test]
+    async fn should_update_rate_limits_of_running_sessions() {
+        let env = Env::new("update_rate_limits_of_running_sessions");
+        let (mut torrent, _tx) = Torrent::new(env.params);
+        let now = Instant::now();
+        // nothing listens on this port, but that doesn't matter as long as
+        // the session is running
+        torrent.peer_list.add(
+            (Ipv4Addr::LOCALHOST, 1).into(),
+            PeerSource::Manual,
+            now,
+        );
+        torrent.connect_peers(now);
+        assert_eq!(torrent.peers.len(), 1);
+
+        torrent
+            .set_conf(TorrentConf {
+                download_rate_limit: Some(1000),
+                upload_rate_limit: Some(2000),
+                peer_download_rate_limit: Some(3000),
+                peer_upload_rate_limit: Some(4000),
+                ..TorrentConf::default()
+            })
+            .await;
+
+        assert_rate_limited(&torrent.ctx.rate_limits.down, 1000);
+        assert_rate_limited(&torrent.ctx.rate_limits.up, 2000);
+        for peer in torrent.peers.values() {
+            assert_rate_limited(&peer.rate_limits.down, 3000);
+            assert_rate_limited(&peer.rate_limits.up, 4000);
+        }
+    }
+
+    /// Asserts that the limiter was just set to the rate: its bucket starts
+    /// out empty, so transferring a second's worth of bytes has to wait
+    /// about a second.
+    fn assert_rate_limited(limiter: &RateLimiter, rate: u64) {
+        let wait = rate_limiter::reserve(iter::once(limiter), rate)
+            .expect("transfer not limited")
+            .saturating_duration_since(Instant::now());
+        assert!(
+            wait > Duration::from_millis(900) && wait <= Duration::from_secs(1),
+            "transfer has to wait {:?}",
+            wait
+        );
+    }
+
+    /// Creates a torrent with the DHT enabled and a connected peer that
+    /// negotiated peer exchange, and returns whether the torrent sent
+    /// commands to the DHT node and to the peer's session when it was time
+    /// to announce to the DHT and to exchange peers.
+    fn uses_dht_and_pex(test_name: &str, is_private: bool) -> (bool, bool) {
+        let mut env = Env::new(test_name);
+        let (dht_tx, mut dht_rx) = mpsc::unbounded_channel();
+        env.params.dht_tx = Some(dht_tx);
+        env.params.is_private = is_private;
+        let (mut torrent, _tx) = Torrent::new(env.params);
+
+        let now = Instant::now();
+        let addr = (Ipv4Addr::LOCALHOST, 1).into();
+        torrent.peer_list.add(addr, PeerSource::Manual, now);
+        torrent.connect_peers(now);
+        // the test stands in for the session, as if it were connected
+        let (session_tx, mut session_rx) = mpsc::unbounded_channel();
+        let peer = torrent.peers.get_mut(&addr).unwrap();
+        peer.tx = Some(session_tx);
+        peer.state.connection = ConnectionState::Connected;
+        peer.extensions = vec![UT_PEX.into()];
+
+        torrent.announce_to_dht(now);
+        torrent.send_pex_peers(now);
+        (dht_rx.try_recv().is_ok(), session_rx.try_recv().is_ok())
+    }
+
+    /// Tests that public torrents use the DHT and peer exchange, but private
+    /// ones don't, even if the DHT is enabled in the engine.
+    #[tokio::test]
+    async fn should_not_use_dht_or_pex_for_private_torrent() {
+        assert_eq!(uses_dht_and_pex("public_dht_and_pex", false), (true, true));
+        assert_eq!(
+            uses_dht_and_pex("private_dht_and_pex", true),
+            (false, false)
+        );
+    }
+
+    /// Tests that a private torrent announces the same peer id and key to its
+    /// trackers, when it's resumed after a pause and when it's restarted from
+    /// its resume data, and that the peer id differs from the engine's.
+    #[tokio::test]
+    async fn should_keep_peer_id_and_key_of_private_torrent() {
+        let resume_dir = Path::new("/tmp/torrent_test_private_resume");
+        if resume_dir.is_dir() {
+            fs::remove_dir_all(resume_dir)
+                .expect("cannot clean up previous resume directory");
+        }
+        let path = "/private_torrent";
+        let new_env = || {
+            let mut env = Env::new("keep_peer_id_and_key_of_private_torrent");
+            env.params.is_private = true;
+            env.params.resume_dir = Some(resume_dir.to_path_buf());
+            env.params.trackers = vec![vec![mock_tracker(path)]];
+            env
+        };
+
+        let Env {
+            params,
+            engine_rx,
+            alert_rx,
+        } = new_env();
+        let client_id = params.client_id;
+        let (torrent, tx) = Torrent::new(params);
+        let peer_id = torrent.ctx.client_id;
+        let key = torrent.announce_key;
+        assert_ne!(peer_id, client_id);
+        assert_eq!(
+            peer_id[..PEER_ID_PREFIX_LEN],
+            client_id[..PEER_ID_PREFIX_LEN]
+        );
+
+        // the torrent announces that it started, stopped when paused,
+        // started again when resumed, and stopped when shut down, then that
+        // it started and stopped again after the restart
+        let announces = mock("GET", path)
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded(
+                    "peer_id".into(),
+                    String::from_utf8(peer_id.to_vec()).unwrap(),
+                ),
+                Matcher::UrlEncoded("key".into(), format!("{:08x}", key)),
+            ]))
+            .with_body("d8:intervali1800e5:peers0:e")
+            .expect(6)
+            .create();
+
+        let torrent =
+            RunningTorrent::start(torrent, tx, engine_rx, alert_rx).await;
+        torrent.command(Command::Pause);
+        torrent.command(Command::Resume);
+        torrent.shutdown().await;
+
+        let Env {
+            mut params,
+            engine_rx,
+            alert_rx,
+        } = new_env();
+        params.resume_data = ResumeData::load(
+            resume_dir,
+            &params.info_hash,
+            &params.storage_info,
+        );
+        assert!(params.resume_data.is_some(), "resume data not saved");
+        let (torrent, tx) = Torrent::new(params);
+        assert_eq!(torrent.ctx.client_id, peer_id);
+        assert_eq!(torrent.announce_key, key);
+        let torrent =
+            RunningTorrent::start(torrent, tx, engine_rx, alert_rx).await;
+        torrent.shutdown().await;
+
+        announces.assert();
+    }
+}
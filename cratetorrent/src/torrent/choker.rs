@@ -0,0 +1,205 @@
+//! This module contains the choke algorithm, which decides which peers the
+//! torrent uploads to.
+//!
+//! We only upload to a limited number of peers at a time, as uploading to
+//! all interested peers would spread our upload capacity too thin. The
+//! choker implements the tit-for-tat strategy: while downloading, we
+//! reciprocate the peers from which we download the fastest, while when
+//! seeding, we upload to the peers to which we can upload the fastest, so
+//! that the pieces spread quickly in the swarm.
+//!
+//! One of the upload slots is reserved for the optimistic unchoke, which is
+//! given to a random interested peer and rotated periodically. This gives
+//! new peers a chance to prove themselves, and lets us discover peers that
+//! would serve us better than the ones currently unchoked.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::IteratorRandom;
+
+/// The choker is run this often, so that peers have time to show how fast
+/// they are.
+pub(super) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke is rotated this often.
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The state of a connected peer, as needed by the choker.
+#[derive(Debug)]
+pub(super) struct Candidate {
+    pub addr: SocketAddr,
+    /// Whether the peer is interested in downloading from us.
+    pub is_interested: bool,
+    /// Whether we currently choke the peer.
+    pub is_choked: bool,
+    /// The rate by which peers are ranked: the peer's download rate while
+    /// we're downloading, and its upload rate while we're seeding.
+    pub rate: u64,
+}
+
+/// The choke and unchoke decisions of a choker round.
+#[derive(Debug, Default, PartialEq)]
+pub(super) struct Decisions {
+    /// The currently unchoked peers that should be choked.
+    pub choke: Vec<SocketAddr>,
+    /// The currently choked peers that should be unchoked.
+    pub unchoke: Vec<SocketAddr>,
+}
+
+/// Decides which peers are unchoked.
+#[derive(Default)]
+pub(super) struct Choker {
+    /// The peer that was last unchoked optimistically, if any.
+    optimistic_unchoke: Option<SocketAddr>,
+    last_optimistic_unchoke_time: Option<Instant>,
+}
+
+impl Choker {
+    /// Runs a round of the choke algorithm, returning the peers whose choke
+    /// state needs to change.
+    ///
+    /// At most `slot_count` interested peers are unchoked, one of which is
+    /// the optimistic unchoke. Peers that are not interested are choked.
+    pub fn run(
+        &mut self,
+        now: Instant,
+        candidates: &[Candidate],
+        slot_count: usize,
+    ) -> Decisions {
+        let mut interested: Vec<_> =
+            candidates.iter().filter(|c| c.is_interested).collect();
+        // the fastest peers first
+        interested.sort_by_key(|c| std::cmp::Reverse(c.rate));
+
+        let regular_slot_count = slot_count.saturating_sub(1);
+        let mut unchoked: HashSet<_> = interested
+            .iter()
+            .take(regular_slot_count)
+            .map(|c| c.addr)
+            .collect();
+
+        if slot_count > 0 {
+            // the optimistic unchoke is kept until it's time to rotate it,
+            // unless it's no longer eligible
+            let current = self.optimistic_unchoke.filter(|addr| {
+                !unchoked.contains(addr)
+                    && interested.iter().any(|c| c.addr == *addr)
+            });
+            let is_rotation_due = match self.last_optimistic_unchoke_time {
+                Some(t) => {
+                    now.saturating_duration_since(t)
+                        >= OPTIMISTIC_UNCHOKE_INTERVAL
+                }
+                None => true,
+            };
+            if current.is_none() || is_rotation_due {
+                let next = interested
+                    .iter()
+                    .map(|c| c.addr)
+                    .filter(|addr| {
+                        !unchoked.contains(addr) && Some(*addr) != current
+                    })
+                    .choose(&mut rand::thread_rng());
+                // if there is no other peer to rotate to, keep the current
+                // one
+                if next.is_some() {
+                    self.optimistic_unchoke = next;
+                    self.last_optimistic_unchoke_time = Some(now);
+                } else {
+                    self.optimistic_unchoke = current;
+                }
+            } else {
+                self.optimistic_unchoke = current;
+            }
+            unchoked.extend(self.optimistic_unchoke);
+        }
+
+        let mut decisions = Decisions::default();
+        for c in candidates {
+            let should_unchoke = unchoked.contains(&c.addr);
+            if should_unchoke && c.is_choked {
+                decisions.unchoke.push(c.addr);
+            } else if !should_unchoke && !c.is_choked {
+                decisions.choke.push(c.addr);
+            }
+        }
+        decisions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn candidate(port: u16, rate: u64) -> Candidate {
+        Candidate {
+            addr: addr(port),
+            is_interested: true,
+            is_choked: true,
+            rate,
+        }
+    }
+
+    #[test]
+    fn should_unchoke_fastest_peers_and_one_optimistically() {
+        let now = Instant::now();
+        let mut choker = Choker::default();
+        let mut candidates: Vec<_> = (1..=5)
+            .map(|port| candidate(port, port as u64 * 100))
+            .collect();
+        // peers that aren't interested are not unchoked, however fast
+        candidates.push(Candidate {
+            is_interested: false,
+            ..candidate(6, 1000)
+        });
+        // and unchoked peers that lost interest are choked
+        candidates.push(Candidate {
+            is_interested: false,
+            is_choked: false,
+            ..candidate(7, 1000)
+        });
+
+        let mut decisions = choker.run(now, &candidates, 3);
+        assert_eq!(decisions.choke, vec![addr(7)]);
+        let optimistic_unchoke = choker.optimistic_unchoke.unwrap();
+        assert!([addr(1), addr(2), addr(3)].contains(&optimistic_unchoke));
+        decisions.unchoke.sort();
+        let mut expected = vec![addr(4), addr(5), optimistic_unchoke];
+        expected.sort();
+        assert_eq!(decisions.unchoke, expected);
+    }
+
+    #[test]
+    fn should_rotate_optimistic_unchoke() {
+        let now = Instant::now();
+        let mut choker = Choker::default();
+        let mut candidates = vec![candidate(1, 100), candidate(2, 200)];
+
+        let decisions = choker.run(now, &candidates, 1);
+        let first = choker.optimistic_unchoke.unwrap();
+        assert_eq!(decisions.unchoke, vec![first]);
+        for c in candidates.iter_mut() {
+            c.is_choked = !decisions.unchoke.contains(&c.addr);
+        }
+
+        // the optimistic unchoke is kept until the rotation is due
+        let decisions =
+            choker.run(now + OPTIMISTIC_UNCHOKE_INTERVAL / 2, &candidates, 1);
+        assert_eq!(decisions, Decisions::default());
+
+        let decisions =
+            choker.run(now + OPTIMISTIC_UNCHOKE_INTERVAL, &candidates, 1);
+        let second = choker.optimistic_unchoke.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(decisions.choke, vec![first]);
+        assert_eq!(decisions.unchoke, vec![second]);
+    }
+}
@@ -2,6 +2,8 @@ use std::fmt;
 
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
+use crate::metainfo::MetainfoError;
+
 pub(crate) type Result<T, E = TorrentError> = std::result::Result<T, E>;
 
 /// Error type returned on failed block reads.
@@ -14,8 +16,18 @@ pub enum TorrentError {
     /// The channel on which some component in engine was listening or sending
     /// died.
     Channel,
+    /// The number of file priorities doesn't match the number of files in the
+    /// torrent.
+    InvalidFilePriorities,
     /// An IO error ocurred.
     Io(std::io::Error),
+    /// The metadata downloaded from peers for a torrent created from a magnet
+    /// link matched its info hash but is not valid metainfo.
+    Metainfo(MetainfoError),
+    /// The metadata of a torrent created from a magnet link could not be
+    /// downloaded from peers within
+    /// [`crate::conf::TorrentConf::metadata_timeout`].
+    MetadataTimeout,
 }
 
 impl fmt::Display for TorrentError {
@@ -23,7 +35,10 @@ impl fmt::Display for TorrentError {
         use TorrentError::*;
         match self {
             Channel => write!(fmt, "channel error"),
+            InvalidFilePriorities => write!(fmt, "invalid file priorities"),
             Io(e) => write!(fmt, "{}", e),
+            Metainfo(e) => write!(fmt, "{}", e),
+            MetadataTimeout => write!(fmt, "metadata download timed out"),
         }
     }
 }
@@ -0,0 +1,573 @@
+//! This module contains the peer list of a torrent, which holds all peers the
+//! torrent knows of, and decides which of them the torrent connects to.
+//!
+//! Connection candidates are ranked by a score that is based on where we
+//! learned of the peer and on how our previous connections to it went. A peer
+//! is not reconnected right after its session ends, and the delay grows
+//! exponentially with each failure in a row, after too many of which the peer
+//! is forgotten. Peers that send corrupt pieces or violate the protocol are
+//! banned, which applies to all peers with the same IP address, as a peer
+//! that connects to us does so from a different port each time.
+//!
+//! IPv4-mapped IPv6 addresses are added as the IPv4 addresses they map, so
+//! that such a peer is not known, or banned, twice.
+//!
+//! Hybrid torrents have two swarms, and each peer is recorded with the swarm
+//! in which we found it, as a peer that only takes part in the v2 swarm
+//! rejects the handshake with the v1 info hash.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use super::stats::{KnownPeerStats, PeerSource};
+use crate::net;
+
+/// A peer is reconnected no sooner than this after its session ends. The
+/// delay is doubled with each failure in a row.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The backoff of failing peers doesn't grow beyond this.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Peers that fail this many times in a row are removed from the list.
+const MAX_FAILURE_COUNT: usize = 5;
+
+/// Peers that take part in sending this many corrupt pieces are banned.
+/// A peer that sends all blocks of a corrupt piece is banned right away, as
+/// then it's the one that sent the corrupt data.
+const MAX_HASH_FAILURE_COUNT: usize = 3;
+
+/// The peers of a torrent, both connected and not.
+#[derive(Default)]
+pub(super) struct PeerList {
+    peers: HashMap<SocketAddr, Entry>,
+    /// The IP addresses of the banned peers.
+    banned_ips: HashSet<IpAddr>,
+}
+
+/// The swarm in which we found a peer, which decides the info hash with which
+/// we connect to it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Swarm {
+    /// The swarm of the torrent's info hash.
+    Main,
+    /// The v2 swarm of a hybrid torrent, which is identified by the truncated
+    /// v2 info hash.
+    Hybrid,
+}
+
+/// A peer in the list.
+struct Entry {
+    source: PeerSource,
+    swarm: Swarm,
+    /// Whether the peer has a session, which may still be connecting.
+    is_connected: bool,
+    /// The number of times in a row that connecting to the peer, or its
+    /// session, failed. This is reset when a session ends cleanly.
+    failure_count: usize,
+    /// The number of corrupt pieces that the peer sent blocks of.
+    hash_failure_count: usize,
+    /// The number of times we completed the handshake with the peer.
+    connection_count: usize,
+    last_seen: Instant,
+    last_connected: Option<Instant>,
+    next_connect_time: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Entry {
+    fn new(source: PeerSource, swarm: Swarm, now: Instant) -> Self {
+        Self {
+            source,
+            swarm,
+            is_connected: false,
+            failure_count: 0,
+            hash_failure_count: 0,
+            connection_count: 0,
+            last_seen: now,
+            last_connected: None,
+            next_connect_time: None,
+            last_error: None,
+        }
+    }
+
+    /// Returns the score by which connection candidates are ranked.
+    ///
+    /// Peers that we were connected to before are the most likely to accept
+    /// our connection again, followed by the peers that the user and the
+    /// trackers gave us, which are more reliable than the ones that other
+    /// peers or the DHT told us of. Each failure in a row counts against the
+    /// peer.
+    fn score(&self) -> isize {
+        let source_score = match self.source {
+            PeerSource::Manual => 3,
+            PeerSource::Tracker => 2,
+            PeerSource::Pex | PeerSource::Dht => 1,
+            PeerSource::Incoming => 0,
+        };
+        let connection_score = if self.connection_count > 0 { 4 } else { 0 };
+        source_score + connection_score - 2 * self.failure_count as isize
+    }
+
+    /// Returns whether we can connect to the peer, either now or later.
+    ///
+    /// We can't connect to peers that connected to us, as they did so from
+    /// a port on which they're not listening.
+    fn is_connectable(&self) -> bool {
+        !self.is_connected && self.source != PeerSource::Incoming
+    }
+}
+
+impl PeerList {
+    /// Adds the peer of the torrent's main swarm if it's not already in the
+    /// list and it's not banned, returning whether it was added.
+    ///
+    /// If the peer is already in the list, its last seen time is updated, but
+    /// its source is kept.
+    pub fn add(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+        now: Instant,
+    ) -> bool {
+        self.add_in_swarm(addr, source, Swarm::Main, now)
+    }
+
+    /// Adds the peer found in the given swarm, like [`Self::add`].
+    ///
+    /// If the peer is already in the list, its swarm is kept too.
+    pub fn add_in_swarm(
+        &mut self,
+        addr: SocketAddr,
+        source: PeerSource,
+        swarm: Swarm,
+        now: Instant,
+    ) -> bool {
+        let addr = net::canonical_addr(addr);
+        if self.banned_ips.contains(&addr.ip()) {
+            return false;
+        }
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.last_seen = now;
+            return false;
+        }
+        self.peers.insert(addr, Entry::new(source, swarm, now));
+        true
+    }
+
+    /// Records the session of a peer that connected to us, returning whether
+    /// the peer may connect, which it may not if it's banned or already has
+    /// a session.
+    pub fn accept(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.banned_ips.contains(&addr.ip()) {
+            return false;
+        }
+        let entry = self.peers.entry(addr).or_insert_with(|| {
+            Entry::new(PeerSource::Incoming, Swarm::Main, now)
+        });
+        if entry.is_connected {
+            return false;
+        }
+        entry.is_connected = true;
+        entry.last_seen = now;
+        true
+    }
+
+    /// Returns at most `count` peers to connect to, in order of their score,
+    /// and marks them as connected.
+    ///
+    /// Only the peers that are not connected, not banned and whose retry
+    /// delay has passed are picked. Peers with the same score are picked in
+    /// order of when we last saw them, the most recent first.
+    pub fn pick_candidates(
+        &mut self,
+        now: Instant,
+        count: usize,
+    ) -> Vec<SocketAddr> {
+        if count == 0 {
+            return Vec::new();
+        }
+        let banned_ips = &self.banned_ips;
+        let mut candidates: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(addr, entry)| {
+                entry.is_connectable()
+                    && !banned_ips.contains(&addr.ip())
+                    && !matches!(entry.next_connect_time, Some(t) if t > now)
+            })
+            .map(|(addr, entry)| (*addr, entry.score(), entry.last_seen))
+            .collect();
+        candidates.sort_by_key(|(addr, score, last_seen)| {
+            (Reverse(*score), Reverse(*last_seen), *addr)
+        });
+        candidates.truncate(count);
+
+        candidates
+            .into_iter()
+            .map(|(addr, _, _)| {
+                if let Some(entry) = self.peers.get_mut(&addr) {
+                    entry.is_connected = true;
+                }
+                addr
+            })
+            .collect()
+    }
+
+    /// Returns the swarm in which we found the peer, which is the main swarm
+    /// for peers not in the list.
+    pub fn swarm(&self, addr: &SocketAddr) -> Swarm {
+        self.peers
+            .get(addr)
+            .map(|entry| entry.swarm)
+            .unwrap_or(Swarm::Main)
+    }
+
+    /// Records that the handshake with the peer completed.
+    pub fn connected(&mut self, addr: SocketAddr, now: Instant) {
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.connection_count += 1;
+            entry.last_connected = Some(now);
+            entry.last_seen = now;
+        }
+    }
+
+    /// Records the error with which the peer's session failed.
+    pub fn record_error(&mut self, addr: SocketAddr, error: String) {
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.last_error = Some(error);
+        }
+    }
+
+    /// Records that the peer's session ended, which is a failure if we
+    /// couldn't connect to the peer or if the session stopped due to an
+    /// error.
+    ///
+    /// The peer can be reconnected after a delay that grows with each
+    /// failure in a row, unless it failed too many times, in which case it's
+    /// removed. Peers that connected to us are removed, as we can't connect
+    /// to them.
+    pub fn disconnected(
+        &mut self,
+        addr: SocketAddr,
+        now: Instant,
+        has_failed: bool,
+    ) {
+        let entry = match self.peers.get_mut(&addr) {
+            Some(entry) => entry,
+            None => return,
+        };
+        entry.is_connected = false;
+        // banned peers are kept so that they show up in the stats
+        if self.banned_ips.contains(&addr.ip()) {
+            return;
+        }
+
+        if has_failed {
+            entry.failure_count += 1;
+        } else {
+            entry.failure_count = 0;
+        }
+        if entry.source == PeerSource::Incoming
+            || entry.failure_count >= MAX_FAILURE_COUNT
+        {
+            log::debug!("Removing peer {} from peer list", addr);
+            self.peers.remove(&addr);
+        } else {
+            entry.last_seen = now;
+            entry.next_connect_time =
+                Some(now + retry_delay(entry.failure_count));
+        }
+    }
+
+    /// Marks all peers as not connected, such as when the torrent is paused,
+    /// without delaying their reconnection.
+    pub fn disconnect_all(&mut self) {
+        let banned_ips = &self.banned_ips;
+        self.peers.retain(|addr, entry| {
+            entry.source != PeerSource::Incoming
+                || banned_ips.contains(&addr.ip())
+        });
+        for entry in self.peers.values_mut() {
+            entry.is_connected = false;
+            entry.next_connect_time = None;
+        }
+    }
+
+    /// Bans the peer's IP address, recording the reason.
+    pub fn ban(&mut self, addr: SocketAddr, reason: String) {
+        log::warn!("Banning peer {}: {}", addr, reason);
+        self.banned_ips.insert(addr.ip());
+        if let Some(entry) = self.peers.get_mut(&addr) {
+            entry.last_error = Some(reason);
+        }
+    }
+
+    /// Records that the peers sent the blocks of a piece that failed the hash
+    /// check, banning the ones that are to blame.
+    pub fn corrupt_piece(&mut self, peers: &[SocketAddr]) {
+        for addr in peers {
+            let hash_failure_count = match self.peers.get_mut(addr) {
+                Some(entry) => {
+                    entry.hash_failure_count += 1;
+                    entry.hash_failure_count
+                }
+                None => continue,
+            };
+            if peers.len() == 1 {
+                self.ban(*addr, "sent corrupt piece".into());
+            } else if hash_failure_count >= MAX_HASH_FAILURE_COUNT {
+                self.ban(
+                    *addr,
+                    format!("sent {} corrupt pieces", hash_failure_count),
+                );
+            }
+        }
+    }
+
+    /// Returns whether the peer's IP address is banned.
+    pub fn is_banned(&self, addr: &SocketAddr) -> bool {
+        self.banned_ips.contains(&addr.ip())
+    }
+
+    /// Returns the number of peers that are not connected but which we may
+    /// connect to, now or after their retry delay.
+    pub fn available_count(&self) -> usize {
+        self.peers
+            .iter()
+            .filter(|(addr, entry)| {
+                entry.is_connectable() && !self.is_banned(addr)
+            })
+            .count()
+    }
+
+    /// Returns the addresses of the peers we may connect to in the future,
+    /// including the connected ones, which are saved in the resume data.
+    pub fn resume_addrs(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(addr, entry)| {
+                entry.source != PeerSource::Incoming && !self.is_banned(addr)
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Returns the state of all peers in the list, for sending to the user.
+    pub fn stats(&self) -> Vec<KnownPeerStats> {
+        self.peers
+            .iter()
+            .map(|(addr, entry)| KnownPeerStats {
+                addr: *addr,
+                source: entry.source,
+                is_connected: entry.is_connected,
+                is_banned: self.is_banned(addr),
+                failure_count: entry.failure_count,
+                connection_count: entry.connection_count,
+                last_seen: entry.last_seen,
+                last_connected: entry.last_connected,
+                next_connect_time: entry.next_connect_time,
+                last_error: entry.last_error.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Returns how long to wait before reconnecting a peer that failed
+/// `failure_count` times in a row.
+fn retry_delay(failure_count: usize) -> Duration {
+    // cap the exponent so that the multiplication doesn't overflow
+    let factor = 1u32 << failure_count.min(16);
+    (RETRY_DELAY * factor).min(MAX_RETRY_DELAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn should_pick_candidates_by_score() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        assert!(list.add(addr(1), PeerSource::Dht, now));
+        assert!(list.add(addr(2), PeerSource::Tracker, now));
+        assert!(list.add(addr(3), PeerSource::Manual, now));
+        // known peers are not added again
+        assert!(!list.add(addr(3), PeerSource::Pex, now));
+
+        assert_eq!(list.pick_candidates(now, 2), vec![addr(3), addr(2)]);
+        // the picked peers are connected so they're not picked again
+        assert_eq!(list.pick_candidates(now, 2), vec![addr(1)]);
+        assert!(list.pick_candidates(now, 2).is_empty());
+        assert_eq!(list.available_count(), 0);
+    }
+
+    #[test]
+    fn should_back_off_failing_peers() {
+        let mut now = Instant::now();
+        let mut list = PeerList::default();
+        list.add(addr(1), PeerSource::Manual, now);
+
+        for failure_count in 1..MAX_FAILURE_COUNT {
+            assert_eq!(list.pick_candidates(now, 1), vec![addr(1)]);
+            list.disconnected(addr(1), now, true);
+            let delay = retry_delay(failure_count);
+            assert!(delay > retry_delay(failure_count - 1));
+            // the peer is not retried before its retry delay passes
+            assert!(list
+                .pick_candidates(now + delay - Duration::from_secs(1), 1)
+                .is_empty());
+            assert_eq!(list.available_count(), 1);
+            let stats = list.stats();
+            assert_eq!(stats[0].failure_count, failure_count);
+            assert_eq!(stats[0].next_connect_time, Some(now + delay));
+            now += delay;
+        }
+
+        // after too many failures the peer is forgotten
+        assert_eq!(list.pick_candidates(now, 1), vec![addr(1)]);
+        list.disconnected(addr(1), now, true);
+        assert_eq!(list.available_count(), 0);
+        assert!(list.stats().is_empty());
+    }
+
+    #[test]
+    fn should_prefer_previously_connected_peers() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        list.add(addr(1), PeerSource::Manual, now);
+        list.add(addr(2), PeerSource::Dht, now);
+
+        assert_eq!(list.pick_candidates(now, 2), vec![addr(1), addr(2)]);
+        list.disconnected(addr(1), now, true);
+        list.connected(addr(2), now);
+        list.disconnected(addr(2), now, false);
+
+        // a clean disconnect is not a failure, but the peer is still not
+        // reconnected right away
+        let later = now + RETRY_DELAY * 2;
+        assert!(list.pick_candidates(now, 2).is_empty());
+        assert_eq!(list.pick_candidates(later, 2), vec![addr(2), addr(1)]);
+        let stats = list.stats();
+        let stats = stats.iter().find(|s| s.addr == addr(2)).unwrap();
+        assert_eq!(stats.failure_count, 0);
+        assert_eq!(stats.connection_count, 1);
+        assert_eq!(stats.last_connected, Some(now));
+    }
+
+    #[test]
+    fn should_ban_peers_by_ip() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        list.add(addr(1), PeerSource::Tracker, now);
+        assert!(list.accept(addr(2), now));
+        // a peer can't have two sessions
+        assert!(!list.accept(addr(2), now));
+
+        list.ban(addr(2), "invalid block info".into());
+        assert!(list.is_banned(&addr(1)));
+        assert!(!list.accept(addr(3), now));
+        assert!(!list.add(addr(4), PeerSource::Pex, now));
+        assert!(list.pick_candidates(now, 2).is_empty());
+        assert!(list.resume_addrs().is_empty());
+
+        // banned peers are kept after they disconnect
+        list.disconnected(addr(2), now, false);
+        let stats = list.stats();
+        let stats = stats.iter().find(|s| s.addr == addr(2)).unwrap();
+        assert!(stats.is_banned);
+        assert_eq!(stats.last_error.as_deref(), Some("invalid block info"));
+    }
+
+    #[test]
+    fn should_treat_ipv4_mapped_peers_as_ipv4() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:1".parse().unwrap();
+        assert!(list.add(mapped, PeerSource::Tracker, now));
+        assert!(!list.add(addr(1), PeerSource::Pex, now));
+        // IPv6 peers are distinct from IPv4 peers
+        let ipv6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 1));
+        assert!(list.add(ipv6, PeerSource::Dht, now));
+        assert_eq!(list.pick_candidates(now, 3), vec![addr(1), ipv6]);
+
+        // banning the IPv4 address bans the mapped address too
+        list.ban(addr(1), "invalid block info".into());
+        let mapped: SocketAddr = "[::ffff:127.0.0.1]:2".parse().unwrap();
+        assert!(!list.add(mapped, PeerSource::Pex, now));
+        assert!(!list.is_banned(&ipv6));
+    }
+
+    #[test]
+    fn should_ban_peers_that_send_corrupt_pieces() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        let a = SocketAddr::from(([10, 0, 0, 1], 1));
+        let b = SocketAddr::from(([10, 0, 0, 2], 1));
+        let c = SocketAddr::from(([10, 0, 0, 3], 1));
+        list.add(a, PeerSource::Tracker, now);
+        list.add(b, PeerSource::Tracker, now);
+        list.add(c, PeerSource::Tracker, now);
+
+        // if multiple peers sent the piece, we can't tell which is to blame
+        for _ in 0..MAX_HASH_FAILURE_COUNT - 1 {
+            list.corrupt_piece(&[a, b]);
+        }
+        assert!(!list.is_banned(&a));
+        assert!(!list.is_banned(&b));
+        list.corrupt_piece(&[a, b]);
+        assert!(list.is_banned(&a));
+        assert!(list.is_banned(&b));
+
+        // but if only one did, it's banned right away
+        list.corrupt_piece(&[c]);
+        assert!(list.is_banned(&c));
+    }
+
+    #[test]
+    fn should_reset_connections() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        list.add(addr(1), PeerSource::Tracker, now);
+        list.accept(addr(2), now);
+        assert_eq!(list.pick_candidates(now, 2), vec![addr(1)]);
+        list.disconnect_all();
+        // incoming peers are forgotten, while the others can be connected
+        // right away
+        assert_eq!(list.resume_addrs(), vec![addr(1)]);
+        assert_eq!(list.pick_candidates(now, 2), vec![addr(1)]);
+    }
+
+    #[test]
+    fn should_keep_swarm_of_known_peers() {
+        let now = Instant::now();
+        let mut list = PeerList::default();
+        list.add(addr(1), PeerSource::Tracker, now);
+        assert!(list.add_in_swarm(
+            addr(2),
+            PeerSource::Dht,
+            Swarm::Hybrid,
+            now
+        ));
+        // a peer of the main swarm that was also found in the v2 swarm is
+        // still connected with the v1 info hash
+        assert!(!list.add_in_swarm(
+            addr(1),
+            PeerSource::Dht,
+            Swarm::Hybrid,
+            now
+        ));
+        assert_eq!(list.swarm(&addr(1)), Swarm::Main);
+        assert_eq!(list.swarm(&addr(2)), Swarm::Hybrid);
+    }
+}
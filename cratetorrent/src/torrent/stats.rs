@@ -13,6 +13,9 @@ pub use crate::peer::{ConnectionState, SessionState};
 /// Aggregated statistics of a torrent.
 #[derive(Clone, Debug, Default)]
 pub struct TorrentStats {
+    /// What the torrent is currently doing.
+    pub state: TorrentState,
+
     /// When the torrent was _first_ started.
     pub start_time: Option<Instant>,
 
@@ -33,6 +36,39 @@ pub struct TorrentStats {
 
     /// Various thruput statistics of the torrent.
     pub thruput: ThruputStats,
+
+    /// The torrent's trackers, with the statistics of the swarm they
+    /// reported.
+    pub trackers: Vec<TrackerStats>,
+
+    /// All peers the torrent knows of, whether or not they're connected.
+    ///
+    /// By default this information is not sent, as it has some overhead. It
+    /// needs to be turned on in the torrent's
+    /// [configuration](crate::conf::TorrentAlertConf::known_peers).
+    pub known_peers: Option<Vec<KnownPeerStats>>,
+}
+
+/// The state of a torrent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TorrentState {
+    /// The torrent is downloading the pieces it doesn't have yet.
+    Downloading,
+    /// The torrent has all the pieces it wants and only uploads to peers.
+    Seeding,
+    /// The pieces on disk are being checked against their hashes. The
+    /// torrent keeps downloading and uploading in the meantime.
+    Checking,
+    /// The torrent has no peers and doesn't look for new ones until it's
+    /// resumed.
+    Paused,
+}
+
+/// The default (and initial) state of a torrent is `Downloading`.
+impl Default for TorrentState {
+    fn default() -> Self {
+        Self::Downloading
+    }
 }
 
 /// Statistics of a torrent's pieces.
@@ -44,6 +80,9 @@ pub struct PieceStats {
     pub pending: usize,
     /// The number of pieces that the torrent has downloaded.
     pub complete: usize,
+    /// The number of pieces that the torrent still needs to download. Pieces
+    /// that only overlap with skipped files are not needed.
+    pub missing: usize,
     /// The pieces that were completed since the last tick.
     ///
     /// By default this information is not sent, as it has some overhead. It
@@ -58,10 +97,16 @@ impl PieceStats {
         self.complete == self.total
     }
 
+    /// Returns whether the torrent has downloaded all the pieces it needs,
+    /// which may not be all pieces if some files are skipped.
+    pub fn is_download_complete(&self) -> bool {
+        self.missing == 0
+    }
+
     /// Returns whether the torrent is in endgame mode (about to finish
     /// download).
     pub fn is_in_endgame(&self) -> bool {
-        self.pending + self.complete == self.total
+        self.pending == self.missing
     }
 }
 
@@ -95,6 +140,32 @@ impl Default for Peers {
     }
 }
 
+/// The state of a torrent's tracker and the statistics of the swarm it
+/// reported, either in the announce or in the scrape response.
+#[derive(Clone, Debug, Default)]
+pub struct TrackerStats {
+    /// The URL of the tracker.
+    pub url: String,
+    /// The tier of the tracker. Trackers in lower tiers are preferred.
+    pub tier: usize,
+    /// The number of peers that have the complete torrent.
+    pub seeder_count: Option<usize>,
+    /// The number of peers that are still downloading the torrent.
+    pub leecher_count: Option<usize>,
+    /// The number of times the torrent was downloaded completely. This is
+    /// only reported when scraping the tracker.
+    pub completed_count: Option<usize>,
+    /// When we last announced to the tracker.
+    pub last_announce_time: Option<Instant>,
+    /// When we will next announce to the tracker, unless we need peers
+    /// sooner. As only one tracker is announced to at a time, this is `None`
+    /// for all but the current tracker, as well as before the first
+    /// announce.
+    pub next_announce_time: Option<Instant>,
+    /// The error of the last request to the tracker, if it failed.
+    pub last_error: Option<String>,
+}
+
 /// Aggregate statistics of a peer session.
 #[derive(Clone, Debug)]
 pub struct PeerSessionStats {
@@ -109,6 +180,56 @@ pub struct PeerSessionStats {
     pub piece_count: usize,
     /// Various thruput statistics of ths peer.
     pub thruput: ThruputStats,
+    /// The names of the extensions that both we and the peer support (e.g.
+    /// `ut_metadata`), negotiated in the extended handshake.
+    pub extensions: Vec<String>,
+}
+
+/// Where the torrent learned of a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    /// A tracker returned the peer in an announce response.
+    Tracker,
+    /// The peer was added by the user, or restored from the resume data.
+    Manual,
+    /// The peer connected to us.
+    Incoming,
+    /// A connected peer told us of the peer via peer exchange.
+    Pex,
+    /// The peer was found in the DHT.
+    Dht,
+}
+
+/// The state of a peer in the torrent's peer list, which holds all peers the
+/// torrent knows of, including the ones it's not connected to.
+#[derive(Clone, Debug)]
+pub struct KnownPeerStats {
+    /// The IP-port pair of the peer.
+    pub addr: SocketAddr,
+    /// Where we first learned of the peer.
+    pub source: PeerSource,
+    /// Whether the peer currently has a session, which may still be
+    /// connecting.
+    pub is_connected: bool,
+    /// Whether the peer is banned, for sending corrupt pieces or violating
+    /// the protocol. Bans apply to all peers with the same IP address.
+    pub is_banned: bool,
+    /// The number of times in a row that connecting to the peer, or its
+    /// session, failed.
+    pub failure_count: usize,
+    /// The number of times we completed the handshake with the peer.
+    pub connection_count: usize,
+    /// When a source last told us of the peer, or when we were last connected
+    /// to it.
+    pub last_seen: Instant,
+    /// When we last completed the handshake with the peer.
+    pub last_connected: Option<Instant>,
+    /// The earliest time we may connect to the peer again, if we were
+    /// connected to it before.
+    pub next_connect_time: Option<Instant>,
+    /// The error that ended the peer's last session, or the reason of its
+    /// ban.
+    pub last_error: Option<String>,
 }
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
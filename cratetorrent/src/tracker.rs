@@ -1,26 +1,27 @@
 use std::{
-    convert::TryInto,
+    collections::HashMap,
     fmt,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::Buf;
 
 use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
-use rand::prelude::*;
 use reqwest::{Client, Url};
 use serde::de;
-
-use tokio::net::UdpSocket;
-use tokio::time::timeout;
+use serde_bytes::ByteBuf;
 
 use crate::{
     metainfo::{BencodeError, TrackerUrl, NetProtocol},
     PeerId, Sha1Hash,
 };
+use udp::UdpTracker;
 
 pub use reqwest::Error as HttpError;
+pub use tokio::io::Error as IoError;
+
+mod udp;
 
 pub(crate) type Result<T, E = TrackerError> = crate::error::Result<T, E>;
 
@@ -30,10 +31,22 @@ pub(crate) type Result<T, E = TrackerError> = crate::error::Result<T, E>;
 pub enum TrackerError {
     /// Holds bencode serialization or deserialization related errors.
     Bencode(BencodeError),
+    /// The tracker responded with an error message, e.g. because it doesn't
+    /// track the torrent.
+    Failure(String),
     /// HTTP related errors when contacting the tracker.
     Http(HttpError),
-    ///UDP Specific: The transaction id received doesn't match the one sent
-    NonMatchingTransactionId,
+    /// The tracker's response is malformed.
+    InvalidResponse,
+    /// Holds IO related errors, e.g. when the UDP tracker's address cannot be
+    /// resolved.
+    Io(IoError),
+    /// The tracker's URL doesn't follow the convention from which the scrape
+    /// URL could be derived, so the tracker can't be scraped.
+    ScrapeNotSupported,
+    /// The tracker didn't respond in time, even after retransmitting the
+    /// request.
+    Timeout,
 }
 
 impl From<BencodeError> for TrackerError {
@@ -48,20 +61,36 @@ impl From<HttpError> for TrackerError {
     }
 }
 
+impl From<IoError> for TrackerError {
+    fn from(e: IoError) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl fmt::Display for TrackerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Bencode(e) => e.fmt(f),
+            Self::Failure(msg) => write!(f, "tracker error: {}", msg),
             Self::Http(e) => e.fmt(f),
-            Self::NonMatchingTransactionId => self.fmt(f),
+            Self::InvalidResponse => write!(f, "invalid tracker response"),
+            Self::Io(e) => e.fmt(f),
+            Self::ScrapeNotSupported => {
+                write!(f, "tracker does not support scrape")
+            }
+            Self::Timeout => write!(f, "tracker timed out"),
         }
     }
 }
 
 /// Parameters for announcing to a tracker.
+#[derive(Clone)]
 pub(crate) struct Announce {
     pub info_hash: Sha1Hash,
     pub peer_id: PeerId,
+    /// A random number that lets the tracker identify us even if our IP address
+    /// changes, which is the same in all announces of a torrent.
+    pub key: u32,
 
     /// The port on which we are listening.
     pub port: u16,
@@ -71,6 +100,10 @@ pub(crate) struct Announce {
     /// proxy, or when the tracker is on the same NAT'd subnet as peer (in which case it
     /// is necessary that tracker not give out an unroutable address to peer).
     pub ip: Option<IpAddr>,
+    /// Our IPv6 address, if we accept IPv6 connections. If we announce over
+    /// IPv4, this tells the tracker that we're reachable over IPv6 too, see
+    /// [BEP 7](http://bittorrent.org/beps/bep_0007.html).
+    pub ipv6: Option<Ipv6Addr>,
 
     /// Number of bytes downloaded so far.
     pub downloaded: u64,
@@ -140,84 +173,88 @@ pub(crate) struct Response {
     #[serde(rename = "incomplete")]
     pub leecher_count: Option<usize>,
 
+    /// The IPv4 and IPv6 peers of the torrent.
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<SocketAddr>,
+
+    /// HTTP trackers may send the IPv6 peers in compact form separately from
+    /// the IPv4 peers. These are moved to `peers` when the response is
+    /// parsed.
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers6")]
+    peers6: Vec<SocketAddr>,
+}
+
+/// The scrape response for a torrent: the statistics of its swarm.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub(crate) struct ScrapeResponse {
+    /// The number of peers that have the complete torrent.
+    #[serde(rename = "complete")]
+    pub seeder_count: usize,
+    /// The number of times the torrent was downloaded completely.
+    #[serde(rename = "downloaded")]
+    pub completed_count: usize,
+    /// The number of peers that are still downloading the torrent.
+    #[serde(rename = "incomplete")]
+    pub leecher_count: usize,
 }
 
-/// The HTTP tracker for a torrent for which we can request peers as well as to
+/// The HTTP tracker's scrape response, which contains the statistics of each
+/// requested torrent, keyed by the torrent's info hash.
+#[derive(Debug, Deserialize)]
+struct HttpScrapeResponse {
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeResponse>,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+}
+
+/// A tracker for a torrent for which we can request peers as well as to
 /// announce transfer progress.
 pub(crate) struct Tracker {
-    /// The HTTP client.
-    client: Client,
     /// The URL of the tracker.
     url: Url,
-    protocol: NetProtocol,
+    transport: Transport,
+}
+
+/// The client for the tracker's protocol.
+enum Transport {
+    Http {
+        client: Client,
+        /// The URL from which swarm statistics can be requested, if the
+        /// tracker supports it.
+        scrape_url: Option<Url>,
+    },
+    /// UDP trackers have a connection state, so each tracker has its own
+    /// client.
+    Udp(Box<UdpTracker>),
 }
 
 impl Tracker {
     pub fn new(url: TrackerUrl) -> Self {
+        let transport = match url.protocol {
+            NetProtocol::HTTP => Transport::Http {
+                client: Client::new(),
+                scrape_url: scrape_url(&url.url),
+            },
+            NetProtocol::UDP => {
+                Transport::Udp(Box::new(UdpTracker::new(url.url.clone())))
+            }
+        };
         Self {
-            client: Client::new(),
             url: url.url,
-            protocol: url.protocol,
+            transport,
         }
     }
 
-    ///https://www.bittorrent.org/beps/bep_0015.html
-    async fn connect_udp(ip_addr: SocketAddr) -> Option<i64> {
-        //Bind to a random port
-        let port = thread_rng().gen_range(1025..u16::MAX);
-
-        let mut sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))
-            .await
-            .unwrap();
-
-        //The magic protocol id number
-        const PROTOCOL_ID: i64 = 0x41727101980;
-        const ACTION: i32 = 0;
-        let transaction_id: i32 = random();
-
-        let mut bytes_to_send = BytesMut::with_capacity(16);
-        bytes_to_send.put_i64(PROTOCOL_ID);
-        bytes_to_send.put_i32(ACTION);
-        bytes_to_send.put_i32(transaction_id.try_into().unwrap());
-
-        let bytes_to_send = &bytes_to_send;
-
-        let mut response_buf: [u8; 16] = [0; 16];
-
-        sock.send_to(bytes_to_send, ip_addr).await.unwrap();
-        
-        let wait_time = Duration::from_secs(3);
-        let mut attempts: u8 = 0;
-
-        let mut could_connect = false;
-
-        while response_buf == [0; 16] && attempts < 5 {
-            could_connect =
-                match timeout(wait_time, sock.recv_from(&mut response_buf)).await {
-                    Ok(_) => true,
-                    Err(_) => false,
-                };
-
-            attempts += 1;
-
-        }
-
-        let transaction_id_recv: i32 =
-            i32::from_be_bytes((&response_buf[4..8]).try_into().unwrap());
-
-        match could_connect && transaction_id == transaction_id_recv {
-            true => Some(i64::from_be_bytes((&response_buf[8..]).try_into().unwrap())),
-            false => None
-
-        }
-
+    /// Returns the URL of the tracker.
+    pub fn url(&self) -> &Url {
+        &self.url
     }
 
     /// Sends an announce request to the tracker with the specified parameters.
-    /// 
+    ///
     /// This may be used by a torrent to request peers to download from and to
     /// report statistics to the tracker.
     ///
@@ -225,14 +262,83 @@ impl Tracker {
     ///
     /// The tracker may not be contacted more often than the minimum interval
     /// returned in the first announce response.
-    pub async fn announce(&self, params: Announce) -> Result<Response> {
-        match &self.protocol {
-            NetProtocol::UDP => self.announce_udp(params).await,
-            NetProtocol::HTTP => self.announce_http(params).await,
+    pub async fn announce(&mut self, params: Announce) -> Result<Response> {
+        match &mut self.transport {
+            Transport::Http { client, .. } => {
+                Self::announce_http(client, &self.url, params).await
+            }
+            Transport::Udp(tracker) => tracker.announce(params).await,
+        }
+    }
+
+    /// Returns whether the tracker can be scraped for the statistics of the
+    /// torrent's swarm.
+    pub fn supports_scrape(&self) -> bool {
+        match &self.transport {
+            Transport::Http { scrape_url, .. } => scrape_url.is_some(),
+            Transport::Udp(_) => true,
+        }
+    }
+
+    /// Requests the statistics of the torrent's swarm from the tracker.
+    ///
+    /// Like announces, the tracker should not be scraped too often.
+    pub async fn scrape(
+        &mut self,
+        info_hash: Sha1Hash,
+    ) -> Result<ScrapeResponse> {
+        match &mut self.transport {
+            Transport::Http {
+                client,
+                scrape_url: Some(url),
+            } => Self::scrape_http(client, url, info_hash).await,
+            Transport::Http { .. } => Err(TrackerError::ScrapeNotSupported),
+            Transport::Udp(tracker) => tracker
+                .scrape(&[info_hash])
+                .await?
+                .pop()
+                .ok_or(TrackerError::InvalidResponse),
         }
     }
 
-    async fn announce_http(&self, params: Announce) -> Result<Response> {
+    async fn scrape_http(
+        client: &Client,
+        url: &Url,
+        info_hash: Sha1Hash,
+    ) -> Result<ScrapeResponse> {
+        // see the comment in `announce_http` as to why the info hash is
+        // encoded by hand
+        let url = format!(
+            "{url}?info_hash={info_hash}",
+            url = url,
+            info_hash =
+                percent_encoding::percent_encode(&info_hash, URL_ENCODE_RESERVED),
+        );
+        let resp = client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let resp: HttpScrapeResponse = serde_bencode::from_bytes(&resp)?;
+        if let Some(failure_reason) = resp.failure_reason {
+            return Err(TrackerError::Failure(failure_reason));
+        }
+        // trackers may omit torrents they don't track, which is the same as
+        // the torrent having an empty swarm
+        Ok(resp
+            .files
+            .get(&ByteBuf::from(info_hash.to_vec()))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    async fn announce_http(
+        client: &Client,
+        url: &Url,
+        params: Announce,
+    ) -> Result<Response> {
         // announce parameters are built up in the query string, see:
         // https://www.bittorrent.org/beps/bep_0003.html trackers section
         let mut query = vec![
@@ -250,9 +356,13 @@ impl Tracker {
         if let Some(peer_count) = params.peer_count {
             query.push(("numwant", peer_count.to_string()));
         }
+        query.push(("key", format!("{:08x}", params.key)));
         if let Some(ip) = &params.ip {
             query.push(("ip", ip.to_string()));
         }
+        if let Some(ipv6) = &params.ipv6 {
+            query.push(("ipv6", ipv6.to_string()));
+        }
 
         // hack:
         // reqwest uses serde_urlencoded which doesn't support encoding a raw
@@ -277,7 +387,7 @@ impl Tracker {
             "{url}\
             ?info_hash={info_hash}\
             &peer_id={peer_id}",
-            url = self.url,
+            url = url,
             info_hash = percent_encoding::percent_encode(
                 &params.info_hash,
                 URL_ENCODE_RESERVED
@@ -289,8 +399,7 @@ impl Tracker {
         );
 
         // send request
-        let resp = self
-            .client
+        let resp = client
             .get(&url)
             .query(&query)
             .send()
@@ -298,155 +407,10 @@ impl Tracker {
             .error_for_status()?
             .bytes()
             .await?;
-        let resp = serde_bencode::from_bytes(&resp)?;
+        let mut resp: Response = serde_bencode::from_bytes(&resp)?;
+        resp.peers.append(&mut resp.peers6);
         Ok(resp)
     }
-
-    async fn announce_udp(&self, params: Announce) -> Result<Response> {
-        let port = thread_rng().gen_range(1025..u16::MAX);
-        let mut sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port)))
-            .await
-            .unwrap();
-
-        // All of the potential addressese of a URL
-        let mut addrs = self.url.socket_addrs(|| None).unwrap();
-        // Shuffle the list
-        addrs.shuffle(&mut thread_rng());
-
-        //TODO: Make an error for not finding an actual IPV4 address
-        let addr = *addrs.iter().find(|a| a.is_ipv4()).unwrap();
-
-        let mut failure_reason = None;
-
-        let connection_id: i64 = Tracker::connect_udp(addr).await.unwrap();
-
-        const ACTION: i32 = 1;
-        let transaction_id: i32 = random();
-        let key: u32 = random();
-
-        let mut bytes_to_send = BytesMut::with_capacity(150);
-        bytes_to_send.put_i64(connection_id);
-        bytes_to_send.put_i32(ACTION);
-        bytes_to_send.put_i32(transaction_id);
-
-        debug_assert_eq!(params.info_hash.len(), 20);
-        debug_assert_eq!(params.peer_id.len(), 20);
-
-        bytes_to_send.put(&params.info_hash[..]);
-        bytes_to_send.put(&params.peer_id[..]);
-        bytes_to_send.put_i64(params.downloaded.try_into().unwrap());
-        bytes_to_send.put_i64(params.left.try_into().unwrap());
-        bytes_to_send.put_i64(params.uploaded.try_into().unwrap());
-
-        bytes_to_send.put_i32(match params.event {
-            Some(val) => match val {
-                crate::tracker::Event::Completed => 1,
-                crate::tracker::Event::Started => 2,
-                crate::tracker::Event::Stopped => 3,
-            },
-            None => 0,
-        });
-        match params.ip {
-            Some(ip) => {
-                match ip {
-                    IpAddr::V4(ip) => {
-                        bytes_to_send.put(&(ip.octets()[..]));
-                    }
-
-                    //The IP address field must be 32 bits wide, so if the IP given is v6, the field must be set to 0
-                    IpAddr::V6(_) => {
-                        bytes_to_send.put_i32(0);
-                    }
-                }
-            }
-            None => {
-                bytes_to_send.put_i32(0);
-            }
-        };
-
-        bytes_to_send.put_u32(key);
-        bytes_to_send.put_i32(match params.peer_count {
-            Some(num) => num.try_into().unwrap(),
-            None => -1,
-        });
-        bytes_to_send.put_u16(params.port);
-
-        let bytes_to_send = &bytes_to_send;
-
-        const MAX_NUM_PEERS: usize = 2048;
-
-        //Supporting around a few hundred peers, just for the test
-
-        let mut response_buf: [u8; MAX_NUM_PEERS] = [0; MAX_NUM_PEERS];
-
-        sock.send_to(bytes_to_send, addr).await.unwrap();
-        let wait_time = match connection_id {
-            0 => Duration::from_secs(0),
-            _ => Duration::from_secs(3),
-        };
-
-        match timeout(wait_time, sock.recv_from(&mut response_buf)).await {
-            Ok(_) => (),
-            Err(_) => {
-                failure_reason =
-                    Some(String::from("Couldn't announce to tracker"))
-            }
-        };
-
-        let transaction_id_recv: i32 =
-            i32::from_be_bytes((&response_buf[4..8]).try_into().unwrap());
-
-        let leechers: i32 =
-            i32::from_be_bytes((&response_buf[12..16]).try_into().unwrap());
-        let seeders: i32 =
-            i32::from_be_bytes((&response_buf[16..20]).try_into().unwrap());
-
-        let mut peer_vec: Vec<SocketAddr> = Vec::new();
-
-        let mut index: usize = 20;
-
-        while index <= response_buf.len() - 6 {
-            let peer_ip_bytes: [u8; 4] =
-                response_buf[index..index + 4].try_into().unwrap();
-            let peer_port_bytes: [u8; 2] =
-                response_buf[index + 4..index + 6].try_into().unwrap();
-
-            if peer_ip_bytes != [0; 4] && peer_port_bytes != [0; 2] {
-                let peer_ipv4 = Ipv4Addr::from(peer_ip_bytes);
-                let peer_string = peer_ipv4.to_string();
-                let peer_port_bytes: [u8; 2] =
-                    response_buf[index + 4..index + 6].try_into().unwrap();
-                let peer_port: u16 = u16::from_be_bytes(peer_port_bytes);
-                let peer_string = format!("{}:{}", peer_string, peer_port);
-                let peer_sock: SocketAddr = peer_string.parse().unwrap();
-
-                peer_vec.push(peer_sock);
-
-                index += 6;
-            } else {
-                break;
-
-            }
-        }
-
-        if transaction_id != transaction_id_recv {
-            failure_reason =
-                Some(String::from("Transaction ID's did not match"));
-        }
-
-        let response = Response {
-            tracker_id: Some(transaction_id_recv.to_string()),
-            failure_reason,
-            warning_message: None,
-            min_interval: None,
-            interval: Some(Duration::from_secs(9)),
-            leecher_count: Some(leechers.try_into().unwrap()),
-            seeder_count: Some(seeders.try_into().unwrap()),
-            peers: peer_vec,
-        };
-
-        Ok(response)
-    }
 }
 
 impl fmt::Display for Tracker {
@@ -455,6 +419,21 @@ impl fmt::Display for Tracker {
     }
 }
 
+/// Returns the scrape URL of an HTTP tracker.
+///
+/// By convention, if the last path segment of the announce URL starts with
+/// "announce", the scrape URL is the announce URL with this replaced by
+/// "scrape". Otherwise, the tracker doesn't support scraping. See:
+/// https://www.bittorrent.org/beps/bep_0048.html
+fn scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let last_segment_start = path.rfind('/')? + 1;
+    let rest = path[last_segment_start..].strip_prefix("announce")?;
+    let mut url = announce_url.clone();
+    url.set_path(&format!("{}scrape{}", &path[..last_segment_start], rest));
+    Some(url)
+}
+
 /// Peers can be sent in two ways: as a bencoded list of dicts including full
 /// peer metadata, or as a single bencoded string that contains only the peer IP
 /// and port (compact representation). This helper method deserializes both into
@@ -489,33 +468,11 @@ where
         /// Each entry is 6 bytes long, where the first 4 bytes are the IPv4
         /// address of the peer, and the last 2 bytes are the port of the peer.
         /// Both are in network byte order.
-        fn visit_bytes<E>(self, mut b: &[u8]) -> Result<Self::Value, E>
+        fn visit_bytes<E>(self, b: &[u8]) -> Result<Self::Value, E>
         where
             E: de::Error,
         {
-            // in compact representation each peer must be 6 bytes
-            // long
-            const ENTRY_LEN: usize = 6;
-            let buf_len = b.len();
-
-            if buf_len % ENTRY_LEN != 0 {
-                return Err(TrackerError::Bencode(BencodeError::InvalidValue(
-                    "peers compact string must be a multiple of 6".into(),
-                )))
-                .map_err(E::custom);
-            }
-
-            let buf_len = b.len();
-            let mut peers = Vec::with_capacity(buf_len / ENTRY_LEN);
-
-            for _ in (0..buf_len).step_by(ENTRY_LEN) {
-                let addr = Ipv4Addr::from(b.get_u32());
-                let port = b.get_u16();
-                let peer = SocketAddr::new(IpAddr::V4(addr), port);
-                peers.push(peer);
-            }
-
-            Ok(peers)
+            parse_compact_peers(b, false).map_err(E::custom)
         }
 
         /// Deserializes a list of dicts containing the peer information.
@@ -546,6 +503,48 @@ where
     deserializer.deserialize_any(Visitor)
 }
 
+/// Deserializes the compact string of IPv6 peers, in which each entry is 18
+/// bytes long: the 16 byte IPv6 address of the peer followed by its 2 byte
+/// port, both in network byte order.
+fn deserialize_peers6<'de, D>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let buf: ByteBuf = de::Deserialize::deserialize(deserializer)?;
+    parse_compact_peers(&buf, true).map_err(de::Error::custom)
+}
+
+/// Parses a compact string of IPv4 or IPv6 peers.
+fn parse_compact_peers(
+    mut buf: &[u8],
+    is_ipv6: bool,
+) -> Result<Vec<SocketAddr>> {
+    // in compact representation each peer must be 6 or 18 bytes long
+    let entry_len = if is_ipv6 { 18 } else { 6 };
+    if buf.len() % entry_len != 0 {
+        return Err(TrackerError::Bencode(BencodeError::InvalidValue(
+            format!("peers compact string must be a multiple of {}", entry_len),
+        )));
+    }
+
+    let mut peers = Vec::with_capacity(buf.len() / entry_len);
+    while buf.has_remaining() {
+        let ip: IpAddr = if is_ipv6 {
+            let mut ip = [0; 16];
+            buf.copy_to_slice(&mut ip);
+            Ipv6Addr::from(ip).into()
+        } else {
+            Ipv4Addr::from(buf.get_u32()).into()
+        };
+        let port = buf.get_u16();
+        peers.push(SocketAddr::new(ip, port));
+    }
+
+    Ok(peers)
+}
+
 /// Deserializes an integer representing seconds into a `Duration`.
 fn deserialize_seconds<'de, D>(
     deserializer: D,
@@ -594,6 +593,38 @@ mod tests {
         assert_eq!(decoded.peers, vec![addr]);
     }
 
+    #[test]
+    fn should_parse_compact_ipv6_peer_list() {
+        #[derive(Deserialize)]
+        struct Peers6Response {
+            #[serde(deserialize_with = "deserialize_peers6")]
+            peers6: Vec<SocketAddr>,
+        }
+
+        let mut encoded = b"d6:peers636:".to_vec();
+        encoded.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        encoded.extend_from_slice(&6881u16.to_be_bytes());
+        encoded.extend_from_slice(
+            &"::ffff:192.168.0.10".parse::<Ipv6Addr>().unwrap().octets(),
+        );
+        encoded.extend_from_slice(&49123u16.to_be_bytes());
+        encoded.push(b'e');
+
+        let decoded: Peers6Response = serde_bencode::from_bytes(&encoded)
+            .expect("cannot decode bencode string of IPv6 peers");
+        assert_eq!(
+            decoded.peers6,
+            vec![
+                "[::1]:6881".parse().unwrap(),
+                "[::ffff:192.168.0.10]:49123".parse().unwrap()
+            ]
+        );
+
+        // entries must be whole
+        let encoded = b"d6:peers66:abcdefe";
+        assert!(serde_bencode::from_bytes::<Peers6Response>(encoded).is_err());
+    }
+
     #[test]
     fn should_parse_full_peer_list() {
         #[derive(Debug, Serialize)]
@@ -639,7 +670,10 @@ mod tests {
     #[tokio::test]
     async fn should_return_peers_on_announce() {
         let addr = mockito::server_url();
-        let tracker = Tracker::new((addr.parse().unwrap(), false));
+        let mut tracker = Tracker::new(TrackerUrl {
+            url: addr.parse().unwrap(),
+            protocol: NetProtocol::HTTP,
+        });
 
         let info_hash_str = "abcdefghij1234567890";
         let mut info_hash = [0; 20];
@@ -652,12 +686,14 @@ mod tests {
         let announce = Announce {
             info_hash,
             peer_id,
+            key: 0x1234,
             port: 16,
             downloaded: 1234,
             uploaded: 1234,
             left: 1234,
             peer_count: Some(2),
             ip: None,
+            ipv6: None,
             event: None,
             tracker_id: None,
         };
@@ -672,6 +708,7 @@ mod tests {
             seeder_count: Some(5),
             leecher_count: Some(3),
             peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
+            peers6: Vec::new(),
         };
 
         let mut encoded_resp = Vec::new();
@@ -710,6 +747,7 @@ mod tests {
                     "numwant".into(),
                     announce.peer_count.unwrap().to_string(),
                 ),
+                Matcher::UrlEncoded("key".into(), "00001234".into()),
             ]))
             .with_status(200)
             .with_body(encoded_resp)
@@ -719,6 +757,131 @@ mod tests {
         assert_eq!(resp, expected_resp);
     }
 
+    #[tokio::test]
+    async fn should_announce_ipv6_address_and_return_ipv6_peers() {
+        let addr = mockito::server_url();
+        let mut tracker = Tracker::new(TrackerUrl {
+            url: format!("{}/ipv6", addr).parse().unwrap(),
+            protocol: NetProtocol::HTTP,
+        });
+
+        let announce = Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 0x1234,
+            port: 6881,
+            downloaded: 0,
+            uploaded: 0,
+            left: 1234,
+            peer_count: None,
+            ip: None,
+            ipv6: Some(Ipv6Addr::LOCALHOST),
+            event: None,
+            tracker_id: None,
+        };
+
+        let mut encoded_resp = b"d8:intervali15e5:peers".to_vec();
+        encoded_resp.extend_from_slice(&encode_compact_peers_list(&[(
+            Ipv4Addr::new(2, 156, 201, 254),
+            49123,
+        )]));
+        encoded_resp.extend_from_slice(b"6:peers618:");
+        encoded_resp.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        encoded_resp.extend_from_slice(&6882u16.to_be_bytes());
+        encoded_resp.push(b'e');
+
+        let _m = mock("GET", "/ipv6")
+            .match_query(Matcher::UrlEncoded("ipv6".into(), "::1".into()))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        // the IPv6 peers are returned along with the IPv4 peers
+        let resp = tracker.announce(announce).await.unwrap();
+        assert_eq!(
+            resp.peers,
+            vec![
+                "2.156.201.254:49123".parse().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+        assert!(resp.peers6.is_empty());
+    }
+
+    #[test]
+    fn should_derive_scrape_url() {
+        let scrape_url = |url: &str| {
+            super::scrape_url(&url.parse().unwrap()).map(|u| u.to_string())
+        };
+        assert_eq!(
+            scrape_url("http://example.com/announce"),
+            Some("http://example.com/scrape".into())
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?key=1"),
+            Some("http://example.com/x/scrape.php?key=1".into())
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn should_return_swarm_stats_on_scrape() {
+        let addr = mockito::server_url();
+        let mut tracker = Tracker::new(TrackerUrl {
+            url: format!("{}/announce", addr).parse().unwrap(),
+            protocol: NetProtocol::HTTP,
+        });
+        assert!(tracker.supports_scrape());
+
+        let info_hash_str = "abcdefghij1234567890";
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(info_hash_str.as_bytes());
+
+        let mut encoded_resp = Vec::new();
+        encoded_resp.extend_from_slice(b"d5:filesd20:");
+        encoded_resp.extend_from_slice(&info_hash);
+        encoded_resp.extend_from_slice(
+            b"d\
+            8:completei5e\
+            10:downloadedi50e\
+            10:incompletei3e\
+            ee\
+            e",
+        );
+
+        let _m = mock("GET", "/scrape")
+            .match_query(Matcher::UrlEncoded(
+                "info_hash".into(),
+                info_hash_str.into(),
+            ))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        let resp = tracker.scrape(info_hash).await.unwrap();
+        assert_eq!(
+            resp,
+            ScrapeResponse {
+                seeder_count: 5,
+                completed_count: 50,
+                leecher_count: 3,
+            }
+        );
+
+        // a torrent the tracker doesn't know has an empty swarm
+        let _m = mock("GET", "/scrape")
+            .match_query(Matcher::UrlEncoded(
+                "info_hash".into(),
+                "\0".repeat(20),
+            ))
+            .with_status(200)
+            .with_body("d5:filesdee")
+            .create();
+        let resp = tracker.scrape([0; 20]).await.unwrap();
+        assert_eq!(resp, ScrapeResponse::default());
+    }
+
     fn encode_compact_peers_list(peers: &[(Ipv4Addr, u16)]) -> Vec<u8> {
         let encoded_peers: Vec<_> = peers
             .into_iter()
@@ -0,0 +1,613 @@
+//! This module contains the client of UDP trackers, as specified in
+//! [BEP 15](http://bittorrent.org/beps/bep_0015.html).
+//!
+//! UDP trackers use a compact binary protocol instead of HTTP. Before
+//! announcing or scraping, the client has to obtain a connection id from the
+//! tracker, which proves that the client owns its IP address. Since UDP is
+//! unreliable, requests that aren't answered in time are retransmitted.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+use reqwest::Url;
+use tokio::{net::UdpSocket, task, time};
+
+use crate::{
+    net,
+    tracker::{
+        Announce, Event, Response, Result, ScrapeResponse, TrackerError,
+    },
+    Sha1Hash,
+};
+
+/// The magic constant sent in connect requests, identifying the protocol.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+/// A connection id may be used for this long after it was received.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+/// A request that isn't answered within `TIMEOUT_BASE * 2 ^ n`, where `n` is
+/// the number of retransmissions so far, is retransmitted.
+const TIMEOUT_BASE: Duration = Duration::from_secs(15);
+
+/// BEP 15 allows retransmitting requests up to 8 times, which would take over
+/// an hour. Since announces block the torrent, we give up much sooner: the
+/// torrent will announce again anyway.
+const MAX_RETRANSMIT_COUNT: u32 = 2;
+
+/// The maximum number of torrents that can be scraped in a single request, so
+/// that the request fits in a single packet.
+const MAX_SCRAPE_COUNT: usize = 74;
+
+/// The largest possible UDP payload.
+const MAX_PACKET_LEN: usize = 65_536;
+
+/// The kind of request or response, sent in each packet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+/// A UDP tracker, along with the state of our connection to it.
+pub(super) struct UdpTracker {
+    url: Url,
+    /// The socket on which we communicate with the tracker and the address of
+    /// the tracker, once resolved.
+    socket: Option<(UdpSocket, SocketAddr)>,
+    /// The connection id obtained from the tracker and the time it was
+    /// received.
+    connection: Option<(u64, Instant)>,
+    /// This is [`TIMEOUT_BASE`], except in tests where it's shortened.
+    timeout_base: Duration,
+}
+
+impl UdpTracker {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            socket: None,
+            connection: None,
+            timeout_base: TIMEOUT_BASE,
+        }
+    }
+
+    /// Announces to the tracker, returning the peers it sent.
+    pub async fn announce(&mut self, params: Announce) -> Result<Response> {
+        let mut payload = Vec::with_capacity(82);
+        payload.put_slice(&params.info_hash);
+        payload.put_slice(&params.peer_id);
+        payload.put_u64(params.downloaded);
+        payload.put_u64(params.left);
+        payload.put_u64(params.uploaded);
+        payload.put_u32(match params.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        });
+        // the field only fits an IPv4 address, otherwise 0 tells the tracker
+        // to use the packet's source address (there is no field for our IPv6
+        // address, so when announcing over IPv6 the tracker always uses the
+        // source address)
+        payload.put_slice(&match params.ip.map(net::canonical_ip) {
+            Some(IpAddr::V4(ip)) => ip.octets(),
+            _ => [0; 4],
+        });
+        payload.put_u32(params.key);
+        // -1 lets the tracker decide how many peers to send
+        payload.put_i32(
+            params
+                .peer_count
+                .map(|count| count.min(i32::MAX as usize) as i32)
+                .unwrap_or(-1),
+        );
+        payload.put_u16(params.port);
+
+        let resp = self.send_request(Action::Announce, &payload).await?;
+        if resp.len() < 12 {
+            return Err(TrackerError::InvalidResponse);
+        }
+        let mut buf = resp.as_slice();
+        let interval = buf.get_u32();
+        let leecher_count = buf.get_u32();
+        let seeder_count = buf.get_u32();
+
+        // the peers' address family is the same as that of the tracker, and
+        // a trailing partial entry is ignored
+        let peer_len = match &self.socket {
+            Some((_, addr)) if addr.is_ipv6() => 18,
+            _ => 6,
+        };
+        let peers = buf.chunks_exact(peer_len).filter_map(parse_peer).collect();
+
+        Ok(Response {
+            tracker_id: None,
+            failure_reason: None,
+            warning_message: None,
+            interval: Some(Duration::from_secs(interval.into())),
+            min_interval: None,
+            seeder_count: Some(seeder_count as usize),
+            leecher_count: Some(leecher_count as usize),
+            peers,
+            peers6: Vec::new(),
+        })
+    }
+
+    /// Requests the swarm statistics of the torrents, returned in the same
+    /// order as the info hashes.
+    pub async fn scrape(
+        &mut self,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<ScrapeResponse>> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for info_hashes in info_hashes.chunks(MAX_SCRAPE_COUNT) {
+            let payload = info_hashes.concat();
+            let resp = self.send_request(Action::Scrape, &payload).await?;
+            if resp.len() < info_hashes.len() * 12 {
+                return Err(TrackerError::InvalidResponse);
+            }
+            let mut buf = resp.as_slice();
+            for _ in info_hashes {
+                stats.push(ScrapeResponse {
+                    seeder_count: buf.get_u32() as usize,
+                    completed_count: buf.get_u32() as usize,
+                    leecher_count: buf.get_u32() as usize,
+                });
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Sends the request, first connecting to the tracker if we don't have
+    /// a valid connection id, and returns the payload of the response.
+    ///
+    /// Requests (including the connect request) that are not answered in time
+    /// are retransmitted with exponentially increasing timeouts.
+    async fn send_request(
+        &mut self,
+        action: Action,
+        payload: &[u8],
+    ) -> Result<Vec<u8>> {
+        let mut retransmit_count = 0;
+        loop {
+            let resp = match self.connection {
+                Some((connection_id, time))
+                    if time.elapsed() < CONNECTION_ID_TTL =>
+                {
+                    self.transact(
+                        connection_id,
+                        action,
+                        payload,
+                        retransmit_count,
+                    )
+                    .await?
+                }
+                _ => {
+                    let resp = self
+                        .transact(
+                            PROTOCOL_ID,
+                            Action::Connect,
+                            &[],
+                            retransmit_count,
+                        )
+                        .await?;
+                    if let Some(resp) = resp {
+                        if resp.len() < 8 {
+                            return Err(TrackerError::InvalidResponse);
+                        }
+                        let connection_id = resp.as_slice().get_u64();
+                        self.connection = Some((connection_id, Instant::now()));
+                        // send the actual request right away
+                        continue;
+                    }
+                    None
+                }
+            };
+
+            match resp {
+                Some(resp) => return Ok(resp),
+                None if retransmit_count < MAX_RETRANSMIT_COUNT => {
+                    retransmit_count += 1;
+                }
+                None => {
+                    // the tracker may have restarted, so get a new connection
+                    // id next time
+                    self.connection = None;
+                    return Err(TrackerError::Timeout);
+                }
+            }
+        }
+    }
+
+    /// Sends a single request and waits for its response, returning its
+    /// payload, or `None` if the response didn't arrive in time.
+    async fn transact(
+        &mut self,
+        connection_id: u64,
+        action: Action,
+        payload: &[u8],
+        retransmit_count: u32,
+    ) -> Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::random();
+        let mut req = Vec::with_capacity(16 + payload.len());
+        req.put_u64(connection_id);
+        req.put_u32(action as u32);
+        req.put_u32(transaction_id);
+        req.put_slice(payload);
+
+        let timeout = self.timeout_base * 2u32.pow(retransmit_count);
+        let (socket, addr) = self.socket().await?;
+        socket.send_to(&req, addr).await?;
+
+        let mut buf = vec![0; MAX_PACKET_LEN];
+        let recv = async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                // ignore stray packets, e.g. late responses to requests that
+                // were retransmitted since
+                if from != addr || len < 8 {
+                    continue;
+                }
+                let mut resp = &buf[..len];
+                let resp_action = resp.get_u32();
+                if resp.get_u32() != transaction_id {
+                    continue;
+                }
+                if resp_action == Action::Error as u32 {
+                    let msg = String::from_utf8_lossy(resp).into_owned();
+                    return Err(TrackerError::Failure(msg));
+                }
+                if resp_action != action as u32 {
+                    return Err(TrackerError::InvalidResponse);
+                }
+                return Ok(resp.to_vec());
+            }
+        };
+        match time::timeout(timeout, recv).await {
+            Ok(resp) => resp.map(Some),
+            Err(_) => {
+                log::debug!("UDP tracker {} request timed out", self.url);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the socket on which to communicate with the tracker and the
+    /// tracker's address, resolving the tracker's host name and binding the
+    /// socket on first use.
+    async fn socket(&mut self) -> Result<(&mut UdpSocket, SocketAddr)> {
+        if self.socket.is_none() {
+            let url = self.url.clone();
+            // resolving the host name may block
+            let addrs = task::spawn_blocking(move || url.socket_addrs(|| None))
+                .await
+                .expect("address resolution task has panicked")?;
+            let addr = *addrs.first().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no tracker address")
+            })?;
+            let local_addr: IpAddr = if addr.is_ipv6() {
+                Ipv6Addr::UNSPECIFIED.into()
+            } else {
+                Ipv4Addr::UNSPECIFIED.into()
+            };
+            // the OS assigns a free port
+            let socket =
+                UdpSocket::bind(SocketAddr::new(local_addr, 0)).await?;
+            self.socket = Some((socket, addr));
+        }
+        let (socket, addr) = self.socket.as_mut().expect("socket not bound");
+        Ok((socket, *addr))
+    }
+}
+
+/// Parses a peer in compact form: its IPv4 or IPv6 address and port, in
+/// network byte order.
+fn parse_peer(mut buf: &[u8]) -> Option<SocketAddr> {
+    let ip: IpAddr = if buf.len() == 18 {
+        let mut ip = [0; 16];
+        buf.copy_to_slice(&mut ip);
+        Ipv6Addr::from(ip).into()
+    } else {
+        Ipv4Addr::from(buf.get_u32()).into()
+    };
+    let port = buf.get_u16();
+    if ip.is_unspecified() || port == 0 {
+        None
+    } else {
+        Some(SocketAddr::new(ip, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    /// The connection id handed out by the tracker stand-in.
+    const CONNECTION_ID: u64 = 0xdead_beef;
+
+    /// Spawns a stand-in for a UDP tracker on the IPv4 loopback address and
+    /// returns its URL.
+    ///
+    /// The handler is passed the action and payload of each request and
+    /// returns the action and payload of the response, or `None` to drop the
+    /// request.
+    async fn spawn_tracker<F>(handler: F) -> Url
+    where
+        F: FnMut(u32, &[u8]) -> Option<(u32, Vec<u8>)> + Send + 'static,
+    {
+        spawn_tracker_on(Ipv4Addr::LOCALHOST.into(), handler).await
+    }
+
+    /// Spawns a stand-in for a UDP tracker on the given address, see
+    /// [`spawn_tracker`].
+    async fn spawn_tracker_on<F>(ip: IpAddr, mut handler: F) -> Url
+    where
+        F: FnMut(u32, &[u8]) -> Option<(u32, Vec<u8>)> + Send + 'static,
+    {
+        let mut socket = UdpSocket::bind((ip, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        task::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET_LEN];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let mut req = &buf[..len];
+                let connection_id = req.get_u64();
+                let action = req.get_u32();
+                let transaction_id = req.get_u32();
+                let expected_connection_id = if action == Action::Connect as u32
+                {
+                    PROTOCOL_ID
+                } else {
+                    CONNECTION_ID
+                };
+                if connection_id != expected_connection_id {
+                    continue;
+                }
+                if let Some((action, payload)) = handler(action, req) {
+                    let mut resp = Vec::new();
+                    resp.put_u32(action);
+                    resp.put_u32(transaction_id);
+                    resp.put_slice(&payload);
+                    socket.send_to(&resp, &from).await.unwrap();
+                }
+            }
+        });
+        format!("udp://{}/announce", addr).parse().unwrap()
+    }
+
+    fn connect_response() -> Option<(u32, Vec<u8>)> {
+        Some((Action::Connect as u32, CONNECTION_ID.to_be_bytes().to_vec()))
+    }
+
+    fn announce_params() -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            key: 3,
+            port: 6881,
+            ip: None,
+            ipv6: None,
+            downloaded: 10,
+            uploaded: 20,
+            left: 30,
+            peer_count: Some(50),
+            tracker_id: None,
+            event: Some(Event::Started),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_announce_and_reuse_connection_id() {
+        let connect_count = Arc::new(AtomicUsize::new(0));
+        let url = spawn_tracker({
+            let connect_count = Arc::clone(&connect_count);
+            move |action, mut req| {
+                if action == Action::Connect as u32 {
+                    connect_count.fetch_add(1, Ordering::SeqCst);
+                    return connect_response();
+                }
+                assert_eq!(action, Action::Announce as u32);
+                assert_eq!(req.len(), 82);
+                assert_eq!(&req[..20], &[1; 20]);
+                assert_eq!(&req[20..40], &[2; 20]);
+                req.advance(40);
+                assert_eq!(req.get_u64(), 10);
+                assert_eq!(req.get_u64(), 30);
+                assert_eq!(req.get_u64(), 20);
+                assert_eq!(req.get_u32(), 2);
+                assert_eq!(req.get_u32(), 0);
+                assert_eq!(req.get_u32(), 3);
+                assert_eq!(req.get_i32(), 50);
+                assert_eq!(req.get_u16(), 6881);
+
+                let mut resp = Vec::new();
+                resp.put_u32(1800);
+                resp.put_u32(3);
+                resp.put_u32(5);
+                resp.put_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                resp.put_slice(&[10, 0, 0, 2, 0x1a, 0xe2]);
+                // a truncated entry is ignored
+                resp.put_slice(&[10, 0, 0]);
+                Some((Action::Announce as u32, resp))
+            }
+        })
+        .await;
+
+        let mut tracker = UdpTracker::new(url);
+        let resp = tracker.announce(announce_params()).await.unwrap();
+        assert_eq!(resp.interval, Some(Duration::from_secs(1800)));
+        assert_eq!(resp.leecher_count, Some(3));
+        assert_eq!(resp.seeder_count, Some(5));
+        assert_eq!(
+            resp.peers,
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+
+        // the connection id is reused while it's valid
+        tracker.announce(announce_params()).await.unwrap();
+        assert_eq!(connect_count.load(Ordering::SeqCst), 1);
+
+        // and a new one is requested once it expires
+        let (connection_id, _) = tracker.connection.unwrap();
+        tracker.connection = Some((
+            connection_id,
+            Instant::now().checked_sub(CONNECTION_ID_TTL).unwrap(),
+        ));
+        tracker.announce(announce_params()).await.unwrap();
+        assert_eq!(connect_count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn should_announce_to_ipv6_tracker() {
+        let url = spawn_tracker_on(Ipv6Addr::LOCALHOST.into(), |action, _| {
+            if action == Action::Connect as u32 {
+                return connect_response();
+            }
+            let mut resp = Vec::new();
+            resp.put_u32(1800);
+            resp.put_u32(0);
+            resp.put_u32(1);
+            // peers of IPv6 trackers are 18 bytes long
+            resp.put_slice(&Ipv6Addr::LOCALHOST.octets());
+            resp.put_u16(6881);
+            Some((Action::Announce as u32, resp))
+        })
+        .await;
+        assert_eq!(url.host_str(), Some("[::1]"));
+
+        let mut tracker = UdpTracker::new(url);
+        let resp = tracker.announce(announce_params()).await.unwrap();
+        assert_eq!(resp.seeder_count, Some(1));
+        assert_eq!(resp.peers, vec!["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn should_retransmit_unanswered_requests() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let url = spawn_tracker({
+            let request_count = Arc::clone(&request_count);
+            move |action, _| {
+                // drop the first connect and the first announce request
+                let count = request_count.fetch_add(1, Ordering::SeqCst);
+                if count == 0 || count == 2 {
+                    None
+                } else if action == Action::Connect as u32 {
+                    connect_response()
+                } else {
+                    Some((Action::Announce as u32, vec![0; 12]))
+                }
+            }
+        })
+        .await;
+
+        let mut tracker = UdpTracker::new(url);
+        tracker.timeout_base = Duration::from_millis(50);
+        let resp = tracker.announce(announce_params()).await.unwrap();
+        assert!(resp.peers.is_empty());
+        assert_eq!(request_count.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn should_time_out_if_tracker_does_not_respond() {
+        let request_count = Arc::new(AtomicUsize::new(0));
+        let url = spawn_tracker({
+            let request_count = Arc::clone(&request_count);
+            move |_, _| {
+                request_count.fetch_add(1, Ordering::SeqCst);
+                None
+            }
+        })
+        .await;
+
+        let mut tracker = UdpTracker::new(url);
+        tracker.timeout_base = Duration::from_millis(10);
+        match tracker.announce(announce_params()).await {
+            Err(TrackerError::Timeout) => (),
+            _ => panic!("announce should time out"),
+        }
+        assert_eq!(
+            request_count.load(Ordering::SeqCst),
+            MAX_RETRANSMIT_COUNT as usize + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn should_return_tracker_error() {
+        let url = spawn_tracker(|action, _| {
+            if action == Action::Connect as u32 {
+                connect_response()
+            } else {
+                Some((Action::Error as u32, b"unregistered torrent".to_vec()))
+            }
+        })
+        .await;
+
+        let mut tracker = UdpTracker::new(url);
+        match tracker.announce(announce_params()).await {
+            Err(TrackerError::Failure(msg)) => {
+                assert_eq!(msg, "unregistered torrent")
+            }
+            _ => panic!("announce should fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn should_scrape() {
+        let url = spawn_tracker(|action, req| {
+            if action == Action::Connect as u32 {
+                return connect_response();
+            }
+            assert_eq!(action, Action::Scrape as u32);
+            assert_eq!(req, [[1; 20], [2; 20]].concat().as_slice());
+            let mut resp = Vec::new();
+            for count in &[[5, 10, 3], [0, 1, 2]] {
+                for n in count {
+                    resp.put_u32(*n);
+                }
+            }
+            Some((Action::Scrape as u32, resp))
+        })
+        .await;
+
+        let mut tracker = UdpTracker::new(url);
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeResponse {
+                    seeder_count: 5,
+                    completed_count: 10,
+                    leecher_count: 3,
+                },
+                ScrapeResponse {
+                    seeder_count: 0,
+                    completed_count: 1,
+                    leecher_count: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn should_parse_ipv6_peers() {
+        let mut buf = Ipv6Addr::LOCALHOST.octets().to_vec();
+        buf.put_u16(6881);
+        assert_eq!(parse_peer(&buf), Some("[::1]:6881".parse().unwrap()));
+        assert_eq!(parse_peer(&[0, 0, 0, 0, 0x1a, 0xe1]), None);
+    }
+}